@@ -46,7 +46,7 @@ fn main() {
     };
 
     vec.par_iter().for_each(|k| {
-        let mut storage = MerkleStorage::new(db.clone());
+        let mut storage = MerkleStorage::new(db.clone()).expect("incompatible database");
         for i in 1..n {
             let mut rng = rand::thread_rng();
             let mut v: Vec<u8> = (1..5).collect();
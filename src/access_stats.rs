@@ -0,0 +1,138 @@
+//! Sampled access statistics by top-level prefix.
+//!
+//! When enabled by [MerkleStorageConfig::access_stats](crate::merkle_storage::MerkleStorageConfig::access_stats),
+//! every n-th read and write of [MerkleStorage](crate::merkle_storage::MerkleStorage) is recorded
+//! with the first fragment of its key. Samples older than the configured window are dropped, so
+//! the report ranks prefixes by their recent load, e.g. to decide what to cache or to shard.
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::merkle_storage::ContextKey;
+
+/// How accesses are sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccessStatsConfig {
+    /// Record one of every `sample_every` accesses, at least 1
+    pub sample_every: u64,
+    /// Samples older than this many seconds are not reported
+    pub window: u64,
+}
+
+impl Default for AccessStatsConfig {
+    fn default() -> Self {
+        AccessStatsConfig { sample_every: 16, window: 60 }
+    }
+}
+
+/// Kind of a sampled access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Estimated accesses of keys under a top-level prefix within the window, sampled counts are
+/// scaled up by [AccessStatsConfig::sample_every]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrefixAccess {
+    /// first fragment of accessed keys, empty for the root
+    pub prefix: String,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// Sampled accesses within the window, oldest first
+pub(crate) struct AccessStats {
+    config: AccessStatsConfig,
+    state: Mutex<SampledAccesses>,
+}
+
+#[derive(Default)]
+struct SampledAccesses {
+    accesses: u64,
+    samples: VecDeque<(u64, String, AccessKind)>,
+}
+
+impl AccessStats {
+    pub(crate) fn new(config: AccessStatsConfig) -> Self {
+        AccessStats { config, state: Mutex::new(SampledAccesses::default()) }
+    }
+
+    /// Count access to `key` at `time`, which is sampled if it is the n-th one
+    pub(crate) fn record(&self, key: &ContextKey, kind: AccessKind, time: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.accesses += 1;
+        if !state.accesses.is_multiple_of(self.config.sample_every.max(1)) {
+            return;
+        }
+        Self::expire(&mut state.samples, self.config.window, time);
+        let prefix = key.first().cloned().unwrap_or_default();
+        state.samples.push_back((time, prefix, kind));
+    }
+
+    /// Up to `limit` prefixes accessed the most within the window ending at `time`, ties are
+    /// ordered by prefix
+    pub(crate) fn hottest(&self, limit: usize, time: u64) -> Vec<PrefixAccess> {
+        let mut state = self.state.lock().unwrap();
+        Self::expire(&mut state.samples, self.config.window, time);
+
+        let scale = self.config.sample_every.max(1);
+        let mut by_prefix: HashMap<&str, PrefixAccess> = HashMap::new();
+        for (_, prefix, kind) in &state.samples {
+            let access = by_prefix.entry(prefix)
+                .or_insert_with(|| PrefixAccess { prefix: prefix.clone(), reads: 0, writes: 0 });
+            match kind {
+                AccessKind::Read => access.reads += scale,
+                AccessKind::Write => access.writes += scale,
+            }
+        }
+
+        let mut ranked: Vec<PrefixAccess> = by_prefix.into_values().collect();
+        ranked.sort_by(|a, b| (b.reads + b.writes).cmp(&(a.reads + a.writes)).then_with(|| a.prefix.cmp(&b.prefix)));
+        ranked.truncate(limit);
+        ranked
+    }
+
+    fn expire(samples: &mut VecDeque<(u64, String, AccessKind)>, window: u64, time: u64) {
+        while matches!(samples.front(), Some((sampled, _, _)) if sampled + window <= time) {
+            samples.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(prefix: &str) -> ContextKey {
+        vec![prefix.to_string(), "x".to_string()]
+    }
+
+    #[test]
+    fn test_access_stats() {
+        let stats = AccessStats::new(AccessStatsConfig { sample_every: 2, window: 10 });
+        for _ in 0..4 {
+            stats.record(&key("a"), AccessKind::Write, 0);
+        }
+        for _ in 0..6 {
+            stats.record(&key("b"), AccessKind::Read, 5);
+        }
+        stats.record(&vec![], AccessKind::Read, 5);
+        stats.record(&vec![], AccessKind::Read, 5);
+
+        assert_eq!(vec![
+            PrefixAccess { prefix: "b".to_string(), reads: 6, writes: 0 },
+            PrefixAccess { prefix: "a".to_string(), reads: 0, writes: 4 },
+            PrefixAccess { prefix: "".to_string(), reads: 2, writes: 0 },
+        ], stats.hottest(10, 5));
+        assert_eq!(1, stats.hottest(1, 5).len());
+
+        // samples of `a` are out of the window
+        let hottest = stats.hottest(10, 10);
+        assert_eq!(2, hottest.len());
+        assert!(hottest.iter().all(|access| access.prefix != "a"));
+        assert!(stats.hottest(10, 15).is_empty());
+    }
+}
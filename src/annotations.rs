@@ -0,0 +1,49 @@
+//! Change summaries of commits.
+//!
+//! When enabled, every commit stores a summary of keys it changed compared to its parent, so
+//! explorers can show the size of each block's change without diffing contexts again.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type AnnotationKV = dyn KeyValueStoreWithSchema<AnnotationSchema> + Sync + Send;
+
+/// Keys changed by a commit, i.e. added, modified or deleted values
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitAnnotation {
+    pub changed_keys: u64,
+    /// changed keys by their first fragment, e.g. `data` or `rolls`
+    pub changed_keys_by_domain: BTreeMap<String, u64>,
+}
+
+impl BincodeEncoded for CommitAnnotation {}
+
+impl CommitAnnotation {
+    pub(crate) fn add_changed_key(&mut self, domain: &str) {
+        self.changed_keys += 1;
+        *self.changed_keys_by_domain.entry(domain.to_string()).or_insert(0) += 1;
+    }
+}
+
+/// Change summaries keyed by commit hash
+pub struct AnnotationSchema;
+
+impl KeyValueSchema for AnnotationSchema {
+    type Key = EntryHash;
+    type Value = CommitAnnotation;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_annotations"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,43 @@
+//! Wall-clock metrics of applying blocks.
+//!
+//! Embedders may record how long applying a block took and how much work it did, next to the
+//! commit the block produced. Metrics are returned by the commit log, so performance of the
+//! whole chain can be analyzed from the database alone.
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type ApplyMetricsKV = dyn KeyValueStoreWithSchema<ApplyMetricsSchema> + Sync + Send;
+
+/// Cost of applying a block, as measured by the embedder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApplyMetrics {
+    pub duration_micros: u64,
+    /// number of context reads done by the block
+    pub reads: u64,
+    /// number of context writes (sets, deletes, copies) done by the block
+    pub writes: u64,
+}
+
+impl BincodeEncoded for ApplyMetrics {}
+
+/// Apply metrics keyed by hash of the commit made by the block
+pub struct ApplyMetricsSchema;
+
+impl KeyValueSchema for ApplyMetricsSchema {
+    type Key = EntryHash;
+    type Value = ApplyMetrics;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_apply_metrics"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,56 @@
+//! Audit log of destructive operations.
+//!
+//! Every maintenance action, which removes data from the store (e.g. deleting a ref or garbage
+//! collection), appends a record with its time, parameters and summary counts. Records are keyed
+//! by a sequence number and never modified, so the log can be exported for operational forensics.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::schema::KeyValueSchema;
+
+pub type AuditLogKV = dyn KeyValueStoreWithSchema<AuditLogSchema> + Sync + Send;
+
+/// Destructive operation recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DestructiveOperation {
+    /// [MerkleStorage::delete_ref](crate::prelude::MerkleStorage::delete_ref)
+    DeleteRef,
+    /// [MerkleStorage::gc](crate::prelude::MerkleStorage::gc)
+    GarbageCollection,
+    /// [MerkleStorage::delete_commit](crate::prelude::MerkleStorage::delete_commit)
+    DeleteCommit,
+}
+
+/// Single destructive operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// seconds since unix epoch
+    pub time: u64,
+    pub operation: DestructiveOperation,
+    pub parameters: BTreeMap<String, String>,
+    /// summary counts, e.g. number of removed items
+    pub counts: BTreeMap<String, u64>,
+}
+
+impl BincodeEncoded for AuditRecord {}
+
+/// Audit records keyed by sequence number, in order of their appending
+pub struct AuditLogSchema;
+
+impl KeyValueSchema for AuditLogSchema {
+    type Key = u64;
+    type Value = AuditRecord;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_audit_log"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,116 @@
+//! Databases [MerkleStorage] can keep its data in.
+//!
+//! [MerkleStorage::with_config] accepts any [MerkleBackend]: a store of every schema the
+//! storage uses, which also applies a [MultiSchemaBatch] atomically. [SledDBWrapper] and
+//! [InMemoryStore] implement it, other databases, e.g. RocksDB, can be plugged in by downstream
+//! crates, which read the batch through [MultiSchemaBatch::writes] and
+//! [MultiSchemaBatch::expectations].
+use sled::IVec;
+
+use crate::annotations::AnnotationSchema;
+use crate::apply_metrics::ApplyMetricsSchema;
+use crate::audit_log::AuditLogSchema;
+use crate::block_contexts::BlockContextSchema;
+use crate::change_filter::ChangeFilterSchema;
+use crate::database::{DBError, KeyValueStoreWithSchema, SledDBWrapper};
+use crate::hash_index::HashIndexSchema;
+use crate::key_history::{CommitHeightSchema, KeyHistorySchema};
+use crate::memory_store::InMemoryStore;
+use crate::merkle_storage::MerkleStorage;
+use crate::metadata::MetadataSchema;
+use crate::ref_counts::RefCountSchema;
+use crate::refs::RefSchema;
+use crate::schema::KeyValueSchema;
+use crate::staging_journal::StagingJournalSchema;
+use crate::tombstones::{TombstoneExpirySchema, TombstoneSchema};
+use crate::transaction::MultiSchemaBatch;
+use crate::value_index::ValueHashIndexSchema;
+use crate::value_transform::TransformKind;
+
+/// Entries in their plain form, each with the size of its stored form
+pub type StoredEntries<'a> = Box<dyn Iterator<Item = Result<(IVec, usize), DBError>> + 'a>;
+
+/// Store of all schemas of [MerkleStorage]
+pub trait MerkleBackend: KeyValueStoreWithSchema<MerkleStorage>
+    + KeyValueStoreWithSchema<TombstoneSchema>
+    + KeyValueStoreWithSchema<TombstoneExpirySchema>
+    + KeyValueStoreWithSchema<HashIndexSchema>
+    + KeyValueStoreWithSchema<MetadataSchema>
+    + KeyValueStoreWithSchema<RefSchema>
+    + KeyValueStoreWithSchema<AnnotationSchema>
+    + KeyValueStoreWithSchema<AuditLogSchema>
+    + KeyValueStoreWithSchema<ValueHashIndexSchema>
+    + KeyValueStoreWithSchema<ApplyMetricsSchema>
+    + KeyValueStoreWithSchema<RefCountSchema>
+    + KeyValueStoreWithSchema<ChangeFilterSchema>
+    + KeyValueStoreWithSchema<KeyHistorySchema>
+    + KeyValueStoreWithSchema<CommitHeightSchema>
+    + KeyValueStoreWithSchema<StagingJournalSchema>
+    + KeyValueStoreWithSchema<BlockContextSchema>
+    + Send + Sync
+{
+    /// Apply all writes of `batch` or none of them. Fails with
+    /// [DBError::BatchPreconditionFailed] if a key does not hold the value the batch expects.
+    fn apply_multi(&self, batch: MultiSchemaBatch) -> Result<(), DBError>;
+
+    /// Iterate over all entries, see [MerkleStorage::compression_stats]. Stored form differs
+    /// from the plain one if the backend transforms values, e.g. compresses them.
+    fn stored_entries(&self) -> Result<StoredEntries<'_>, DBError>;
+
+    /// Stages values of entries pass through when stored, recorded in the database header
+    fn entry_transforms(&self) -> Vec<TransformKind> {
+        Vec::new()
+    }
+
+    /// Make all writes durable, see
+    /// [MerkleStorageConfig::sync_staging_journal](crate::merkle_storage::MerkleStorageConfig::sync_staging_journal)
+    fn flush(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+
+    /// Flush all writes and stop background work, see [MerkleStorage::close]
+    fn close(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+}
+
+impl MerkleBackend for SledDBWrapper {
+    fn apply_multi(&self, batch: MultiSchemaBatch) -> Result<(), DBError> {
+        SledDBWrapper::apply_multi(self, batch)
+    }
+
+    fn stored_entries(&self) -> Result<StoredEntries<'_>, DBError> {
+        let entries = self.stored_values::<MerkleStorage>()?.map(move |stored| {
+            let stored = stored?;
+            let stored_len = stored.len();
+            Ok((self.decode_stored::<MerkleStorage>(stored)?, stored_len))
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn entry_transforms(&self) -> Vec<TransformKind> {
+        self.value_pipeline::<MerkleStorage>().map_or_else(Vec::new, |pipeline| pipeline.kinds())
+    }
+
+    fn flush(&self) -> Result<(), DBError> {
+        SledDBWrapper::flush(self).map(|_| ())
+    }
+
+    fn close(&self) -> Result<(), DBError> {
+        self.shutdown().map(|_| ())
+    }
+}
+
+impl MerkleBackend for InMemoryStore {
+    fn apply_multi(&self, batch: MultiSchemaBatch) -> Result<(), DBError> {
+        InMemoryStore::apply_multi(self, batch)
+    }
+
+    fn stored_entries(&self) -> Result<StoredEntries<'_>, DBError> {
+        let entries = self.values_of_tree(<MerkleStorage as KeyValueSchema>::tree_name());
+        Ok(Box::new(entries.into_iter().map(|value| {
+            let len = value.len();
+            Ok((IVec::from(value), len))
+        })))
+    }
+}
@@ -0,0 +1,96 @@
+//! External storage of huge values.
+//!
+//! Values above a threshold set by [MerkleStorage::set_blob_sink](crate::merkle_storage::MerkleStorage::set_blob_sink)
+//! are handed over to a [BlobSink] on commit, the database keeps only their hash and length.
+//! Hashes of values, trees and commits are the same as if the values were stored inline.
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use failure::Fail;
+
+use crate::merkle_storage::EntryHash;
+
+#[derive(Debug, Fail)]
+pub enum BlobSinkError {
+    #[fail(display = "Blob sink I/O error: {}", error)]
+    IoError { error: io::Error },
+    #[fail(display = "Blob {} is not in the sink", hash)]
+    NotFound { hash: String },
+}
+
+impl From<io::Error> for BlobSinkError {
+    fn from(error: io::Error) -> Self {
+        BlobSinkError::IoError { error }
+    }
+}
+
+/// Content addressed store of values, keyed by their [value_hash](crate::merkle_storage::value_hash)
+pub trait BlobSink: Send + Sync {
+    /// Store `value` under `hash`. Storing the same value twice must succeed.
+    fn put(&self, hash: &EntryHash, value: &[u8]) -> Result<(), BlobSinkError>;
+
+    /// Open value stored under `hash` for reading.
+    fn reader(&self, hash: &EntryHash) -> Result<Box<dyn Read + Send>, BlobSinkError>;
+}
+
+/// [BlobSink] keeping every value in its own file named by the hex encoded hash
+pub struct FsBlobSink {
+    dir: PathBuf,
+}
+
+impl FsBlobSink {
+    /// Use directory `dir`, which is created if missing.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self, BlobSinkError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FsBlobSink { dir })
+    }
+
+    fn path(&self, hash: &EntryHash) -> PathBuf {
+        self.dir.join(hex::encode(hash))
+    }
+}
+
+impl BlobSink for FsBlobSink {
+    fn put(&self, hash: &EntryHash, value: &[u8]) -> Result<(), BlobSinkError> {
+        let path = self.path(hash);
+        if path.exists() {
+            return Ok(());
+        }
+        // readers never see a partially written file
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, value)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn reader(&self, hash: &EntryHash) -> Result<Box<dyn Read + Send>, BlobSinkError> {
+        match File::open(self.path(hash)) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Err(BlobSinkError::NotFound { hash: hex::encode(hash) }),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_blob_sink() -> Result<(), BlobSinkError> {
+        let dir = std::env::temp_dir().join(format!("_fs_blob_sink_test_{}", std::process::id()));
+        let sink = FsBlobSink::new(&dir)?;
+        sink.put(&[1; 32], b"value")?;
+        sink.put(&[1; 32], b"value")?;
+
+        let mut value = Vec::new();
+        sink.reader(&[1; 32])?.read_to_end(&mut value)?;
+        assert_eq!(b"value".to_vec(), value);
+        assert!(matches!(sink.reader(&[2; 32]), Err(BlobSinkError::NotFound { .. })));
+
+        let _ = fs::remove_dir_all(&dir);
+        Ok(())
+    }
+}
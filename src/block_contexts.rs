@@ -0,0 +1,30 @@
+//! Contexts committed for blocks.
+//!
+//! [MerkleStorage::commit_block](crate::merkle_storage::MerkleStorage::commit_block) records the
+//! commit made for a block in the same batch as the commit itself, so no block is left without
+//! its context after a crash. Unlike refs, records do not keep their commits from being
+//! collected, so a node applying every block does not pin the whole chain.
+use crate::database::KeyValueStoreWithSchema;
+use crate::hash::BlockHash;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type BlockContextKV = dyn KeyValueStoreWithSchema<BlockContextSchema> + Sync + Send;
+
+/// Commit hashes keyed by hash of the block they were committed for
+pub struct BlockContextSchema;
+
+impl KeyValueSchema for BlockContextSchema {
+    type Key = BlockHash;
+    type Value = EntryHash;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_block_contexts"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,109 @@
+//! Summaries of prefixes changed by commits.
+//!
+//! When enabled, every commit stores a small bloom filter of the prefixes (up to
+//! [CHANGE_FILTER_DEPTH] fragments) of keys it changed. Queries for commits touching a prefix,
+//! like [MerkleStorage::commits_touching](crate::merkle_storage::MerkleStorage::commits_touching),
+//! skip commits ruled out by their filter without diffing their trees. Filters may report
+//! prefixes, which were not changed, but never miss a changed one.
+use serde::{Deserialize, Serialize};
+
+use crate::blake2b;
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+/// Deepest prefix of changed keys recorded, deeper prefixes are matched by their ancestor
+pub const CHANGE_FILTER_DEPTH: usize = 3;
+
+/// Size of a filter in bits
+pub const CHANGE_FILTER_BITS: usize = 1024;
+
+/// Number of bits set for every recorded prefix
+const CHANGE_FILTER_HASHES: usize = 4;
+
+pub type ChangeFilterKV = dyn KeyValueStoreWithSchema<ChangeFilterSchema> + Sync + Send;
+
+/// Bloom filter of prefixes of keys changed by a commit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeFilter(Vec<u8>);
+
+impl BincodeEncoded for ChangeFilter {}
+
+impl Default for ChangeFilter {
+    fn default() -> Self {
+        ChangeFilter(vec![0; CHANGE_FILTER_BITS / 8])
+    }
+}
+
+impl ChangeFilter {
+    /// Record change of value under `key`
+    pub fn add_key(&mut self, key: &[String]) {
+        for depth in 1..=key.len().min(CHANGE_FILTER_DEPTH) {
+            for bit in bit_positions(&key[..depth]) {
+                self.0[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+    }
+
+    /// Whether a value under `prefix` may have changed, `false` is always right
+    pub fn may_touch(&self, prefix: &[String]) -> bool {
+        if prefix.is_empty() {
+            return true;
+        }
+        bit_positions(&prefix[..prefix.len().min(CHANGE_FILTER_DEPTH)])
+            .all(|bit| self.0[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+fn bit_positions(prefix: &[String]) -> impl Iterator<Item = usize> {
+    // bincode encoding of fragments is unambiguous, unlike joined paths
+    let digest = blake2b::digest_256(&bincode::serialize(prefix).unwrap_or_default());
+    (0..CHANGE_FILTER_HASHES).map(move |i| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&digest[i * 4..i * 4 + 4]);
+        u32::from_le_bytes(bytes) as usize % CHANGE_FILTER_BITS
+    })
+}
+
+/// Change filters keyed by commit hash
+pub struct ChangeFilterSchema;
+
+impl KeyValueSchema for ChangeFilterSchema {
+    type Key = EntryHash;
+    type Value = ChangeFilter;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_change_filters"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &str) -> Vec<String> {
+        path.split('/').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_change_filter() {
+        let mut filter = ChangeFilter::default();
+        filter.add_key(&key("data/rolls/owner/current/1"));
+        filter.add_key(&key("data/votes"));
+
+        for prefix in &["data", "data/rolls", "data/rolls/owner", "data/rolls/owner/snapshot", "data/votes"] {
+            assert!(filter.may_touch(&key(prefix)), "{}", prefix);
+        }
+        assert!(filter.may_touch(&[]));
+        assert!(!filter.may_touch(&key("data/contracts")));
+        assert!(!filter.may_touch(&key("rolls")));
+        assert!(!ChangeFilter::default().may_touch(&key("data")));
+    }
+}
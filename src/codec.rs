@@ -130,7 +130,22 @@ impl<K, V> BincodeEncoded for BTreeMap<K, V>
           V: Serialize + for<'a> Deserialize<'a>
 {}
 
-impl BincodeEncoded for () {}
+/// Unit values are stored as empty values, so schemas with `()` values are sets of keys
+impl Encoder for () {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        Ok(Vec::new())
+    }
+}
+
+impl Decoder for () {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
 
 #[macro_export(local_inner_macros)]
 macro_rules! num_from_slice {
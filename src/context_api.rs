@@ -0,0 +1,218 @@
+//! Adapter for the context API of the tezedge node.
+//!
+//! [ContextApi] mirrors `storage::context::ContextApi` of tezedge, with the same hash types
+//! (byte vectors) and semantics. The node implements its own trait for [SledContext] by
+//! forwarding every call, which lets this store replace the RocksDB backed context without
+//! changes to the protocol runner or the RPCs. Built only with the `context-api` feature.
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+
+use failure::Fail;
+
+use crate::hash::{BlockHash, ContextHash};
+use crate::merkle_storage::{ContextKey, ContextKeyValues, ContextValue, EntryHash, MerkleError, MerkleStorage, MerkleStorageStats};
+
+#[derive(Debug, Fail)]
+pub enum ContextError {
+    #[fail(display = "Merkle storage error: {}", error)]
+    MerkleStorageError { error: MerkleError },
+    #[fail(display = "Invalid context hash: {}", hash)]
+    InvalidContextHash { hash: String },
+    #[fail(display = "Context hash mismatch, expected {}, computed {}", expected, computed)]
+    ContextHashMismatch { expected: String, computed: String },
+    #[fail(display = "Invalid commit date: {}", date)]
+    InvalidDate { date: i64 },
+    #[fail(display = "Merkle storage lock is poisoned")]
+    LockPoisoned,
+}
+
+impl From<MerkleError> for ContextError {
+    fn from(error: MerkleError) -> Self {
+        match error {
+            MerkleError::CommitHashMismatch { expected, computed } => ContextError::ContextHashMismatch { expected, computed },
+            error => ContextError::MerkleStorageError { error },
+        }
+    }
+}
+
+/// Context operations called by the protocol runner and the RPCs of the node
+pub trait ContextApi {
+    /// Set key/value in the working tree. `context_hash` is the context the block is applied
+    /// on, it is not checked.
+    fn set(&self, context_hash: &Option<ContextHash>, key: &ContextKey, value: &ContextValue) -> Result<(), ContextError>;
+
+    /// Switch the working tree to context `context_hash`, dropping uncommitted changes.
+    fn checkout(&self, context_hash: &ContextHash) -> Result<(), ContextError>;
+
+    /// Commit the working tree as the context of `block_hash`. Commit hash computed by the store
+    /// must equal `new_context_hash` computed by the protocol, otherwise nothing is committed and
+    /// the working tree is left intact.
+    fn commit(&self, block_hash: &BlockHash, parent_context_hash: &Option<ContextHash>, new_context_hash: &ContextHash,
+              author: String, message: String, date: i64) -> Result<(), ContextError>;
+
+    /// Delete value or subtree under `key_prefix_to_delete` from the working tree.
+    fn delete_to_diff(&self, context_hash: &Option<ContextHash>, key_prefix_to_delete: &ContextKey) -> Result<(), ContextError>;
+
+    /// Delete value or subtree under `key_prefix_to_remove` from the working tree.
+    fn remove_recursively_to_diff(&self, context_hash: &Option<ContextHash>, key_prefix_to_remove: &ContextKey) -> Result<(), ContextError>;
+
+    /// Copy value or subtree `from_key` to `to_key` in the working tree.
+    fn copy_to_diff(&self, context_hash: &Option<ContextHash>, from_key: &ContextKey, to_key: &ContextKey) -> Result<(), ContextError>;
+
+    /// Get value from the working tree.
+    fn get_key(&self, key: &ContextKey) -> Result<ContextValue, ContextError>;
+
+    /// Get value from context `context_hash`, `None` if the key or the context is missing.
+    fn get_key_from_history(&self, context_hash: &ContextHash, key: &ContextKey) -> Result<Option<ContextValue>, ContextError>;
+
+    /// Get all key/values under `prefix` in context `context_hash`.
+    fn get_key_values_by_prefix(&self, context_hash: &ContextHash, prefix: &ContextKey) -> Result<Option<ContextKeyValues>, ContextError>;
+
+    fn get_last_commit_hash(&self) -> Option<Vec<u8>>;
+
+    fn get_merkle_stats(&self) -> Result<MerkleStorageStats, ContextError>;
+}
+
+/// [ContextApi] over a shared [MerkleStorage]. Context of every committed block is recorded
+/// together with its commit, see [SledContext::get_context_hash_by_block].
+#[derive(Clone)]
+pub struct SledContext {
+    merkle: Arc<RwLock<MerkleStorage>>,
+}
+
+impl SledContext {
+    pub fn new(merkle: Arc<RwLock<MerkleStorage>>) -> Self {
+        SledContext { merkle }
+    }
+
+    /// Get context committed for block `block_hash`.
+    pub fn get_context_hash_by_block(&self, block_hash: &BlockHash) -> Result<Option<ContextHash>, ContextError> {
+        self.read(|merkle| Ok(merkle.get_block_context(block_hash)?.map(|hash| hash.to_vec())))
+    }
+
+    fn write<R, F: FnOnce(&mut MerkleStorage) -> Result<R, MerkleError>>(&self, f: F) -> Result<R, ContextError> {
+        let mut merkle = self.merkle.write().map_err(|_| ContextError::LockPoisoned)?;
+        Ok(f(&mut merkle)?)
+    }
+
+    fn read<R, F: FnOnce(&MerkleStorage) -> Result<R, MerkleError>>(&self, f: F) -> Result<R, ContextError> {
+        let merkle = self.merkle.read().map_err(|_| ContextError::LockPoisoned)?;
+        Ok(f(&merkle)?)
+    }
+}
+
+impl ContextApi for SledContext {
+    fn set(&self, _context_hash: &Option<ContextHash>, key: &ContextKey, value: &ContextValue) -> Result<(), ContextError> {
+        self.write(|merkle| merkle.set(key, value))
+    }
+
+    fn checkout(&self, context_hash: &ContextHash) -> Result<(), ContextError> {
+        let context_hash = to_entry_hash(context_hash)?;
+        self.write(|merkle| merkle.checkout(&context_hash))
+    }
+
+    fn commit(&self, block_hash: &BlockHash, _parent_context_hash: &Option<ContextHash>, new_context_hash: &ContextHash,
+              author: String, message: String, date: i64) -> Result<(), ContextError> {
+        let expected = to_entry_hash(new_context_hash)?;
+        let time = date.try_into().map_err(|_| ContextError::InvalidDate { date })?;
+        self.write(|merkle| merkle.commit_block(block_hash, time, author, message, &expected).map(|_| ()))
+    }
+
+    fn delete_to_diff(&self, _context_hash: &Option<ContextHash>, key_prefix_to_delete: &ContextKey) -> Result<(), ContextError> {
+        self.write(|merkle| merkle.delete(key_prefix_to_delete))
+    }
+
+    fn remove_recursively_to_diff(&self, _context_hash: &Option<ContextHash>, key_prefix_to_remove: &ContextKey) -> Result<(), ContextError> {
+        self.write(|merkle| merkle.delete_recursively(key_prefix_to_remove))
+    }
+
+    fn copy_to_diff(&self, _context_hash: &Option<ContextHash>, from_key: &ContextKey, to_key: &ContextKey) -> Result<(), ContextError> {
+        self.write(|merkle| merkle.copy(from_key, to_key))
+    }
+
+    fn get_key(&self, key: &ContextKey) -> Result<ContextValue, ContextError> {
+        self.write(|merkle| merkle.get(key))
+    }
+
+    fn get_key_from_history(&self, context_hash: &ContextHash, key: &ContextKey) -> Result<Option<ContextValue>, ContextError> {
+        let context_hash = to_entry_hash(context_hash)?;
+        self.read(|merkle| match merkle.get_history(&context_hash, key) {
+            Ok(value) => Ok(Some(value)),
+            Err(MerkleError::ValueNotFound { .. }) | Err(MerkleError::EntryNotFound { .. }) => Ok(None),
+            Err(error) => Err(error),
+        })
+    }
+
+    fn get_key_values_by_prefix(&self, context_hash: &ContextHash, prefix: &ContextKey) -> Result<Option<ContextKeyValues>, ContextError> {
+        let context_hash = to_entry_hash(context_hash)?;
+        self.read(|merkle| merkle.get_key_values_by_prefix(&context_hash, prefix))
+    }
+
+    fn get_last_commit_hash(&self) -> Option<Vec<u8>> {
+        let merkle = self.merkle.read().ok()?;
+        merkle.get_last_commit_hash().map(|hash| hash.to_vec())
+    }
+
+    fn get_merkle_stats(&self) -> Result<MerkleStorageStats, ContextError> {
+        self.read(|merkle| merkle.get_merkle_stats())
+    }
+}
+
+fn to_entry_hash(hash: &[u8]) -> Result<EntryHash, ContextError> {
+    hash.try_into().map_err(|_| ContextError::InvalidContextHash { hash: hex::encode(hash) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SledDBWrapper;
+
+    fn get_context() -> SledContext {
+        let db = sled::Config::new().temporary(true).open().expect("error opening database");
+        let merkle = MerkleStorage::new(Arc::new(SledDBWrapper::new(db))).unwrap();
+        SledContext::new(Arc::new(RwLock::new(merkle)))
+    }
+
+    fn key(path: &str) -> ContextKey {
+        path.split('/').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_context_api() -> Result<(), ContextError> {
+        let (context, reference) = (get_context(), get_context());
+        for context in &[&context, &reference] {
+            context.set(&None, &key("data/a"), &vec![1])?;
+            context.set(&None, &key("data/b"), &vec![2])?;
+            context.copy_to_diff(&None, &key("data"), &key("copy"))?;
+            context.delete_to_diff(&None, &key("data/b"))?;
+        }
+        assert_eq!(vec![2], context.get_key(&key("copy/b"))?);
+
+        // hash computed by the protocol is checked
+        let expected = reference.merkle.write().unwrap().commit(1, "a".to_string(), "m".to_string())?.to_vec();
+        let block_hash = vec![1; 32];
+        context.commit(&block_hash, &None, &expected, "a".to_string(), "m".to_string(), 1)?;
+        assert_eq!(Some(expected.clone()), context.get_context_hash_by_block(&block_hash)?);
+        assert_eq!(Some(expected.clone()), context.get_last_commit_hash());
+        // blocks are not recorded as refs, which would retain all their commits
+        assert!(context.merkle.read().unwrap().list_refs()?.is_empty());
+
+        context.set(&Some(expected.clone()), &key("data/c"), &vec![3])?;
+        assert!(matches!(context.commit(&vec![2; 32], &Some(expected.clone()), &vec![0; 32], "a".to_string(), "m".to_string(), 2),
+                         Err(ContextError::ContextHashMismatch { .. })));
+        assert!(matches!(context.commit(&vec![2; 32], &Some(expected.clone()), &vec![0; 32], "a".to_string(), "m".to_string(), -1),
+                         Err(ContextError::InvalidDate { date: -1 })));
+        // nothing is committed on mismatch, the working tree stays staged
+        assert_eq!(None, context.get_context_hash_by_block(&vec![2; 32])?);
+        assert_eq!(Some(expected.clone()), context.get_last_commit_hash());
+        assert_eq!(vec![3], context.get_key(&key("data/c"))?);
+
+        context.checkout(&expected)?;
+        assert_eq!(Some(vec![1]), context.get_key_from_history(&expected, &key("data/a"))?);
+        assert_eq!(None, context.get_key_from_history(&expected, &key("data/b"))?);
+        assert_eq!(None, context.get_key_from_history(&vec![0; 32], &key("data/a"))?);
+        assert_eq!(2, context.get_key_values_by_prefix(&expected, &key("copy"))?.unwrap().len());
+        assert!(matches!(context.checkout(&vec![0; 3]), Err(ContextError::InvalidContextHash { .. })));
+        Ok(())
+    }
+}
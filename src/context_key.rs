@@ -0,0 +1,193 @@
+//! Builders of context keys.
+//!
+//! Keys are lists of fragments, which are joined by `/` in their string form and hashed with
+//! a one byte length prefix. Builders in this module reject fragments, which would break
+//! either of the two.
+//!
+//! Keys written by other means may still contain such fragments, so the string form (path)
+//! used in logs, errors and indexes escapes `/` and `\` inside fragments with a backslash, see
+//! [key_to_path] and [key_from_path].
+use std::borrow::Cow;
+
+use crate::merkle_storage::{ContextKey, MerkleError};
+
+/// Longest fragment allowed, length of a fragment is hashed as a single byte
+pub const MAX_KEY_FRAGMENT_LEN: usize = 255;
+
+/// Check that `fragment` can be used as a part of a context key.
+pub fn validate_key_fragment(fragment: &str) -> Result<(), MerkleError> {
+    let reason = if fragment.is_empty() {
+        "fragment is empty"
+    } else if fragment.contains('/') {
+        "fragment contains '/'"
+    } else if fragment.len() > MAX_KEY_FRAGMENT_LEN {
+        "fragment is longer than 255 bytes"
+    } else {
+        return Ok(());
+    };
+    Err(MerkleError::InvalidKeyFragment { fragment: fragment.to_string(), reason })
+}
+
+/// Escape `/` and `\` in `fragment` with a backslash, so it can be joined into a path.
+pub fn escape_fragment(fragment: &str) -> Cow<'_, str> {
+    if !fragment.contains(&['/', '\\'][..]) {
+        return Cow::Borrowed(fragment);
+    }
+    let mut escaped = String::with_capacity(fragment.len() + 2);
+    for c in fragment.chars() {
+        if c == '/' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Cow::Owned(escaped)
+}
+
+/// Path of `key`, its escaped fragments joined by `/`, e.g. `data/contracts/a\/b`.
+pub fn key_to_path(key: &[String]) -> String {
+    key.iter().map(|fragment| escape_fragment(fragment)).collect::<Vec<_>>().join("/")
+}
+
+/// Parse path created by [key_to_path]. Empty path is the empty key.
+pub fn key_from_path(path: &str) -> Result<ContextKey, MerkleError> {
+    let invalid = |reason| MerkleError::InvalidKeyPath { path: path.to_string(), reason };
+    let mut key = Vec::new();
+    if path.is_empty() {
+        return Ok(key);
+    }
+    let mut fragment = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' => key.push(std::mem::take(&mut fragment)),
+            '\\' => match chars.next() {
+                Some(escaped) if escaped == '/' || escaped == '\\' => fragment.push(escaped),
+                Some(_) => return Err(invalid("invalid escape sequence")),
+                None => return Err(invalid("path ends with '\\'")),
+            },
+            c => fragment.push(c),
+        }
+    }
+    key.push(fragment);
+    Ok(key)
+}
+
+/// Build key from fragments, used by [key!](crate::key).
+///
+/// # Panics
+/// If any fragment is not valid, see [validate_key_fragment].
+#[doc(hidden)]
+pub fn checked_key(fragments: Vec<String>) -> ContextKey {
+    for fragment in &fragments {
+        if let Err(err) = validate_key_fragment(fragment) {
+            panic!("{}", err);
+        }
+    }
+    fragments
+}
+
+/// Build a [ContextKey] from fragments of any `ToString` type, e.g.
+/// `key!["data", "contracts", addr]`.
+///
+/// # Panics
+/// If any fragment is not valid, see [validate_key_fragment].
+#[macro_export]
+macro_rules! key {
+    ($($fragment:expr),* $(,)?) => {
+        $crate::prelude::checked_key(vec![$(ToString::to_string(&$fragment)),*])
+    };
+}
+
+/// Builders of child keys
+pub trait ContextKeyExt {
+    /// Key of `fragment` under this key.
+    ///
+    /// # Panics
+    /// If `fragment` is not valid, see [validate_key_fragment].
+    fn child<F: ToString>(&self, fragment: F) -> ContextKey;
+
+    /// Key of `fragment` under this key, fails if `fragment` is not valid.
+    fn try_child<F: ToString>(&self, fragment: F) -> Result<ContextKey, MerkleError>;
+}
+
+impl ContextKeyExt for ContextKey {
+    fn child<F: ToString>(&self, fragment: F) -> ContextKey {
+        self.try_child(fragment).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn try_child<F: ToString>(&self, fragment: F) -> Result<ContextKey, MerkleError> {
+        let fragment = fragment.to_string();
+        validate_key_fragment(&fragment)?;
+        let mut key = self.clone();
+        key.push(fragment);
+        Ok(key)
+    }
+}
+
+/// Constant key prefix, e.g. `const CONTRACTS: KeyPrefix = KeyPrefix(&["data", "contracts"]);`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPrefix(pub &'static [&'static str]);
+
+impl KeyPrefix {
+    pub fn to_key(&self) -> ContextKey {
+        checked_key(self.0.iter().map(|fragment| fragment.to_string()).collect())
+    }
+
+    /// Key of `fragment` under this prefix.
+    ///
+    /// # Panics
+    /// If the prefix or `fragment` is not valid, see [validate_key_fragment].
+    pub fn child<F: ToString>(&self, fragment: F) -> ContextKey {
+        self.to_key().child(fragment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTRACTS: KeyPrefix = KeyPrefix(&["data", "contracts"]);
+
+    #[test]
+    fn test_key_macro() {
+        let addr = "tz1";
+        assert_eq!(key!["data", "contracts", addr], vec!["data".to_string(), "contracts".to_string(), "tz1".to_string()]);
+        assert_eq!(key![1, "a",], vec!["1".to_string(), "a".to_string()]);
+        assert!(key![].is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_key_macro_invalid_fragment() {
+        key!["data", "a/b"];
+    }
+
+    #[test]
+    fn test_child_keys() {
+        assert_eq!(CONTRACTS.child("tz1"), key!["data", "contracts", "tz1"]);
+        assert_eq!(CONTRACTS.to_key().child(5).child("x"), key!["data", "contracts", 5, "x"]);
+
+        let key = CONTRACTS.to_key();
+        assert!(matches!(key.try_child(""), Err(MerkleError::InvalidKeyFragment { .. })));
+        assert!(matches!(key.try_child("a/b"), Err(MerkleError::InvalidKeyFragment { .. })));
+        assert!(matches!(key.try_child("x".repeat(MAX_KEY_FRAGMENT_LEN + 1)), Err(MerkleError::InvalidKeyFragment { .. })));
+        assert!(key.try_child("x".repeat(MAX_KEY_FRAGMENT_LEN)).is_ok());
+    }
+
+    #[test]
+    fn test_key_paths() -> Result<(), MerkleError> {
+        let plain = key!["data", "contracts", "tz1"];
+        assert_eq!("data/contracts/tz1", key_to_path(&plain));
+        assert_eq!(plain, key_from_path("data/contracts/tz1")?);
+
+        let odd: ContextKey = vec!["a/b".to_string(), "c\\".to_string(), "".to_string(), "\\/".to_string()];
+        let path = key_to_path(&odd);
+        assert_eq!("a\\/b/c\\\\//\\\\\\/", path);
+        assert_eq!(odd, key_from_path(&path)?);
+
+        assert!(key_from_path("").unwrap().is_empty());
+        assert!(matches!(key_from_path("a\\x"), Err(MerkleError::InvalidKeyPath { .. })));
+        assert!(matches!(key_from_path("a\\"), Err(MerkleError::InvalidKeyPath { .. })));
+        Ok(())
+    }
+}
@@ -1,11 +1,22 @@
-use crate::schema::KeyValueSchema;
+use crate::schema::{decode_value, KeyValueSchema};
 use crate::codec::{SchemaError, Encoder, Decoder};
-use sled::{Error, Iter, IVec, Db, Batch};
+use sled::{Error, Event, IVec, Batch, Subscriber};
 use failure::Fail;
 use std::marker::PhantomData;
 use crate::db_iterator;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 use crate::db_iterator::{DBIterator, DBIterationHandler};
+use crate::value_transform::{TransformError, ValuePipeline};
+use crate::flush::BackgroundFlusher;
+#[cfg(feature = "testing")]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Bytes following the common prefix of the first and last key, split points are interpolated
+/// on, see [SledDBWrapper::split_points]
+const SPLIT_POINT_BYTES: usize = 8;
 
 impl From<SchemaError> for DBError {
     fn from(error: SchemaError) -> Self {
@@ -23,6 +34,28 @@ pub enum DBError {
     SchemaError {
         error: SchemaError
     },
+    #[fail(display = "Schema {} is not part of the transaction", schema)]
+    SchemaNotInTransaction {
+        schema: &'static str
+    },
+    #[fail(display = "Value transform error: {}", error)]
+    TransformError {
+        error: TransformError
+    },
+    #[fail(display = "Schema {} is stored in the default tree, which cannot be dropped", schema)]
+    DefaultTreeNotDroppable {
+        schema: &'static str
+    },
+    #[fail(display = "Batch not applied, a key of schema {} does not hold the expected value", schema)]
+    BatchPreconditionFailed {
+        schema: &'static str
+    },
+}
+
+impl From<TransformError> for DBError {
+    fn from(error: TransformError) -> Self {
+        DBError::TransformError { error }
+    }
 }
 
 impl From<Error> for DBError {
@@ -31,6 +64,18 @@ impl From<Error> for DBError {
     }
 }
 
+impl DBError {
+    /// Whether the operation may succeed if repeated, e.g. an interrupted or timed out IO.
+    /// Corruption, bugs and errors of schemas are fatal.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DBError::SledError { error: Error::Io(error) } => matches!(error.kind(),
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut),
+            _ => false,
+        }
+    }
+}
+
 impl slog::Value for DBError {
     fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
         serializer.emit_arguments(key, &format_args!("{}", self))
@@ -38,7 +83,23 @@ impl slog::Value for DBError {
 }
 
 pub struct DBStats {
-    size_on_disk: u64
+    pub(crate) size_on_disk: u64
+}
+
+impl DBStats {
+    pub fn size_on_disk(&self) -> u64 {
+        self.size_on_disk
+    }
+}
+
+/// Contents of the sled tree holding a schema, see [SledDBWrapper::schema_stats]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct TreeStats {
+    pub entries: u64,
+    pub key_bytes: u64,
+    /// Size of values in their stored form
+    pub value_bytes: u64,
 }
 
 
@@ -64,12 +125,29 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// * `value` - Value to be inserted associated with given key, specified by schema
     fn merge(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError>;
 
+    /// Atomically replace value of given key with `new`, if its current value is `expected`.
+    /// `None` stands for a missing key, on both sides. If the current value differs, nothing is
+    /// written and the current value is returned as `Err`.
+    ///
+    /// # Arguments
+    /// * `key` - Value of key specified by schema
+    /// * `expected` - Value the key has to hold for the swap to happen
+    /// * `new` - Value to be stored, `None` deletes the key
+    fn compare_and_swap(&self, key: &S::Key, expected: Option<&S::Value>, new: Option<&S::Value>) -> Result<Result<(), Option<S::Value>>, DBError>;
+
     /// Read value associated with given key, if exists.
     ///
     /// # Arguments
     /// * `key` - Value of key specified by schema
     fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError>;
 
+    /// Read raw bytes of value associated with given key, if exists. Returned buffer is shared
+    /// with the database cache, so no decoding nor copying takes place.
+    ///
+    /// # Arguments
+    /// * `key` - Value of key specified by schema
+    fn get_raw(&self, key: &S::Key) -> Result<Option<IVec>, DBError>;
+
     /// Read all entries in database.
     ///
     /// # Arguments
@@ -77,12 +155,19 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// arbitrary position to end.
     fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<S>, DBError>;
 
-    /// Starting from given key, read all entries to the end.
+    /// Read all entries, which keys start with given key, in key order.
     ///
     /// # Arguments
-    /// * `key` - Key (specified by schema), from which to start reading entries
+    /// * `key` - Key (specified by schema), prefix of read entries
     fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<S>, DBError>;
 
+    /// Subscribe to changes of entries, which keys start with given key. Puts held back by the
+    /// write coalescer are reported once they are written.
+    ///
+    /// # Arguments
+    /// * `key` - Key (specified by schema), prefix of watched entries
+    fn watch_prefix(&self, key: &S::Key) -> Result<SchemaSubscriber<S>, DBError>;
+
     /// Check, if database contains given key
     ///
     /// # Arguments
@@ -104,9 +189,46 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
 
     /// Get memory usage statistics from DB
     fn get_mem_use_stats(&self) -> Result<DBStats, DBError>;
+
+    /// Get approximate keys splitting the database into `n` ranges of similar size, e.g. to drive
+    /// parallel scans. Less than `n - 1` keys are returned if there are not enough distinct keys.
+    /// Stores without a cheap way to find them return no keys by default, so the database is
+    /// scanned as a single range.
+    ///
+    /// # Arguments
+    /// * `n` - Number of ranges
+    fn split_points(&self, _n: usize) -> Result<Vec<S::Key>, DBError> {
+        Ok(Vec::new())
+    }
 }
 
-pub struct IteratorWithSchema<'a, S: KeyValueSchema>(DBIterator<'a>, PhantomData<S>);
+/// Convenience methods of schemas with `()` values, which are sets of keys, e.g. of pinned
+/// hashes. Keys are stored with empty values, membership is checked with
+/// [KeyValueStoreWithSchema::contains] without reading any value.
+pub trait KeySetWithSchema<S: KeyValueSchema<Value = ()>>: KeyValueStoreWithSchema<S> {
+    /// Add key to the set
+    ///
+    /// # Arguments
+    /// * `key` - Value of key specified by schema
+    fn insert(&self, key: &S::Key) -> Result<(), DBError> {
+        self.put(key, &())
+    }
+}
+
+impl<S: KeyValueSchema<Value = ()>, T: KeyValueStoreWithSchema<S> + ?Sized> KeySetWithSchema<S> for T {}
+
+pub struct IteratorWithSchema<'a, S: KeyValueSchema>(DBIterator<'a>, Option<ValuePipeline>, PhantomData<S>);
+
+impl<'a, S: KeyValueSchema> IteratorWithSchema<'a, S> {
+    pub(crate) fn new(iter: DBIterator<'a>, pipeline: Option<ValuePipeline>) -> Self {
+        IteratorWithSchema(iter.with_batch_size(S::read_batch_size()), pipeline, PhantomData)
+    }
+
+    /// Read `batch_size` entries at once, instead of [KeyValueSchema::read_batch_size]
+    pub fn read_batch(self, batch_size: usize) -> Self {
+        IteratorWithSchema(self.0.with_batch_size(batch_size), self.1, self.2)
+    }
+}
 
 impl<'a, S: KeyValueSchema> Iterator for IteratorWithSchema<'a, S> {
     type Item = (Result<S::Key, SchemaError>, Result<S::Value, SchemaError>);
@@ -124,7 +246,11 @@ impl<'a, S: KeyValueSchema> Iterator for IteratorWithSchema<'a, S> {
 
         match i {
             Ok((k, v)) => {
-                Some((S::Key::decode(&k), S::Value::decode(&v)))
+                let value = match &self.1 {
+                    Some(pipeline) => pipeline.decode(&v).map_err(|_| SchemaError::DecodeError).and_then(|v| decode_value::<S>(&v)),
+                    None => decode_value::<S>(&v),
+                };
+                Some((S::Key::decode(&k), value.map(|(value, _)| value)))
             }
             Err(_) => {
                 None
@@ -133,16 +259,461 @@ impl<'a, S: KeyValueSchema> Iterator for IteratorWithSchema<'a, S> {
     }
 }
 
+/// Changed key with its new value, `None` if the key was deleted
+pub type SchemaEvent<S> = Result<(<S as KeyValueSchema>::Key, Option<<S as KeyValueSchema>::Value>), SchemaError>;
+
+/// Decoded changes of entries under a prefix, see [KeyValueStoreWithSchema::watch_prefix].
+/// Iteration blocks until the next change and ends once the database is dropped.
+pub struct SchemaSubscriber<S: KeyValueSchema>(pub(crate) EventStream, pub(crate) Option<ValuePipeline>, pub(crate) PhantomData<S>);
+
+/// Raw changes watched by a [SchemaSubscriber]
+pub(crate) enum EventStream {
+    Sled(Subscriber),
+    /// Events sent by an [InMemoryStore](crate::memory_store::InMemoryStore)
+    Memory(Receiver<Event>),
+}
+
+impl<S: KeyValueSchema> SchemaSubscriber<S> {
+    /// Wait for the next change at most `timeout`, `None` if there was none.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<SchemaEvent<S>> {
+        let event = match &mut self.0 {
+            EventStream::Sled(subscriber) => subscriber.next_timeout(timeout).ok(),
+            EventStream::Memory(receiver) => receiver.recv_timeout(timeout).ok(),
+        };
+        event.map(|event| self.decode(event))
+    }
+
+    fn decode(&self, event: Event) -> SchemaEvent<S> {
+        match event {
+            Event::Insert { key, value } => {
+                let value = match &self.1 {
+                    Some(pipeline) => pipeline.decode(&value).map_err(|_| SchemaError::DecodeError).and_then(|v| decode_value::<S>(&v))?.0,
+                    None => decode_value::<S>(&value)?.0,
+                };
+                Ok((S::Key::decode(&key)?, Some(value)))
+            }
+            Event::Remove { key } => Ok((S::Key::decode(&key)?, None)),
+        }
+    }
+}
+
+impl<S: KeyValueSchema> Iterator for SchemaSubscriber<S> {
+    type Item = SchemaEvent<S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = match &mut self.0 {
+            EventStream::Sled(subscriber) => subscriber.next()?,
+            EventStream::Memory(receiver) => receiver.recv().ok()?,
+        };
+        Some(self.decode(event))
+    }
+}
+
 pub struct SledDBWrapper {
-    db: sled::Db
+    db: sled::Db,
+    coalescer: Option<WriteCoalescer>,
+    // keyed by schema name
+    value_pipelines: HashMap<&'static str, ValuePipeline>,
+    tree_per_schema: bool,
+    read_retries: Option<RetryPolicy>,
+    flusher: BackgroundFlusher,
+    #[cfg(feature = "testing")]
+    failures: FailureInjection,
+}
+
+/// Retries of reads failing with a [transient](DBError::is_transient) error. Wait before a retry
+/// starts at `initial_backoff` and doubles up to `max_backoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Thresholds of buffered puts, at which a write coalescer flushes them
+#[derive(Debug, Clone, Copy)]
+pub struct WriteCoalescerConfig {
+    /// Approximate size of buffered keys and values
+    pub max_bytes: usize,
+    /// Age of the oldest buffered put, checked whenever a put is buffered
+    pub max_delay: Duration,
+}
+
+/// Puts of schemas with [KeyValueSchema::coalesce_writes], waiting to be written as batches
+struct WriteCoalescer {
+    config: WriteCoalescerConfig,
+    buffer: Mutex<CoalescedWrites>,
+}
+
+#[derive(Default)]
+struct CoalescedWrites {
+    // values keyed by tree name and key
+    trees: HashMap<Option<&'static str>, BTreeMap<Vec<u8>, Vec<u8>>>,
+    bytes: usize,
+    oldest: Option<Instant>,
 }
 
 impl SledDBWrapper {
     pub fn new(db: sled::Db) -> Self {
         SledDBWrapper {
-            db
+            db,
+            coalescer: None,
+            value_pipelines: HashMap::new(),
+            tree_per_schema: false,
+            read_retries: None,
+            flusher: BackgroundFlusher::default(),
+            #[cfg(feature = "testing")]
+            failures: FailureInjection::default(),
         }
     }
+
+    /// Create wrapper, which buffers puts of schemas with [KeyValueSchema::coalesce_writes] and
+    /// writes them as one batch per tree once a threshold in `config` is hit. Any other
+    /// operation on such schema writes buffered puts first.
+    pub fn with_write_coalescer(db: sled::Db, config: WriteCoalescerConfig) -> Self {
+        let mut wrapper = Self::new(db);
+        wrapper.coalescer = Some(WriteCoalescer { config, buffer: Mutex::new(CoalescedWrites::default()) });
+        wrapper
+    }
+
+    /// Pass values of schema `S` through `pipeline` on write and reverse it on read. Values
+    /// stored before are not rewritten, so a pipeline should be set up before the first write,
+    /// or include all stages of the previous one.
+    pub fn with_value_pipeline<S: KeyValueSchema>(mut self, pipeline: ValuePipeline) -> Self {
+        self.value_pipelines.insert(S::name(), pipeline);
+        self
+    }
+
+    /// Retry `get`, `get_raw` and `contains` failing with a transient error by `policy`
+    pub fn with_read_retries(mut self, policy: RetryPolicy) -> Self {
+        self.read_retries = Some(policy);
+        self
+    }
+
+    /// Pipeline of values of schema `S`, if configured
+    pub fn value_pipeline<S: KeyValueSchema>(&self) -> Option<&ValuePipeline> {
+        self.value_pipelines.get(S::name())
+    }
+
+    /// Store every schema in its own sled tree named after [KeyValueSchema::name], unless it
+    /// names a tree by [KeyValueSchema::tree_name]. Keys of different schemas then never collide
+    /// and iteration never crosses schemas. Data written in the other mode is not visible, so
+    /// the mode of a database must not change after the first write.
+    pub fn with_tree_per_schema(mut self) -> Self {
+        self.tree_per_schema = true;
+        self
+    }
+
+    /// Name of the sled tree holding schema `S`, `None` is the default tree. Schemas taking part
+    /// in a [SledDBWrapper::transaction] are identified by it.
+    pub fn schema_tree_name<S: KeyValueSchema>(&self) -> Option<&'static str> {
+        self.tree_name_of(S::name(), S::tree_name())
+    }
+
+    /// [SledDBWrapper::schema_tree_name] of a schema given by its name and
+    /// [KeyValueSchema::tree_name]
+    pub(crate) fn tree_name_of(&self, schema: &'static str, tree_name: Option<&'static str>) -> Option<&'static str> {
+        match tree_name {
+            None if self.tree_per_schema => Some(schema),
+            tree_name => tree_name,
+        }
+    }
+
+    /// Count entries in the tree holding schema `S`, including entries of other schemas
+    /// sharing the tree.
+    pub fn schema_stats<S: KeyValueSchema>(&self) -> Result<TreeStats, DBError> {
+        self.flush_coalesced::<S>()?;
+        let mut stats = TreeStats::default();
+        for item in self.tree::<S>()?.iter() {
+            let (key, value) = item?;
+            stats.entries += 1;
+            stats.key_bytes += key.len() as u64;
+            stats.value_bytes += value.len() as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Drop the tree holding schema `S` with all its data, including data of other schemas
+    /// sharing the tree. Returns false if the tree did not exist.
+    pub fn drop_schema_tree<S: KeyValueSchema>(&self) -> Result<bool, DBError> {
+        let tree_name = self.schema_tree_name::<S>()
+            .ok_or(DBError::DefaultTreeNotDroppable { schema: S::name() })?;
+        self.before_write()?;
+        self.flush_coalesced::<S>()?;
+        Ok(self.db.drop_tree(tree_name)?)
+    }
+
+    /// Encode value of schema `S` in its stored form.
+    pub(crate) fn encode_value<S: KeyValueSchema>(&self, value: &S::Value) -> Result<Vec<u8>, DBError> {
+        self.encode_stored(S::name(), value.encode()?)
+    }
+
+    /// Pass encoded value of schema named `schema` through its value pipeline.
+    pub(crate) fn encode_stored(&self, schema: &str, value: Vec<u8>) -> Result<Vec<u8>, DBError> {
+        match self.value_pipelines.get(schema) {
+            Some(pipeline) => Ok(pipeline.encode(&value)?),
+            None => Ok(value),
+        }
+    }
+
+    /// Reverse value pipeline of schema `S` on a stored value.
+    pub(crate) fn decode_stored<S: KeyValueSchema>(&self, stored: IVec) -> Result<IVec, DBError> {
+        self.decode_stored_named(S::name(), stored)
+    }
+
+    /// Reverse value pipeline of schema named `schema` on a stored value.
+    pub(crate) fn decode_stored_named(&self, schema: &str, stored: IVec) -> Result<IVec, DBError> {
+        match self.value_pipelines.get(schema) {
+            Some(pipeline) => Ok(IVec::from(pipeline.decode(&stored)?)),
+            None => Ok(stored),
+        }
+    }
+
+    /// Iterate over values of schema `S` in their stored form, before the value pipeline is
+    /// reversed.
+    pub(crate) fn stored_values<S: KeyValueSchema>(&self) -> Result<impl Iterator<Item = Result<IVec, DBError>>, DBError> {
+        self.flush_coalesced::<S>()?;
+        Ok(self.tree::<S>()?.iter().values().map(|value| value.map_err(DBError::from)))
+    }
+
+    /// Write all puts buffered by the write coalescer, e.g. at the end of a block.
+    pub fn flush_writes(&self) -> Result<(), DBError> {
+        let coalescer = match &self.coalescer {
+            Some(coalescer) => coalescer,
+            None => return Ok(()),
+        };
+        let writes = std::mem::take(&mut *coalescer.buffer.lock().unwrap());
+        for (tree_name, values) in writes.trees {
+            let mut batch = Batch::default();
+            for (key, value) in values {
+                batch.insert(key, value);
+            }
+            self.tree_named(tree_name)?.apply_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Buffer put of schema `S`, returns false if puts of `S` are not coalesced.
+    fn coalesce_put<S: KeyValueSchema>(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool, DBError> {
+        let coalescer = match &self.coalescer {
+            Some(coalescer) if S::coalesce_writes() => coalescer,
+            _ => return Ok(false),
+        };
+        let flush = {
+            let mut writes = coalescer.buffer.lock().unwrap();
+            writes.bytes += key.len() + value.len();
+            writes.oldest.get_or_insert_with(Instant::now);
+            writes.trees.entry(self.schema_tree_name::<S>()).or_default().insert(key, value);
+            writes.bytes >= coalescer.config.max_bytes
+                || matches!(writes.oldest, Some(oldest) if oldest.elapsed() >= coalescer.config.max_delay)
+        };
+        if flush {
+            self.flush_writes()?;
+        }
+        Ok(true)
+    }
+
+    /// Get buffered value of schema `S`.
+    fn coalesced_value<S: KeyValueSchema>(&self, key: &[u8]) -> Option<IVec> {
+        match &self.coalescer {
+            Some(coalescer) if S::coalesce_writes() => coalescer.buffer.lock().unwrap()
+                .trees.get(&self.schema_tree_name::<S>())
+                .and_then(|values| values.get(key))
+                .map(|value| IVec::from(value.as_slice())),
+            _ => None,
+        }
+    }
+
+    /// Compare and swap value of schema `S` with a value pipeline. Stored form of a value can
+    /// differ between writes (e.g. by encryption nonce), so the current value is compared in its
+    /// decoded form and swapped in its stored form, until no concurrent write gets in between.
+    fn compare_and_swap_transformed<S: KeyValueSchema>(&self, key: &[u8], expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<Result<(), Option<S::Value>>, DBError> {
+        let tree = self.tree::<S>()?;
+        loop {
+            let current = tree.get(key)?;
+            let current_value = current.clone().map(|v| self.decode_stored::<S>(v)).transpose()?;
+            if current_value.as_deref() != expected.as_deref() {
+                return Ok(Err(current_value.map(|v| decode_value::<S>(&v).map(|(v, _)| v)).transpose()?));
+            }
+            if tree.compare_and_swap(key, current, new.clone())?.is_ok() {
+                return Ok(Ok(()));
+            }
+        }
+    }
+
+    /// Run `read`, repeating it on transient errors as long as the retry policy allows.
+    fn retry_read<R, F: FnMut() -> Result<R, DBError>>(&self, mut read: F) -> Result<R, DBError> {
+        let policy = match self.read_retries {
+            Some(policy) => policy,
+            None => return read(),
+        };
+        let mut backoff = policy.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match read() {
+                Err(error) if error.is_transient() && retries < policy.max_retries => {
+                    std::thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, policy.max_backoff);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Write value upgraded on read back in the current format, unless it was overwritten since
+    /// it was read as `stored`.
+    fn rewrite_upgraded<S: KeyValueSchema>(&self, key: &[u8], stored: IVec, value: &S::Value) -> Result<(), DBError> {
+        self.before_write()?;
+        let new = self.encode_value::<S>(value)?;
+        let _ = self.tree::<S>()?.compare_and_swap(key, Some(stored), Some(new))?;
+        Ok(())
+    }
+
+    /// Write buffered puts before an operation on schema `S`, which does not see the buffer.
+    fn flush_coalesced<S: KeyValueSchema>(&self) -> Result<(), DBError> {
+        if S::coalesce_writes() {
+            self.flush_writes()?;
+        }
+        Ok(())
+    }
+
+    /// Access failures injected into operations of this database
+    #[cfg(feature = "testing")]
+    pub fn failure_injection(&self) -> &FailureInjection {
+        &self.failures
+    }
+
+    /// Sled tree holding data of schema `S`
+    fn tree<S: KeyValueSchema>(&self) -> Result<sled::Tree, DBError> {
+        self.tree_named(self.schema_tree_name::<S>())
+    }
+
+    pub(crate) fn sled_db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    pub(crate) fn flusher(&self) -> &BackgroundFlusher {
+        &self.flusher
+    }
+
+    /// Sled tree of given [KeyValueSchema::tree_name], `None` is the default tree
+    pub(crate) fn tree_named(&self, name: Option<&str>) -> Result<sled::Tree, DBError> {
+        match name {
+            None => Ok(sled::Tree::clone(&self.db)),
+            Some(name) => Ok(self.db.open_tree(name)?),
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn before_write(&self) -> Result<(), DBError> {
+        self.failures.before_write()
+    }
+
+    #[cfg(not(feature = "testing"))]
+    #[inline]
+    pub(crate) fn before_write(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    fn before_read(&self) -> Result<(), DBError> {
+        self.failures.before_read()
+    }
+
+    #[cfg(not(feature = "testing"))]
+    #[inline]
+    fn before_read(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+}
+
+impl Drop for SledDBWrapper {
+    fn drop(&mut self) {
+        // nobody to report the error to
+        let _ = self.flush_writes();
+    }
+}
+
+/// Artificial failures of [SledDBWrapper] operations, so embedders can test their recovery
+/// paths against storage errors
+#[cfg(feature = "testing")]
+#[derive(Default)]
+pub struct FailureInjection {
+    // 0 means disabled
+    fail_write_at: AtomicU64,
+    writes: AtomicU64,
+    fail_reads_decode: AtomicBool,
+    transient_read_failures: AtomicU64,
+    read_delay_ms: AtomicU64,
+}
+
+#[cfg(feature = "testing")]
+impl FailureInjection {
+    /// Fail the `n`-th write (put, delete, merge or batch) from now on with a sled IO error
+    pub fn fail_nth_write(&self, n: u64) {
+        self.writes.store(0, Ordering::SeqCst);
+        self.fail_write_at.store(n, Ordering::SeqCst);
+    }
+
+    /// Make every read fail with a decode error
+    pub fn fail_decodes(&self, enabled: bool) {
+        self.fail_reads_decode.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Fail the next `n` reads with a transient IO error
+    pub fn fail_reads_transiently(&self, n: u64) {
+        self.transient_read_failures.store(n, Ordering::SeqCst);
+    }
+
+    /// Delay every read by given duration
+    pub fn delay_reads(&self, delay: Duration) {
+        self.read_delay_ms.store(delay.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Disable all injected failures
+    pub fn reset(&self) {
+        self.fail_nth_write(0);
+        self.fail_decodes(false);
+        self.fail_reads_transiently(0);
+        self.delay_reads(Duration::from_millis(0));
+    }
+
+    fn before_write(&self) -> Result<(), DBError> {
+        let fail_write_at = self.fail_write_at.load(Ordering::SeqCst);
+        if fail_write_at > 0 && self.writes.fetch_add(1, Ordering::SeqCst) + 1 == fail_write_at {
+            return Err(DBError::SledError {
+                error: Error::Io(std::io::Error::other("injected write failure"))
+            });
+        }
+        Ok(())
+    }
+
+    fn before_read(&self) -> Result<(), DBError> {
+        let delay = self.read_delay_ms.load(Ordering::SeqCst);
+        if delay > 0 {
+            std::thread::sleep(Duration::from_millis(delay));
+        }
+        if self.fail_reads_decode.load(Ordering::SeqCst) {
+            return Err(DBError::SchemaError { error: SchemaError::DecodeError });
+        }
+        if self.transient_read_failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+            return Err(DBError::SledError {
+                error: Error::Io(std::io::Error::new(std::io::ErrorKind::Interrupted, "injected read failure"))
+            });
+        }
+        Ok(())
+    }
 }
 
 /// Database iterator direction
@@ -156,13 +727,19 @@ pub enum IteratorMode<'a, S: KeyValueSchema> {
     Start,
     End,
     From(&'a S::Key, Direction),
+    /// Last `n` entries of the schema, starting with the last one
+    Tail(usize),
 }
 
 impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for SledDBWrapper {
     fn put(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        self.before_write()?;
         let key = key.encode()?;
-        let value = value.encode()?;
-        match self.db.insert(key, value) {
+        let value = self.encode_value::<S>(value)?;
+        if self.coalesce_put::<S>(key.clone(), value.clone())? {
+            return Ok(());
+        }
+        match self.tree::<S>()?.insert(key, value) {
             Ok(_) => {
                 Ok(())
             }
@@ -175,8 +752,10 @@ impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for SledDBWrapper {
     }
 
     fn delete(&self, key: &S::Key) -> Result<(), DBError> {
+        self.before_write()?;
+        self.flush_coalesced::<S>()?;
         let key = key.encode()?;
-        match self.db.remove(key) {
+        match self.tree::<S>()?.remove(key) {
             Ok(_) => {
                 Ok(())
             }
@@ -189,10 +768,12 @@ impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for SledDBWrapper {
     }
 
     fn merge(&self, key: &S::Key, value: &<S as KeyValueSchema>::Value) -> Result<(), DBError> {
+        self.before_write()?;
+        self.flush_coalesced::<S>()?;
         let key = key.encode()?;
-        let value = value.encode()?;
+        let value = self.encode_value::<S>(value)?;
 
-        match self.db.merge(&key, &value) {
+        match self.tree::<S>()?.merge(&key, &value) {
             Ok(_) => {
                 Ok(())
             }
@@ -204,12 +785,22 @@ impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for SledDBWrapper {
         }
     }
 
-    fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
+    fn compare_and_swap(&self, key: &S::Key, expected: Option<&S::Value>, new: Option<&S::Value>) -> Result<Result<(), Option<S::Value>>, DBError> {
+        self.before_write()?;
+        self.flush_coalesced::<S>()?;
         let key = key.encode()?;
+        let expected = expected.map(|value| value.encode()).transpose()?;
+        let new = new.map(|value| self.encode_value::<S>(value)).transpose()?;
+        if self.value_pipeline::<S>().is_some() {
+            return self.compare_and_swap_transformed::<S>(&key, expected, new);
+        }
 
-        match self.db.get(&key) {
-            Ok(v) => {
-                Ok(Some(S::Value::decode(&v.unwrap_or_default())?))
+        match self.tree::<S>()?.compare_and_swap(key, expected, new) {
+            Ok(Ok(())) => {
+                Ok(Ok(()))
+            }
+            Ok(Err(conflict)) => {
+                Ok(Err(conflict.current.map(|v| decode_value::<S>(&v).map(|(v, _)| v)).transpose()?))
             }
             Err(error) => {
                 Err(DBError::SledError {
@@ -219,57 +810,127 @@ impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for SledDBWrapper {
         }
     }
 
+    fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
+        self.retry_read(|| {
+            self.before_read()?;
+            let key = key.encode()?;
+            if let Some(v) = self.coalesced_value::<S>(&key) {
+                return Ok(Some(decode_value::<S>(&self.decode_stored::<S>(v)?)?.0));
+            }
+
+            match self.tree::<S>()?.get(&key) {
+                Ok(Some(v)) => {
+                    let (value, upgraded) = decode_value::<S>(&self.decode_stored::<S>(v.clone())?)?;
+                    if upgraded && S::rewrite_upgraded() {
+                        self.rewrite_upgraded::<S>(&key, v, &value)?;
+                    }
+                    Ok(Some(value))
+                }
+                Ok(None) => {
+                    Ok(None)
+                }
+                Err(error) => {
+                    Err(DBError::SledError {
+                        error
+                    })
+                }
+            }
+        })
+    }
+
+    fn get_raw(&self, key: &S::Key) -> Result<Option<IVec>, DBError> {
+        self.retry_read(|| {
+            self.before_read()?;
+            let key = key.encode()?;
+            if let Some(v) = self.coalesced_value::<S>(&key) {
+                return Ok(Some(self.decode_stored::<S>(v)?));
+            }
+
+            match self.tree::<S>()?.get(&key) {
+                Ok(v) => {
+                    Ok(v.map(|v| self.decode_stored::<S>(v)).transpose()?)
+                }
+                Err(error) => {
+                    Err(DBError::SledError {
+                        error
+                    })
+                }
+            }
+        })
+    }
+
     fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<S>, DBError> {
+        self.flush_coalesced::<S>()?;
+        let tree = self.tree::<S>()?;
         let iter = match mode {
             IteratorMode::Start => {
-                self.db.iterator(db_iterator::IteratorMode::Start)
+                tree.iterator(db_iterator::IteratorMode::Start)
             }
             IteratorMode::End => {
-                self.db.iterator(db_iterator::IteratorMode::End)
+                tree.iterator(db_iterator::IteratorMode::End)
             }
             IteratorMode::From(key, direction) => {
                 let key = key.encode()?;
                 match direction {
                     Direction::Forward => {
-                        self.db.iterator(db_iterator::IteratorMode::From(key.into(), db_iterator::Direction::Forward))
+                        tree.iterator(db_iterator::IteratorMode::From(key.into(), db_iterator::Direction::Forward))
                     }
                     Direction::Reverse => {
-                        self.db.iterator(db_iterator::IteratorMode::From(key.into(), db_iterator::Direction::Reverse))
+                        tree.iterator(db_iterator::IteratorMode::From(key.into(), db_iterator::Direction::Reverse))
                     }
                 }
             }
+            IteratorMode::Tail(n) => {
+                tree.iterator(db_iterator::IteratorMode::Tail(n))
+            }
         };
-        Ok(IteratorWithSchema(iter, PhantomData))
+        Ok(IteratorWithSchema::new(iter, self.value_pipeline::<S>().cloned()))
     }
 
     fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<S>, DBError> {
+        self.flush_coalesced::<S>()?;
         let key = key.encode()?;
-        let iter = self.db.scan_prefix_iterator(&key);
-        Ok(IteratorWithSchema(iter, PhantomData))
+        let iter = self.tree::<S>()?.scan_prefix_iterator(&key);
+        Ok(IteratorWithSchema::new(iter, self.value_pipeline::<S>().cloned()))
+    }
+
+    fn watch_prefix(&self, key: &S::Key) -> Result<SchemaSubscriber<S>, DBError> {
+        let key = key.encode()?;
+        let subscriber = self.tree::<S>()?.watch_prefix(key);
+        Ok(SchemaSubscriber(EventStream::Sled(subscriber), self.value_pipeline::<S>().cloned(), PhantomData))
     }
 
     fn contains(&self, key: &S::Key) -> Result<bool, DBError> {
-        match self.db.contains_key(key.encode()?) {
-            Ok(b) => {
-                Ok(b)
+        self.retry_read(|| {
+            self.before_read()?;
+            let key = key.encode()?;
+            if self.coalesced_value::<S>(&key).is_some() {
+                return Ok(true);
             }
-            Err(error) => {
-                Err(DBError::SledError {
-                    error
-                })
+            match self.tree::<S>()?.contains_key(key) {
+                Ok(b) => {
+                    Ok(b)
+                }
+                Err(error) => {
+                    Err(DBError::SledError {
+                        error
+                    })
+                }
             }
-        }
+        })
     }
 
     fn put_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
         let key = key.encode()?;
-        let value = value.encode()?;
+        let value = self.encode_value::<S>(value)?;
         batch.insert(key, value);
         Ok(())
     }
 
     fn write_batch(&self, batch: Batch) -> Result<(), DBError> {
-        match self.db.apply_batch(batch) {
+        self.before_write()?;
+        self.flush_coalesced::<S>()?;
+        match self.tree::<S>()?.apply_batch(batch) {
             Ok(_) => {
                 Ok(())
             }
@@ -286,6 +947,491 @@ impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for SledDBWrapper {
             size_on_disk: self.db.size_on_disk().unwrap_or(0)
         })
     }
+
+    fn split_points(&self, n: usize) -> Result<Vec<S::Key>, DBError> {
+        if n < 2 {
+            return Ok(Vec::new());
+        }
+        self.flush_coalesced::<S>()?;
+
+        // points are interpolated between the first and the last key and moved to the next stored
+        // key, so the tree is not scanned and ranges are balanced for evenly spread keys, e.g. hashes
+        let tree = self.tree::<S>()?;
+        let (first, last) = match (tree.first()?, tree.last()?) {
+            (Some((first, _)), Some((last, _))) => (first, last),
+            _ => return Ok(Vec::new()),
+        };
+        let prefix_len = first.iter().zip(last.iter()).take_while(|(a, b)| a == b).count();
+        let position = |key: &[u8]| {
+            let mut bytes = [0u8; SPLIT_POINT_BYTES];
+            for (byte, key_byte) in bytes.iter_mut().zip(&key[prefix_len..]) {
+                *byte = *key_byte;
+            }
+            u64::from_be_bytes(bytes) as u128
+        };
+        let (low, high) = (position(&first), position(&last));
+
+        let mut points: Vec<IVec> = Vec::new();
+        for i in 1..n {
+            let mut candidate = first[..prefix_len].to_vec();
+            candidate.extend(&((low + (high - low) * i as u128 / n as u128) as u64).to_be_bytes());
+            if let Some(key) = tree.range(candidate..).keys().next().transpose()? {
+                if key != first && points.last() != Some(&key) {
+                    points.push(key);
+                }
+            }
+        }
+
+        points.iter()
+            .map(|key| S::Key::decode(key).map_err(DBError::from))
+            .collect()
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value_transform::{Checksum, Encryption, CHECKSUM_STAGE_ID, ENCRYPTION_STAGE_ID};
+
+    struct TestSchema;
+
+    impl KeyValueSchema for TestSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_schema"
+        }
+    }
+
+    struct TestOtherSchema;
+
+    impl KeyValueSchema for TestOtherSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_other_schema"
+        }
+    }
+
+    struct TestSetSchema;
+
+    impl KeyValueSchema for TestSetSchema {
+        type Key = u64;
+        type Value = ();
+
+        fn name() -> &'static str {
+            "test_set_schema"
+        }
+
+        fn tree_name() -> Option<&'static str> {
+            Some(Self::name())
+        }
+    }
+
+    struct TestCoalescedSchema;
+
+    impl KeyValueSchema for TestCoalescedSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_coalesced_schema"
+        }
+
+        fn tree_name() -> Option<&'static str> {
+            Some(Self::name())
+        }
+
+        fn coalesce_writes() -> bool {
+            true
+        }
+    }
+
+    struct TestBatchedSchema;
+
+    impl KeyValueSchema for TestBatchedSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_batched_schema"
+        }
+
+        fn read_batch_size() -> usize {
+            4
+        }
+    }
+
+    /// Values were `u32` in the older format of the schema
+    struct TestUpgradedSchema;
+
+    impl KeyValueSchema for TestUpgradedSchema {
+        type Key = u64;
+        type Value = u64;
+
+        fn name() -> &'static str {
+            "test_upgraded_schema"
+        }
+
+        fn tree_name() -> Option<&'static str> {
+            Some(Self::name())
+        }
+
+        fn upgrade_value(bytes: &[u8]) -> Option<Self::Value> {
+            crate::schema::upgrade_from(bytes, |old: u32| old as u64)
+        }
+
+        fn rewrite_upgraded() -> bool {
+            true
+        }
+    }
+
+    fn get_db() -> SledDBWrapper {
+        SledDBWrapper::new(sled::Config::new().temporary(true).open().expect("error opening database"))
+    }
+
+    #[test]
+    fn test_tail_iterator() -> Result<(), DBError> {
+        let db = get_db();
+        for i in 0..10u64 {
+            KeyValueStoreWithSchema::<TestSchema>::put(&db, &i, &i.to_string())?;
+        }
+
+        let keys: Vec<u64> = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Tail(3))?
+            .map(|(k, _)| k.unwrap())
+            .collect();
+        assert_eq!(vec![9, 8, 7], keys);
 
+        let all = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Tail(100))?.count();
+        assert_eq!(10, all);
+        assert_eq!(0, KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Tail(0))?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iterator_modes() -> Result<(), DBError> {
+        let db = get_db();
+        for i in 0..5u64 {
+            KeyValueStoreWithSchema::<TestSchema>::put(&db, &i, &i.to_string())?;
+        }
+        let keys = |mode| -> Result<Vec<u64>, DBError> {
+            Ok(KeyValueStoreWithSchema::<TestSchema>::iterator(&db, mode)?.map(|(k, _)| k.unwrap()).collect())
+        };
+
+        assert_eq!(vec![0, 1, 2, 3, 4], keys(IteratorMode::Start)?);
+        assert_eq!(vec![4, 3, 2, 1, 0], keys(IteratorMode::End)?);
+        assert_eq!(vec![2, 3, 4], keys(IteratorMode::From(&2, Direction::Forward))?);
+        assert_eq!(vec![2, 1, 0], keys(IteratorMode::From(&2, Direction::Reverse))?);
+
+        let prefixed: Vec<u64> = KeyValueStoreWithSchema::<TestSchema>::prefix_iterator(&db, &3)?
+            .map(|(k, _)| k.unwrap())
+            .collect();
+        assert_eq!(vec![3], prefixed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_iterator() -> Result<(), DBError> {
+        let db = get_db();
+        let count = 10_000u64;
+        for i in 0..count {
+            KeyValueStoreWithSchema::<TestSchema>::put(&db, &i, &i.to_string())?;
+        }
+
+        let mut forward = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Start)?;
+        assert!((0..count).all(|i| forward.next().map(|(k, _)| k.unwrap()) == Some(i)));
+        assert!(forward.next().is_none());
+        let mut reverse = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::End)?;
+        assert!((0..count).rev().all(|i| reverse.next().map(|(k, _)| k.unwrap()) == Some(i)));
+        assert!(reverse.next().is_none());
+
+        // cursor keeps its position while the tree changes
+        let mut iter = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::From(&5000, Direction::Forward))?;
+        assert_eq!(Some(5000), iter.next().map(|(k, _)| k.unwrap()));
+        KeyValueStoreWithSchema::<TestSchema>::delete(&db, &0)?;
+        assert_eq!(Some(5001), iter.next().map(|(k, _)| k.unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_iterator() -> Result<(), DBError> {
+        let db = get_db();
+        for i in 0..10u64 {
+            KeyValueStoreWithSchema::<TestSchema>::put(&db, &i, &i.to_string())?;
+        }
+        let keys = |iter: IteratorWithSchema<TestSchema>| -> Vec<u64> { iter.map(|(k, _)| k.unwrap()).collect() };
+
+        for batch_size in &[0, 1, 3, 256] {
+            let iter = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Start)?.read_batch(*batch_size);
+            assert_eq!((0..10).collect::<Vec<u64>>(), keys(iter));
+            let iter = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Tail(3))?.read_batch(*batch_size);
+            assert_eq!(vec![9, 8, 7], keys(iter));
+            let iter = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::From(&4, Direction::Reverse))?.read_batch(*batch_size);
+            assert_eq!(vec![4, 3, 2, 1, 0], keys(iter));
+        }
+
+        // the rest of the first batch was read before the delete
+        let mut iter = KeyValueStoreWithSchema::<TestBatchedSchema>::iterator(&db, IteratorMode::Start)?;
+        assert_eq!(Some(0), iter.next().map(|(k, _)| k.unwrap()));
+        KeyValueStoreWithSchema::<TestSchema>::delete(&db, &3)?;
+        assert_eq!(vec![1, 2, 3], iter.take(3).map(|(k, _)| k.unwrap()).collect::<Vec<u64>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap() -> Result<(), DBError> {
+        let db = get_db();
+        let (a, b) = ("a".to_string(), "b".to_string());
+
+        assert!(KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, None, Some(&a))?.is_ok());
+        assert_eq!(KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, None, Some(&b))?, Err(Some(a.clone())));
+        assert!(KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, Some(&a), Some(&b))?.is_ok());
+        assert_eq!(KeyValueStoreWithSchema::<TestSchema>::get(&db, &1)?, Some(b.clone()));
+        assert!(KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, Some(&b), None)?.is_ok());
+        assert_eq!(KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, Some(&b), None)?, Err(None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_raw() -> Result<(), DBError> {
+        let db = get_db();
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &"a".to_string())?;
+
+        let raw = KeyValueStoreWithSchema::<TestSchema>::get_raw(&db, &1)?.unwrap();
+        assert_eq!("a".to_string().encode()?, raw.to_vec());
+        assert!(KeyValueStoreWithSchema::<TestSchema>::get_raw(&db, &2)?.is_none());
+        assert!(KeyValueStoreWithSchema::<TestSchema>::get(&db, &2)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_set() -> Result<(), DBError> {
+        let db = get_db();
+        KeySetWithSchema::<TestSetSchema>::insert(&db, &1)?;
+        KeySetWithSchema::<TestSetSchema>::insert(&db, &1)?;
+        KeySetWithSchema::<TestSetSchema>::insert(&db, &2)?;
+
+        assert!(KeyValueStoreWithSchema::<TestSetSchema>::contains(&db, &1)?);
+        assert!(!KeyValueStoreWithSchema::<TestSetSchema>::contains(&db, &3)?);
+        assert_eq!(Some(IVec::default()), KeyValueStoreWithSchema::<TestSetSchema>::get_raw(&db, &2)?);
+        assert_eq!(Some(()), KeyValueStoreWithSchema::<TestSetSchema>::get(&db, &2)?);
+        let keys: Vec<u64> = KeyValueStoreWithSchema::<TestSetSchema>::iterator(&db, IteratorMode::Start)?
+            .map(|(k, v)| { v.unwrap(); k.unwrap() })
+            .collect();
+        assert_eq!(vec![1, 2], keys);
+
+        KeyValueStoreWithSchema::<TestSetSchema>::delete(&db, &1)?;
+        assert!(!KeyValueStoreWithSchema::<TestSetSchema>::contains(&db, &1)?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_failure_injection() -> Result<(), DBError> {
+        let db = get_db();
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &"a".to_string())?;
+
+        db.failure_injection().fail_nth_write(2);
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &2, &"b".to_string())?;
+        assert!(matches!(KeyValueStoreWithSchema::<TestSchema>::put(&db, &3, &"c".to_string()), Err(DBError::SledError { .. })));
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &4, &"d".to_string())?;
+
+        db.failure_injection().fail_decodes(true);
+        assert!(matches!(KeyValueStoreWithSchema::<TestSchema>::get(&db, &1), Err(DBError::SchemaError { .. })));
+
+        db.failure_injection().reset();
+        assert_eq!(Some("a".to_string()), KeyValueStoreWithSchema::<TestSchema>::get(&db, &1)?);
+        assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(&db, &3)?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_read_retries() -> Result<(), DBError> {
+        let policy = RetryPolicy { max_retries: 2, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let db = get_db().with_read_retries(policy);
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &"a".to_string())?;
+
+        db.failure_injection().fail_reads_transiently(2);
+        assert_eq!(Some("a".to_string()), KeyValueStoreWithSchema::<TestSchema>::get(&db, &1)?);
+        db.failure_injection().fail_reads_transiently(3);
+        let error = KeyValueStoreWithSchema::<TestSchema>::contains(&db, &1).unwrap_err();
+        assert!(error.is_transient());
+
+        // fatal errors are not retried
+        db.failure_injection().reset();
+        db.failure_injection().fail_decodes(true);
+        assert!(!KeyValueStoreWithSchema::<TestSchema>::get_raw(&db, &1).unwrap_err().is_transient());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_points() -> Result<(), DBError> {
+        let db = get_db();
+        assert!(KeyValueStoreWithSchema::<TestSchema>::split_points(&db, 4)?.is_empty());
+
+        for i in 0..200u64 {
+            KeyValueStoreWithSchema::<TestSchema>::put(&db, &i, &i.to_string())?;
+        }
+
+        // keys are spread evenly, so split points are exact
+        assert_eq!(vec![50, 100, 150], KeyValueStoreWithSchema::<TestSchema>::split_points(&db, 4)?);
+        assert!(KeyValueStoreWithSchema::<TestSchema>::split_points(&db, 1)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_coalescer() -> Result<(), DBError> {
+        let config = WriteCoalescerConfig { max_bytes: 80, max_delay: Duration::from_secs(3600) };
+        let db = SledDBWrapper::with_write_coalescer(sled::Config::new().temporary(true).open().expect("error opening database"), config);
+        let stored = |key: u64| db.tree_named(TestCoalescedSchema::tree_name()).unwrap().contains_key(key.encode().unwrap()).unwrap();
+
+        // buffered puts are visible through the wrapper only
+        KeyValueStoreWithSchema::<TestCoalescedSchema>::put(&db, &1, &"a".to_string())?;
+        assert!(!stored(1));
+        assert_eq!(Some("a".to_string()), KeyValueStoreWithSchema::<TestCoalescedSchema>::get(&db, &1)?);
+        assert!(KeyValueStoreWithSchema::<TestCoalescedSchema>::contains(&db, &1)?);
+
+        // other schemas are written directly
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &"a".to_string())?;
+        assert!(db.tree_named(None)?.contains_key(1u64.encode()?)?);
+
+        db.flush_writes()?;
+        assert!(stored(1));
+
+        // byte threshold, every put buffers 18 bytes
+        for i in 2..6u64 {
+            KeyValueStoreWithSchema::<TestCoalescedSchema>::put(&db, &i, &"x".repeat(10))?;
+        }
+        assert!(!stored(5));
+        KeyValueStoreWithSchema::<TestCoalescedSchema>::put(&db, &6, &"x".repeat(10))?;
+        assert!(stored(2) && stored(6));
+
+        // delete is not overtaken by a buffered put of the same key
+        KeyValueStoreWithSchema::<TestCoalescedSchema>::put(&db, &7, &"b".to_string())?;
+        KeyValueStoreWithSchema::<TestCoalescedSchema>::delete(&db, &7)?;
+        assert_eq!(None, KeyValueStoreWithSchema::<TestCoalescedSchema>::get(&db, &7)?);
+        db.flush_writes()?;
+        assert!(!stored(7));
+
+        // iterators see buffered puts
+        KeyValueStoreWithSchema::<TestCoalescedSchema>::put(&db, &8, &"c".to_string())?;
+        assert_eq!(7, KeyValueStoreWithSchema::<TestCoalescedSchema>::iterator(&db, IteratorMode::Start)?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_pipeline() -> Result<(), DBError> {
+        let pipeline = ValuePipeline::new().then(Encryption::new([5; 32])).then(Checksum);
+        let db = get_db().with_value_pipeline::<TestSchema>(pipeline);
+        let (a, b) = ("a".to_string(), "b".to_string());
+
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &a)?;
+        assert_eq!(Some(a.clone()), KeyValueStoreWithSchema::<TestSchema>::get(&db, &1)?);
+        assert_eq!(Some(IVec::from("a")), KeyValueStoreWithSchema::<TestSchema>::get_raw(&db, &1)?);
+        let stored = db.tree_named(None)?.get(1u64.encode()?)?.unwrap();
+        assert_eq!(&[2, ENCRYPTION_STAGE_ID, CHECKSUM_STAGE_ID], &stored[..3]);
+
+        // stored forms of equal values differ, comparison is done on decoded values
+        assert_eq!(Err(Some(a.clone())), KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, Some(&b), Some(&b))?);
+        assert!(KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&db, &1, Some(&a), Some(&b))?.is_ok());
+
+        let mut batch = Batch::default();
+        KeyValueStoreWithSchema::<TestSchema>::put_batch(&db, &mut batch, &2, &a)?;
+        KeyValueStoreWithSchema::<TestSchema>::write_batch(&db, batch)?;
+        let values: Vec<String> = KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Start)?
+            .map(|(_, v)| v.unwrap())
+            .collect();
+        assert_eq!(vec![b.clone(), a.clone()], values);
+
+        // other schemas are not transformed
+        KeyValueStoreWithSchema::<TestCoalescedSchema>::put(&db, &1, &a)?;
+        assert_eq!(IVec::from("a"), db.tree_named(TestCoalescedSchema::tree_name())?.get(1u64.encode()?)?.unwrap());
+
+        let value = db.transaction(&[TestSchema::tree_name()], |tx| {
+            tx.put::<TestSchema>(&3, &"c".to_string())?;
+            tx.get::<TestSchema>(&3)
+        })?;
+        assert_eq!(Some("c".to_string()), value);
+        assert_eq!(Some("c".to_string()), KeyValueStoreWithSchema::<TestSchema>::get(&db, &3)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_per_schema() -> Result<(), DBError> {
+        let db = get_db().with_tree_per_schema();
+        assert_eq!(Some(TestSchema::name()), db.schema_tree_name::<TestSchema>());
+        assert_eq!(TestSetSchema::tree_name(), db.schema_tree_name::<TestSetSchema>());
+
+        let (a, b) = ("a".to_string(), "b".to_string());
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &a)?;
+        KeyValueStoreWithSchema::<TestOtherSchema>::put(&db, &1, &b)?;
+        KeyValueStoreWithSchema::<TestOtherSchema>::put(&db, &2, &b)?;
+        assert_eq!(Some(a.clone()), KeyValueStoreWithSchema::<TestSchema>::get(&db, &1)?);
+        assert_eq!(Some(b.clone()), KeyValueStoreWithSchema::<TestOtherSchema>::get(&db, &1)?);
+        assert_eq!(1, KeyValueStoreWithSchema::<TestSchema>::iterator(&db, IteratorMode::Start)?.count());
+        assert!(db.tree_named(None)?.is_empty());
+
+        assert_eq!(TreeStats { entries: 2, key_bytes: 16, value_bytes: 2 }, db.schema_stats::<TestOtherSchema>()?);
+        let value = db.transaction(&[db.schema_tree_name::<TestSchema>()], |tx| tx.get::<TestSchema>(&1))?;
+        assert_eq!(Some(a), value);
+
+        assert!(db.drop_schema_tree::<TestOtherSchema>()?);
+        assert_eq!(None, KeyValueStoreWithSchema::<TestOtherSchema>::get(&db, &1)?);
+        assert_eq!(1, db.schema_stats::<TestSchema>()?.entries);
+        assert!(matches!(get_db().drop_schema_tree::<TestSchema>(), Err(DBError::DefaultTreeNotDroppable { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_prefix() -> Result<(), DBError> {
+        let db = std::sync::Arc::new(get_db().with_value_pipeline::<TestSchema>(ValuePipeline::new().then(Checksum)));
+        let mut subscriber = KeyValueStoreWithSchema::<TestSchema>::watch_prefix(db.as_ref(), &1)?;
+
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<(), DBError> {
+                KeyValueStoreWithSchema::<TestSchema>::put(db.as_ref(), &2, &"b".to_string())?;
+                KeyValueStoreWithSchema::<TestSchema>::put(db.as_ref(), &1, &"a".to_string())?;
+                KeyValueStoreWithSchema::<TestSchema>::delete(db.as_ref(), &1)
+            })
+        };
+        assert_eq!((1, Some("a".to_string())), subscriber.next().unwrap()?);
+        assert_eq!((1, None), subscriber.next().unwrap()?);
+        writer.join().unwrap()?;
+        assert!(subscriber.next_timeout(Duration::from_millis(10)).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_on_read() -> Result<(), DBError> {
+        let db = get_db();
+        let tree = db.tree_named(TestUpgradedSchema::tree_name())?;
+        tree.insert(1u64.encode()?, 7u32.encode()?)?;
+        tree.insert(2u64.encode()?, 8u32.encode()?)?;
+        tree.insert(3u64.encode()?, vec![1, 2, 3])?;
+
+        let values: Vec<u64> = KeyValueStoreWithSchema::<TestUpgradedSchema>::iterator(&db, IteratorMode::Start)?
+            .take(2)
+            .map(|(_, v)| v.unwrap())
+            .collect();
+        assert_eq!(vec![7, 8], values);
+        assert_eq!(4, tree.get(1u64.encode()?)?.unwrap().len());
+
+        // get writes the upgraded value back
+        assert_eq!(Some(7), KeyValueStoreWithSchema::<TestUpgradedSchema>::get(&db, &1)?);
+        assert_eq!(7u64.encode()?, tree.get(1u64.encode()?)?.unwrap().to_vec());
+        assert_eq!(Some(7), KeyValueStoreWithSchema::<TestUpgradedSchema>::get(&db, &1)?);
+
+        // values in no known format still fail
+        assert!(KeyValueStoreWithSchema::<TestUpgradedSchema>::get(&db, &3).is_err());
+        Ok(())
+    }
+}
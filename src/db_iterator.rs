@@ -1,5 +1,7 @@
-use sled::{Error, Iter, IVec, Db};
+use sled::{Error, Iter, IVec, Tree};
 use crate::schema::KeyValueSchema;
+use std::collections::{BTreeMap, VecDeque};
+use std::marker::PhantomData;
 
 
 /// Database iterator direction
@@ -15,61 +17,148 @@ pub enum IteratorMode{
     Start,
     End,
     From(IVec, Direction),
+    /// Last `n` entries in reverse order
+    Tail(usize),
 }
 
+/// Streaming iterator over a tree, holds a live sled cursor advanced by every call of `next`,
+/// or once per batch of entries, see [DBIterator::with_batch_size]
 pub struct DBIterator<'a> {
-    raw: &'a Db,
-    mode: IteratorMode,
+    raw: Cursor,
+    direction: Direction,
+    /// Number of entries left to return, for [IteratorMode::Tail]
+    remaining: Option<usize>,
+    batch_size: usize,
+    /// Entries read from the cursor, but not returned yet
+    buffer: VecDeque<Result<(IVec, IVec)>>,
+    _db: PhantomData<&'a ()>,
 }
 
 impl<'a> DBIterator<'a> {
-    pub(crate) fn new(raw: &'a Db, mode: IteratorMode) -> Self {
+    pub(crate) fn new(raw: &Tree, mode: IteratorMode) -> Self {
+        let (raw, direction, remaining) = match mode {
+            IteratorMode::Start => (raw.iter(), Direction::Forward, None),
+            IteratorMode::End => (raw.iter(), Direction::Reverse, None),
+            IteratorMode::From(key, Direction::Forward) => (raw.range(key..), Direction::Forward, None),
+            IteratorMode::From(key, Direction::Reverse) => (raw.range(..=key), Direction::Reverse, None),
+            IteratorMode::Tail(n) => (raw.iter(), Direction::Reverse, Some(n)),
+        };
+        Self::from_cursor(Cursor::Sled(Box::new(raw)), direction, remaining)
+    }
+
+    pub(crate) fn with_prefix(raw: &Tree, prefix: &[u8]) -> Self {
+        Self::from_cursor(Cursor::Sled(Box::new(raw.scan_prefix(prefix))), Direction::Forward, None)
+    }
+
+    /// Iterate over a copy of the entries of `tree` taken now, later writes are not seen
+    pub(crate) fn over_snapshot(tree: &BTreeMap<Vec<u8>, Vec<u8>>, mode: IteratorMode) -> Self {
+        let (entries, direction, remaining) = match mode {
+            IteratorMode::Start => (snapshot(tree.iter()), Direction::Forward, None),
+            IteratorMode::End => (snapshot(tree.iter()), Direction::Reverse, None),
+            IteratorMode::From(key, Direction::Forward) => (snapshot(tree.range(key.to_vec()..)), Direction::Forward, None),
+            IteratorMode::From(key, Direction::Reverse) => (snapshot(tree.range(..=key.to_vec())), Direction::Reverse, None),
+            IteratorMode::Tail(n) => (snapshot(tree.iter()), Direction::Reverse, Some(n)),
+        };
+        Self::from_cursor(Cursor::Snapshot(entries.into_iter()), direction, remaining)
+    }
+
+    /// Iterate over a copy of the entries of `tree`, which keys start with `prefix`
+    pub(crate) fn over_snapshot_with_prefix(tree: &BTreeMap<Vec<u8>, Vec<u8>>, prefix: &[u8]) -> Self {
+        let entries = snapshot(tree.range(prefix.to_vec()..).take_while(|(key, _)| key.starts_with(prefix)));
+        Self::from_cursor(Cursor::Snapshot(entries.into_iter()), Direction::Forward, None)
+    }
+
+    fn from_cursor(raw: Cursor, direction: Direction, remaining: Option<usize>) -> Self {
         DBIterator {
             raw,
-            mode,
+            direction,
+            remaining,
+            batch_size: 1,
+            buffer: VecDeque::new(),
+            _db: PhantomData,
+        }
+    }
+
+    /// Read up to `batch_size` entries from the cursor at once and return them from a buffer,
+    /// which cuts per-entry overhead of large scans. Entries read in a batch are not affected by
+    /// later writes. Sizes below 1 are treated as 1.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    fn read_raw(&mut self) -> Option<Result<(IVec, IVec)>> {
+        match (&mut self.raw, &self.direction) {
+            (Cursor::Sled(raw), Direction::Forward) => raw.next(),
+            (Cursor::Sled(raw), Direction::Reverse) => raw.next_back(),
+            (Cursor::Snapshot(entries), Direction::Forward) => entries.next().map(Ok),
+            (Cursor::Snapshot(entries), Direction::Reverse) => entries.next_back().map(Ok),
+        }
+    }
+
+    /// Fill buffer with the next batch, not reading past the end of a tail
+    fn read_batch(&mut self) {
+        let batch_size = match self.remaining {
+            // the entry about to be returned is already deducted
+            Some(remaining) => self.batch_size.min(remaining + 1),
+            None => self.batch_size,
+        };
+        while self.buffer.len() < batch_size {
+            match self.read_raw() {
+                Some(Ok(entry)) => self.buffer.push_back(Ok(entry)),
+                // an error is returned after the entries read before it
+                Some(Err(error)) => {
+                    self.buffer.push_back(Err(error));
+                    break;
+                }
+                None => break,
+            }
         }
     }
 }
 
+enum Cursor {
+    Sled(Box<Iter>),
+    Snapshot(std::vec::IntoIter<(IVec, IVec)>),
+}
+
+fn snapshot<'t, I: Iterator<Item = (&'t Vec<u8>, &'t Vec<u8>)>>(entries: I) -> Vec<(IVec, IVec)> {
+    entries.map(|(key, value)| (IVec::from(key.as_slice()), IVec::from(value.as_slice()))).collect()
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl<'a> Iterator for DBIterator<'a> {
     type Item = Result<(IVec, IVec)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &self.mode {
-            IteratorMode::Start => {
-                self.raw.iter().next()
-            }
-            IteratorMode::End => {
-                self.raw.iter().last()
-            }
-            IteratorMode::From(k, direction) => {
-                let key = k.to_vec();
-                match direction {
-                    Direction::Forward => {
-                        self.raw.range(key..).next()
-                    }
-                    Direction::Reverse => {
-                        self.raw.range(key..).last()
-                    }
-                }
+        if let Some(remaining) = &mut self.remaining {
+            if *remaining == 0 {
+                return None;
             }
+            *remaining -= 1;
+        }
+        if self.batch_size == 1 {
+            return self.read_raw();
+        }
+        if self.buffer.is_empty() {
+            self.read_batch();
         }
+        self.buffer.pop_front()
     }
 }
 
 pub trait DBIterationHandler {
-    fn iterator(&self, mode: IteratorMode) -> DBIterator;
-    fn scan_prefix_iterator(&self, prefix: &[u8]) -> DBIterator;
+    fn iterator<'a>(&self, mode: IteratorMode) -> DBIterator<'a>;
+    fn scan_prefix_iterator<'a>(&self, prefix: &[u8]) -> DBIterator<'a>;
 }
 
-impl DBIterationHandler for Db {
-    fn iterator(&self, mode: IteratorMode) -> DBIterator {
+impl DBIterationHandler for Tree {
+    fn iterator<'a>(&self, mode: IteratorMode) -> DBIterator<'a> {
         DBIterator::new(self, mode)
     }
 
-    fn scan_prefix_iterator(&self, prefix: &[u8]) -> DBIterator {
-        DBIterator::new(self, IteratorMode::From(IVec::from(prefix), Direction::Forward))
+    fn scan_prefix_iterator<'a>(&self, prefix: &[u8]) -> DBIterator<'a> {
+        DBIterator::with_prefix(self, prefix)
     }
 }
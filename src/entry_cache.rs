@@ -0,0 +1,165 @@
+//! Scan-resistant cache of decoded entries.
+//!
+//! Entries are admitted to a probationary segment and promoted to a protected segment only on
+//! their second hit. One-off traversals (materialization, exports) touch most entries once, so
+//! they churn the probationary segment only, while the working set of block application stays
+//! protected.
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Share of capacity reserved for the protected segment, in percent
+const PROTECTED_PERCENT: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Probation,
+    Protected,
+}
+
+/// Segmented LRU map with capacity counted in items
+pub(crate) struct SegmentedLru<K, V> {
+    capacity: usize,
+    protected_capacity: usize,
+    items: HashMap<K, (V, Segment, u64)>,
+    // least recently used items first, keyed by last use
+    probation: BTreeMap<u64, K>,
+    protected: BTreeMap<u64, K>,
+    tick: u64,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> SegmentedLru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        SegmentedLru {
+            capacity,
+            protected_capacity: capacity * PROTECTED_PERCENT / 100,
+            items: HashMap::new(),
+            probation: BTreeMap::new(),
+            protected: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Get item, promoting it to the protected segment.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        let (segment, used) = match self.items.get(key) {
+            Some((_, segment, used)) => (*segment, *used),
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        self.hits += 1;
+        self.tick += 1;
+        match segment {
+            Segment::Probation => self.probation.remove(&used),
+            Segment::Protected => self.protected.remove(&used),
+        };
+        self.protected.insert(self.tick, key.clone());
+        let item = self.items.get_mut(key).unwrap();
+        item.1 = Segment::Protected;
+        item.2 = self.tick;
+        let value = item.0.clone();
+
+        // least recently used protected item gets another chance in probation
+        if self.protected.len() > self.protected_capacity {
+            let (_, demoted) = pop_first(&mut self.protected).unwrap();
+            self.tick += 1;
+            self.probation.insert(self.tick, demoted.clone());
+            let item = self.items.get_mut(&demoted).unwrap();
+            item.1 = Segment::Probation;
+            item.2 = self.tick;
+        }
+        Some(value)
+    }
+
+    /// Admit item to the probationary segment, evicting least recently used items over capacity.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 || self.items.contains_key(&key) {
+            return;
+        }
+        self.tick += 1;
+        self.probation.insert(self.tick, key.clone());
+        self.items.insert(key, (value, Segment::Probation, self.tick));
+
+        while self.items.len() > self.capacity {
+            let evicted = match pop_first(&mut self.probation) {
+                Some((_, key)) => key,
+                None => pop_first(&mut self.protected).unwrap().1,
+            };
+            self.items.remove(&evicted);
+        }
+    }
+
+    /// Drop item, e.g. after it was deleted from the database.
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some((_, segment, used)) = self.items.remove(key) {
+            match segment {
+                Segment::Probation => self.probation.remove(&used),
+                Segment::Protected => self.protected.remove(&used),
+            };
+        }
+    }
+}
+
+fn pop_first<K: Clone>(map: &mut BTreeMap<u64, K>) -> Option<(u64, K)> {
+    let first = *map.keys().next()?;
+    map.remove(&first).map(|key| (first, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_resistance() {
+        let mut cache = SegmentedLru::new(10);
+        for hot in 0..5 {
+            cache.insert(hot, hot);
+            assert_eq!(Some(hot), cache.get(&hot));
+        }
+
+        // one-off scan much larger than the cache
+        for cold in 100..200 {
+            cache.insert(cold, cold);
+        }
+        assert_eq!(10, cache.len());
+        for hot in 0..5 {
+            assert_eq!(Some(hot), cache.get(&hot));
+        }
+        assert_eq!(None, cache.get(&100));
+        assert_eq!(Some(199), cache.get(&199));
+        assert_eq!(11, cache.hits);
+        assert_eq!(1, cache.misses);
+    }
+
+    #[test]
+    fn test_protected_overflow() {
+        let mut cache = SegmentedLru::new(5);
+        for i in 0..5 {
+            cache.insert(i, i);
+            cache.get(&i);
+        }
+        // protected segment holds 4 items, the least recently used was demoted and is evicted first
+        cache.insert(5, 5);
+        assert_eq!(5, cache.len());
+        assert_eq!(None, cache.get(&0));
+        assert!((1..6).all(|i| cache.get(&i).is_some()));
+
+        cache.remove(&3);
+        cache.remove(&100);
+        assert_eq!(4, cache.len());
+        assert_eq!(None, cache.get(&3));
+
+        let mut disabled = SegmentedLru::new(0);
+        disabled.insert(1, 1);
+        assert_eq!(None, disabled.get(&1));
+    }
+}
@@ -0,0 +1,192 @@
+//! Codecs of merkle storage entries chosen by entry kind.
+//!
+//! Trees are small and full of hashes, which hardly compress, while blobs are larger and often
+//! compress well. [EntryCodecs] is a [ValueTransform] stage passing every entry to the codec
+//! configured for its kind, so e.g. trees can be stored as they are and blobs compressed.
+//! [MerkleStorage::compression_stats](crate::merkle_storage::MerkleStorage::compression_stats)
+//! reports how well entries of each kind are compressed by the pipeline.
+use std::sync::Arc;
+
+use crate::merkle_storage::EntryKind;
+use crate::value_transform::{TransformError, TransformKind, ValueTransform, ENTRY_CODECS_STAGE_ID};
+
+/// Marks entries stored as they are by [EntryCodecs]
+const NO_CODEC: u8 = 0;
+
+/// Stage applying the codec configured for the kind of each entry, e.g.
+/// `EntryCodecs::new().blobs(compression)`. It must be the first stage of the pipeline of
+/// [MerkleStorage](crate::merkle_storage::MerkleStorage), later stages no longer see the kind.
+/// Entries of kinds without a codec are stored as they are.
+#[derive(Clone, Default)]
+pub struct EntryCodecs {
+    trees: Option<Arc<dyn ValueTransform>>,
+    blobs: Option<Arc<dyn ValueTransform>>,
+    commits: Option<Arc<dyn ValueTransform>>,
+}
+
+impl EntryCodecs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trees<T: ValueTransform + 'static>(mut self, codec: T) -> Self {
+        self.trees = Some(Arc::new(codec));
+        self
+    }
+
+    pub fn blobs<T: ValueTransform + 'static>(mut self, codec: T) -> Self {
+        self.blobs = Some(Arc::new(codec));
+        self
+    }
+
+    pub fn commits<T: ValueTransform + 'static>(mut self, codec: T) -> Self {
+        self.commits = Some(Arc::new(codec));
+        self
+    }
+
+    fn codec(&self, kind: EntryKind) -> Option<&Arc<dyn ValueTransform>> {
+        match kind {
+            EntryKind::Tree => self.trees.as_ref(),
+            EntryKind::Blob => self.blobs.as_ref(),
+            EntryKind::Commit => self.commits.as_ref(),
+        }
+    }
+}
+
+impl ValueTransform for EntryCodecs {
+    fn id(&self) -> u8 {
+        ENTRY_CODECS_STAGE_ID
+    }
+
+    fn kind(&self) -> TransformKind {
+        TransformKind::Compression
+    }
+
+    /// Prepend id of the applied codec, so entries stay readable when codecs are reconfigured.
+    fn apply(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let codec = EntryKind::of(value).and_then(|kind| self.codec(kind));
+        let (id, encoded) = match codec {
+            Some(codec) => (codec.id(), codec.apply(value)?),
+            None => (NO_CODEC, value.to_vec()),
+        };
+        let mut stored = Vec::with_capacity(1 + encoded.len());
+        stored.push(id);
+        stored.extend(encoded);
+        Ok(stored)
+    }
+
+    fn reverse(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let (id, encoded) = value.split_first().ok_or(TransformError::InvalidHeader)?;
+        if *id == NO_CODEC {
+            return Ok(encoded.to_vec());
+        }
+        let codec = [&self.trees, &self.blobs, &self.commits].iter()
+            .filter_map(|codec| codec.as_ref())
+            .find(|codec| codec.id() == *id)
+            .ok_or(TransformError::UnknownStage { id: *id })?;
+        codec.reverse(encoded)
+    }
+}
+
+/// Sizes of entries of one kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct KindCompression {
+    pub entries: u64,
+    /// bytes of serialized entries
+    pub plain_bytes: u64,
+    /// bytes stored in the database, after the value pipeline
+    pub stored_bytes: u64,
+}
+
+impl KindCompression {
+    /// Stored bytes per plain byte, below 1 if entries are compressed, `None` without entries
+    pub fn ratio(&self) -> Option<f64> {
+        match self.plain_bytes {
+            0 => None,
+            plain_bytes => Some(self.stored_bytes as f64 / plain_bytes as f64),
+        }
+    }
+
+    pub(crate) fn add(&mut self, plain_bytes: usize, stored_bytes: usize) {
+        self.entries += 1;
+        self.plain_bytes += plain_bytes as u64;
+        self.stored_bytes += stored_bytes as u64;
+    }
+}
+
+/// Result of [MerkleStorage::compression_stats](crate::merkle_storage::MerkleStorage::compression_stats)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressionStats {
+    pub trees: KindCompression,
+    pub blobs: KindCompression,
+    pub commits: KindCompression,
+}
+
+impl CompressionStats {
+    pub(crate) fn kind_mut(&mut self, kind: EntryKind) -> &mut KindCompression {
+        match kind {
+            EntryKind::Tree => &mut self.trees,
+            EntryKind::Blob => &mut self.blobs,
+            EntryKind::Commit => &mut self.commits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value_transform::{ValuePipeline, FIRST_CUSTOM_STAGE_ID};
+
+    /// Drops trailing zeros and records their count, stands in for a compression codec
+    struct TrimZeros;
+
+    impl ValueTransform for TrimZeros {
+        fn id(&self) -> u8 {
+            FIRST_CUSTOM_STAGE_ID
+        }
+
+        fn kind(&self) -> TransformKind {
+            TransformKind::Compression
+        }
+
+        fn apply(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+            let len = value.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+            let mut trimmed = value[..len].to_vec();
+            trimmed.extend(&((value.len() - len) as u32).to_le_bytes());
+            Ok(trimmed)
+        }
+
+        fn reverse(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+            let (trimmed, zeros) = value.split_at(value.len() - 4);
+            let mut count = [0; 4];
+            count.copy_from_slice(zeros);
+            let mut restored = trimmed.to_vec();
+            restored.resize(trimmed.len() + u32::from_le_bytes(count) as usize, 0);
+            Ok(restored)
+        }
+    }
+
+    #[test]
+    fn test_entry_codecs() -> Result<(), TransformError> {
+        let tree = [0, 0, 0, 0, 5, 0, 0, 0, 0, 0];
+        let blob = [1, 0, 0, 0, 7, 0, 0, 0, 0, 0];
+        assert_eq!(Some(EntryKind::Tree), EntryKind::of(&tree));
+        assert_eq!(Some(EntryKind::Blob), EntryKind::of(&blob));
+        assert_eq!(None, EntryKind::of(&[1, 0]));
+
+        let pipeline = ValuePipeline::new().then(EntryCodecs::new().blobs(TrimZeros));
+        let (stored_tree, stored_blob) = (pipeline.encode(&tree)?, pipeline.encode(&blob)?);
+        assert_eq!(2 + 1 + tree.len(), stored_tree.len());
+        assert_eq!(2 + 1 + 5 + 4, stored_blob.len());
+        assert_eq!(tree.to_vec(), pipeline.decode(&stored_tree)?);
+        assert_eq!(blob.to_vec(), pipeline.decode(&stored_blob)?);
+
+        // blobs written by a codec, which is no longer configured, cannot be read
+        let plain = ValuePipeline::new().then(EntryCodecs::new());
+        assert_eq!(tree.to_vec(), plain.decode(&stored_tree)?);
+        assert!(matches!(plain.decode(&stored_blob), Err(TransformError::UnknownStage { id: FIRST_CUSTOM_STAGE_ID })));
+        Ok(())
+    }
+}
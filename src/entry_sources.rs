@@ -0,0 +1,99 @@
+//! Sources of entries missing in the database.
+//!
+//! [MerkleStorage::add_entry_source](crate::merkle_storage::MerkleStorage::add_entry_source)
+//! appends a source to the chain tried, in order, by every read of an entry, which is not in the
+//! database, e.g. snapshot pack files followed by a client of a remote peer. Fetched entries are
+//! checked against their hash and stored in the database, so a node can start from a commit hash
+//! alone and sync the state lazily, entry by entry, as it is read.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use bincode::Options;
+use failure::Fail;
+
+use crate::merkle_storage::{EntryHash, ExportHeader, MAX_IMPORT_RECORD_LEN};
+use crate::metadata::{is_readable_entry_format, ENTRY_FORMAT_VERSION};
+
+#[derive(Debug, Fail)]
+pub enum EntrySourceError {
+    #[fail(display = "Entry source I/O error: {}", error)]
+    IoError { error: io::Error },
+    #[fail(display = "Invalid pack file: {}", reason)]
+    InvalidPack { reason: String },
+    #[fail(display = "Entry source failed: {}", reason)]
+    Failed { reason: String },
+}
+
+impl From<io::Error> for EntrySourceError {
+    fn from(error: io::Error) -> Self {
+        EntrySourceError::IoError { error }
+    }
+}
+
+/// Read-only store of serialized entries keyed by their hash
+pub trait EntrySource: Send + Sync {
+    /// Get entry serialized as by [Entry::encode](crate::merkle_storage::Entry::encode), `None` if
+    /// the source does not have it.
+    fn get(&self, hash: &EntryHash) -> Result<Option<Vec<u8>>, EntrySourceError>;
+}
+
+/// [EntrySource] reading a stream written by
+/// [Snapshot::export](crate::merkle_storage::Snapshot::export). Offsets of entries are indexed
+/// when the file is opened, their bytes are read on demand.
+pub struct PackEntrySource {
+    file: Mutex<File>,
+    // offset and length of every entry
+    index: HashMap<EntryHash, (u64, usize)>,
+    head: Option<EntryHash>,
+}
+
+impl PackEntrySource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, EntrySourceError> {
+        let invalid_pack = |error: bincode::Error| EntrySourceError::InvalidPack { reason: error.to_string() };
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_IMPORT_RECORD_LEN);
+        let mut reader = BufReader::new(File::open(path)?);
+        let header: ExportHeader = options.deserialize_from(&mut reader).map_err(invalid_pack)?;
+        if !is_readable_entry_format(header.entry_format_version) {
+            return Err(EntrySourceError::InvalidPack {
+                reason: format!("entry format version is {}, expected at most {}", header.entry_format_version, ENTRY_FORMAT_VERSION),
+            });
+        }
+
+        let mut index = HashMap::new();
+        while let Some((hash, bytes)) = options.deserialize_from::<_, Option<(EntryHash, Vec<u8>)>>(&mut reader).map_err(invalid_pack)? {
+            // bytes of the entry end the record
+            let end = reader.stream_position()?;
+            index.insert(hash, (end - bytes.len() as u64, bytes.len()));
+        }
+        Ok(PackEntrySource { file: Mutex::new(reader.into_inner()), index, head: header.head })
+    }
+
+    /// Commit the pack was exported from
+    pub fn head(&self) -> Option<EntryHash> {
+        self.head
+    }
+
+    pub fn entries(&self) -> usize {
+        self.index.len()
+    }
+}
+
+impl EntrySource for PackEntrySource {
+    fn get(&self, hash: &EntryHash) -> Result<Option<Vec<u8>>, EntrySourceError> {
+        let (offset, len) = match self.index.get(hash) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len];
+        file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+}
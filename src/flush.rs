@@ -0,0 +1,192 @@
+//! Background flushing of a store.
+//!
+//! Sled flushes its write buffers on a fixed interval set when the database is opened. Stores
+//! opened with `flush_every_ms(None)` can hand flushing over to their [SledDBWrapper] instead,
+//! which flushes on an interval adjustable at runtime and reports every flush to observers.
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::database::{DBError, SledDBWrapper};
+
+/// Called after every flush of a store
+pub type FlushObserver = Box<dyn Fn(&FlushEvent) + Send + Sync>;
+
+/// Flush of a store, passed to [FlushObserver]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlushEvent {
+    pub bytes_written: u64,
+    pub duration: Duration,
+    /// flush was made by the background thread, not by [SledDBWrapper::flush]
+    pub background: bool,
+}
+
+#[derive(Default)]
+struct FlusherState {
+    interval: Option<Duration>,
+    stopped: bool,
+}
+
+/// Thread flushing a store on an interval, started by the first [SledDBWrapper::set_flush_interval]
+#[derive(Default)]
+pub(crate) struct BackgroundFlusher {
+    state: Arc<(Mutex<FlusherState>, Condvar)>,
+    observers: Arc<Mutex<Vec<FlushObserver>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl BackgroundFlusher {
+    fn run(db: sled::Db, state: Arc<(Mutex<FlusherState>, Condvar)>, observers: Arc<Mutex<Vec<FlushObserver>>>) {
+        let (lock, condvar) = &*state;
+        let mut guard = lock.lock().unwrap();
+        while !guard.stopped {
+            let interval = match guard.interval {
+                Some(interval) => interval,
+                None => {
+                    guard = condvar.wait(guard).unwrap();
+                    continue;
+                }
+            };
+            let (next, timeout) = condvar.wait_timeout(guard, interval).unwrap();
+            guard = next;
+            // changed interval restarts the wait
+            if !timeout.timed_out() || guard.stopped {
+                continue;
+            }
+            drop(guard);
+            // errors will surface on the next explicit flush or write
+            let _ = flush_db(&db, &observers, true);
+            guard = lock.lock().unwrap();
+        }
+    }
+}
+
+impl BackgroundFlusher {
+    /// Stop the thread, waiting for a flush in progress
+    fn stop(&self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().unwrap().stopped = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for BackgroundFlusher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn flush_db(db: &sled::Db, observers: &Mutex<Vec<FlushObserver>>, background: bool) -> Result<u64, DBError> {
+    let started = Instant::now();
+    let bytes_written = db.flush()? as u64;
+    let event = FlushEvent { bytes_written, duration: started.elapsed(), background };
+    for observer in observers.lock().unwrap().iter() {
+        observer(&event);
+    }
+    Ok(bytes_written)
+}
+
+impl SledDBWrapper {
+    /// Flush the store every `interval`, `None` stops flushing. Sled keeps flushing on its
+    /// own interval too, unless the store was opened with `flush_every_ms(None)`.
+    pub fn set_flush_interval(&self, interval: Option<Duration>) {
+        let flusher = self.flusher();
+        let (lock, condvar) = &*flusher.state;
+        lock.lock().unwrap().interval = interval;
+        condvar.notify_all();
+
+        let mut thread = flusher.thread.lock().unwrap();
+        if thread.is_none() && interval.is_some() {
+            let (db, state, observers) = (self.sled_db().clone(), flusher.state.clone(), flusher.observers.clone());
+            *thread = Some(std::thread::spawn(move || BackgroundFlusher::run(db, state, observers)));
+        }
+    }
+
+    /// Interval of background flushes set by [SledDBWrapper::set_flush_interval]
+    pub fn flush_interval(&self) -> Option<Duration> {
+        self.flusher().state.0.lock().unwrap().interval
+    }
+
+    /// Register observer of flushes, both background and explicit.
+    pub fn add_flush_observer(&self, observer: FlushObserver) {
+        self.flusher().observers.lock().unwrap().push(observer);
+    }
+
+    /// Write coalesced puts and flush the store, returns number of bytes flushed.
+    pub fn flush(&self) -> Result<u64, DBError> {
+        self.flush_writes()?;
+        flush_db(self.sled_db(), &self.flusher().observers, false)
+    }
+
+    /// Stop background flushing for good and flush the store, returns number of bytes flushed.
+    /// Unlike dropping the store, errors of the final flush are reported.
+    pub fn shutdown(&self) -> Result<u64, DBError> {
+        self.flusher().stop();
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::database::KeyValueStoreWithSchema;
+    use crate::schema::KeyValueSchema;
+
+    struct TestSchema;
+
+    impl KeyValueSchema for TestSchema {
+        type Key = u64;
+        type Value = Vec<u8>;
+
+        fn name() -> &'static str {
+            "test_schema"
+        }
+    }
+
+    #[test]
+    fn test_flush_interval() -> Result<(), DBError> {
+        let db = sled::Config::new().temporary(true).flush_every_ms(None).open().expect("error opening database");
+        let db = SledDBWrapper::new(db);
+        let (background, explicit) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let (b, e) = (background.clone(), explicit.clone());
+        db.add_flush_observer(Box::new(move |event| {
+            let counter = if event.background { &b } else { &e };
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        KeyValueStoreWithSchema::<TestSchema>::put(&db, &1, &vec![1u8; 100])?;
+        db.flush()?;
+        assert_eq!(1, explicit.load(Ordering::SeqCst));
+        assert_eq!(None, db.flush_interval());
+
+        db.set_flush_interval(Some(Duration::from_millis(10)));
+        assert_eq!(Some(Duration::from_millis(10)), db.flush_interval());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while background.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(background.load(Ordering::SeqCst) >= 2);
+
+        db.set_flush_interval(None);
+        std::thread::sleep(Duration::from_millis(20));
+        let stopped_at = background.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(stopped_at, background.load(Ordering::SeqCst));
+
+        // shutdown joins the thread and flushes once more
+        db.set_flush_interval(Some(Duration::from_millis(1)));
+        db.shutdown()?;
+        assert!(db.flusher().thread.lock().unwrap().is_none());
+        let (stopped_at, explicit_at) = (background.load(Ordering::SeqCst), explicit.load(Ordering::SeqCst));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(stopped_at, background.load(Ordering::SeqCst));
+        assert_eq!(2, explicit_at);
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+//! Index of entry hashes by their short prefix.
+//!
+//! When enabled, every entry written by a commit is recorded under the first
+//! [HASH_INDEX_PREFIX_LEN] bytes of its hash, so debugging tools can resolve abbreviated hex
+//! hashes (like `9ae1b2`) to full entry hashes with a single point lookup.
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+/// Number of leading hash bytes entries are indexed by
+pub const HASH_INDEX_PREFIX_LEN: usize = 3;
+
+/// Shortest abbreviated hash (in hex characters) accepted for resolution, covers the indexed bytes
+pub const MIN_HASH_PREFIX_HEX_LEN: usize = HASH_INDEX_PREFIX_LEN * 2;
+
+pub type HashPrefix = [u8; HASH_INDEX_PREFIX_LEN];
+
+pub type HashIndexKV = dyn KeyValueStoreWithSchema<HashIndexSchema> + Sync + Send;
+
+impl BincodeEncoded for HashPrefix {}
+
+/// Full hashes of all indexed entries sharing one [HashPrefix]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HashCandidates(pub Vec<EntryHash>);
+
+impl BincodeEncoded for HashCandidates {}
+
+/// Leading bytes of `hash` used as index key
+pub fn hash_prefix(hash: &EntryHash) -> HashPrefix {
+    let mut prefix = HashPrefix::default();
+    prefix.copy_from_slice(&hash[..HASH_INDEX_PREFIX_LEN]);
+    prefix
+}
+
+/// Full entry hashes keyed by [HashPrefix]
+pub struct HashIndexSchema;
+
+impl KeyValueSchema for HashIndexSchema {
+    type Key = HashPrefix;
+    type Value = HashCandidates;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_hash_index"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,96 @@
+//! History cells of context keys.
+//!
+//! When enabled, every commit appends a version to the cell of each key it changed, with the
+//! hash of the value before and after the commit, and records its own height (number of
+//! ancestors). Value of a key at an ancestor commit is then found among the versions of that
+//! key by height, without walking the parent chain from the head down to the ancestor.
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type KeyHistoryKV = dyn KeyValueStoreWithSchema<KeyHistorySchema> + Sync + Send;
+pub type CommitHeightKV = dyn KeyValueStoreWithSchema<CommitHeightSchema> + Sync + Send;
+
+/// Change of a key by a commit, `None` value hashes stand for a missing key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyVersion {
+    pub commit_hash: EntryHash,
+    pub height: u64,
+    pub old_value_hash: Option<EntryHash>,
+    pub new_value_hash: Option<EntryHash>,
+}
+
+/// All recorded changes of a single key, oldest first
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyHistory(pub Vec<KeyVersion>);
+
+impl BincodeEncoded for KeyHistory {}
+
+impl KeyHistory {
+    /// Hash of the value at height `height` of the recorded line of history, `None` inside the
+    /// option if the key was missing. Versions recorded on other branches are not told apart.
+    pub fn value_hash_at(&self, height: u64) -> Option<Option<EntryHash>> {
+        match self.0.iter().rposition(|version| version.height <= height) {
+            Some(index) => Some(self.0[index].new_value_hash),
+            None => self.0.first().map(|version| version.old_value_hash),
+        }
+    }
+}
+
+/// History cells keyed by slash separated context key
+pub struct KeyHistorySchema;
+
+impl KeyValueSchema for KeyHistorySchema {
+    type Key = String;
+    type Value = KeyHistory;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_key_history"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
+
+/// Number of ancestors of commits made while key history was recorded
+pub struct CommitHeightSchema;
+
+impl KeyValueSchema for CommitHeightSchema {
+    type Key = EntryHash;
+    type Value = u64;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_commit_heights"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(height: u64, old: u8, new: u8) -> KeyVersion {
+        KeyVersion { commit_hash: [height as u8; 32], height, old_value_hash: Some([old; 32]), new_value_hash: Some([new; 32]) }
+    }
+
+    #[test]
+    fn test_value_hash_at() {
+        let history = KeyHistory(vec![version(2, 1, 2), version(5, 2, 3)]);
+        assert_eq!(Some(Some([1; 32])), history.value_hash_at(0));
+        assert_eq!(Some(Some([2; 32])), history.value_hash_at(2));
+        assert_eq!(Some(Some([2; 32])), history.value_hash_at(4));
+        assert_eq!(Some(Some([3; 32])), history.value_hash_at(9));
+        assert_eq!(None, KeyHistory::default().value_hash_at(1));
+    }
+}
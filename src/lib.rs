@@ -9,12 +9,75 @@ mod codec;
 mod  merkle_storage;
 mod database;
 mod db_iterator;
+mod tombstones;
+mod hash_index;
+mod metadata;
+mod context_key;
+mod refs;
+mod annotations;
+mod profile;
+mod audit_log;
+mod transaction;
+mod value_index;
+mod apply_metrics;
+mod value_transform;
+mod flush;
+mod entry_cache;
+mod blob_store;
+mod proof;
+mod replay;
+mod ref_counts;
+mod change_filter;
+mod key_history;
+mod entry_codecs;
+mod access_stats;
+mod staging_journal;
+mod maintenance;
+mod entry_sources;
+mod memory_store;
+mod backend;
+mod block_contexts;
+#[cfg(feature = "context-api")]
+mod context_api;
 
 pub mod prelude {
     pub use crate::database::*;
+    pub use crate::schema::*;
     pub use crate::merkle_storage::*;
     pub use crate::db_iterator::*;
+    // schema typed iteration of the store traits, rather than of raw sled trees
+    pub use crate::database::{Direction, IteratorMode};
     pub use crate::codec::*;
+    pub use crate::tombstones::*;
+    pub use crate::hash_index::*;
+    pub use crate::metadata::*;
+    pub use crate::context_key::*;
+    pub use crate::refs::*;
+    pub use crate::annotations::*;
+    pub use crate::profile::*;
+    pub use crate::audit_log::*;
+    pub use crate::transaction::*;
+    pub use crate::value_index::*;
+    pub use crate::apply_metrics::*;
+    pub use crate::value_transform::*;
+    pub use crate::flush::*;
+    pub use crate::blob_store::*;
+    pub use crate::proof::*;
+    pub use crate::replay::*;
+    pub use crate::ref_counts::*;
+    pub use crate::change_filter::*;
+    pub use crate::key_history::*;
+    pub use crate::entry_codecs::*;
+    pub use crate::access_stats::*;
+    pub use crate::staging_journal::*;
+    pub use crate::maintenance::*;
+    pub use crate::entry_sources::*;
+    pub use crate::memory_store::*;
+    pub use crate::backend::*;
+    pub use crate::block_contexts::*;
+    #[cfg(feature = "context-api")]
+    pub use crate::context_api::*;
+    pub use sled::IVec;
 }
 
 
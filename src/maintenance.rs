@@ -0,0 +1,207 @@
+//! Offline maintenance of the database directory.
+//!
+//! Sled is log-structured, space of overwritten and deleted values is reused but rarely returned
+//! to the operating system, so a database stays as large as it ever was even after
+//! [MerkleStorage::gc](crate::merkle_storage::MerkleStorage::gc) deleted most entries.
+//! [vacuum] rewrites the database into a fresh directory holding only the current contents of
+//! every tree and swaps it in place of the original one.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use failure::Fail;
+
+use crate::database::DBError;
+
+/// Entries copied in one batch, progress is reported after every batch
+const VACUUM_BATCH_ENTRIES: u64 = 10_000;
+
+#[derive(Debug, Fail)]
+pub enum VacuumError {
+    #[fail(display = "Vacuum database error: {}", error)]
+    DBError { error: DBError },
+    #[fail(display = "Vacuum I/O error: {}", error)]
+    IoError { error: io::Error },
+}
+
+impl From<DBError> for VacuumError {
+    fn from(error: DBError) -> Self {
+        VacuumError::DBError { error }
+    }
+}
+
+impl From<sled::Error> for VacuumError {
+    fn from(error: sled::Error) -> Self {
+        DBError::from(error).into()
+    }
+}
+
+impl From<io::Error> for VacuumError {
+    fn from(error: io::Error) -> Self {
+        VacuumError::IoError { error }
+    }
+}
+
+/// Result of [vacuum], reported also as progress while it runs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct VacuumReport {
+    pub trees: u64,
+    pub entries: u64,
+    /// bytes of copied keys and values
+    pub bytes: u64,
+    pub size_before: u64,
+    /// known once the copy is flushed, 0 in progress reports until then
+    pub size_after: u64,
+}
+
+/// Rewrite database at `path`, see [vacuum_with_progress].
+pub fn vacuum<P: AsRef<Path>>(path: P) -> Result<VacuumReport, VacuumError> {
+    vacuum_with_progress(path, |_| ())
+}
+
+/// Copy current contents of every tree of the database at `path` into a fresh database, which
+/// then replaces the original directory. The database must not be open, so run it offline,
+/// after [MerkleStorage::gc](crate::merkle_storage::MerkleStorage::gc) removed entries, which
+/// are no longer needed. `progress` is called after every batch of copied entries.
+///
+/// The copy is written to `<path>.vacuum` and the original is kept as `<path>.vacuum-old` until
+/// the copy is in place. If a previous vacuum was interrupted between the two renames, the
+/// original is restored before anything else happens, so `path` never ends up half-written.
+/// Fails with [io::ErrorKind::NotFound] if neither of them exists.
+pub fn vacuum_with_progress<P: AsRef<Path>, F: FnMut(&VacuumReport)>(path: P, mut progress: F) -> Result<VacuumReport, VacuumError> {
+    let path = path.as_ref();
+    let (new_path, old_path) = (sibling(path, ".vacuum"), sibling(path, ".vacuum-old"));
+    if !path.exists() {
+        if !old_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no database at {}", path.display())).into());
+        }
+        fs::rename(&old_path, path)?;
+    }
+    remove_dir_if_exists(&new_path)?;
+    remove_dir_if_exists(&old_path)?;
+
+    let mut report = VacuumReport::default();
+    {
+        let db = open_without_flusher(path)?;
+        let vacuumed = open_without_flusher(&new_path)?;
+        report.size_before = db.size_on_disk()?;
+        for name in db.tree_names() {
+            let (tree, copy) = (db.open_tree(&name)?, vacuumed.open_tree(&name)?);
+            report.trees += 1;
+            let mut batch = sled::Batch::default();
+            let mut batch_entries = 0;
+            for entry in tree.iter() {
+                let (key, value) = entry?;
+                report.entries += 1;
+                report.bytes += (key.len() + value.len()) as u64;
+                batch.insert(key, value);
+                batch_entries += 1;
+                if batch_entries == VACUUM_BATCH_ENTRIES {
+                    copy.apply_batch(std::mem::take(&mut batch))?;
+                    batch_entries = 0;
+                    progress(&report);
+                }
+            }
+            copy.apply_batch(batch)?;
+            progress(&report);
+        }
+        vacuumed.flush()?;
+        report.size_after = vacuumed.size_on_disk()?;
+    }
+
+    fs::rename(path, &old_path)?;
+    fs::rename(&new_path, path)?;
+    fs::remove_dir_all(&old_path)?;
+    Ok(report)
+}
+
+/// Open database flushed only explicitly, so no flusher thread outlives it and holds the lock
+/// of its directory after it is dropped.
+fn open_without_flusher(path: &Path) -> sled::Result<sled::Db> {
+    sled::Config::new().path(path).flush_every_ms(None).open()
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn remove_dir_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::database::SledDBWrapper;
+    use crate::merkle_storage::MerkleStorage;
+
+    fn open_storage(path: &Path) -> MerkleStorage {
+        let db = when_unlocked(|| open_without_flusher(path));
+        MerkleStorage::new(Arc::new(SledDBWrapper::new(db))).unwrap()
+    }
+
+    /// Retry `open` of a database closed just before, sled IO threads may still hold the lock of
+    /// its directory for a moment.
+    fn when_unlocked<T, E: std::fmt::Debug>(mut open: impl FnMut() -> Result<T, E>) -> T {
+        for _ in 0..100 {
+            if let Ok(value) = open() {
+                return value;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        open().unwrap()
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let db_path = &std::env::temp_dir().join(format!("_merkle_db_vacuum_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(db_path);
+        let key = |i: u32| vec!["data".to_string(), i.to_string()];
+        let value = |round: u32, i: u32| (round * 1000 + i).to_be_bytes().to_vec();
+
+        let mut storage = open_storage(db_path);
+        let mut commit = None;
+        for round in 0..5 {
+            for i in 0..200 {
+                storage.set(&key(i), &value(round, i)).unwrap();
+            }
+            commit = Some(storage.commit(round.into(), "".to_string(), "".to_string()).unwrap());
+        }
+        let commit = commit.unwrap();
+        storage.gc(1).unwrap();
+        storage.close().unwrap();
+
+        let mut reports = 0;
+        let report = when_unlocked(|| vacuum_with_progress(db_path, |_| reports += 1));
+        assert!(reports >= report.trees as usize);
+        assert!(report.entries > 200);
+        assert!(report.size_after > 0);
+        assert!(!sibling(db_path, ".vacuum").exists());
+        assert!(!sibling(db_path, ".vacuum-old").exists());
+
+        let mut storage = open_storage(db_path);
+        storage.checkout(&commit).unwrap();
+        assert_eq!(value(4, 7), storage.get(&key(7)).unwrap());
+        storage.close().unwrap();
+
+        // vacuum interrupted between the renames
+        fs::rename(db_path, sibling(db_path, ".vacuum-old")).unwrap();
+        assert_eq!(report.entries, when_unlocked(|| vacuum(db_path)).entries);
+        let mut storage = open_storage(db_path);
+        storage.checkout(&commit).unwrap();
+        storage.close().unwrap();
+        fs::remove_dir_all(db_path).unwrap();
+
+        // nothing to vacuum must not leave an empty database behind
+        assert!(matches!(vacuum(db_path), Err(VacuumError::IoError { error }) if error.kind() == io::ErrorKind::NotFound));
+        assert!(!db_path.exists());
+    }
+}
@@ -0,0 +1,396 @@
+//! [KeyValueStoreWithSchema] kept in memory, for tests and benchmarks of code generic over the
+//! store, which then need neither a temporary directory nor a sled instance.
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+
+use sled::{Batch, Event, IVec};
+
+use crate::codec::{Decoder, Encoder};
+use crate::database::{DBError, DBStats, Direction, EventStream, IteratorMode, IteratorWithSchema, KeyValueStoreWithSchema, SchemaSubscriber};
+use crate::db_iterator::{self, DBIterator};
+use crate::schema::{decode_value, KeyValueSchema};
+use crate::transaction::MultiSchemaBatch;
+
+type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Writes of keys to values, `None` deletes the key
+type Writes = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// Schemas stored in ordered maps, one per [KeyValueSchema::tree_name], so keys of schemas
+/// sharing a tree collide as they do in [SledDBWrapper](crate::database::SledDBWrapper). Values
+/// are stored encoded, without value pipelines and write coalescing. Iterators walk a copy of the
+/// entries taken when they are created. Can back a
+/// [MerkleStorage](crate::merkle_storage::MerkleStorage), see
+/// [MerkleBackend](crate::backend::MerkleBackend).
+#[derive(Default)]
+pub struct InMemoryStore {
+    trees: Mutex<HashMap<Option<&'static str>, Tree>>,
+    watchers: Mutex<Vec<Watcher>>,
+}
+
+struct Watcher {
+    tree_name: Option<&'static str>,
+    prefix: Vec<u8>,
+    events: Sender<Event>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of entries of the tree holding schema `S`, including entries of other schemas
+    /// sharing the tree.
+    pub fn len<S: KeyValueSchema>(&self) -> usize {
+        self.trees.lock().unwrap().get(&S::tree_name()).map_or(0, |tree| tree.len())
+    }
+
+    /// Whether the tree holding schema `S` has no entries, see [InMemoryStore::len].
+    pub fn is_empty<S: KeyValueSchema>(&self) -> bool {
+        self.len::<S>() == 0
+    }
+
+    /// Apply writes, `None` deletes the key, to the tree holding schema `S` at once and notify
+    /// watchers of the changed keys.
+    fn write<S: KeyValueSchema>(&self, writes: Writes) {
+        {
+            let mut trees = self.trees.lock().unwrap();
+            let tree = trees.entry(S::tree_name()).or_default();
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => tree.insert(key.clone(), value.clone()),
+                    None => tree.remove(key),
+                };
+            }
+        }
+        self.notify(S::tree_name(), writes);
+    }
+
+    fn notify(&self, tree_name: Option<&'static str>, writes: Writes) {
+        let mut watchers = self.watchers.lock().unwrap();
+        for (key, value) in writes {
+            let event = match value {
+                Some(value) => Event::Insert { key: IVec::from(key.as_slice()), value: IVec::from(value) },
+                None => Event::Remove { key: IVec::from(key) },
+            };
+            // subscribers, which were dropped, are forgotten
+            watchers.retain(|watcher| {
+                watcher.tree_name != tree_name || !event_key(&event).starts_with(&watcher.prefix)
+                    || watcher.events.send(event.clone()).is_ok()
+            });
+        }
+    }
+
+    /// Apply `batch` at once, if all its expectations hold, see [MultiSchemaBatch::expect].
+    pub fn apply_multi(&self, batch: MultiSchemaBatch) -> Result<(), DBError> {
+        {
+            let mut trees = self.trees.lock().unwrap();
+            for expectation in &batch.expectations {
+                let current = trees.get(&expectation.tree_name).and_then(|tree| tree.get(&expectation.key));
+                if current != expectation.value.as_ref() {
+                    return Err(DBError::BatchPreconditionFailed { schema: expectation.schema });
+                }
+            }
+            for write in &batch.writes {
+                let tree = trees.entry(write.tree_name).or_default();
+                match &write.value {
+                    Some(value) => tree.insert(write.key.clone(), value.clone()),
+                    None => tree.remove(&write.key),
+                };
+            }
+        }
+        for write in batch.writes {
+            self.notify(write.tree_name, vec![(write.key, write.value)]);
+        }
+        Ok(())
+    }
+
+    /// Values of the tree of given [KeyValueSchema::tree_name] in key order
+    pub(crate) fn values_of_tree(&self, tree_name: Option<&'static str>) -> Vec<Vec<u8>> {
+        self.trees.lock().unwrap().get(&tree_name).map_or_else(Vec::new, |tree| tree.values().cloned().collect())
+    }
+
+    fn get_stored<S: KeyValueSchema>(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.trees.lock().unwrap().get(&S::tree_name()).and_then(|tree| tree.get(key).cloned())
+    }
+}
+
+fn event_key(event: &Event) -> &IVec {
+    match event {
+        Event::Insert { key, .. } | Event::Remove { key } => key,
+    }
+}
+
+/// Writes held by `batch`. Sled has no accessor of them, so they are read back from the debug
+/// form of the batch, e.g. `Batch { writes: {[1, 2]: Some([3]), [4]: None} }`.
+fn batch_writes(batch: &Batch) -> Result<Writes, DBError> {
+    let invalid = || DBError::SledError { error: sled::Error::Unsupported(format!("cannot read batch {:?}", batch)) };
+    let debug = format!("{:?}", batch);
+    let mut rest = debug.as_str();
+    let mut writes = Vec::new();
+    while let Some(start) = rest.find('[') {
+        let (key, after_key) = parse_bytes(&rest[start..]).ok_or_else(invalid)?;
+        let after_key = after_key.strip_prefix(": ").ok_or_else(invalid)?;
+        rest = match after_key.strip_prefix("None") {
+            Some(after) => {
+                writes.push((key, None));
+                after
+            }
+            None => {
+                let (value, after) = parse_bytes(after_key.strip_prefix("Some(").ok_or_else(invalid)?).ok_or_else(invalid)?;
+                writes.push((key, Some(value)));
+                after
+            }
+        };
+    }
+    Ok(writes)
+}
+
+/// Parse bytes in the debug form of a slice, e.g. `[1, 2]`, returns them with the rest of `s`.
+fn parse_bytes(s: &str) -> Option<(Vec<u8>, &str)> {
+    let end = s.find(']')?;
+    let bytes = s.strip_prefix('[')?[..end - 1].split(", ")
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| byte.parse().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((bytes, &s[end + 1..]))
+}
+
+impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for InMemoryStore {
+    fn put(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        self.write::<S>(vec![(key.encode()?, Some(value.encode()?))]);
+        Ok(())
+    }
+
+    fn delete(&self, key: &S::Key) -> Result<(), DBError> {
+        self.write::<S>(vec![(key.encode()?, None)]);
+        Ok(())
+    }
+
+    fn merge(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::put(self, key, value)
+    }
+
+    fn compare_and_swap(&self, key: &S::Key, expected: Option<&S::Value>, new: Option<&S::Value>) -> Result<Result<(), Option<S::Value>>, DBError> {
+        let key = key.encode()?;
+        let expected = expected.map(|value| value.encode()).transpose()?;
+        let new = new.map(|value| value.encode()).transpose()?;
+        {
+            let mut trees = self.trees.lock().unwrap();
+            let tree = trees.entry(S::tree_name()).or_default();
+            let current = tree.get(&key);
+            if current != expected.as_ref() {
+                return Ok(Err(current.map(|v| decode_value::<S>(v).map(|(v, _)| v)).transpose()?));
+            }
+            match &new {
+                Some(new) => tree.insert(key.clone(), new.clone()),
+                None => tree.remove(&key),
+            };
+        }
+        self.notify(S::tree_name(), vec![(key, new)]);
+        Ok(Ok(()))
+    }
+
+    fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
+        let key = key.encode()?;
+        match self.get_stored::<S>(&key) {
+            Some(stored) => {
+                let (value, upgraded) = decode_value::<S>(&stored)?;
+                if upgraded && S::rewrite_upgraded() {
+                    self.write::<S>(vec![(key, Some(value.encode()?))]);
+                }
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_raw(&self, key: &S::Key) -> Result<Option<IVec>, DBError> {
+        Ok(self.get_stored::<S>(&key.encode()?).map(IVec::from))
+    }
+
+    fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<S>, DBError> {
+        let mode = match mode {
+            IteratorMode::Start => db_iterator::IteratorMode::Start,
+            IteratorMode::End => db_iterator::IteratorMode::End,
+            IteratorMode::From(key, Direction::Forward) => db_iterator::IteratorMode::From(key.encode()?.into(), db_iterator::Direction::Forward),
+            IteratorMode::From(key, Direction::Reverse) => db_iterator::IteratorMode::From(key.encode()?.into(), db_iterator::Direction::Reverse),
+            IteratorMode::Tail(n) => db_iterator::IteratorMode::Tail(n),
+        };
+        let trees = self.trees.lock().unwrap();
+        let iter = DBIterator::over_snapshot(trees.get(&S::tree_name()).unwrap_or(&Tree::new()), mode);
+        Ok(IteratorWithSchema::new(iter, None))
+    }
+
+    fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<S>, DBError> {
+        let key = key.encode()?;
+        let trees = self.trees.lock().unwrap();
+        let iter = DBIterator::over_snapshot_with_prefix(trees.get(&S::tree_name()).unwrap_or(&Tree::new()), &key);
+        Ok(IteratorWithSchema::new(iter, None))
+    }
+
+    fn watch_prefix(&self, key: &S::Key) -> Result<SchemaSubscriber<S>, DBError> {
+        let (events, receiver) = mpsc::channel();
+        self.watchers.lock().unwrap().push(Watcher { tree_name: S::tree_name(), prefix: key.encode()?, events });
+        Ok(SchemaSubscriber(EventStream::Memory(receiver), None, PhantomData))
+    }
+
+    fn contains(&self, key: &S::Key) -> Result<bool, DBError> {
+        let key = key.encode()?;
+        Ok(self.trees.lock().unwrap().get(&S::tree_name()).is_some_and(|tree| tree.contains_key(&key)))
+    }
+
+    fn put_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        batch.insert(key.encode()?, value.encode()?);
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: Batch) -> Result<(), DBError> {
+        self.write::<S>(batch_writes(&batch)?);
+        Ok(())
+    }
+
+    fn get_mem_use_stats(&self) -> Result<DBStats, DBError> {
+        Ok(DBStats { size_on_disk: 0 })
+    }
+
+    fn split_points(&self, n: usize) -> Result<Vec<S::Key>, DBError> {
+        if n < 2 {
+            return Ok(Vec::new());
+        }
+        let trees = self.trees.lock().unwrap();
+        let keys: Vec<&Vec<u8>> = match trees.get(&S::tree_name()) {
+            Some(tree) => tree.keys().collect(),
+            None => return Ok(Vec::new()),
+        };
+        // every key is known, so ranges are balanced exactly
+        let mut points: Vec<&Vec<u8>> = (1..n)
+            .map(|i| i * keys.len() / n)
+            .filter(|idx| *idx > 0 && *idx < keys.len())
+            .map(|idx| keys[idx])
+            .collect();
+        points.dedup();
+
+        points.into_iter()
+            .map(|key| S::Key::decode(key).map_err(DBError::from))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::merkle_storage::{GcMode, MerkleError, MerkleStorage, MerkleStorageConfig};
+
+    struct TestSchema;
+
+    impl KeyValueSchema for TestSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_schema"
+        }
+    }
+
+    struct TestOtherSchema;
+
+    impl KeyValueSchema for TestOtherSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_other_schema"
+        }
+
+        fn tree_name() -> Option<&'static str> {
+            Some(Self::name())
+        }
+    }
+
+    fn keys(iter: IteratorWithSchema<TestSchema>) -> Vec<u64> {
+        iter.map(|(key, _)| key.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_in_memory_store() -> Result<(), DBError> {
+        let store = InMemoryStore::new();
+        for i in 0..10u64 {
+            KeyValueStoreWithSchema::<TestSchema>::put(&store, &i, &format!("value {}", i))?;
+        }
+        KeyValueStoreWithSchema::<TestOtherSchema>::put(&store, &3, &"other".to_string())?;
+        assert_eq!(Some("value 3".to_string()), KeyValueStoreWithSchema::<TestSchema>::get(&store, &3)?);
+        assert_eq!(Some("other".to_string()), KeyValueStoreWithSchema::<TestOtherSchema>::get(&store, &3)?);
+        assert_eq!(10, store.len::<TestSchema>());
+
+        KeyValueStoreWithSchema::<TestSchema>::delete(&store, &9)?;
+        assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(&store, &9)?);
+        assert_eq!(Err(Some("value 2".to_string())),
+                   KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&store, &2, None, Some(&"swapped".to_string()))?);
+        assert_eq!(Ok(()), KeyValueStoreWithSchema::<TestSchema>::compare_and_swap(&store, &2, Some(&"value 2".to_string()), None)?);
+        assert!(KeyValueStoreWithSchema::<TestSchema>::get(&store, &2)?.is_none());
+
+        assert_eq!(vec![0, 1, 3, 4, 5, 6, 7, 8], keys(store.iterator(IteratorMode::Start)?));
+        assert_eq!(vec![8, 7, 6], keys(store.iterator(IteratorMode::Tail(3))?));
+        assert_eq!(vec![5, 6, 7, 8], keys(store.iterator(IteratorMode::From(&5, Direction::Forward))?));
+        assert_eq!(vec![3, 1, 0], keys(store.iterator(IteratorMode::From(&3, Direction::Reverse))?));
+        assert_eq!(vec![4], keys(KeyValueStoreWithSchema::<TestSchema>::prefix_iterator(&store, &4)?));
+        assert_eq!(vec![3, 5, 7], KeyValueStoreWithSchema::<TestSchema>::split_points(&store, 4)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merkle_storage_in_memory() {
+        let storage_config = || MerkleStorageConfig {
+            auto_advance_ref: Some("main".to_string()),
+            gc_mode: GcMode::RefCounting,
+            ..MerkleStorageConfig::default()
+        };
+        let store = Arc::new(InMemoryStore::new());
+        let mut storage = MerkleStorage::with_config(store.clone(), storage_config()).unwrap();
+        storage.set(&vec!["a".to_string()], &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&vec!["a".to_string()], &vec![2u8]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        let mut storage = MerkleStorage::with_config(store, storage_config()).unwrap();
+        assert!(storage.open_report().is_clean());
+        assert_eq!(Some(commit2), storage.get_ref("main").unwrap());
+        assert_eq!(vec![1u8], storage.get_history(&commit1, &vec!["a".to_string()]).unwrap());
+        // ref expected by the batch moved on
+        storage.checkout(&commit1).unwrap();
+        storage.set(&vec!["b".to_string()], &vec![3u8]).unwrap();
+        assert!(matches!(storage.commit(2, "".to_string(), "".to_string()), Err(MerkleError::NonFastForward { .. })));
+        assert!(storage.compression_stats().is_ok());
+    }
+
+    #[test]
+    fn test_in_memory_store_batch_and_watch() -> Result<(), DBError> {
+        let store = InMemoryStore::new();
+        let mut subscriber = KeyValueStoreWithSchema::<TestSchema>::watch_prefix(&store, &1)?;
+        KeyValueStoreWithSchema::<TestSchema>::put(&store, &2, &"old".to_string())?;
+
+        let mut batch = Batch::default();
+        KeyValueStoreWithSchema::<TestSchema>::put_batch(&store, &mut batch, &1, &"a".to_string())?;
+        KeyValueStoreWithSchema::<TestSchema>::put_batch(&store, &mut batch, &3, &String::new())?;
+        batch.remove(2u64.encode()?);
+        KeyValueStoreWithSchema::<TestSchema>::write_batch(&store, batch)?;
+        assert_eq!(Some("a".to_string()), KeyValueStoreWithSchema::<TestSchema>::get(&store, &1)?);
+        assert_eq!(Some(String::new()), KeyValueStoreWithSchema::<TestSchema>::get(&store, &3)?);
+        assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(&store, &2)?);
+
+        // only the key under the watched prefix is reported
+        assert_eq!(Some((1, Some("a".to_string()))), subscriber.next_timeout(Duration::from_millis(100)).map(Result::unwrap));
+        assert!(subscriber.next_timeout(Duration::from_millis(10)).is_none());
+        KeyValueStoreWithSchema::<TestSchema>::delete(&store, &1)?;
+        assert_eq!(Some((1, None)), subscriber.next().map(Result::unwrap));
+        drop(store);
+        assert!(subscriber.next().is_none());
+        Ok(())
+    }
+}
@@ -42,45 +42,163 @@
 //! ``
 //!
 //! Reference: https://git-scm.com/book/en/v2/Git-Internals-Git-Objects
+//!
+//! # Untrusted data
+//! Contents of the database and of imported snapshots are treated as untrusted: malformed,
+//! truncated or corrupted entries are reported as [MerkleError] and never cause a panic.
+//! Panics are reserved for misuse of the API, documented in `# Panics` sections (e.g.
+//! [key!](crate::key) with an invalid fragment).
 use std::hash::Hash;
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::ops::Bound;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use im::OrdMap;
 use failure::Fail;
-use std::sync::Arc;
-use std::time::Instant;
-use crate::hash::HashType;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::hash::{BlockHash, HashType};
+use crate::base58::FromBase58Check;
 use std::convert::TryInto;
+use bincode::Options;
 use sled::{Db, Error, IVec, Batch};
 use sodiumoxide::crypto::generichash::State;
 use crate::codec::BincodeEncoded;
 use crate::schema::KeyValueSchema;
-use crate::database::{KeyValueStoreWithSchema, SledDBWrapper};
-use crate::database::DBError;
+use crate::backend::MerkleBackend;
+use crate::database::KeyValueStoreWithSchema;
+use crate::database::{DBError, Direction, IteratorMode};
+use crate::tombstones::{Tombstone, TombstoneExpiryKV, TombstoneExpirySchema, TombstoneKV, TombstoneSchema};
+use crate::refs::{RefSchema, RefsKV};
+use crate::annotations::{AnnotationKV, AnnotationSchema, CommitAnnotation};
+use crate::audit_log::{AuditLogKV, AuditRecord, DestructiveOperation};
+use crate::value_index::{ValueHashIndexKV, ValueHashIndexKey, ValueHashIndexSchema, VALUE_HASH_INDEX_ROOT_KEY};
+use crate::apply_metrics::{ApplyMetrics, ApplyMetricsKV, ApplyMetricsSchema};
+use crate::value_transform::TransformKind;
+use crate::blob_store::{BlobSink, BlobSinkError};
+use crate::entry_sources::{EntrySource, EntrySourceError};
+use crate::entry_cache::SegmentedLru;
+use crate::context_key::{escape_fragment, key_from_path, key_to_path};
+use crate::metadata::{DatabaseHeader, MetadataKV, MetadataSchema, PersistentCounters, COUNTERS_KEY, HEADER_KEY, HASH_SCHEME, ENTRY_FORMAT_VERSION, is_readable_entry_format};
+use crate::hash_index::{hash_prefix, HashIndexKV, HashIndexSchema, HashPrefix, MIN_HASH_PREFIX_HEX_LEN};
+use crate::transaction::MultiSchemaBatch;
+use crate::ref_counts::{RefCountKV, RefCountSchema, REF_COUNTS_KEY};
+use crate::change_filter::{ChangeFilter, ChangeFilterKV, ChangeFilterSchema};
+use crate::block_contexts::{BlockContextKV, BlockContextSchema};
+use crate::entry_codecs::CompressionStats;
+use crate::key_history::{CommitHeightKV, CommitHeightSchema, KeyHistory, KeyHistoryKV, KeyHistorySchema, KeyVersion};
+use crate::staging_journal::{StagedAction, StagingJournalKV, StagingJournalSchema, STAGING_BASE_KEY};
+use crate::access_stats::{AccessKind, AccessStats, AccessStatsConfig, PrefixAccess};
+use crate::proof::MerkleProof;
 
 const HASH_LEN: usize = 32;
 
+/// Stored blobs start with a header of [BLOB_VARIANT] (u32) and value length (u64), both little
+/// endian, followed by the value. Written by [Entry::encode] and read by [Entry::blob_value].
+const BLOB_HEADER_LEN: usize = 12;
+
+/// Variant indices of [Entry] in its stored form, see [EntryKind::of]
+const TREE_VARIANT: u32 = 0;
+const BLOB_VARIANT: u32 = 1;
+const COMMIT_VARIANT: u32 = 2;
+const EXTERNAL_BLOB_VARIANT: u32 = 3;
+const COMMIT_WITH_METADATA_VARIANT: u32 = 4;
+const PREFIX_COMPRESSED_TREE_VARIANT: u32 = 5;
+
+/// Number of imported entries written in one batch by [MerkleStorage::import_snapshot]
+const IMPORT_BATCH_ENTRIES: usize = 4096;
+
+/// Longest record accepted by [MerkleStorage::import_snapshot]
+pub(crate) const MAX_IMPORT_RECORD_LEN: u64 = 1 << 30;
+
+/// Number of unreachable entries deleted in one batch by [MerkleStorage::gc]
+const GC_BATCH_ENTRIES: usize = 4096;
+
+/// Independent subtrees hashed in parallel per thread of the pool, so uneven subtrees balance out
+#[cfg(feature = "parallel-hashing")]
+const PARALLEL_SUBTREES_PER_THREAD: usize = 4;
+
+/// Number of refs checked when the storage is opened, see [OpenReport]
+const OPEN_CHECK_MAX_REFS: usize = 64;
+
+/// Maximum number of fragments a key may consist of, unless configured otherwise
+pub const DEFAULT_MAX_KEY_DEPTH: usize = 64;
+
 pub type ContextKey = Vec<String>;
 pub type ContextValue = Vec<u8>;
+pub type ContextKeyValues = Vec<(ContextKey, ContextValue)>;
 pub type EntryHash = [u8; HASH_LEN];
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-enum NodeKind {
+/// Kind of a child of a [Tree], a leaf points to a value and a non-leaf to a subtree
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeKind {
     NonLeaf,
     Leaf,
 }
 
+/// Child of a [Tree]
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct Node {
+pub struct Node {
     node_kind: NodeKind,
     entry_hash: EntryHash,
 }
 
-type Tree = OrdMap<String, Node>;
+impl Node {
+    pub fn new(node_kind: NodeKind, entry_hash: EntryHash) -> Self {
+        Node { node_kind, entry_hash }
+    }
+
+    pub fn node_kind(&self) -> NodeKind {
+        self.node_kind
+    }
+
+    /// Hash of the value or subtree the node points to
+    pub fn entry_hash(&self) -> &EntryHash {
+        &self.entry_hash
+    }
+}
+
+/// Directory, children by name
+pub type Tree = OrdMap<String, Node>;
+
+/// Value of [CommitMetadata]
+#[derive(Debug, Hash, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommitMetadataValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+}
+
+/// Metadata attached to a commit by [MerkleStorage::commit_with_metadata], e.g. protocol level
+/// or block hash
+pub type CommitMetadata = BTreeMap<String, CommitMetadataValue>;
 
 #[derive(Debug, Hash, Clone, Serialize, Deserialize)]
-struct Commit {
+pub struct Commit {
+    parent_commit_hash: Option<EntryHash>,
+    root_hash: EntryHash,
+    time: u64,
+    author: String,
+    message: String,
+    metadata: CommitMetadata,
+}
+
+/// [Commit] in the form stored before metadata was added, which commits without metadata keep
+#[derive(Serialize)]
+struct PlainCommitRef<'a> {
+    parent_commit_hash: &'a Option<EntryHash>,
+    root_hash: &'a EntryHash,
+    time: u64,
+    author: &'a str,
+    message: &'a str,
+}
+
+#[derive(Deserialize)]
+struct PlainCommit {
     parent_commit_hash: Option<EntryHash>,
     root_hash: EntryHash,
     time: u64,
@@ -88,25 +206,684 @@ struct Commit {
     message: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum Entry {
+impl Commit {
+    /// Commit without metadata, e.g. to compute its hash with [hash_commit]
+    pub fn new(parent_commit_hash: Option<EntryHash>, root_hash: EntryHash, time: u64, author: String, message: String) -> Self {
+        Commit { parent_commit_hash, root_hash, time, author, message, metadata: CommitMetadata::new() }
+    }
+
+    pub fn with_metadata(self, metadata: CommitMetadata) -> Self {
+        Commit { metadata, ..self }
+    }
+
+    pub fn parent_commit_hash(&self) -> Option<&EntryHash> {
+        self.parent_commit_hash.as_ref()
+    }
+
+    /// Hash of the root [Tree] of the commit
+    pub fn root_hash(&self) -> &EntryHash {
+        &self.root_hash
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn metadata(&self) -> &CommitMetadata {
+        &self.metadata
+    }
+}
+
+/// Entry of the content addressed store, as read by [MerkleStorage::read_entry] or
+/// [ContextReader::read_entry].
+///
+/// The serialized form produced by [Entry::encode] is the form entries are stored and exported
+/// in. It only changes together with [ENTRY_FORMAT_VERSION], which is recorded in the database
+/// header and in snapshots.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "StoredEntry")]
+pub enum Entry {
     Tree(Tree),
     Blob(ContextValue),
     Commit(Commit),
+    /// Value kept in a [BlobSink], see [MerkleStorage::set_blob_sink]
+    External { hash: EntryHash, len: u64 },
+}
+
+/// Serialized form of [Entry]. Commits with metadata and prefix compressed trees are stored as
+/// variants of their own appended after the others, so entries stored before they were added
+/// read unchanged.
+#[derive(Deserialize)]
+enum StoredEntry {
+    Tree(Tree),
+    Blob(ContextValue),
+    Commit(PlainCommit),
+    External { hash: EntryHash, len: u64 },
+    CommitWithMetadata(Commit),
+    CompressedTree(#[serde(with = "prefix_compressed_tree")] Tree),
+}
+
+impl From<StoredEntry> for Entry {
+    fn from(entry: StoredEntry) -> Self {
+        match entry {
+            StoredEntry::Tree(tree) | StoredEntry::CompressedTree(tree) => Entry::Tree(tree),
+            StoredEntry::Blob(value) => Entry::Blob(value),
+            StoredEntry::Commit(PlainCommit { parent_commit_hash, root_hash, time, author, message }) => {
+                Entry::Commit(Commit { parent_commit_hash, root_hash, time, author, message, metadata: CommitMetadata::new() })
+            }
+            StoredEntry::External { hash, len } => Entry::External { hash, len },
+            StoredEntry::CommitWithMetadata(commit) => Entry::Commit(commit),
+        }
+    }
+}
+
+impl Serialize for Entry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStructVariant;
+
+        match self {
+            Entry::Tree(tree) => serializer.serialize_newtype_variant("Entry", PREFIX_COMPRESSED_TREE_VARIANT, "CompressedTree", &PrefixCompressedTree(tree)),
+            Entry::Blob(value) => serializer.serialize_newtype_variant("Entry", BLOB_VARIANT, "Blob", value),
+            Entry::Commit(commit) if commit.metadata.is_empty() => {
+                let plain = PlainCommitRef {
+                    parent_commit_hash: &commit.parent_commit_hash,
+                    root_hash: &commit.root_hash,
+                    time: commit.time,
+                    author: &commit.author,
+                    message: &commit.message,
+                };
+                serializer.serialize_newtype_variant("Entry", COMMIT_VARIANT, "Commit", &plain)
+            }
+            Entry::External { hash, len } => {
+                let mut variant = serializer.serialize_struct_variant("Entry", EXTERNAL_BLOB_VARIANT, "External", 2)?;
+                variant.serialize_field("hash", hash)?;
+                variant.serialize_field("len", len)?;
+                variant.end()
+            }
+            Entry::Commit(commit) => serializer.serialize_newtype_variant("Entry", COMMIT_WITH_METADATA_VARIANT, "CommitWithMetadata", commit),
+        }
+    }
+}
+
+struct PrefixCompressedTree<'a>(&'a Tree);
+
+impl Serialize for PrefixCompressedTree<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        prefix_compressed_tree::serialize(self.0, serializer)
+    }
+}
+
+/// Serialization of trees with child names delta-encoded against their predecessor, as the
+/// length of the shared prefix followed by the rest of the name. Wide directories of similar
+/// names (hashes, addresses) take substantially less space. Hashes of trees are computed from
+/// full names, so they are not affected.
+mod prefix_compressed_tree {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::{Node, Tree};
+
+    pub fn serialize<S: Serializer>(tree: &Tree, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut previous = "";
+        serializer.collect_seq(tree.iter().map(|(name, node)| {
+            let shared = shared_prefix_len(previous, name);
+            previous = name;
+            (shared as u32, &name[shared..], node)
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Tree, D::Error> {
+        let children: Vec<(u32, String, Node)> = Vec::deserialize(deserializer)?;
+        let mut tree = Tree::new();
+        let mut previous = String::new();
+        for (shared, suffix, node) in children {
+            let shared = shared as usize;
+            if shared > previous.len() || !previous.is_char_boundary(shared) {
+                return Err(D::Error::custom(format!("invalid shared prefix length {}", shared)));
+            }
+            previous.truncate(shared);
+            previous.push_str(&suffix);
+            tree.insert(previous.clone(), node);
+        }
+        Ok(tree)
+    }
+
+    /// Length of the longest common prefix of `a` and `b` ending on a char boundary
+    fn shared_prefix_len(a: &str, b: &str) -> usize {
+        let mut len = a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count();
+        while !b.is_char_boundary(len) {
+            len -= 1;
+        }
+        len
+    }
+}
+
+/// Kind of an entry stored in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum EntryKind {
+    Tree,
+    Blob,
+    Commit,
+}
+
+impl EntryKind {
+    /// Kind of a serialized entry, read from its variant tag without decoding it
+    pub fn of(entry_bytes: &[u8]) -> Option<EntryKind> {
+        if entry_bytes.len() < 4 {
+            return None;
+        }
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&entry_bytes[..4]);
+        match u32::from_le_bytes(tag) {
+            TREE_VARIANT | PREFIX_COMPRESSED_TREE_VARIANT => Some(EntryKind::Tree),
+            BLOB_VARIANT | EXTERNAL_BLOB_VARIANT => Some(EntryKind::Blob),
+            COMMIT_VARIANT | COMMIT_WITH_METADATA_VARIANT => Some(EntryKind::Commit),
+            _ => None,
+        }
+    }
+}
+
+impl Entry {
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            Entry::Tree(_) => EntryKind::Tree,
+            Entry::Blob(_) | Entry::External { .. } => EntryKind::Blob,
+            Entry::Commit(_) => EntryKind::Commit,
+        }
+    }
+
+    /// Hash the entry is stored under
+    pub fn hash(&self) -> EntryHash {
+        hash_entry(self)
+    }
+
+    /// Serialize entry into its stored form. Blobs get the header described at [BLOB_HEADER_LEN],
+    /// so their values can be sliced out of stored bytes without decoding them.
+    pub fn encode(&self) -> Result<Vec<u8>, MerkleError> {
+        match self {
+            Entry::Blob(value) => {
+                let mut bytes = Vec::with_capacity(BLOB_HEADER_LEN + value.len());
+                bytes.extend(&BLOB_VARIANT.to_le_bytes());
+                bytes.extend(&(value.len() as u64).to_le_bytes());
+                bytes.extend(value);
+                Ok(bytes)
+            }
+            entry => Ok(bincode::serialize(entry)?),
+        }
+    }
+
+    /// Value of a blob in its stored form, sliced out of `bytes` without copying, `None` if
+    /// they are not a stored blob.
+    pub(crate) fn blob_value(bytes: IVec) -> Result<Option<IVec>, MerkleError> {
+        if bytes.len() < BLOB_HEADER_LEN || bytes[..4] != BLOB_VARIANT.to_le_bytes() {
+            return Ok(None);
+        }
+        let mut len_bytes = [0u8; 8];
+        len_bytes.copy_from_slice(&bytes[4..BLOB_HEADER_LEN]);
+        let len = u64::from_le_bytes(len_bytes);
+        if (bytes.len() - BLOB_HEADER_LEN) as u64 != len {
+            return Err(MerkleError::SerializationError {
+                error: Box::new(bincode::ErrorKind::Custom(format!("invalid blob length {}", len)))
+            });
+        }
+        Ok(Some(bytes.subslice(BLOB_HEADER_LEN, len as usize)))
+    }
+
+    /// Deserialize entry from its stored form. Bytes come from untrusted sources, so the hash of
+    /// the result should be checked against the expected one.
+    pub fn decode(bytes: &[u8]) -> Result<Entry, MerkleError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
 }
 
 pub type MerkleStorageKV = dyn KeyValueStoreWithSchema<MerkleStorage> + Sync + Send;
 
+/// What happens to a directory (tree) when its last key is deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum EmptyTreePolicy {
+    /// Empty tree is removed from its parent, so it does not contribute to the hash
+    Prune,
+    /// Empty tree is kept in its parent and contributes to the hash
+    Keep,
+}
+
+/// How a commit writes new entries to the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum CommitWriteMode {
+    /// All entries are written atomically in a single batch
+    SingleBatch,
+    /// Entries are written in batches of `run_len` entries, children before the trees referencing
+    /// them and the commit entry last. Entries of the same height above the leaves are sorted by
+    /// hash, so writes are mostly sequential in the key space, which speeds up bulk ingest. A
+    /// commit interrupted by a crash leaves only complete subtrees behind, so a retried commit
+    /// can skip whatever it finds stored.
+    SortedRuns { run_len: usize },
+    /// Entries are written in one transaction together with all other records of the commit
+    /// (counters, tombstones, annotation and indexes), so a crash leaves either all of them or
+    /// none. Other modes write the records right after the entries.
+    Atomic,
+}
+
+/// What happens when an entry about to be written is already stored with different bytes.
+/// Entries are content addressed, so it means broken hashing or a corrupted database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum WriteOnceMode {
+    /// Stored entries are not read back, only their presence is checked
+    Off,
+    /// Stored entry is kept and the hook set by [MerkleStorage::set_write_once_violation_hook]
+    /// is called
+    Report,
+    /// Write fails with [MerkleError::EntryOverwrite]
+    Reject,
+}
+
+/// How unreachable entries are reclaimed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum GcMode {
+    /// Only by [MerkleStorage::gc], which marks reachable entries and sweeps all others
+    MarkAndSweep,
+    /// Entries count references to them, so [MerkleStorage::delete_commit] reclaims entries of
+    /// a commit at once. Commits are written as with [CommitWriteMode::Atomic], so counts never
+    /// miss an entry. Counts are rebuilt when a store, which did not maintain them, is opened.
+    RefCounting,
+}
+
+/// Store-level settings of [MerkleStorage]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct MerkleStorageConfig {
+    /// Writes of keys with more fragments fail with [MerkleError::KeyTooDeep]
+    pub max_key_depth: usize,
+    /// Whether trees emptied by a delete are removed from the hashed structure
+    pub empty_tree_policy: EmptyTreePolicy,
+    /// How long (in units of commit time) tombstones of deleted keys are retained, `None`
+    /// disables recording of tombstones
+    pub tombstone_retention: Option<u64>,
+    /// Index committed entries by short hash prefix, see [MerkleStorage::resolve_hash_prefix]
+    pub hash_prefix_index: bool,
+    pub commit_write_mode: CommitWriteMode,
+    /// Store a summary of changed keys with every commit, see [MerkleStorage::get_commit_annotation]
+    pub commit_annotations: bool,
+    /// Read committed values by slicing them from the buffer read from database, instead of
+    /// decoding the whole entry first
+    pub decode_on_demand: bool,
+    /// Ignore deletes of keys, which do not exist, so they neither touch the staging area nor
+    /// mark the storage dirty, see [MerklePerfStats::elided_deletes]
+    pub elide_noop_deletes: bool,
+    /// Changes of more distinct keys between commits fail with [MerkleError::StagedKeysQuotaExceeded]
+    pub max_staged_keys: Option<usize>,
+    /// Sets of more value bytes between commits fail with [MerkleError::StagedBytesQuotaExceeded]
+    pub max_staged_bytes: Option<u64>,
+    /// Index keys of the head context by hash of their value, see
+    /// [MerkleStorage::find_keys_with_value_hash]
+    pub value_hash_index: bool,
+    /// Number of decoded entries cached in memory, shared with readers, 0 disables the cache.
+    /// Entries are cached only once read twice, so traversals do not evict the working set.
+    pub entry_cache_capacity: usize,
+    /// Ref fast-forwarded to every new commit. Commits, which parent is not the commit the ref
+    /// points to, fail with [MerkleError::NonFastForward]. A missing ref is created.
+    pub auto_advance_ref: Option<String>,
+    pub gc_mode: GcMode,
+    /// Store a filter of prefixes changed by every commit, see [MerkleStorage::commits_touching]
+    pub change_filters: bool,
+    /// Record changes of every key in its history cell, see [MerkleStorage::get_value_at]
+    pub key_history: bool,
+    /// Sample reads and writes by top-level prefix, see [MerkleStorage::hottest_prefixes]
+    pub access_stats: Option<AccessStatsConfig>,
+    /// Journal changes of the staging area to the database, so they survive a crash of the
+    /// process, see [MerkleStorage::recover_staging]
+    pub persist_staging: bool,
+    /// Flush the database after every change journaled by [MerkleStorageConfig::persist_staging],
+    /// so journaled changes survive also a crash of the machine. Otherwise changes journaled
+    /// since the last flush of the database, e.g. within sled's `flush_every_ms`, can be lost
+    /// with the machine
+    pub sync_staging_journal: bool,
+    /// Compare entries written by commits and imports with the stored ones of the same hash.
+    /// Values kept in the blob sink are not compared.
+    pub write_once: WriteOnceMode,
+    /// Hash and serialize independent staged subtrees on the rayon thread pool when committing.
+    /// Entries written and the commit hash are the same as when done on one thread.
+    #[cfg(feature = "parallel-hashing")]
+    pub parallel_hashing: bool,
+}
+
+impl Default for MerkleStorageConfig {
+    fn default() -> Self {
+        MerkleStorageConfig {
+            max_key_depth: DEFAULT_MAX_KEY_DEPTH,
+            empty_tree_policy: EmptyTreePolicy::Prune,
+            tombstone_retention: None,
+            hash_prefix_index: false,
+            commit_write_mode: CommitWriteMode::SingleBatch,
+            commit_annotations: false,
+            decode_on_demand: false,
+            elide_noop_deletes: false,
+            max_staged_keys: None,
+            max_staged_bytes: None,
+            value_hash_index: false,
+            entry_cache_capacity: 0,
+            auto_advance_ref: None,
+            gc_mode: GcMode::MarkAndSweep,
+            change_filters: false,
+            key_history: false,
+            access_stats: None,
+            persist_staging: false,
+            sync_staging_journal: true,
+            write_once: WriteOnceMode::Off,
+            #[cfg(feature = "parallel-hashing")]
+            parallel_hashing: false,
+        }
+    }
+}
+
+/// Decoded entries shared by a storage and its readers
+type EntryCache = Arc<Mutex<SegmentedLru<EntryHash, Entry>>>;
+
+/// Called when [MerkleStorage] with uncommitted changes is dropped, with the hash of the last
+/// commit and the number of staged entries
+pub type DirtyDropHook = Box<dyn Fn(Option<EntryHash>, usize) + Send + Sync>;
+
+/// Called in [WriteOnceMode::Report] with the hash of an entry, its stored bytes and the
+/// different bytes, which were about to overwrite them
+pub type WriteOnceViolationHook = Box<dyn Fn(&EntryHash, &[u8], &[u8]) + Send + Sync>;
+
+/// Called by [MerkleStorage::commit] before anything is persisted, an error aborts the commit
+/// with [MerkleError::CommitRejected]
+pub type CommitValidator = Box<dyn Fn(&CommitProposal) -> Result<(), String> + Send + Sync>;
+
+/// Source of the current time in seconds since the Unix epoch, used by
+/// [MerkleStorage::commit_now] and for audit records. Tests and replays inject a deterministic
+/// one by [MerkleStorage::set_clock].
+pub type Clock = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+/// [Clock] reading the system time
+pub fn system_clock() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Commit about to be persisted, passed to [CommitValidator]
+#[derive(Debug, Clone)]
+pub struct CommitProposal<'a> {
+    pub parent_commit_hash: Option<EntryHash>,
+    /// hash of the root tree the commit would point to
+    pub root_hash: EntryHash,
+    pub time: u64,
+    pub author: &'a str,
+    pub message: &'a str,
+    pub metadata: &'a CommitMetadata,
+    /// values changed since the parent commit
+    pub changes: Vec<ValueChange>,
+}
+
+/// Operation staged on a path, see [MerkleStorage::staged_changes]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum ChangeKind {
+    Set,
+    Deleted,
+    Copied { from: ContextKey },
+}
+
+/// Counts of staged operations, see [MerkleStorage::staged_summary]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct StagedSummary {
+    pub sets: usize,
+    pub deletes: usize,
+    pub copies: usize,
+    /// bytes of values set since the last commit or checkout, including overwritten ones
+    pub value_bytes: u64,
+}
+
+/// Value changed between two contexts, hashes are `None` where the key is missing or is a
+/// directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueChange {
+    pub key: ContextKey,
+    pub old_value_hash: Option<EntryHash>,
+    pub new_value_hash: Option<EntryHash>,
+}
+
+/// Value added, removed or modified between two commits, see [ContextReader::diff]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum ContextChange {
+    Added { key: ContextKey, value: ContextValue },
+    Removed { key: ContextKey, value: ContextValue },
+    Modified { key: ContextKey, old_value: ContextValue, new_value: ContextValue },
+}
+
+impl ContextChange {
+    pub fn key(&self) -> &ContextKey {
+        match self {
+            ContextChange::Added { key, .. } | ContextChange::Removed { key, .. } | ContextChange::Modified { key, .. } => key,
+        }
+    }
+}
+
+/// Entry visited by [ContextReader::fold]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum FoldEntry {
+    /// Directory with the number of its children
+    Tree { hash: EntryHash, children: usize },
+    Value { hash: EntryHash, value: ContextValue },
+}
+
 pub struct MerkleStorage {
+    config: MerkleStorageConfig,
     current_stage_tree: Option<Tree>,
     db: Arc<MerkleStorageKV>,
+    // writes of commit records, which span several schemas
+    schemas: Arc<dyn MerkleBackend>,
+    tombstones: Arc<TombstoneKV>,
+    tombstone_expiry: Arc<TombstoneExpiryKV>,
+    hash_index: Arc<HashIndexKV>,
+    metadata: Arc<MetadataKV>,
+    refs: Arc<RefsKV>,
+    annotations: Arc<AnnotationKV>,
+    audit_log: Arc<AuditLogKV>,
+    value_index: Arc<ValueHashIndexKV>,
+    apply_metrics: Arc<ApplyMetricsKV>,
+    ref_counts: Arc<RefCountKV>,
+    change_filters: Arc<ChangeFilterKV>,
+    key_history: Arc<KeyHistoryKV>,
+    commit_heights: Arc<CommitHeightKV>,
+    staging_journal: Arc<StagingJournalKV>,
+    block_contexts: Arc<BlockContextKV>,
+    // number of actions in the staging journal
+    journal_len: u64,
+    // journal found on open was neither recovered nor discarded yet
+    staging_recovery_pending: bool,
     staged: HashMap<EntryHash, Entry>,
+    // keys deleted since last commit, recorded as tombstones on commit
+    staged_deletes: HashSet<ContextKey>,
+    // keys changed since last commit, tracked only if their number is limited
+    staged_keys: HashSet<ContextKey>,
+    // last operation on every path written since last commit
+    staged_changes: BTreeMap<ContextKey, ChangeKind>,
+    // bytes of values set since last commit
+    staged_value_bytes: u64,
     last_commit: Option<Commit>,
     map_stats: MerkleMapStats,
     cumul_set_exec_time: f64,
     // divide this by the next field to get avg time spent in _set
     set_exec_times: u64,
     set_exec_times_to_discard: u64, // first N measurements to discard
+    // bytes of entries not written on commit, because they were already persisted
+    skipped_write_bytes: u64,
+    elided_deletes: u64,
+    // staging area contains changes made after last commit or checkout
+    dirty: bool,
+    dirty_drop_hook: Option<DirtyDropHook>,
+    commit_validator: Option<CommitValidator>,
+    write_once_violation_hook: Option<WriteOnceViolationHook>,
+    clock: Clock,
+    access_stats: Option<AccessStats>,
+    // stages of value pipeline of entries
+    entry_transforms: Vec<TransformKind>,
+    entry_cache: Option<EntryCache>,
+    blob_sink: Option<Arc<dyn BlobSink>>,
+    // tried in order for entries missing in the database
+    entry_sources: Vec<Arc<dyn EntrySource>>,
+    // values of at least this many bytes are kept in the blob sink
+    external_blob_threshold: usize,
+    // incremented whenever head moves (commit or checkout)
+    epoch: u64,
+    // writes are restricted to these prefixes after a partial checkout
+    writable_prefixes: Option<Vec<ContextKey>>,
+    pins: Pins,
+    open_report: OpenReport,
+}
+
+/// Depth-first iterator over all entries reachable from a commit, each entry is visited once.
+///
+/// Yields `(hash, kind, serialized_size)` of every entry, starting with the commit itself,
+/// followed by its root tree and descendants in key order.
+pub struct DagIterator<'a> {
+    store: &'a dyn EntryStore,
+    stack: Vec<EntryHash>,
+    visited: HashSet<EntryHash>,
+    skipped: u64,
+}
+
+impl<'a> DagIterator<'a> {
+    fn new(store: &'a dyn EntryStore, hash: &EntryHash) -> Self {
+        Self::with_visited(store, hash, HashSet::new())
+    }
+
+    /// Create iterator which skips already `visited` entries and their subtrees.
+    fn with_visited(store: &'a dyn EntryStore, hash: &EntryHash, visited: HashSet<EntryHash>) -> Self {
+        Self::from_roots(store, vec![*hash], visited)
+    }
+
+    /// Create iterator over entries reachable from any of `roots`, visited in the given order.
+    fn from_roots(store: &'a dyn EntryStore, roots: Vec<EntryHash>, visited: HashSet<EntryHash>) -> Self {
+        DagIterator {
+            store,
+            stack: roots.into_iter().rev().collect(),
+            visited,
+            skipped: 0,
+        }
+    }
+
+    fn into_visited(self) -> HashSet<EntryHash> {
+        self.visited
+    }
+
+    /// Load next unvisited entry and schedule its children, returns it with its serialized form.
+    fn next_entry(&mut self) -> Option<Result<(EntryHash, Entry, IVec), MerkleError>> {
+        let hash = loop {
+            let hash = self.stack.pop()?;
+            if self.visited.insert(hash) {
+                break hash;
+            }
+            self.skipped += 1;
+        };
+
+        let bytes = match self.store.get_entry_raw(&hash) {
+            Ok(bytes) => bytes,
+            Err(err) => return Some(Err(err)),
+        };
+        let entry: Entry = match bincode::deserialize(&bytes) {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err.into())),
+        };
+
+        match &entry {
+            Entry::Blob(_) | Entry::External { .. } => {}
+            // push in reverse, so children are visited in key order
+            Entry::Tree(tree) => self.stack.extend(tree.values().rev().map(|node| node.entry_hash)),
+            Entry::Commit(commit) => self.stack.push(commit.root_hash),
+        }
+
+        Some(Ok((hash, entry, bytes)))
+    }
+}
+
+impl<'a> Iterator for DagIterator<'a> {
+    type Item = Result<(EntryHash, EntryKind, usize), MerkleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry()
+            .map(|res| res.map(|(hash, entry, bytes)| (hash, entry.kind(), bytes.len())))
+    }
+}
+
+/// Depth-first iterator over all key-values of a commit in key order, see
+/// [ContextReader::materialize]. Entries are loaded only when visited, so memory use is
+/// proportional to the depth and width of the tree, not to its size.
+pub struct MaterializeIterator {
+    reader: ContextReader,
+    stack: Vec<(ContextKey, EntryHash)>,
+    _pin: CommitPin,
+}
+
+impl Iterator for MaterializeIterator {
+    type Item = Result<(ContextKey, ContextValue), MerkleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, hash)) = self.stack.pop() {
+            match self.reader.get_entry(&hash) {
+                Ok(Entry::Blob(blob)) => return Some(Ok((key, blob))),
+                // push in reverse, so children are visited in key order
+                Ok(Entry::Tree(tree)) => self.stack.extend(tree.iter().rev().map(|(fragment, node)| {
+                    let mut child_key = key.clone();
+                    child_key.push(fragment.clone());
+                    (child_key, node.entry_hash)
+                })),
+                // resolved by `get_entry`
+                Ok(Entry::External { .. }) => {}
+                Ok(Entry::Commit(_)) => {
+                    self.stack.clear();
+                    return Some(Err(MerkleError::FoundUnexpectedStructure {
+                        sought: "tree or blob".to_string(),
+                        found: "commit".to_string(),
+                    }));
+                }
+                Err(err) => {
+                    self.stack.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over ancestry of a commit, see [ContextReader::history]
+pub struct HistoryIterator {
+    reader: ContextReader,
+    next: Option<(EntryHash, Commit)>,
+}
+
+impl Iterator for HistoryIterator {
+    type Item = Result<CommitInfo, MerkleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (commit_hash, commit) = self.next.take()?;
+        if let Some(parent_hash) = commit.parent_commit_hash {
+            match self.reader.get_commit(&parent_hash) {
+                Ok(parent) => self.next = Some((parent_hash, parent)),
+                Err(MerkleError::EntryNotFound { .. }) => (),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        Some(Ok(CommitInfo::new(commit_hash, commit)))
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -123,16 +900,76 @@ pub enum MerkleError {
     MissingAncestorCommit,
     #[fail(display = "There is a commit or three under key {:?}, but not a value!", key)]
     ValueIsNotABlob { key: String },
+    #[fail(display = "There is a value under key {:?}, its absence cannot be proven.", key)]
+    ValueExists { key: String },
     #[fail(display = "Found wrong structure. Was looking for {}, but found {}", sought, found)]
     FoundUnexpectedStructure { sought: String, found: String },
     #[fail(display = "Entry not found! Hash={}", hash)]
     EntryNotFound { hash: String },
+    #[fail(display = "Entry stored under hash {} has hash {}!", hash, computed)]
+    EntryHashMismatch { hash: String, computed: String },
+    #[fail(display = "Entry {} is already stored with different bytes!", hash)]
+    EntryOverwrite { hash: String },
+    #[fail(display = "Database is incompatible with this build, its {} is {:?}, expected {:?}.", field, found, expected)]
+    IncompatibleDatabase { field: &'static str, expected: String, found: String },
+    #[fail(display = "Worker thread of {} panicked!", worker)]
+    WorkerPanicked { worker: &'static str },
+    #[fail(display = "Blob sink error: {}", error)]
+    BlobSinkError { error: BlobSinkError },
+    #[fail(display = "Entry source error: {}", error)]
+    EntrySourceError { error: EntrySourceError },
+    #[fail(display = "Value {} is kept in a blob sink, but no blob sink is set!", hash)]
+    BlobSinkMissing { hash: String },
 
     /// Wrong user input errors
     #[fail(display = "No value under key {:?}.", key)]
     ValueNotFound { key: String },
     #[fail(display = "Cannot search for an empty key.")]
     KeyEmpty,
+    #[fail(display = "Invalid key fragment {:?}: {}.", fragment, reason)]
+    InvalidKeyFragment { fragment: String, reason: &'static str },
+    #[fail(display = "Invalid key path {:?}: {}.", path, reason)]
+    InvalidKeyPath { path: String, reason: &'static str },
+    #[fail(display = "Key {:?} has depth {}, maximum allowed depth is {}.", key, depth, max_depth)]
+    KeyTooDeep { key: String, depth: usize, max_depth: usize },
+    #[fail(display = "Too many keys changed since last commit, at most {} allowed.", limit)]
+    StagedKeysQuotaExceeded { limit: usize },
+    #[fail(display = "Too many value bytes set since last commit, at most {} allowed.", limit)]
+    StagedBytesQuotaExceeded { limit: u64 },
+    #[fail(display = "Commit rejected by validator: {}.", reason)]
+    CommitRejected { reason: String },
+    #[fail(display = "Commit hash mismatch, expected {}, computed {}.", expected, computed)]
+    CommitHashMismatch { expected: String, computed: String },
+    #[fail(display = "Commit {} is not an ancestor of commit {}.", ancestor, commit)]
+    NotAnAncestor { ancestor: String, commit: String },
+    #[fail(display = "Ref {} was moved by another writer, expected {:?}, found {:?}.", name, expected, found)]
+    RefUpdateConflict { name: String, expected: Option<String>, found: Option<String> },
+    #[fail(display = "Ref {} does not exist.", name)]
+    RefNotFound { name: String },
+    #[fail(display = "Commit with parent {:?} does not fast-forward ref {} pointing to {:?}.", parent, name, tip)]
+    NonFastForward { name: String, tip: Option<String>, parent: Option<String> },
+    #[fail(display = "Tombstones are not recorded, tombstone retention is not configured.")]
+    TombstonesDisabled,
+    #[fail(display = "Key history is not recorded, it is not enabled in the configuration.")]
+    KeyHistoryDisabled,
+    #[fail(display = "Staging journal holds {} actions, recover or reset them first.", actions)]
+    StagingNotRecovered { actions: u64 },
+    #[fail(display = "Keys are not indexed by value hash, value hash index is not enabled.")]
+    ValueHashIndexDisabled,
+    #[fail(display = "Entries are not indexed by hash prefix, hash prefix index is not enabled.")]
+    HashPrefixIndexDisabled,
+    #[fail(display = "Invalid hash {:?}: {}.", hash, reason)]
+    InvalidHash { hash: String, reason: &'static str },
+    #[fail(display = "Invalid hash prefix {:?}, expected {} to {} hex characters.", prefix, min_len, max_len)]
+    InvalidHashPrefix { prefix: String, min_len: usize, max_len: usize },
+    #[fail(display = "Hash prefix {:?} is ambiguous, it matches {} entries.", prefix, matches)]
+    AmbiguousHashPrefix { prefix: String, matches: usize },
+    #[fail(display = "Key {:?} is outside prefixes of the partial checkout.", key)]
+    OutsidePartialCheckout { key: String },
+    #[fail(display = "Entries do not count references, GC mode is not ref counting.")]
+    RefCountingDisabled,
+    #[fail(display = "Commit {} is used by {}, it cannot be deleted.", hash, user)]
+    CommitInUse { hash: String, user: String },
 }
 
 impl From<DBError> for MerkleError {
@@ -140,176 +977,951 @@ impl From<DBError> for MerkleError {
 }
 
 
+impl From<BlobSinkError> for MerkleError {
+    fn from(error: BlobSinkError) -> Self { MerkleError::BlobSinkError { error } }
+}
+
+impl From<EntrySourceError> for MerkleError {
+    fn from(error: EntrySourceError) -> Self { MerkleError::EntrySourceError { error } }
+}
+
 impl From<bincode::Error> for MerkleError {
     fn from(error: bincode::Error) -> Self { MerkleError::SerializationError { error } }
 }
 
 #[derive(Serialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialize", derive(Deserialize))]
 pub struct MerkleMapStats {
     staged_area_elems: u64,
     current_tree_elems: u64,
 }
 
 #[derive(Serialize, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serialize", derive(Deserialize))]
 pub struct MerklePerfStats {
     pub avg_set_exec_time_ns: f64,
+    /// bytes of already persisted entries, which were skipped by commits
+    pub skipped_write_bytes: u64,
+    /// deletes of nonexistent keys ignored, see [MerkleStorageConfig::elide_noop_deletes]
+    pub elided_deletes: u64,
+    /// reads of committed entries served by the entry cache, see
+    /// [MerkleStorageConfig::entry_cache_capacity]
+    pub entry_cache_hits: u64,
+    pub entry_cache_misses: u64,
 }
 
-#[derive(Serialize, Debug, Clone)]
-pub struct MerkleStorageStats {
-    map_stats: MerkleMapStats,
-    pub perf_stats: MerklePerfStats,
+/// Result of a successful [MerkleStorage::verify_range]
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct VerificationReport {
+    pub commits_verified: u64,
+    pub entries_verified: u64,
+    /// references to entries already verified as part of an older commit in the range
+    pub entries_reused: u64,
 }
 
-impl BincodeEncoded for EntryHash {}
+/// Settings of [Snapshot::export]
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ExportConfig {
+    /// Limit of the rate export is written at, `None` for unlimited
+    pub max_bytes_per_sec: Option<u64>,
+}
 
-impl KeyValueSchema for MerkleStorage {
-    type Key = EntryHash;
-    type Value = Vec<u8>;
+/// Settings of [MerkleStorage::import_snapshot]
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ImportConfig {
+    /// Point refs to the commits they pointed to in the export, otherwise only entries are
+    /// imported
+    pub restore_refs: bool,
+}
 
-    #[inline]
-    fn name() -> &'static str {
-        "merkle_storage"
-    }
+/// Result of [Snapshot::export] and [MerkleStorage::import_snapshot]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ExportReport {
+    /// commit the export was taken at
+    pub head: Option<EntryHash>,
+    pub entries: u64,
+    /// bytes of serialized entries
+    pub bytes: u64,
 }
 
-impl MerkleStorage {
-    pub fn new(db: Arc<SledDBWrapper>) -> Self {
-        MerkleStorage {
-            db,
-            staged: HashMap::new(),
-            current_stage_tree: None,
-            last_commit: None,
-            map_stats: MerkleMapStats { staged_area_elems: 0, current_tree_elems: 0 },
-            cumul_set_exec_time: 0.0,
-            set_exec_times: 0,
-            set_exec_times_to_discard: 20,
-        }
-    }
+/// Phase of [MerkleStorage::gc_with_progress]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum GcPhase {
+    /// entries reachable from retained commits are collected
+    Mark,
+    /// unreachable entries are deleted
+    Sweep,
+}
 
-    /// Get value. Staging area is checked first, then last (checked out) commit.
-    pub fn get(&mut self, key: &ContextKey) -> Result<ContextValue, MerkleError> {
-        let root = &self.get_staged_root()?;
-        let root_hash = self.hash_tree(&root);
+/// Result of [MerkleStorage::gc], reported also as progress while it runs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct GcReport {
+    pub commits_retained: u64,
+    /// entries reachable from retained commits
+    pub entries_marked: u64,
+    pub entries_scanned: u64,
+    pub entries_deleted: u64,
+    /// bytes of deleted entries
+    pub bytes_reclaimed: u64,
+}
+
+/// Header of a stream written by [Snapshot::export]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ExportHeader {
+    pub(crate) entry_format_version: u32,
+    pub(crate) head: Option<EntryHash>,
+    /// refs of the exported snapshot by name
+    pub(crate) refs: BTreeMap<String, EntryHash>,
+}
 
-        self.get_from_tree(&root_hash, key)
+/// Export running in a background thread, see [Snapshot::export]
+pub struct ExportHandle {
+    bytes_written: Arc<AtomicU64>,
+    thread: JoinHandle<Result<ExportReport, MerkleError>>,
+}
+
+impl ExportHandle {
+    /// Bytes of entries written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
     }
 
-    /// Get value. Staging area is checked first, then last (checked out) commit.
-    pub fn get_by_prefix(&mut self, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
-        let root = self.get_staged_root()?;
-        self._get_key_values_by_prefix(root, prefix)
+    /// Wait for the export to finish.
+    pub fn join(self) -> Result<ExportReport, MerkleError> {
+        self.thread.join().map_err(|_| MerkleError::WorkerPanicked { worker: "export" })?
     }
+}
 
-    /// Get value from historical context identified by commit hash.
-    pub fn get_history(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
-        let commit = self.get_commit(commit_hash)?;
+/// Result of the quick integrity check done when the storage is opened, see
+/// [MerkleStorage::open_report]. Only refs and the commits and root trees they point to are
+/// checked, so the check takes the same time regardless of the database size.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct OpenReport {
+    /// database had no header yet, so it was initialized by this open
+    pub created: bool,
+    /// commit of [MerkleStorageConfig::auto_advance_ref], checked before other refs
+    pub head: Option<EntryHash>,
+    pub refs_checked: usize,
+    /// more refs than [OPEN_CHECK_MAX_REFS] exist, refs beyond the limit were neither checked nor
+    /// counted
+    pub refs_truncated: bool,
+    /// names of refs, which commit or its root tree is missing or cannot be decoded, with the error
+    pub broken_refs: Vec<(String, String)>,
+    /// actions left in the staging journal by a process, which did not commit or reset them
+    pub staged_actions: usize,
+}
 
-        self.get_from_tree(&commit.root_hash, key)
+impl OpenReport {
+    pub fn is_clean(&self) -> bool {
+        self.broken_refs.is_empty()
     }
+}
 
-    fn get_from_tree(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
-        let mut full_path = key.clone();
-        let file = full_path.pop().ok_or(MerkleError::KeyEmpty)?;
-        let path = full_path;
-        let root = self.get_tree(root_hash)?;
-        let node = self.find_tree(&root, &path)?;
+/// Result of [MerkleStorage::audit_entries]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct AuditReport {
+    pub entries_checked: u64,
+    pub bytes_checked: u64,
+    /// keys of entries, which content hashes to a different key
+    pub mismatched: Vec<EntryHash>,
+    /// keys of entries, which cannot be deserialized
+    pub undecodable: Vec<EntryHash>,
+}
 
-        let node = match node.get(&file) {
-            None => return Err(MerkleError::ValueNotFound { key: self.key_to_string(key) }),
-            Some(entry) => entry,
-        };
-        match self.get_entry(&node.entry_hash)? {
-            Entry::Blob(blob) => Ok(blob),
-            _ => Err(MerkleError::ValueIsNotABlob { key: self.key_to_string(key) })
-        }
+impl AuditReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.undecodable.is_empty()
     }
 
-    // TODO: recursion is risky (stack overflow) and inefficient, try to do it iteratively..
-    fn get_key_values_from_tree_recursively(&self, path: &str, entry: &Entry, entries: &mut Vec<(ContextKey, ContextValue)>) -> Result<(), MerkleError> {
-        match entry {
-            Entry::Blob(blob) => {
-                // push key-value pair
-                entries.push((self.string_to_key(path), blob.to_vec()));
-                Ok(())
-            }
-            Entry::Tree(tree) => {
-                // Go through all descendants and gather errors. Remap error if there is a failure
-                // anywhere in the recursion paths. TODO: is revert possible?
-                tree.iter().map(|(key, child_node)| {
-                    let fullpath = path.to_owned() + "/" + key;
-                    match self.get_entry(&child_node.entry_hash) {
-                        Err(_) => Ok(()),
-                        Ok(entry) => self.get_key_values_from_tree_recursively(&fullpath, &entry, entries),
-                    }
-                }).find_map(|res| {
-                    match res {
-                        Ok(_) => None,
-                        Err(err) => Some(Err(err)),
-                    }
-                }).unwrap_or(Ok(()))
-            }
-            Entry::Commit(commit) => {
-                match self.get_entry(&commit.root_hash) {
-                    Err(err) => Err(err),
-                    Ok(entry) => self.get_key_values_from_tree_recursively(path, &entry, entries),
-                }
-            }
+    fn merge(&mut self, other: AuditReport) {
+        self.entries_checked += other.entries_checked;
+        self.bytes_checked += other.bytes_checked;
+        self.mismatched.extend(other.mismatched);
+        self.undecodable.extend(other.undecodable);
+    }
+}
+
+/// Distribution of values in power of two buckets. Bucket `i` counts values in
+/// `2^(i-1)..2^i`, bucket 0 counts zeros.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, value: u64) {
+        let bucket = (64 - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
         }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
     }
 
-    pub fn get_key_values_by_prefix(&self, context_hash: &EntryHash, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
-        let commit = self.get_commit(context_hash)?;
-        let root_tree = self.get_tree(&commit.root_hash)?;
-        self._get_key_values_by_prefix(root_tree, prefix)
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
     }
+}
 
-    fn _get_key_values_by_prefix(&self, root_tree: Tree, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
-        let prefixed_tree = self.find_tree(&root_tree, prefix)?;
-        let mut keyvalues: Vec<(ContextKey, ContextValue)> = Vec::new();
+/// Shape of the data in a commit, see [MerkleStorage::node_histograms]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct NodeHistograms {
+    /// number of children of directories
+    pub directory_fan_out: Histogram,
+    /// bytes of values
+    pub blob_sizes: Histogram,
+}
 
-        for (key, child_node) in prefixed_tree.iter() {
-            let entry = self.get_entry(&child_node.entry_hash)?;
-            let delimiter: &str;
-            if prefix.is_empty() {
-                delimiter = "";
-            } else {
-                delimiter = "/";
+/// Limits of work an expensive query may do before it is stopped. Default budget is unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct QueryBudget {
+    /// Maximum number of entries (trees and blobs) visited
+    pub max_entries: Option<u64>,
+    /// Maximum number of bytes of visited entries in their serialized form
+    pub max_bytes: Option<u64>,
+    /// Maximum time since the query started, checked at every visited entry
+    pub max_duration: Option<Duration>,
+}
+
+/// Result of a query run with a [QueryBudget]. If the budget was exceeded, the query was stopped
+/// early and `value` holds only the part of the result collected until then.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Budgeted<T> {
+    pub value: T,
+    pub budget_exceeded: bool,
+}
+
+/// Work done by a running query, checked against its budget
+struct BudgetTracker {
+    budget: QueryBudget,
+    started: Instant,
+    entries: u64,
+    bytes: u64,
+    exceeded: bool,
+}
+
+impl BudgetTracker {
+    fn new(budget: QueryBudget) -> Self {
+        BudgetTracker { budget, started: Instant::now(), entries: 0, bytes: 0, exceeded: false }
+    }
+
+    /// Account for a visited entry. Returns false once the budget is exceeded.
+    fn charge(&mut self, entry: &Entry) -> Result<bool, MerkleError> {
+        self.entries += 1;
+        if self.budget.max_bytes.is_some() {
+            self.bytes += bincode::serialized_size(entry)?;
+        }
+        if matches!(self.budget.max_entries, Some(max) if self.entries > max)
+            || matches!(self.budget.max_bytes, Some(max) if self.bytes > max)
+            || matches!(self.budget.max_duration, Some(max) if self.started.elapsed() >= max) {
+            self.exceeded = true;
+        }
+        Ok(!self.exceeded)
+    }
+
+    fn finish<T>(self, value: T) -> Budgeted<T> {
+        Budgeted { value, budget_exceeded: self.exceeded }
+    }
+}
+
+/// How a path differs between two compared contexts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum PathDivergence {
+    /// Path exists only in the left context
+    LeftOnly,
+    /// Path exists only in the right context
+    RightOnly,
+    /// Path exists in both contexts, with different values or kinds (value vs directory)
+    Different,
+}
+
+/// Optional subsystems compiled in and active in a [MerkleStorage], see
+/// [MerkleStorage::capabilities]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct Capabilities {
+    pub hash_algorithm: String,
+    pub entry_format_version: u32,
+    /// entries are compressed in the database, see [ValuePipeline](crate::value_transform::ValuePipeline)
+    pub compression: bool,
+    /// entries are encrypted in the database, see [ValuePipeline](crate::value_transform::ValuePipeline)
+    pub encryption: bool,
+    /// unreachable entries can be garbage collected, see [MerkleStorage::gc]
+    pub garbage_collection: bool,
+    /// async API is available
+    pub async_api: bool,
+    /// public report types implement serde traits (`serialize` feature)
+    pub serialize: bool,
+    /// failures can be injected into the database (`testing` feature)
+    pub failure_injection: bool,
+    pub tombstones: bool,
+    pub hash_prefix_index: bool,
+    pub commit_annotations: bool,
+    pub value_hash_index: bool,
+    /// huge values are kept in a [BlobSink]
+    pub external_blobs: bool,
+    /// entries count references to them, see [GcMode::RefCounting]
+    pub ref_counting: bool,
+    pub change_filters: bool,
+    pub key_history: bool,
+}
+
+/// Commit returned by [ContextReader::log]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct LogEntry {
+    pub commit_hash: EntryHash,
+    pub parent_commit_hash: Option<EntryHash>,
+    pub time: u64,
+    pub author: String,
+    pub message: String,
+    /// cost of applying the block recorded by [MerkleStorage::set_apply_metrics]
+    pub apply_metrics: Option<ApplyMetrics>,
+}
+
+/// Commit yielded by [HistoryIterator]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct CommitInfo {
+    pub commit_hash: EntryHash,
+    pub parent_commit_hash: Option<EntryHash>,
+    pub root_hash: EntryHash,
+    pub time: u64,
+    pub author: String,
+    pub message: String,
+    pub metadata: CommitMetadata,
+}
+
+impl CommitInfo {
+    fn new(commit_hash: EntryHash, commit: Commit) -> Self {
+        CommitInfo {
+            commit_hash,
+            parent_commit_hash: commit.parent_commit_hash,
+            root_hash: commit.root_hash,
+            time: commit.time,
+            author: commit.author,
+            message: commit.message,
+            metadata: commit.metadata,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Deserialize))]
+pub struct MerkleStorageStats {
+    map_stats: MerkleMapStats,
+    pub perf_stats: MerklePerfStats,
+    /// cumulative counters persisted across restarts
+    pub counters: PersistentCounters,
+}
+
+impl BincodeEncoded for EntryHash {}
+
+impl KeyValueSchema for MerkleStorage {
+    type Key = EntryHash;
+    type Value = Vec<u8>;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage"
+    }
+}
+
+impl MerkleStorage {
+    pub fn new<B: MerkleBackend + 'static>(db: Arc<B>) -> Result<Self, MerkleError> {
+        Self::with_config(db, MerkleStorageConfig::default())
+    }
+
+    /// Open storage in `db`, e.g. a [SledDBWrapper](crate::database::SledDBWrapper). Database
+    /// header is written if the database has none yet, otherwise it is checked to be compatible
+    /// with this build, see [DatabaseHeader].
+    pub fn with_config<B: MerkleBackend + 'static>(db: Arc<B>, config: MerkleStorageConfig) -> Result<Self, MerkleError> {
+        let entry_transforms = db.entry_transforms();
+        let entry_cache = match config.entry_cache_capacity {
+            0 => None,
+            capacity => Some(Arc::new(Mutex::new(SegmentedLru::new(capacity)))),
+        };
+        let access_stats = config.access_stats.map(AccessStats::new);
+        let mut storage = MerkleStorage {
+            config,
+            tombstones: db.clone(),
+            tombstone_expiry: db.clone(),
+            hash_index: db.clone(),
+            metadata: db.clone(),
+            refs: db.clone(),
+            annotations: db.clone(),
+            audit_log: db.clone(),
+            value_index: db.clone(),
+            apply_metrics: db.clone(),
+            ref_counts: db.clone(),
+            change_filters: db.clone(),
+            key_history: db.clone(),
+            commit_heights: db.clone(),
+            staging_journal: db.clone(),
+            block_contexts: db.clone(),
+            journal_len: 0,
+            staging_recovery_pending: false,
+            schemas: db.clone(),
+            db,
+            staged: HashMap::new(),
+            staged_deletes: HashSet::new(),
+            staged_keys: HashSet::new(),
+            staged_changes: BTreeMap::new(),
+            staged_value_bytes: 0,
+            current_stage_tree: None,
+            last_commit: None,
+            map_stats: MerkleMapStats { staged_area_elems: 0, current_tree_elems: 0 },
+            cumul_set_exec_time: 0.0,
+            set_exec_times: 0,
+            set_exec_times_to_discard: 20,
+            skipped_write_bytes: 0,
+            elided_deletes: 0,
+            dirty: false,
+            dirty_drop_hook: None,
+            commit_validator: None,
+            write_once_violation_hook: None,
+            clock: Arc::new(system_clock),
+            access_stats,
+            entry_transforms,
+            entry_cache,
+            blob_sink: None,
+            entry_sources: Vec::new(),
+            external_blob_threshold: 0,
+            epoch: 0,
+            writable_prefixes: None,
+            pins: Pins::default(),
+            open_report: OpenReport::default(),
+        };
+        let created = storage.check_header()?;
+        storage.init_ref_counts()?;
+        storage.open_report = storage.quick_check(created)?;
+        storage.open_staging_journal()?;
+        Ok(storage)
+    }
+
+    /// Result of the integrity check done when the storage was opened. Broken refs do not fail
+    /// the open, so they can still be repaired through this storage.
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    /// Write header of this build, or validate the existing one. Returns whether the header was
+    /// written. Database without a header, which already holds entries, was written before
+    /// headers were introduced, see [DatabaseHeader::legacy]. Headers of older readable entry
+    /// formats are upgraded to the current one, as new entries are written in it.
+    fn check_header(&self) -> Result<bool, MerkleError> {
+        let current = DatabaseHeader::current();
+        let key = HEADER_KEY.to_string();
+        let stored = match self.metadata.get(&key)? {
+            Some(stored) => Some(stored),
+            None if self.db.iterator(IteratorMode::Start)?.next().is_some() => None,
+            None => match self.metadata.compare_and_swap(&key, None, Some(&bincode::serialize(&current)?))? {
+                Ok(()) => return Ok(true),
+                Err(found) => Some(found.unwrap_or_default()),
+            },
+        };
+        let found = match &stored {
+            Some(stored) => bincode::deserialize(stored).map_err(|_| MerkleError::IncompatibleDatabase {
+                field: "header",
+                expected: format!("{:?}", current),
+                found: hex::encode(stored),
+            })?,
+            None => DatabaseHeader::legacy(),
+        };
+
+        let mismatch = |field, expected: String, found: String| Err(MerkleError::IncompatibleDatabase { field, expected, found });
+        if found.hash_scheme != current.hash_scheme {
+            return mismatch("hash scheme", current.hash_scheme, found.hash_scheme);
+        }
+        if !is_readable_entry_format(found.entry_format_version) {
+            return mismatch("entry format version", current.entry_format_version.to_string(), found.entry_format_version.to_string());
+        }
+        if found.key_encoding != current.key_encoding {
+            return mismatch("key encoding", current.key_encoding, found.key_encoding);
+        }
+        if found.entry_format_version != current.entry_format_version {
+            // lost only to another open upgrading it at the same time
+            let _ = self.metadata.compare_and_swap(&key, stored.as_ref(), Some(&bincode::serialize(&current)?))?;
+        }
+        Ok(false)
+    }
+
+    /// Check that up to [OPEN_CHECK_MAX_REFS] refs, head first, point to commits which decode
+    /// and which root tree is present.
+    fn quick_check(&self, created: bool) -> Result<OpenReport, MerkleError> {
+        let mut report = OpenReport { created, ..OpenReport::default() };
+        let mut refs = Vec::new();
+        if let Some(name) = &self.config.auto_advance_ref {
+            report.head = self.refs.get(name)?;
+            refs.extend(report.head.map(|hash| (name.clone(), hash)));
+        }
+        for (name, hash) in self.refs.iterator(IteratorMode::Start)? {
+            let name = name.map_err(DBError::from)?;
+            if Some(&name) == self.config.auto_advance_ref.as_ref() {
+                continue;
+            }
+            if refs.len() == OPEN_CHECK_MAX_REFS {
+                report.refs_truncated = true;
+                break;
             }
-            let fullpath = self.key_to_string(prefix) + delimiter + key;
-            self.get_key_values_from_tree_recursively(&fullpath, &entry, &mut keyvalues)?;
+            refs.push((name, hash.map_err(DBError::from)?));
         }
 
-        if keyvalues.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(keyvalues))
+        for (name, hash) in refs {
+            report.refs_checked += 1;
+            match self.get_commit_with_root(&hash) {
+                Ok(_) => (),
+                Err(error @ MerkleError::DBError { .. }) => return Err(error),
+                Err(error) => report.broken_refs.push((name, error.to_string())),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Count actions left in the staging journal, they block writes until recovered or reset.
+    fn open_staging_journal(&mut self) -> Result<(), MerkleError> {
+        let actions = self.staging_journal.iterator(IteratorMode::Start)?.count();
+        self.open_report.staged_actions = actions;
+        if !self.config.persist_staging {
+            return Ok(());
+        }
+        if actions == 0 {
+            // base of a cleared journal no longer applies, staging starts from an empty tree
+            self.metadata.delete(&STAGING_BASE_KEY.to_string())?;
+        }
+        self.journal_len = actions as u64;
+        self.staging_recovery_pending = actions > 0;
+        Ok(())
+    }
+
+    /// Rebuild ref counts if they were not maintained, or mark them stale if they will not be.
+    fn init_ref_counts(&self) -> Result<(), MerkleError> {
+        let key = REF_COUNTS_KEY.to_string();
+        match self.config.gc_mode {
+            GcMode::RefCounting if self.metadata.get(&key)?.is_none() => self.rebuild_ref_counts(),
+            GcMode::RefCounting => Ok(()),
+            GcMode::MarkAndSweep => Ok(self.metadata.delete(&key)?),
+        }
+    }
+
+    /// Count references to all stored entries from scratch.
+    fn rebuild_ref_counts(&self) -> Result<(), MerkleError> {
+        let mut counts: HashMap<EntryHash, u64> = HashMap::new();
+        for (_, bytes) in self.db.iterator(IteratorMode::Start)? {
+            let entry: Entry = bincode::deserialize(&bytes.map_err(DBError::from)?)?;
+            for child in referenced_entries(&entry) {
+                *counts.entry(child).or_insert(0) += 1;
+            }
+        }
+
+        let mut batch = MultiSchemaBatch::default();
+        for (hash, _) in self.ref_counts.iterator(IteratorMode::Start)? {
+            batch.delete::<RefCountSchema>(&hash.map_err(DBError::from)?)?;
+        }
+        for (hash, count) in counts {
+            batch.put::<RefCountSchema>(&hash, &count)?;
+        }
+        batch.put::<MetadataSchema>(&REF_COUNTS_KEY.to_string(), &Vec::new())?;
+        Ok(self.schemas.apply_multi(batch)?)
+    }
+
+    /// Create read-only handle to committed data, which can be cloned and sent to other threads.
+    pub fn reader(&self) -> ContextReader {
+        ContextReader {
+            db: self.db.clone(),
+            refs: self.refs.clone(),
+            annotations: self.annotations.clone(),
+            apply_metrics: self.apply_metrics.clone(),
+            entry_cache: self.entry_cache.clone(),
+            blob_sink: self.blob_sink.clone(),
+            entry_sources: self.entry_sources.clone(),
+            pins: self.pins.clone(),
+        }
+    }
+
+    /// Check whether there are changes which were not committed yet.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Paths set, deleted or copied to since the last commit or checkout, in key order, with the
+    /// last operation on each. An operation on a directory hides earlier ones below it.
+    pub fn staged_changes(&self) -> Vec<(ContextKey, ChangeKind)> {
+        self.staged_changes.iter().map(|(key, kind)| (key.clone(), kind.clone())).collect()
+    }
+
+    /// Count operations listed by [MerkleStorage::staged_changes].
+    pub fn staged_summary(&self) -> StagedSummary {
+        let mut summary = StagedSummary { value_bytes: self.staged_value_bytes, ..StagedSummary::default() };
+        for kind in self.staged_changes.values() {
+            match kind {
+                ChangeKind::Set => summary.sets += 1,
+                ChangeKind::Deleted => summary.deletes += 1,
+                ChangeKind::Copied { .. } => summary.copies += 1,
+            }
+        }
+        summary
+    }
+
+    /// Register hook called when storage is dropped with uncommitted changes, useful to catch
+    /// half-applied blocks.
+    pub fn set_dirty_drop_hook(&mut self, hook: DirtyDropHook) {
+        self.dirty_drop_hook = Some(hook);
+    }
+
+    /// Register validator of commits, which can enforce protocol invariants before changes are
+    /// persisted. Staging area of a rejected commit is left intact.
+    pub fn set_commit_validator(&mut self, validator: CommitValidator) {
+        self.commit_validator = Some(validator);
+    }
+
+    /// Register hook called when an entry is about to overwrite the stored one with different
+    /// bytes, see [WriteOnceMode::Report].
+    pub fn set_write_once_violation_hook(&mut self, hook: WriteOnceViolationHook) {
+        self.write_once_violation_hook = Some(hook);
+    }
+
+    /// Take the current time from `clock` instead of the system time
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// Up to `limit` top-level prefixes read and written the most within the window of
+    /// [MerkleStorageConfig::access_stats], most accessed first. Empty if sampling is disabled.
+    pub fn hottest_prefixes(&self, limit: usize) -> Vec<PrefixAccess> {
+        match &self.access_stats {
+            Some(stats) => stats.hottest(limit, (self.clock)()),
+            None => Vec::new(),
+        }
+    }
+
+    fn record_access(&self, key: &ContextKey, kind: AccessKind) {
+        if let Some(stats) = &self.access_stats {
+            stats.record(key, kind, (self.clock)());
+        }
+    }
+
+    /// Keep values of at least `threshold` bytes in `sink` instead of the database, starting
+    /// with the next commit. Only hash and length of such values are stored, their bytes are
+    /// read back from the sink and checked against the hash. The sink has to be set before
+    /// readers are created and whenever a database holding such values is opened.
+    ///
+    /// Exports, audits and [MerkleStorage::verify_range] cover only the stored references, not
+    /// the bytes in the sink.
+    pub fn set_blob_sink(&mut self, sink: Arc<dyn BlobSink>, threshold: usize) {
+        self.blob_sink = Some(sink);
+        self.external_blob_threshold = threshold;
+    }
+
+    /// Append `source` to the chain of sources tried, in order, for entries missing in the
+    /// database. Fetched entries are checked against their hash and stored in the database, so
+    /// each is fetched once. Sources have to be added before readers are created.
+    pub fn add_entry_source(&mut self, source: Arc<dyn EntrySource>) {
+        self.entry_sources.push(source);
+    }
+
+    /// Get value. Staging area is checked first, then last (checked out) commit.
+    pub fn get(&mut self, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        self.record_access(key, AccessKind::Read);
+        let root = &self.get_staged_root()?;
+        let root_hash = hash_tree(&root);
+
+        self.get_value(&root_hash, key)
+    }
+
+    /// Like [MerkleStorage::get], but committed values are returned in the buffer read from
+    /// database without copying.
+    pub fn get_raw(&mut self, key: &ContextKey) -> Result<IVec, MerkleError> {
+        self.record_access(key, AccessKind::Read);
+        let root = self.get_staged_root()?;
+        let root_hash = hash_tree(&root);
+
+        self.get_raw_from_tree(&root_hash, key)
+    }
+
+    /// Open value under `key` for reading. Values kept in the blob sink are streamed from it
+    /// and checked against their hash as they are read, see [MerkleStorage::set_blob_sink].
+    /// Staging area is checked first, then last (checked out) commit.
+    pub fn get_blob_reader(&mut self, key: &ContextKey) -> Result<Box<dyn Read + Send>, MerkleError> {
+        let root = self.get_staged_root()?;
+        let node = self.find_node(&hash_tree(&root), key)?;
+        let entry = match self.staged.get(&node.entry_hash) {
+            Some(entry) => entry.clone(),
+            None => load_entry_from_db(self.db.as_ref(), self.entry_cache.as_ref(), &self.entry_sources, &node.entry_hash)?,
+        };
+        match entry {
+            Entry::Blob(blob) => Ok(Box::new(Cursor::new(blob))),
+            Entry::External { hash, len } => Ok(Box::new(open_external_blob(self.blob_sink.as_deref(), &hash, len)?)),
+            _ => Err(MerkleError::ValueIsNotABlob { key: key_to_path(key) }),
+        }
+    }
+
+    /// Check whether a value is stored under `key`, empty values included. Staging area is
+    /// checked first, then last (checked out) commit. Directories are not values, so `false`
+    /// is returned for them.
+    pub fn exists(&mut self, key: &ContextKey) -> Result<bool, MerkleError> {
+        let root = self.get_staged_root()?;
+        let root_hash = hash_tree(&root);
+
+        self.value_exists(&root_hash, key)
+    }
+
+    /// Check whether a value is stored under `key` in the working tree, like `mem` of the Tezos
+    /// context. Only trees along the path are read, the value is not, see [MerkleStorage::exists].
+    pub fn mem(&mut self, key: &ContextKey) -> Result<bool, MerkleError> {
+        self.exists(key)
+    }
+
+    /// Check whether a directory is stored under `prefix` in the working tree, like `dir_mem` of
+    /// the Tezos context. Values are not directories. Empty `prefix` is the root, which exists
+    /// unless the working tree is empty.
+    pub fn dirmem(&mut self, prefix: &ContextKey) -> Result<bool, MerkleError> {
+        let root = self.get_staged_root()?;
+        if prefix.is_empty() {
+            return Ok(!root.is_empty());
+        }
+        self.dir_exists(&hash_tree(&root), prefix)
+    }
+
+    /// Get key of the child following the last fragment of `key` in its parent directory, in
+    /// key order. `key` itself does not need to exist, so directories can be paged through by
+    /// passing the last key of the previous page. Staging area is checked first, then last
+    /// (checked out) commit.
+    pub fn next_sibling(&mut self, key: &ContextKey) -> Result<Option<ContextKey>, MerkleError> {
+        let root = self.get_staged_root()?;
+        self.find_sibling(&root, key, true)
+    }
+
+    /// Like [MerkleStorage::next_sibling], but gets the preceding child.
+    pub fn prev_sibling(&mut self, key: &ContextKey) -> Result<Option<ContextKey>, MerkleError> {
+        let root = self.get_staged_root()?;
+        self.find_sibling(&root, key, false)
+    }
+
+    /// Get value. Staging area is checked first, then last (checked out) commit.
+    pub fn get_by_prefix(&mut self, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
+        Ok(self.get_by_prefix_with_budget(prefix, QueryBudget::default())?.value)
+    }
+
+    /// Like [MerkleStorage::get_by_prefix], but stops once `budget` is exceeded.
+    pub fn get_by_prefix_with_budget(&mut self, prefix: &ContextKey, budget: QueryBudget) -> Result<Budgeted<Option<ContextKeyValues>>, MerkleError> {
+        let root = self.get_staged_root()?;
+        let mut budget = BudgetTracker::new(budget);
+        let keyvalues = self._get_key_values_by_prefix(root, prefix, &mut budget)?;
+        Ok(budget.finish(keyvalues))
+    }
+
+    /// Get value from historical context identified by commit hash.
+    pub fn get_history(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        self.record_access(key, AccessKind::Read);
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+
+        self.get_value(&commit.root_hash, key)
+    }
+
+    /// Get value under `key`, committed values are sliced from the read buffer when
+    /// [MerkleStorageConfig::decode_on_demand] is set.
+    fn get_value(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        if !self.config.decode_on_demand {
+            return self.get_from_tree(root_hash, key);
+        }
+        let node = self.find_node(root_hash, key)?;
+        match self.staged.get(&node.entry_hash) {
+            Some(Entry::Blob(blob)) => Ok(blob.clone()),
+            Some(_) => Err(MerkleError::ValueIsNotABlob { key: key_to_path(key) }),
+            None => {
+                let bytes = get_entry_raw_from_db(self.db.as_ref(), &self.entry_sources, &node.entry_hash)?;
+                if is_external_blob(&bytes) {
+                    return self.get_from_tree(root_hash, key);
+                }
+                Ok(blob_from_raw(bytes, key)?.to_vec())
+            }
         }
     }
 
+    /// Like [MerkleStorage::get_history], but the value is returned in the buffer read from
+    /// database without copying.
+    pub fn get_history_raw(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<IVec, MerkleError> {
+        self.record_access(key, AccessKind::Read);
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+
+        self.get_raw_from_tree(&commit.root_hash, key)
+    }
+
+    /// Get all values under `prefix` in the tree of commit `context_hash`, sorted by key, `None`
+    /// if nothing is stored there. The working tree is left as it is.
+    pub fn get_key_values_by_prefix(&self, context_hash: &EntryHash, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
+        Ok(self.get_key_values_by_prefix_with_budget(context_hash, prefix, QueryBudget::default())?.value)
+    }
+
+    /// Like [MerkleStorage::get_key_values_by_prefix], but stops once `budget` is exceeded.
+    pub fn get_key_values_by_prefix_with_budget(&self, context_hash: &EntryHash, prefix: &ContextKey, budget: QueryBudget) -> Result<Budgeted<Option<ContextKeyValues>>, MerkleError> {
+        let _pin = self.pins.pin_commit(context_hash)?;
+        let (_, root_tree) = self.get_commit_with_root(context_hash)?;
+        let mut budget = BudgetTracker::new(budget);
+        let keyvalues = self._get_key_values_by_prefix(root_tree, prefix, &mut budget)?;
+        Ok(budget.finish(keyvalues))
+    }
+
+
     /// Flush the staging area and and move to work on a certain commit from history.
     pub fn checkout(&mut self, context_hash: &EntryHash) -> Result<(), MerkleError> {
-        let commit = self.get_commit(&context_hash)?;
-        self.current_stage_tree = Some(self.get_tree(&commit.root_hash)?);
+        self.check_staging_recovered()?;
+        let (commit, root) = self.get_commit_with_root(context_hash)?;
+        let commit_root_hash = commit.root_hash;
+        self.current_stage_tree = Some(root);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
         self.last_commit = Some(commit);
         self.staged = HashMap::new();
         self.map_stats.staged_area_elems = 0;
+        self.staged_deletes.clear();
+        self.staged_changes.clear();
+        self.reset_staging_quotas();
+        self.dirty = false;
+        self.epoch += 1;
+        self.writable_prefixes = None;
+        let mut batch = MultiSchemaBatch::default();
+        if self.config.value_hash_index {
+            self.update_value_hash_index(&commit_root_hash, &mut batch)?;
+        }
+        self.clear_staging_journal(Some(context_hash), &mut batch)?;
+        self.schemas.apply_multi(batch)?;
+        self.journal_len = 0;
+        Ok(())
+    }
+
+    /// Like [MerkleStorage::checkout], but sets, deletes and copies are allowed only under one of
+    /// `prefixes`, others fail with [MerkleError::OutsidePartialCheckout]. Subtrees are loaded on
+    /// first access, so patching a small part of a huge context loads only the touched subtrees.
+    /// Commits include the rest of the context unchanged. The restriction lasts until the next
+    /// checkout.
+    pub fn checkout_partial(&mut self, context_hash: &EntryHash, prefixes: &[ContextKey]) -> Result<(), MerkleError> {
+        for prefix in prefixes {
+            self.check_key_depth(prefix)?;
+        }
+        self.checkout(context_hash)?;
+        self.writable_prefixes = Some(prefixes.to_vec());
         Ok(())
     }
 
+    /// Prefixes writes are restricted to by [MerkleStorage::checkout_partial]
+    pub fn writable_prefixes(&self) -> Option<&[ContextKey]> {
+        self.writable_prefixes.as_deref()
+    }
+
+    /// Like [MerkleStorage::commit], with the time taken from the clock, see
+    /// [MerkleStorage::set_clock].
+    pub fn commit_now(&mut self, author: String, message: String) -> Result<EntryHash, MerkleError> {
+        let time = (self.clock)();
+        self.commit(time, author, message)
+    }
+
     /// Take the current changes in the staging area, create a commit and persist all changes
     /// to database under the new commit. Return last commit if there are no changes, that is
     /// empty commits are not allowed.
+    /// Ref [MerkleStorageConfig::auto_advance_ref] is moved to the new commit.
+    ///
+    /// Trees are ordered by key, so the hash depends only on the staged contents, not on the
+    /// order keys were staged in, see [check_order_independence](crate::replay::check_order_independence).
     pub fn commit(&mut self,
                   time: u64,
                   author: String,
                   message: String,
     ) -> Result<EntryHash, MerkleError> {
+        self.commit_with_metadata(time, author, message, CommitMetadata::new())
+    }
+
+    /// Like [MerkleStorage::commit], with `metadata` attached to the commit and included in its
+    /// hash. Hash of a commit with empty metadata is the same as of a commit made without it.
+    pub fn commit_with_metadata(&mut self,
+                                time: u64,
+                                author: String,
+                                message: String,
+                                metadata: CommitMetadata,
+    ) -> Result<EntryHash, MerkleError> {
+        self.commit_checked(time, author, message, metadata, None, None)
+    }
+
+    /// Like [MerkleStorage::commit], but the commit is made only if its hash equals
+    /// `expected_hash`, e.g. computed by the protocol. Otherwise nothing is persisted, the
+    /// staging area is left intact and [MerkleError::CommitHashMismatch] is returned.
+    pub fn commit_expecting(&mut self,
+                            time: u64,
+                            author: String,
+                            message: String,
+                            expected_hash: &EntryHash,
+    ) -> Result<EntryHash, MerkleError> {
+        self.commit_checked(time, author, message, CommitMetadata::new(), Some(expected_hash), None)
+    }
+
+    /// Like [MerkleStorage::commit_expecting], recording the commit as the context of block
+    /// `block_hash` together with it, see [MerkleStorage::get_block_context].
+    pub fn commit_block(&mut self,
+                        block_hash: &BlockHash,
+                        time: u64,
+                        author: String,
+                        message: String,
+                        expected_hash: &EntryHash,
+    ) -> Result<EntryHash, MerkleError> {
+        self.commit_checked(time, author, message, CommitMetadata::new(), Some(expected_hash), Some(block_hash))
+    }
+
+    /// Get commit made for block `block_hash` by [MerkleStorage::commit_block]. The record does
+    /// not retain the commit, it may have been collected by [MerkleStorage::gc] since.
+    pub fn get_block_context(&self, block_hash: &BlockHash) -> Result<Option<EntryHash>, MerkleError> {
+        Ok(self.block_contexts.get(block_hash)?)
+    }
+
+    fn commit_checked(&mut self,
+                      time: u64,
+                      author: String,
+                      message: String,
+                      metadata: CommitMetadata,
+                      expected_hash: Option<&EntryHash>,
+                      block_hash: Option<&BlockHash>,
+    ) -> Result<EntryHash, MerkleError> {
+        self.check_staging_recovered()?;
         let staged_root = self.get_staged_root()?;
-        let staged_root_hash = self.hash_tree(&staged_root);
+        let staged_root_hash = hash_tree(&staged_root);
         let parent_commit_hash = self.last_commit.as_ref()
-            .map_or(None, |c| Some(self.hash_commit(&c)));
+            .map_or(None, |c| Some(hash_commit(&c)));
+        let ref_tip = match &self.config.auto_advance_ref {
+            Some(name) => self.check_fast_forward(name, parent_commit_hash.as_ref())?,
+            None => None,
+        };
+        if let Some(validator) = &self.commit_validator {
+            let mut changes = Vec::new();
+            let parent_root_hash = self.last_commit.as_ref().map(|c| c.root_hash);
+            for_each_changed_value(self, parent_root_hash.as_ref(), &staged_root_hash, |key, old, new| {
+                changes.push(ValueChange { key: key.clone(), old_value_hash: old.copied(), new_value_hash: new.copied() });
+                Ok(())
+            })?;
+            let proposal = CommitProposal { parent_commit_hash, root_hash: staged_root_hash, time, author: &author, message: &message, metadata: &metadata, changes };
+            validator(&proposal).map_err(|reason| MerkleError::CommitRejected { reason })?;
+        }
 
         let new_commit = Commit {
             root_hash: staged_root_hash,
@@ -317,28 +1929,113 @@ impl MerkleStorage {
             time,
             author,
             message,
+            metadata,
         };
         let entry = Entry::Commit(new_commit.clone());
+        let new_commit_hash = hash_commit(&new_commit);
+        if let Some(expected_hash) = expected_hash {
+            if *expected_hash != new_commit_hash {
+                return Err(MerkleError::CommitHashMismatch {
+                    expected: HashType::ContextHash.bytes_to_string(expected_hash),
+                    computed: HashType::ContextHash.bytes_to_string(&new_commit_hash),
+                });
+            }
+        }
 
-        self.put_to_staging_area(&self.hash_commit(&new_commit), entry.clone());
-        self.persist_staged_entry_to_db(&entry)?;
+        self.put_to_staging_area(&new_commit_hash, entry.clone());
+        // records of the commit, written after its entries or together with them
+        let mut batch = MultiSchemaBatch::default();
+        let (written_entries, skipped_bytes) = self.persist_staged_entry_to_db(&entry, &mut batch)?;
+        self.skipped_write_bytes += skipped_bytes;
+        self.stage_counters(&mut batch, |counters| {
+            counters.commits += 1;
+            counters.entries_written += written_entries;
+        })?;
+        self.persist_tombstones(&new_commit_hash, new_commit.time, &mut batch)?;
+        if self.config.commit_annotations {
+            let parent_root_hash = self.last_commit.as_ref().map(|c| c.root_hash);
+            let annotation = annotate_changes(self, parent_root_hash.as_ref(), &staged_root_hash)?;
+            batch.put::<AnnotationSchema>(&new_commit_hash, &annotation)?;
+        }
+        if self.config.change_filters {
+            let parent_root_hash = self.last_commit.as_ref().map(|c| c.root_hash);
+            let mut filter = ChangeFilter::default();
+            for_each_changed_value(self, parent_root_hash.as_ref(), &staged_root_hash, |key, _, _| {
+                filter.add_key(key);
+                Ok(())
+            })?;
+            batch.put::<ChangeFilterSchema>(&new_commit_hash, &filter)?;
+        }
+        if self.config.key_history {
+            self.persist_key_history(parent_commit_hash.as_ref(), &new_commit_hash, &staged_root_hash, &mut batch)?;
+        }
+        if self.config.value_hash_index {
+            self.update_value_hash_index(&staged_root_hash, &mut batch)?;
+        }
+        if let Some(block_hash) = block_hash {
+            batch.put::<BlockContextSchema>(block_hash, &new_commit_hash)?;
+        }
+        if let Some(name) = &self.config.auto_advance_ref {
+            // fails the whole commit if another writer moved the ref in the meantime
+            batch.expect::<RefSchema>(name, ref_tip.as_ref())?;
+            batch.put::<RefSchema>(name, &new_commit_hash)?;
+        }
+        self.clear_staging_journal(Some(&new_commit_hash), &mut batch)?;
+        match (self.schemas.apply_multi(batch), &self.config.auto_advance_ref) {
+            (Ok(()), _) => (),
+            (Err(DBError::BatchPreconditionFailed { .. }), Some(name)) => return Err(self.non_fast_forward(name, parent_commit_hash.as_ref())?),
+            (Err(error), _) => return Err(error.into()),
+        }
+        self.staged_deletes.clear();
+        self.staged_changes.clear();
+        self.journal_len = 0;
         self.staged = HashMap::new();
         self.map_stats.staged_area_elems = 0;
+        self.reset_staging_quotas();
         self.last_commit = Some(new_commit.clone());
-        Ok(self.hash_commit(&new_commit))
+        self.dirty = false;
+        self.epoch += 1;
+        Ok(new_commit_hash)
+    }
+
+    /// Get commit ref `name` points to, provided a commit with parent `parent` fast-forwards it.
+    fn check_fast_forward(&self, name: &str, parent: Option<&EntryHash>) -> Result<Option<EntryHash>, MerkleError> {
+        match self.refs.get(&name.to_string())? {
+            None => Ok(None),
+            Some(tip) if Some(&tip) == parent => Ok(Some(tip)),
+            Some(_) => Err(self.non_fast_forward(name, parent)?),
+        }
+    }
+
+    fn non_fast_forward(&self, name: &str, parent: Option<&EntryHash>) -> Result<MerkleError, MerkleError> {
+        Ok(MerkleError::NonFastForward {
+            name: name.to_string(),
+            tip: self.refs.get(&name.to_string())?.map(|tip| HashType::ContextHash.bytes_to_string(&tip)),
+            parent: parent.map(|hash| HashType::ContextHash.bytes_to_string(hash)),
+        })
     }
 
     /// Set key/val to the staging area.
+    ///
+    /// An empty `value` is a value like any other: the key exists, [MerkleStorage::get] returns
+    /// an empty vector and the key contributes to the hash of its tree. Use
+    /// [MerkleStorage::delete] to remove a key.
     pub fn set(&mut self, key: &ContextKey, value: &ContextValue) -> Result<(), MerkleError> {
+        self.check_writable(key)?;
+        self.record_access(key, AccessKind::Write);
+        self.charge_staging_quotas(key, value.len() as u64)?;
+        self.staged_deletes.remove(key);
         let root = self.get_staged_root()?;
         let new_root_hash = &self._set(&root, key, value)?;
         self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
-        Ok(())
+        self.stage_change(key, ChangeKind::Set);
+        self.dirty = true;
+        self.journal(|| StagedAction::Set { key: key.clone(), value: value.clone() })
     }
 
     fn _set(&mut self, root: &Tree, key: &ContextKey, value: &ContextValue) -> Result<EntryHash, MerkleError> {
-        let blob_hash = self.hash_blob(&value);
+        let blob_hash = hash_blob(&value);
         self.put_to_staging_area(&blob_hash, Entry::Blob(value.clone()));
         let new_node = Node { entry_hash: blob_hash, node_kind: NodeKind::Leaf };
         let instant = Instant::now();
@@ -351,36 +2048,280 @@ impl MerkleStorage {
         rv
     }
 
-    /// Delete an item from the staging area.
+    /// Delete an item from the staging area. Deleted key does not exist and does not contribute
+    /// to the hash, unlike a key set to an empty value.
     pub fn delete(&mut self, key: &ContextKey) -> Result<(), MerkleError> {
+        self.check_writable(key)?;
+        self.record_access(key, AccessKind::Write);
         let root = self.get_staged_root()?;
+        if self.config.elide_noop_deletes && !key.is_empty() && !self.find_tree(&root, &key[..key.len() - 1])?.contains_key(&key[key.len() - 1]) {
+            self.elided_deletes += 1;
+            return Ok(());
+        }
+        self.charge_staging_quotas(key, 0)?;
+        if self.config.tombstone_retention.is_some() {
+            self.staged_deletes.insert(key.clone());
+        }
         let new_root_hash = &self._delete(&root, key)?;
         self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
-        Ok(())
+        self.stage_change(key, ChangeKind::Deleted);
+        self.dirty = true;
+        self.journal(|| StagedAction::Delete { key: key.clone() })
     }
 
-    fn _delete(&mut self, root: &Tree, key: &ContextKey) -> Result<EntryHash, MerkleError> {
-        if key.is_empty() { return Ok(self.hash_tree(root)); }
+    /// Delete value or whole subtree under `prefix` from the staging area in one step, however
+    /// many values it holds. Empty `prefix` deletes everything staged.
+    pub fn delete_recursively(&mut self, prefix: &ContextKey) -> Result<(), MerkleError> {
+        if !prefix.is_empty() {
+            return self.delete(prefix);
+        }
+        self.check_writable(prefix)?;
+        self.record_access(prefix, AccessKind::Write);
+        self.charge_staging_quotas(prefix, 0)?;
+        if self.config.tombstone_retention.is_some() {
+            self.staged_deletes.insert(prefix.clone());
+        }
+        let tree = Tree::new();
+        self.put_to_staging_area(&hash_tree(&tree), Entry::Tree(tree.clone()));
+        self.current_stage_tree = Some(tree);
+        self.map_stats.current_tree_elems = 0;
+        self.stage_change(prefix, ChangeKind::Deleted);
+        self.dirty = true;
+        self.journal(|| StagedAction::DeleteRecursively { prefix: prefix.clone() })
+    }
 
-        self.compute_new_root_with_change(root, &key, None)
+    /// Discard all changes staged since the last commit or checkout. Writes stay restricted by
+    /// [MerkleStorage::checkout_partial], staging quotas are reset. Actions left in the staging
+    /// journal by a crashed process are discarded too.
+    pub fn reset(&mut self) -> Result<(), MerkleError> {
+        let mut batch = MultiSchemaBatch::default();
+        let base = self.last_commit.as_ref().map(hash_commit);
+        self.clear_staging_journal(base.as_ref(), &mut batch)?;
+        self.schemas.apply_multi(batch)?;
+        self.journal_len = 0;
+        self.staging_recovery_pending = false;
+        self.current_stage_tree = match &self.last_commit {
+            Some(commit) => Some(self.get_tree(&commit.root_hash)?),
+            None => None,
+        };
+        self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().map_or(0, |tree| tree.len() as u64);
+        self.staged = HashMap::new();
+        self.map_stats.staged_area_elems = 0;
+        self.staged_deletes.clear();
+        self.staged_changes.clear();
+        self.reset_staging_quotas();
+        self.dirty = false;
+        Ok(())
     }
 
-    /// Copy subtree under a new path.
-    /// TODO Consider copying values!
-    pub fn copy(&mut self, from_key: &ContextKey, to_key: &ContextKey) -> Result<(), MerkleError> {
+    /// Discard changes staged under `key` since the last commit or checkout, so the value or
+    /// subtree is the committed one again. Changes of other keys are kept, so are quotas they
+    /// were charged. Empty `key` discards everything, like [MerkleStorage::reset].
+    pub fn revert(&mut self, key: &ContextKey) -> Result<(), MerkleError> {
+        let (name, path) = match key.split_last() {
+            Some(split) => split,
+            None => return self.reset(),
+        };
+        self.check_staging_recovered()?;
+        let committed_root_hash = self.last_commit.as_ref().map(|commit| commit.root_hash);
+        let committed = match &committed_root_hash {
+            Some(root_hash) => self.find_tree(&self.get_tree(root_hash)?, path)?.get(name).cloned(),
+            None => None,
+        };
+        self.staged_deletes.retain(|deleted| !deleted.starts_with(key));
+        self.staged_changes.remove(key);
+        self.unstage_changes_below(key);
         let root = self.get_staged_root()?;
-        let new_root_hash = &self._copy(&root, from_key, to_key)?;
-        self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
+        let new_root_hash = self.compute_new_root_with_change(&root, key, committed)?;
+        self.current_stage_tree = Some(self.get_tree(&new_root_hash)?);
         self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
-        Ok(())
+        self.dirty = match committed_root_hash {
+            Some(root_hash) => root_hash != new_root_hash,
+            None => !self.current_stage_tree.as_ref().unwrap().is_empty(),
+        };
+        self.journal(|| StagedAction::Revert { key: key.clone() })
     }
 
-    fn _copy(&mut self, root: &Tree, from_key: &ContextKey, to_key: &ContextKey) -> Result<EntryHash, MerkleError> {
-        let source_tree = self.find_tree(root, &from_key)?;
-        let source_tree_hash = self.hash_tree(&source_tree);
-        Ok(self.compute_new_root_with_change(
-            &root, &to_key, Some(self.get_non_leaf(source_tree_hash)))?)
+    /// Replay actions left in the staging journal by a crashed process on top of the commit they
+    /// were staged on, see [MerkleStorageConfig::persist_staging]. Returns the number of replayed
+    /// actions, which stay journaled until the next commit, checkout or reset. Use
+    /// [MerkleStorage::reset] to discard them instead.
+    pub fn recover_staging(&mut self) -> Result<usize, MerkleError> {
+        if !self.staging_recovery_pending {
+            return Ok(0);
+        }
+        let mut actions = Vec::new();
+        for (_, action) in self.staging_journal.iterator(IteratorMode::Start)? {
+            actions.push(action.map_err(DBError::from)?);
+        }
+        let base: Option<EntryHash> = match self.metadata.get(&STAGING_BASE_KEY.to_string())? {
+            Some(bytes) => Some(bincode::deserialize(&bytes)?),
+            None => None,
+        };
+
+        self.staging_recovery_pending = false;
+        // replayed actions are in the journal already
+        self.config.persist_staging = false;
+        let replayed = self.replay_staging(base.as_ref(), &actions);
+        self.config.persist_staging = true;
+        if let Err(error) = replayed {
+            self.staging_recovery_pending = true;
+            return Err(error);
+        }
+        self.journal_len = actions.len() as u64;
+        Ok(actions.len())
+    }
+
+    fn replay_staging(&mut self, base: Option<&EntryHash>, actions: &[StagedAction]) -> Result<(), MerkleError> {
+        match base {
+            Some(commit_hash) => self.checkout(commit_hash)?,
+            None => self.reset()?,
+        }
+        for action in actions {
+            match action {
+                StagedAction::Set { key, value } => self.set(key, value)?,
+                StagedAction::Delete { key } => self.delete(key)?,
+                StagedAction::DeleteRecursively { prefix } => self.delete_recursively(prefix)?,
+                StagedAction::Copy { from_key, to_key } => self.copy(from_key, to_key)?,
+                StagedAction::Revert { key } => self.revert(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn _delete(&mut self, root: &Tree, key: &ContextKey) -> Result<EntryHash, MerkleError> {
+        if key.is_empty() { return Ok(hash_tree(root)); }
+
+        self.compute_new_root_with_change(root, &key, None)
+    }
+
+    /// Copy subtree or value under `from_key` to `to_key`, replacing what was stored there. The
+    /// copy refers to the entries of the source, so it costs the same however large the subtree
+    /// is. Like `copy` of the Tezos context, copying a missing key changes nothing. Fails with
+    /// [MerkleError::KeyTooDeep] if a path under `from_key` gets deeper than `max_key_depth` once
+    /// moved under `to_key`.
+    pub fn copy(&mut self, from_key: &ContextKey, to_key: &ContextKey) -> Result<(), MerkleError> {
+        self.check_writable(to_key)?;
+        self.record_access(from_key, AccessKind::Read);
+        self.record_access(to_key, AccessKind::Write);
+        let root = self.get_staged_root()?;
+        let source = match from_key.split_last() {
+            None => Some(self.get_non_leaf(hash_tree(&root))),
+            Some((name, path)) => self.find_tree(&root, path)?.get(name).cloned(),
+        };
+        let source = match source {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        if to_key.len() > from_key.len() {
+            self.check_copy_depth(&source, to_key)?;
+        }
+        self.charge_staging_quotas(to_key, 0)?;
+        let new_root_hash = &self.compute_new_root_with_change(&root, to_key, Some(source))?;
+        self.current_stage_tree = Some(self.get_tree(new_root_hash)?);
+        self.map_stats.current_tree_elems = self.current_stage_tree.as_ref().unwrap().len() as u64;
+        self.stage_change(to_key, ChangeKind::Copied { from: from_key.clone() });
+        self.dirty = true;
+        self.journal(|| StagedAction::Copy { from_key: from_key.clone(), to_key: to_key.clone() })
+    }
+
+    /// Record operation on `key`, it supersedes operations staged on paths below it.
+    fn stage_change(&mut self, key: &ContextKey, kind: ChangeKind) {
+        self.unstage_changes_below(key);
+        self.staged_changes.insert(key.clone(), kind);
+    }
+
+    fn unstage_changes_below(&mut self, key: &ContextKey) {
+        // paths below `key` directly follow it in key order
+        let below: Vec<ContextKey> = self.staged_changes.range::<ContextKey, _>((Bound::Excluded(key), Bound::Unbounded))
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(key))
+            .cloned()
+            .collect();
+        for path in below {
+            self.staged_changes.remove(&path);
+        }
+    }
+
+    /// Append action to the staging journal, if it is enabled.
+    fn journal<F: FnOnce() -> StagedAction>(&mut self, action: F) -> Result<(), MerkleError> {
+        if self.config.persist_staging {
+            self.staging_journal.put(&self.journal_len, &action())?;
+            self.journal_len += 1;
+            if self.config.sync_staging_journal {
+                self.schemas.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all actions from the staging journal and record `base`, the commit actions journaled
+    /// next are staged on.
+    fn clear_staging_journal(&self, base: Option<&EntryHash>, batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        if !self.config.persist_staging {
+            return Ok(());
+        }
+        for seq in 0..self.journal_len {
+            batch.delete::<StagingJournalSchema>(&seq)?;
+        }
+        let key = STAGING_BASE_KEY.to_string();
+        match base {
+            Some(base) => batch.put::<MetadataSchema>(&key, &bincode::serialize(base)?)?,
+            None => batch.delete::<MetadataSchema>(&key)?,
+        }
+        Ok(())
+    }
+
+    /// Fail while actions of a crashed process wait in the staging journal, so they are not
+    /// mixed with new changes.
+    fn check_staging_recovered(&self) -> Result<(), MerkleError> {
+        if self.staging_recovery_pending {
+            return Err(MerkleError::StagingNotRecovered { actions: self.journal_len });
+        }
+        Ok(())
+    }
+
+    /// Check that `key` may be written, see [MerkleStorage::checkout_partial].
+    fn check_writable(&self, key: &ContextKey) -> Result<(), MerkleError> {
+        self.check_staging_recovered()?;
+        self.check_key_depth(key)?;
+        match &self.writable_prefixes {
+            Some(prefixes) if !prefixes.iter().any(|prefix| key.starts_with(prefix)) => {
+                Err(MerkleError::OutsidePartialCheckout { key: key_to_path(key) })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject keys deeper than configured `max_key_depth`, so writes cannot build arbitrarily
+    /// deep trees.
+    fn check_key_depth(&self, key: &ContextKey) -> Result<(), MerkleError> {
+        if key.len() > self.config.max_key_depth {
+            return Err(MerkleError::KeyTooDeep {
+                key: key_to_path(key),
+                depth: key.len(),
+                max_depth: self.config.max_key_depth,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reject copy of `source` to `to_key` if any path under it would be deeper than configured
+    /// `max_key_depth` under `to_key`. Only levels within the limit are walked.
+    fn check_copy_depth(&self, source: &Node, to_key: &ContextKey) -> Result<(), MerkleError> {
+        let mut stack = vec![(source.clone(), to_key.clone())];
+        while let Some((node, path)) = stack.pop() {
+            self.check_key_depth(&path)?;
+            if node.node_kind == NodeKind::NonLeaf {
+                for (name, child) in self.get_tree(&node.entry_hash)?.iter() {
+                    let mut child_path = path.clone();
+                    child_path.push(name.clone());
+                    stack.push((child.clone(), child_path));
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Get a new tree with `new_entry_hash` put under given `key`.
@@ -397,55 +2338,75 @@ impl MerkleStorage {
     ) -> Result<EntryHash, MerkleError> {
         if key.is_empty() {
             return Ok(new_node.unwrap_or_else(
-                || self.get_non_leaf(self.hash_tree(root))).entry_hash);
+                || self.get_non_leaf(hash_tree(root))).entry_hash);
+        }
+
+        // trees along the path, `path_trees[i]` is the tree under `key[..i]`
+        let mut path_trees = Vec::with_capacity(key.len());
+        path_trees.push(root.clone());
+        for fragment in &key[..key.len() - 1] {
+            let parent = path_trees.last().unwrap();
+            let tree = self.find_tree(parent, std::slice::from_ref(fragment))?;
+            path_trees.push(tree);
         }
 
-        let last = key.last().unwrap();
-        let path = &key[..key.len() - 1];
-        let mut tree = self.find_tree(root, path)?;
+        // apply the change bottom-up, emptied trees are removed from their parent unless the
+        // policy keeps them; trees which did not exist are never created empty
+        let mut new_node = new_node;
+        for (fragment, mut tree) in key.iter().zip(path_trees).rev() {
+            let was_empty = tree.is_empty();
+            match new_node {
+                None => tree.remove(fragment),
+                Some(node) => tree.insert(fragment.clone(), node),
+            };
+
+            let keep_empty = !was_empty && self.config.empty_tree_policy == EmptyTreePolicy::Keep;
+            new_node = if tree.is_empty() && !keep_empty {
+                None
+            } else {
+                let new_tree_hash = hash_tree(&tree);
+                self.put_to_staging_area(&new_tree_hash, Entry::Tree(tree));
+                Some(self.get_non_leaf(new_tree_hash))
+            };
+        }
 
         match new_node {
-            None => tree.remove(last),
-            Some(new_node) => {
-                tree.insert(last.clone(), new_node)
+            Some(node) => Ok(node.entry_hash),
+            None => {
+                // whole tree was deleted
+                let tree = Tree::new();
+                let tree_hash = hash_tree(&tree);
+                self.put_to_staging_area(&tree_hash, Entry::Tree(tree));
+                Ok(tree_hash)
             }
-        };
-
-        if tree.is_empty() {
-            self.compute_new_root_with_change(root, path, None)
-        } else {
-            let new_tree_hash = self.hash_tree(&tree);
-            self.put_to_staging_area(&new_tree_hash, Entry::Tree(tree));
-            self.compute_new_root_with_change(
-                root, path, Some(self.get_non_leaf(new_tree_hash)))
         }
     }
 
-    /// Find tree by path. Return an empty tree if no tree under this path exists or if a blob
-    /// (= value) is encountered along the way.
-    ///
-    /// # Arguments
-    ///
-    /// * `root` - reference to a tree in which we search
-    /// * `key` - sought path
-    fn find_tree(&self, root: &Tree, key: &[String]) -> Result<Tree, MerkleError> {
-        if key.is_empty() { return Ok(root.clone()); }
-
-        let child_node = match root.get(key.first().unwrap()) {
-            Some(hash) => hash,
-            None => return Ok(Tree::new()),
-        };
 
-        match self.get_entry(&child_node.entry_hash)? {
-            Entry::Tree(tree) => {
-                self.find_tree(&tree, &key[1..])
+    /// Account for a change of `key` setting `value_len` bytes, or fail without accounting if it
+    /// would exceed configured staging quotas. A copied directory counts as a single key.
+    fn charge_staging_quotas(&mut self, key: &ContextKey, value_len: u64) -> Result<(), MerkleError> {
+        if let Some(limit) = self.config.max_staged_keys {
+            if !self.staged_keys.contains(key) && self.staged_keys.len() >= limit {
+                return Err(MerkleError::StagedKeysQuotaExceeded { limit });
             }
-            Entry::Blob(_) => Ok(Tree::new()),
-            Entry::Commit { .. } => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "tree".to_string(),
-                found: "commit".to_string(),
-            })
         }
+        if let Some(limit) = self.config.max_staged_bytes {
+            if self.staged_value_bytes + value_len > limit {
+                return Err(MerkleError::StagedBytesQuotaExceeded { limit });
+            }
+        }
+
+        if self.config.max_staged_keys.is_some() {
+            self.staged_keys.insert(key.clone());
+        }
+        self.staged_value_bytes += value_len;
+        Ok(())
+    }
+
+    fn reset_staging_quotas(&mut self) {
+        self.staged_keys.clear();
+        self.staged_value_bytes = 0;
     }
 
     /// Get latest staged tree. If it's empty, init genesis  and return genesis root.
@@ -453,7 +2414,7 @@ impl MerkleStorage {
         match &self.current_stage_tree {
             None => {
                 let tree = Tree::new();
-                self.put_to_staging_area(&self.hash_tree(&tree), Entry::Tree(tree.clone()));
+                self.put_to_staging_area(&hash_tree(&tree), Entry::Tree(tree.clone()));
                 self.map_stats.current_tree_elems = tree.len() as u64;
                 Ok(tree)
             }
@@ -470,457 +2431,5158 @@ impl MerkleStorage {
     }
 
     /// Persists an entry and its descendants from staged area to database on disk.
-    fn persist_staged_entry_to_db(&self, entry: &Entry) -> Result<(), MerkleError> {
-        let mut batch = Batch::default(); // batch containing DB key values to persist
+    /// Returns number of written entries and number of bytes which were not written, because
+    /// entries were already present. Entries are added to `batch` in [CommitWriteMode::Atomic],
+    /// index records always.
+    fn persist_staged_entry_to_db(&self, entry: &Entry, batch: &mut MultiSchemaBatch) -> Result<(u64, u64), MerkleError> {
+        // build list of entries to be persisted, `entry` comes first
+        let mut entries = Vec::new();
+        let skipped_bytes = self.get_entries_to_persist(entry, &mut entries)?;
+
+        let write_mode = match self.config.gc_mode {
+            GcMode::RefCounting => CommitWriteMode::Atomic,
+            GcMode::MarkAndSweep => self.config.commit_write_mode,
+        };
+        match write_mode {
+            CommitWriteMode::SingleBatch => {
+                // atomically write all entries in one batch to DB
+                self.write_entries_batch(&entries)?;
+            }
+            CommitWriteMode::SortedRuns { run_len } => {
+                // `entry` is written last, only after all entries it references
+                if let Some((first, descendants)) = entries.split_first_mut() {
+                    sort_children_first(descendants)?;
+                    for run in descendants.chunks(run_len.max(1)) {
+                        self.write_entries_batch(run)?;
+                    }
+                    self.write_entries_batch(std::slice::from_ref(first))?;
+                }
+            }
+            CommitWriteMode::Atomic => {
+                for (hash, bytes) in &entries {
+                    batch.put::<MerkleStorage>(hash, bytes)?;
+                }
+            }
+        }
 
-        // build list of entries to be persisted
-        self.get_entries_recursively(entry, &mut batch)?;
+        if self.config.hash_prefix_index {
+            let written: Vec<EntryHash> = entries.iter().map(|(hash, _)| *hash).collect();
+            self.index_entry_hashes(&written, batch)?;
+        }
+        if self.config.gc_mode == GcMode::RefCounting {
+            self.count_references(&entries, batch)?;
+        }
 
-        // atomically write all entries in one batch to DB
-        self.db.write_batch(batch)?;
+        Ok((entries.len() as u64, skipped_bytes))
+    }
 
+    /// Add references from newly written `entries` to ref counts in `batch`.
+    fn count_references(&self, entries: &[(EntryHash, Vec<u8>)], batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        let mut added: HashMap<EntryHash, u64> = HashMap::new();
+        for (_, bytes) in entries {
+            for child in referenced_entries(&bincode::deserialize(bytes)?) {
+                *added.entry(child).or_insert(0) += 1;
+            }
+        }
+        for (hash, added) in added {
+            let count = self.ref_counts.get(&hash)?.unwrap_or(0) + added;
+            batch.put::<RefCountSchema>(&hash, &count)?;
+        }
         Ok(())
     }
 
-    /// Builds vector of entries to be persisted to DB, recursively
-    fn get_entries_recursively(&self, entry: &Entry, batch: &mut Batch) -> Result<(), MerkleError> {
-        // add entry to batch
-        //self.db.apply_batch()
+    fn write_entries_batch(&self, entries: &[(EntryHash, Vec<u8>)]) -> Result<(), MerkleError> {
+        let mut batch = Batch::default();
+        for (hash, bytes) in entries {
+            self.db.put_batch(&mut batch, hash, bytes)?;
+        }
+        self.db.write_batch(batch)?;
+        Ok(())
+    }
 
-        let k = &self.hash_entry(entry);
-        let v = bincode::serialize(entry)?;
-        self.db.put_batch(batch, k, &v);
-        match entry {
-            Entry::Blob(_) => Ok(()),
+    /// Adds serialized entry and its staged descendants to `entries`. Entries which are not
+    /// staged are already persisted, so their subtrees are not visited.
+    ///
+    /// Entries are content addressed and always written together with their descendants, so an
+    /// entry already present in DB is skipped along with its subtree. Returns number of skipped
+    /// bytes.
+    fn get_entries_to_persist(&self, entry: &Entry, entries: &mut Vec<(EntryHash, Vec<u8>)>) -> Result<u64, MerkleError> {
+        // entries shared by several trees of the commit are collected once
+        let mut collected = HashSet::new();
+        #[cfg(feature = "parallel-hashing")]
+        {
+            if self.config.parallel_hashing {
+                return self.get_entries_to_persist_parallel(entry, &mut collected, entries);
+            }
+        }
+        self.collect_entries_to_persist(vec![Cow::Borrowed(entry)], &mut collected, entries)
+    }
+
+    /// Visit entries on `stack` and their staged descendants depth-first, see
+    /// [MerkleStorage::get_entries_to_persist].
+    fn collect_entries_to_persist<'a>(&'a self,
+                                      mut stack: Vec<Cow<'a, Entry>>,
+                                      collected: &mut HashSet<EntryHash>,
+                                      entries: &mut Vec<(EntryHash, Vec<u8>)>,
+    ) -> Result<u64, MerkleError> {
+        let mut skipped_bytes = 0;
+        while let Some(entry) = stack.pop() {
+            skipped_bytes += self.visit_entry_to_persist(entry, collected, entries, &mut stack)?;
+        }
+        Ok(skipped_bytes)
+    }
+
+    /// Add serialized `entry` to `entries` and its staged children to `children`, unless it was
+    /// collected or persisted already. Returns number of skipped bytes.
+    fn visit_entry_to_persist<'a>(&'a self,
+                                  entry: Cow<'a, Entry>,
+                                  collected: &mut HashSet<EntryHash>,
+                                  entries: &mut Vec<(EntryHash, Vec<u8>)>,
+                                  children: &mut Vec<Cow<'a, Entry>>,
+    ) -> Result<u64, MerkleError> {
+        let k = hash_entry(&entry);
+        if collected.contains(&k) {
+            return Ok(0);
+        }
+        if self.is_entry_stored(&k, entry.as_ref())? {
+            return Ok(bincode::serialized_size(entry.as_ref())?);
+        }
+        let v = self.serialize_for_db(&k, entry.as_ref())?;
+        collected.insert(k);
+        entries.push((k, v));
+
+        match entry.as_ref() {
+            Entry::Blob(_) | Entry::External { .. } => {}
             Entry::Tree(tree) => {
-                // Go through all descendants and gather errors. Remap error if there is a failure
-                // anywhere in the recursion paths. TODO: is revert possible?
-                tree.iter().map(|(_, child_node)| {
-                    match self.staged.get(&child_node.entry_hash) {
-                        None => Ok(()),
-                        Some(entry) => self.get_entries_recursively(entry, batch),
-                    }
-                }).find_map(|res| {
-                    match res {
-                        Ok(_) => None,
-                        Err(err) => Some(Err(err)),
-                    }
-                }).unwrap_or(Ok(()))
+                children.extend(tree.values()
+                    .filter_map(|child_node| self.staged.get(&child_node.entry_hash))
+                    .map(Cow::Borrowed));
             }
             Entry::Commit(commit) => {
-                match self.get_entry(&commit.root_hash) {
-                    Err(err) => Err(err),
-                    Ok(entry) => self.get_entries_recursively(&entry, batch),
-                }
+                children.push(Cow::Owned(self.get_entry(&commit.root_hash)?));
             }
         }
+        Ok(0)
     }
 
-    fn hash_entry(&self, entry: &Entry) -> EntryHash {
-        match entry {
-            Entry::Commit(commit) => self.hash_commit(&commit),
-            Entry::Tree(tree) => self.hash_tree(&tree),
-            Entry::Blob(blob) => self.hash_blob(blob),
+    /// Check whether entry is already stored, comparing it with the stored bytes unless
+    /// [MerkleStorageConfig::write_once] is off.
+    fn is_entry_stored(&self, hash: &EntryHash, entry: &Entry) -> Result<bool, MerkleError> {
+        if self.config.write_once == WriteOnceMode::Off {
+            return Ok(self.db.contains(hash)?);
+        }
+        match self.db.get_raw(hash)? {
+            Some(stored) => {
+                self.check_write_once(hash, &stored, &entry.encode()?)?;
+                Ok(true)
+            }
+            None => Ok(false),
         }
     }
 
-    fn hash_commit(&self, commit: &Commit) -> EntryHash {
-        let mut hasher = State::new(HASH_LEN, None).unwrap();
-        hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&commit.root_hash).expect("hasher");
+    /// Check that `bytes` about to be written under `hash` do not differ from the `stored` ones,
+    /// returns false if they do and [WriteOnceMode::Report] lets the write be skipped.
+    fn check_write_once(&self, hash: &EntryHash, stored: &[u8], bytes: &[u8]) -> Result<bool, MerkleError> {
+        // value in the blob sink would have to be read to compare it
+        if stored == bytes || is_external_blob(stored) || is_external_blob(bytes) {
+            return Ok(true);
+        }
+        // trees of older entry formats differ from their current form
+        if stored.starts_with(&TREE_VARIANT.to_le_bytes()) && Entry::decode(stored)?.encode()? == bytes {
+            return Ok(true);
+        }
+        match self.config.write_once {
+            WriteOnceMode::Off => Ok(true),
+            WriteOnceMode::Report => {
+                if let Some(hook) = &self.write_once_violation_hook {
+                    hook(hash, stored, bytes);
+                }
+                Ok(false)
+            }
+            WriteOnceMode::Reject => Err(MerkleError::EntryOverwrite { hash: HashType::ContextHash.bytes_to_string(hash) }),
+        }
+    }
 
-        if commit.parent_commit_hash.is_none() {
-            hasher.update(&(0 as u64).to_be_bytes()).expect("hasher");
-        } else {
-            hasher.update(&(1 as u64).to_be_bytes()).expect("hasher"); // # of parents; we support only 1
-            hasher.update(&(commit.parent_commit_hash.unwrap().len() as u64).to_be_bytes()).expect("hasher");
-            hasher.update(&commit.parent_commit_hash.unwrap()).expect("hasher");
+    /// Like [MerkleStorage::collect_entries_to_persist], but once the top levels give enough
+    /// independent subtrees, they are hashed and serialized in parallel. Entries of a subtree
+    /// stay in depth-first order after the levels above it.
+    #[cfg(feature = "parallel-hashing")]
+    fn get_entries_to_persist_parallel(&self,
+                                       entry: &Entry,
+                                       collected: &mut HashSet<EntryHash>,
+                                       entries: &mut Vec<(EntryHash, Vec<u8>)>,
+    ) -> Result<u64, MerkleError> {
+        use rayon::prelude::*;
+
+        let mut skipped_bytes = 0;
+        let mut level = vec![Cow::Borrowed(entry)];
+        let subtrees = rayon::current_num_threads() * PARALLEL_SUBTREES_PER_THREAD;
+        while !level.is_empty() && level.len() < subtrees {
+            let mut next_level = Vec::new();
+            for entry in level {
+                skipped_bytes += self.visit_entry_to_persist(entry, collected, entries, &mut next_level)?;
+            }
+            level = next_level;
         }
-        hasher.update(&(commit.time as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&(commit.author.len() as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&commit.author.clone().into_bytes()).expect("hasher");
-        hasher.update(&(commit.message.len() as u64).to_be_bytes()).expect("hasher");
-        hasher.update(&commit.message.clone().into_bytes()).expect("hasher");
 
-        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+        let collected_subtrees: Vec<(Vec<_>, u64)> = level.into_par_iter()
+            .map(|subtree| {
+                let mut subtree_entries = Vec::new();
+                let skipped_bytes = self.collect_entries_to_persist(vec![subtree], &mut HashSet::new(), &mut subtree_entries)?;
+                Ok((subtree_entries, skipped_bytes))
+            })
+            .collect::<Result<_, MerkleError>>()?;
+        for (subtree_entries, subtree_skipped_bytes) in collected_subtrees {
+            skipped_bytes += subtree_skipped_bytes;
+            // subtrees may share entries, e.g. after a copy
+            entries.extend(subtree_entries.into_iter().filter(|(hash, _)| collected.insert(*hash)));
+        }
+        Ok(skipped_bytes)
     }
 
-    fn hash_tree(&self, tree: &Tree) -> EntryHash {
-        let mut hasher = State::new(HASH_LEN, None).unwrap();
+    /// Serialize entry to be persisted. Values over the threshold of the blob sink are written to
+    /// the sink and replaced by a reference.
+    fn serialize_for_db(&self, hash: &EntryHash, entry: &Entry) -> Result<Vec<u8>, MerkleError> {
+        match (entry, &self.blob_sink) {
+            (Entry::Blob(blob), Some(sink)) if blob.len() >= self.external_blob_threshold => {
+                sink.put(hash, blob)?;
+                Entry::External { hash: *hash, len: blob.len() as u64 }.encode()
+            }
+            _ => entry.encode(),
+        }
+    }
 
-        hasher.update(&(tree.len() as u64).to_be_bytes()).expect("hasher");
-        tree.iter().for_each(|(k, v)| {
-            hasher.update(&self.encode_irmin_node_kind(&v.node_kind)).expect("hasher");
-            hasher.update(&[k.len() as u8]).expect("hasher");
-            hasher.update(&k.clone().into_bytes()).expect("hasher");
-            hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
-            hasher.update(&v.entry_hash).expect("hasher");
-        });
+    /// Get commit `commit_hash` with its metadata, see [ContextReader::get_commit_info].
+    pub fn get_commit_info(&self, commit_hash: &EntryHash) -> Result<CommitInfo, MerkleError> {
+        self.reader().get_commit_info(commit_hash)
+    }
 
-        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+    /// Get root hash of commit `commit_hash`, see [ContextReader::root_hash_bytes].
+    pub fn root_hash_bytes(&self, commit_hash: &EntryHash) -> Result<EntryHash, MerkleError> {
+        self.reader().root_hash_bytes(commit_hash)
     }
 
-    fn hash_blob(&self, blob: &ContextValue) -> EntryHash {
-        let mut hasher = State::new(HASH_LEN, None).unwrap();
-        hasher.update(&(blob.len() as u64).to_be_bytes()).expect("Failed to update hasher state");
-        hasher.update(blob).expect("Failed to update hasher state");
+    /// Walk commits from `commit_hash` back through its parents, see [ContextReader::history].
+    pub fn history(&self, commit_hash: &EntryHash) -> Result<HistoryIterator, MerkleError> {
+        self.reader().history(commit_hash)
+    }
+
+    /// Fold trees and values under `prefix` of given commit, see [ContextReader::fold].
+    pub fn fold<A, F>(&self, commit_hash: &EntryHash, prefix: &ContextKey, depth_limit: Option<usize>, init: A, f: F) -> Result<A, MerkleError>
+        where F: FnMut(A, &ContextKey, &FoldEntry) -> Result<A, MerkleError>
+    {
+        self.reader().fold(commit_hash, prefix, depth_limit, init, f)
+    }
+
+    /// Like [MerkleStorage::fold], but stops once `budget` is exceeded, see
+    /// [ContextReader::fold_with_budget].
+    pub fn fold_with_budget<A, F>(&self, commit_hash: &EntryHash, prefix: &ContextKey, depth_limit: Option<usize>, init: A, budget: QueryBudget, f: F)
+                                  -> Result<Budgeted<A>, MerkleError>
+        where F: FnMut(A, &ContextKey, &FoldEntry) -> Result<A, MerkleError>
+    {
+        self.reader().fold_with_budget(commit_hash, prefix, depth_limit, init, budget, f)
+    }
 
-        hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+    /// Stream all key-values of given commit in key order, see [ContextReader::materialize].
+    pub fn materialize(&self, commit_hash: &EntryHash) -> Result<MaterializeIterator, MerkleError> {
+        self.reader().materialize(commit_hash)
     }
 
-    fn encode_irmin_node_kind(&self, kind: &NodeKind) -> Vec<u8> {
-        match kind {
-            NodeKind::NonLeaf => vec![0, 0, 0, 0, 0, 0, 0, 0],
-            NodeKind::Leaf => vec![255, 0, 0, 0, 0, 0, 0, 0],
+    /// Iterate over all entries reachable from given commit (commit, trees and blobs).
+    /// Shared subtrees are yielded only once.
+    pub fn dag_iterator(&self, commit_hash: &EntryHash) -> Result<DagIterator, MerkleError> {
+        // fail early if hash does not point to a commit
+        self.get_commit(commit_hash)?;
+        Ok(DagIterator::new(self, commit_hash))
+    }
+
+    /// Get entry stored under `hash`, staged or committed. Values kept in the blob sink are
+    /// returned as [Entry::External], use [MerkleStorage::get_blob_reader] to read them.
+    pub fn read_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        match self.staged.get(hash) {
+            None => load_entry_from_db(self.db.as_ref(), self.entry_cache.as_ref(), &self.entry_sources, hash),
+            Some(entry) => Ok(entry.clone()),
         }
     }
 
+    /// Get proof that the value under `key` is part of commit `commit_hash`, to be checked by
+    /// clients with [MerkleProof::verify].
+    pub fn get_proof(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        inclusion_proof(self, commit_hash, key)
+    }
 
-    fn get_tree(&self, hash: &EntryHash) -> Result<Tree, MerkleError> {
-        match self.get_entry(hash)? {
-            Entry::Tree(tree) => Ok(tree),
-            Entry::Blob(_) => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "tree".to_string(),
-                found: "blob".to_string(),
-            }),
-            Entry::Commit { .. } => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "tree".to_string(),
-                found: "commit".to_string(),
-            }),
+    /// Get proof that no value is stored under `key` in commit `commit_hash`, to be checked by
+    /// clients with [MerkleProof::verify_exclusion]. The proof ends with the tree, in which the
+    /// path to `key` ends, together with the neighbours of the missing child.
+    pub fn get_exclusion_proof(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        exclusion_proof(self, commit_hash, key)
+    }
+
+    /// Get distribution of directory fan-out and value sizes in the last commit, e.g. to pick
+    /// thresholds of sharding or chunking. Directories and values shared by several paths are
+    /// counted once, as they are stored.
+    pub fn node_histograms(&self) -> Result<NodeHistograms, MerkleError> {
+        let mut histograms = NodeHistograms::default();
+        let head = match self.get_last_commit_hash() {
+            Some(head) => head,
+            None => return Ok(histograms),
+        };
+        let mut iter = DagIterator::new(self, &head);
+        while let Some(res) = iter.next_entry() {
+            match res?.1 {
+                Entry::Tree(tree) => histograms.directory_fan_out.record(tree.len() as u64),
+                Entry::Blob(blob) => histograms.blob_sizes.record(blob.len() as u64),
+                Entry::External { len, .. } => histograms.blob_sizes.record(len),
+                Entry::Commit(_) => {}
+            }
         }
+        Ok(histograms)
     }
 
-    fn get_commit(&self, hash: &EntryHash) -> Result<Commit, MerkleError> {
-        match self.get_entry(hash)? {
-            Entry::Commit(commit) => Ok(commit),
-            Entry::Tree(_) => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "commit".to_string(),
-                found: "tree".to_string(),
-            }),
-            Entry::Blob(_) => Err(MerkleError::FoundUnexpectedStructure {
-                sought: "commit".to_string(),
-                found: "blob".to_string(),
-            }),
+    /// Verify that every entry reachable from commits in range `from_commit..=to_commit` hashes
+    /// to the key it is stored under. `from_commit` has to be an ancestor of `to_commit`.
+    ///
+    /// Commits are verified from the oldest one and entries shared with already verified commits
+    /// are not visited again, so verifying a long history costs roughly as much as verifying
+    /// the entries introduced by each commit.
+    pub fn verify_range(&self, from_commit: &EntryHash, to_commit: &EntryHash) -> Result<VerificationReport, MerkleError> {
+        let commits = self.commits_between(from_commit, to_commit)?;
+
+        let mut report = VerificationReport::default();
+        let mut verified = HashSet::new();
+        for commit_hash in commits.iter().rev() {
+            self.get_commit(commit_hash)?;
+            let mut iter = DagIterator::with_visited(self, commit_hash, verified);
+            while let Some(res) = iter.next_entry() {
+                let (hash, entry, _) = res?;
+                let computed = hash_entry(&entry);
+                if computed != hash {
+                    return Err(MerkleError::EntryHashMismatch {
+                        hash: HashType::ContextHash.bytes_to_string(&hash),
+                        computed: HashType::ContextHash.bytes_to_string(&computed),
+                    });
+                }
+                report.entries_verified += 1;
+            }
+            report.entries_reused += iter.skipped;
+            report.commits_verified += 1;
+            verified = iter.into_visited();
         }
+
+        Ok(report)
     }
 
-    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
-        match self.staged.get(hash) {
-            None => {
-                let entry_bytes = self.db.get(hash)?;
-                match entry_bytes {
-                    None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(hash) }),
-                    Some(entry_bytes) => {
-                        Ok(bincode::deserialize(entry_bytes.as_ref())?)
+    /// Compare serialized and stored sizes of all entries in the database by entry kind, to see
+    /// how well the value pipeline compresses them, see [EntryCodecs](crate::entry_codecs::EntryCodecs).
+    pub fn compression_stats(&self) -> Result<CompressionStats, MerkleError> {
+        let mut stats = CompressionStats::default();
+        for entry in self.schemas.stored_entries()? {
+            let (plain, stored_len) = entry?;
+            if let Some(kind) = EntryKind::of(&plain) {
+                stats.kind_mut(kind).add(plain.len(), stored_len);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Check every entry stored in the database, reachable or not, by hashing its content and
+    /// comparing the hash with the key it is stored under. The key space is split into ranges
+    /// audited by `workers` threads in parallel. Staged entries are not checked.
+    pub fn audit_entries(&self, workers: usize) -> Result<AuditReport, MerkleError> {
+        let mut bounds: Vec<Option<EntryHash>> = vec![None];
+        bounds.extend(self.db.split_points(workers.max(1))?.into_iter().map(Some));
+        bounds.push(None);
+
+        let handles: Vec<_> = bounds.windows(2)
+            .map(|range| {
+                let (db, start, end) = (self.db.clone(), range[0], range[1]);
+                std::thread::spawn(move || audit_entry_range(db.as_ref(), start, end))
+            })
+            .collect();
+
+        let mut report = AuditReport::default();
+        for handle in handles {
+            report.merge(handle.join().map_err(|_| MerkleError::WorkerPanicked { worker: "audit" })??);
+        }
+        report.mismatched.sort();
+        report.undecodable.sort();
+        Ok(report)
+    }
+
+    /// Load entries exported by [Snapshot::export]. Every entry is checked to hash to the key it
+    /// was exported under. Head of the export is not checked out, refs of the export are restored
+    /// with [ImportConfig::restore_refs]. With [GcMode::RefCounting], ref counts are rebuilt
+    /// afterwards. Entries stored already are not written again, those stored with different
+    /// bytes are handled according to [MerkleStorageConfig::write_once].
+    pub fn import_snapshot<R: Read>(&mut self, mut reader: R, config: ImportConfig) -> Result<ExportReport, MerkleError> {
+        // lengths in the stream are untrusted, so they must not drive unbounded allocations
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_IMPORT_RECORD_LEN);
+        let header: ExportHeader = options.deserialize_from(&mut reader)?;
+        if !is_readable_entry_format(header.entry_format_version) {
+            return Err(MerkleError::IncompatibleDatabase {
+                field: "export entry format version",
+                expected: format!("at most {}", ENTRY_FORMAT_VERSION),
+                found: header.entry_format_version.to_string(),
+            });
+        }
+
+        let mut report = ExportReport { head: header.head, ..ExportReport::default() };
+        let mut batch = Batch::default();
+        let mut batch_entries = 0;
+        while let Some((hash, bytes)) = options.deserialize_from::<_, Option<(EntryHash, Vec<u8>)>>(&mut reader)? {
+            let computed = hash_entry(&bincode::deserialize(&bytes)?);
+            if computed != hash {
+                return Err(MerkleError::EntryHashMismatch {
+                    hash: HashType::ContextHash.bytes_to_string(&hash),
+                    computed: HashType::ContextHash.bytes_to_string(&computed),
+                });
+            }
+            if self.db.contains(&hash)? {
+                if self.config.write_once != WriteOnceMode::Off {
+                    if let Some(stored) = self.db.get_raw(&hash)? {
+                        self.check_write_once(&hash, &stored, &bytes)?;
                     }
                 }
+                self.skipped_write_bytes += bytes.len() as u64;
+                continue;
             }
-            Some(entry) => Ok(entry.clone()),
+            report.entries += 1;
+            report.bytes += bytes.len() as u64;
+            self.db.put_batch(&mut batch, &hash, &bytes)?;
+            batch_entries += 1;
+            if batch_entries == IMPORT_BATCH_ENTRIES {
+                self.db.write_batch(std::mem::take(&mut batch))?;
+                batch_entries = 0;
+            }
+        }
+        self.db.write_batch(batch)?;
+        let entries = report.entries;
+        self.update_counters(|counters| counters.entries_written += entries)?;
+        if self.config.gc_mode == GcMode::RefCounting {
+            self.rebuild_ref_counts()?;
+        }
+        if config.restore_refs {
+            for (name, commit_hash) in &header.refs {
+                self.update_ref(name, self.get_ref(name)?.as_ref(), commit_hash)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Get hashes of commits from `to_commit` back to its ancestor `from_commit` (both included).
+    fn commits_between(&self, from_commit: &EntryHash, to_commit: &EntryHash) -> Result<Vec<EntryHash>, MerkleError> {
+        let mut commits = vec![*to_commit];
+        let mut commit_hash = *to_commit;
+        while commit_hash != *from_commit {
+            commit_hash = match self.get_commit(&commit_hash)?.parent_commit_hash {
+                Some(parent_hash) => parent_hash,
+                None => return Err(MerkleError::NotAnAncestor {
+                    ancestor: HashType::ContextHash.bytes_to_string(from_commit),
+                    commit: HashType::ContextHash.bytes_to_string(to_commit),
+                }),
+            };
+            commits.push(commit_hash);
+        }
+        Ok(commits)
+    }
+
+    /// Record keys deleted since last commit as tombstones of the new commit and drop all
+    /// tombstones older than the retention window.
+    fn persist_tombstones(&self, commit_hash: &EntryHash, time: u64, batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        let retention = match self.config.tombstone_retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+
+        let keys: HashSet<String> = self.staged_deletes.iter().map(|key| key_to_path(key)).collect();
+        for key in &keys {
+            let mut tombstones = self.tombstones.get(key)?.unwrap_or_default();
+            tombstones.retain_since(time, retention);
+            tombstones.0.push(Tombstone { commit_hash: *commit_hash, time });
+            batch.put::<TombstoneSchema>(key, &tombstones)?;
+        }
+        self.prune_tombstones(time, retention, &keys, batch)?;
+        if !keys.is_empty() {
+            let mut tombstoned = self.tombstone_expiry.get(&time)?.unwrap_or_default();
+            tombstoned.0.extend(keys);
+            batch.put::<TombstoneExpirySchema>(&time, &tombstoned)?;
+        }
+        Ok(())
+    }
+
+    /// Drop tombstones which fell out of the retention window ending at `now`, going through
+    /// keys tombstoned at the oldest times until the window is reached. Tombstones of `recorded`
+    /// keys were already dropped while recording the new ones.
+    fn prune_tombstones(&self, now: u64, retention: u64, recorded: &HashSet<String>, batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        for (time, keys) in self.tombstone_expiry.iterator(IteratorMode::Start)? {
+            let (time, keys) = (time.map_err(DBError::from)?, keys.map_err(DBError::from)?);
+            if time.saturating_add(retention) >= now {
+                break;
+            }
+            for key in keys.0.iter().filter(|key| !recorded.contains(*key)) {
+                if let Some(mut tombstones) = self.tombstones.get(key)? {
+                    tombstones.retain_since(now, retention);
+                    if tombstones.0.is_empty() {
+                        batch.delete::<TombstoneSchema>(key)?;
+                    } else {
+                        batch.put::<TombstoneSchema>(key, &tombstones)?;
+                    }
+                }
+            }
+            batch.delete::<TombstoneExpirySchema>(&time)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether `key` or any of its parent directories was deleted by a commit after
+    /// `from_commit` up to and including `to_commit`, using recorded tombstones instead of diffing.
+    ///
+    /// Answers are reliable only within the configured tombstone retention window.
+    pub fn was_deleted_between(&self, key: &ContextKey, from_commit: &EntryHash, to_commit: &EntryHash) -> Result<bool, MerkleError> {
+        if self.config.tombstone_retention.is_none() {
+            return Err(MerkleError::TombstonesDisabled);
+        }
+
+        let mut commits: HashSet<EntryHash> = self.commits_between(from_commit, to_commit)?.into_iter().collect();
+        commits.remove(from_commit);
+
+        for depth in 1..=key.len() {
+            if let Some(tombstones) = self.tombstones.get(&key_to_path(&key[..depth]))? {
+                if tombstones.0.iter().any(|tombstone| commits.contains(&tombstone.commit_hash)) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Get commits after `from_commit` up to `to_commit` (included), which changed a value under
+    /// `prefix`, newest first. Commits, which change filter rules the prefix out, are skipped
+    /// without comparing their trees, see [MerkleStorageConfig::change_filters].
+    pub fn commits_touching(&self, from_commit: &EntryHash, to_commit: &EntryHash, prefix: &ContextKey) -> Result<Vec<EntryHash>, MerkleError> {
+        let mut touching = Vec::new();
+        for commit_hash in self.commits_between(from_commit, to_commit)? {
+            if commit_hash == *from_commit {
+                break;
+            }
+            if let Some(filter) = self.change_filters.get(&commit_hash)? {
+                if !filter.may_touch(prefix) {
+                    continue;
+                }
+            }
+            let commit = self.get_commit(&commit_hash)?;
+            let old_node = match commit.parent_commit_hash {
+                Some(parent_hash) => self.find_node_or_root(&self.get_commit(&parent_hash)?.root_hash, prefix)?,
+                None => None,
+            };
+            let new_node = self.find_node_or_root(&commit.root_hash, prefix)?;
+            if old_node.map(|node| node.entry_hash) != new_node.map(|node| node.entry_hash) {
+                touching.push(commit_hash);
+            }
+        }
+        Ok(touching)
+    }
+
+    /// Get filter of prefixes changed by commit `commit_hash`. Returns `None` for commits made
+    /// while [MerkleStorageConfig::change_filters] was disabled.
+    pub fn get_change_filter(&self, commit_hash: &EntryHash) -> Result<Option<ChangeFilter>, MerkleError> {
+        Ok(self.change_filters.get(commit_hash)?)
+    }
+
+    /// Append version of every value changed by the new commit to the history cell of its key and
+    /// record height of the commit.
+    fn persist_key_history(&self, parent_commit_hash: Option<&EntryHash>, commit_hash: &EntryHash, root_hash: &EntryHash, batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        let height = match parent_commit_hash {
+            Some(parent_hash) => self.commit_height(parent_hash)? + 1,
+            None => 0,
+        };
+        batch.put::<CommitHeightSchema>(commit_hash, &height)?;
+        let parent_root_hash = self.last_commit.as_ref().map(|c| c.root_hash);
+        for_each_changed_value(self, parent_root_hash.as_ref(), root_hash, |key, old, new| {
+            let path = key_to_path(key);
+            let mut history = self.key_history.get(&path)?.unwrap_or_default();
+            history.0.push(KeyVersion { commit_hash: *commit_hash, height, old_value_hash: old.copied(), new_value_hash: new.copied() });
+            Ok(batch.put::<KeyHistorySchema>(&path, &history)?)
+        })
+    }
+
+    /// Number of ancestors of commit `commit_hash`. Commits made before key history was enabled
+    /// are counted by walking their parents, until one with recorded height.
+    fn commit_height(&self, commit_hash: &EntryHash) -> Result<u64, MerkleError> {
+        let mut walked = 0;
+        for info in self.history(commit_hash)? {
+            let info = info?;
+            if let Some(height) = self.commit_heights.get(&info.commit_hash)? {
+                return Ok(height + walked);
+            }
+            walked += 1;
+        }
+        Ok(walked - 1)
+    }
+
+    /// Get value of `key` at commit `commit_hash` from the history cell of the key, so the cost
+    /// depends on the number of changes of the key, not on the distance of the commit from the
+    /// head. Commits are ordered by height, so the commit must be on the line of history the
+    /// changes were recorded on. Keys without recorded changes and commits made before key
+    /// history was enabled are looked up in the tree of the commit. Returns `None` for a missing
+    /// key, see [MerkleStorageConfig::key_history].
+    pub fn get_value_at(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<Option<ContextValue>, MerkleError> {
+        if !self.config.key_history {
+            return Err(MerkleError::KeyHistoryDisabled);
+        }
+        let value_hash = match (self.key_history.get(&key_to_path(key))?, self.commit_heights.get(commit_hash)?) {
+            (Some(history), Some(height)) => history.value_hash_at(height),
+            _ => None,
+        };
+        match value_hash {
+            Some(None) => Ok(None),
+            Some(Some(hash)) => match self.get_entry(&hash)? {
+                Entry::Blob(blob) => Ok(Some(blob)),
+                _ => Err(MerkleError::ValueIsNotABlob { key: key_to_path(key) }),
+            },
+            None => match self.get_history(commit_hash, key) {
+                Ok(value) => Ok(Some(value)),
+                Err(MerkleError::ValueNotFound { .. }) => Ok(None),
+                Err(error) => Err(error),
+            },
+        }
+    }
+
+    /// Get history cell of `key`, empty if no change of the key was recorded.
+    pub fn get_key_history(&self, key: &ContextKey) -> Result<KeyHistory, MerkleError> {
+        Ok(self.key_history.get(&key_to_path(key))?.unwrap_or_default())
+    }
+
+    /// Bring the value hash index from the previously indexed context to context `root_hash`.
+    fn update_value_hash_index(&self, root_hash: &EntryHash, batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        let indexed_root = match self.metadata.get(&VALUE_HASH_INDEX_ROOT_KEY.to_string())? {
+            Some(bytes) => Some(bincode::deserialize::<EntryHash>(&bytes)?),
+            None => None,
+        };
+        for_each_changed_value(self, indexed_root.as_ref(), root_hash, |key, old_value, new_value| {
+            let key = key_to_path(key);
+            if let Some(value_hash) = old_value {
+                batch.delete::<ValueHashIndexSchema>(&ValueHashIndexKey { value_hash: *value_hash, key: key.clone() })?;
+            }
+            if let Some(value_hash) = new_value {
+                batch.put::<ValueHashIndexSchema>(&ValueHashIndexKey { value_hash: *value_hash, key }, &())?;
+            }
+            Ok(())
+        })?;
+        batch.put::<MetadataSchema>(&VALUE_HASH_INDEX_ROOT_KEY.to_string(), &bincode::serialize(root_hash)?)?;
+        Ok(())
+    }
+
+    /// Find keys holding a value with hash `value_hash` (see [value_hash]) in the context of the
+    /// last commit or checkout. Keys are returned in key order.
+    pub fn find_keys_with_value_hash(&self, value_hash: &EntryHash) -> Result<Vec<ContextKey>, MerkleError> {
+        if !self.config.value_hash_index {
+            return Err(MerkleError::ValueHashIndexDisabled);
+        }
+
+        let mut keys = Vec::new();
+        for (index_key, _) in self.value_index.prefix_iterator(&ValueHashIndexKey { value_hash: *value_hash, key: String::new() })? {
+            keys.push(key_from_path(&index_key.map_err(DBError::from)?.key)?);
+        }
+        Ok(keys)
+    }
+
+    /// Add hashes of newly persisted entries to the hash prefix index.
+    fn index_entry_hashes(&self, hashes: &[EntryHash], batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        let mut buckets: HashMap<HashPrefix, Vec<EntryHash>> = HashMap::new();
+        for hash in hashes {
+            buckets.entry(hash_prefix(hash)).or_default().push(*hash);
+        }
+
+        for (prefix, hashes) in buckets {
+            let mut candidates = self.hash_index.get(&prefix)?.unwrap_or_default();
+            for hash in hashes {
+                if !candidates.0.contains(&hash) {
+                    candidates.0.push(hash);
+                }
+            }
+            batch.put::<HashIndexSchema>(&prefix, &candidates)?;
+        }
+        Ok(())
+    }
+
+    /// Remove `hashes` of deleted entries from the hash prefix index in `batch`.
+    fn unindex_entry_hashes(&self, hashes: &[EntryHash], batch: &mut MultiSchemaBatch) -> Result<(), MerkleError> {
+        let mut buckets: HashMap<HashPrefix, Vec<EntryHash>> = HashMap::new();
+        for hash in hashes {
+            buckets.entry(hash_prefix(hash)).or_default().push(*hash);
+        }
+
+        for (prefix, hashes) in buckets {
+            let mut candidates = match self.hash_index.get(&prefix)? {
+                Some(candidates) => candidates,
+                None => continue,
+            };
+            candidates.0.retain(|hash| !hashes.contains(hash));
+            if candidates.0.is_empty() {
+                batch.delete::<HashIndexSchema>(&prefix)?;
+            } else {
+                batch.put::<HashIndexSchema>(&prefix, &candidates)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve abbreviated hex hash of a committed entry (commit, tree or blob) to its full
+    /// hash, like git resolves short commit hashes. The prefix has to match exactly one entry.
+    ///
+    /// Only entries committed while [MerkleStorageConfig::hash_prefix_index] was enabled are found.
+    pub fn resolve_hash_prefix(&self, prefix: &str) -> Result<EntryHash, MerkleError> {
+        if !self.config.hash_prefix_index {
+            return Err(MerkleError::HashPrefixIndexDisabled);
+        }
+
+        let hex_prefix = prefix.to_ascii_lowercase();
+        let max_len = HASH_LEN * 2;
+        if hex_prefix.len() < MIN_HASH_PREFIX_HEX_LEN || hex_prefix.len() > max_len
+            || !hex_prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(MerkleError::InvalidHashPrefix {
+                prefix: prefix.to_string(),
+                min_len: MIN_HASH_PREFIX_HEX_LEN,
+                max_len,
+            });
+        }
+
+        let mut key = HashPrefix::default();
+        hex::decode_to_slice(&hex_prefix[..MIN_HASH_PREFIX_HEX_LEN], &mut key)
+            .expect("prefix was checked to be hex");
+        let matches: Vec<EntryHash> = self.hash_index.get(&key)?
+            .unwrap_or_default().0
+            .into_iter()
+            .filter(|hash| hex::encode(hash).starts_with(&hex_prefix))
+            .collect();
+
+        match matches.len() {
+            0 => Err(MerkleError::EntryNotFound { hash: prefix.to_string() }),
+            1 => Ok(matches[0]),
+            n => Err(MerkleError::AmbiguousHashPrefix { prefix: prefix.to_string(), matches: n }),
+        }
+    }
+
+    fn get_non_leaf(&self, hash: EntryHash) -> Node {
+        Node { node_kind: NodeKind::NonLeaf, entry_hash: hash }
+    }
+
+    /// Create read-only handle observing the store as of now: reads through it see the current
+    /// head even after new commits are made. While the snapshot is alive, its epoch is pinned,
+    /// see [MerkleStorage::oldest_pinned_epoch].
+    pub fn snapshot(&self) -> Result<Snapshot, MerkleError> {
+        let reader = self.reader();
+        let refs: BTreeMap<String, EntryHash> = reader.list_refs()?.into_iter().collect();
+
+        let head = self.get_last_commit_hash();
+        self.pins.pin(self.epoch, head.into_iter().chain(refs.values().copied()));
+        Ok(Snapshot {
+            reader,
+            head,
+            refs,
+            epoch: self.epoch,
+            pins: self.pins.clone(),
+        })
+    }
+
+    /// Get commit the ref `name` points to, see [ContextReader::get_ref].
+    pub fn get_ref(&self, name: &str) -> Result<Option<EntryHash>, MerkleError> {
+        self.reader().get_ref(name)
+    }
+
+    /// Names of all refs with the commits they point to, see [ContextReader::list_refs].
+    pub fn list_refs(&self) -> Result<Vec<(String, EntryHash)>, MerkleError> {
+        self.reader().list_refs()
+    }
+
+    /// Point ref `name` to `commit_hash`, wherever it pointed before. Use
+    /// [MerkleStorage::update_ref] when other writers may move the ref too.
+    pub fn set_ref(&self, name: &str, commit_hash: &EntryHash) -> Result<(), MerkleError> {
+        // refs may point to commits only
+        self.get_commit(commit_hash)?;
+        Ok(self.refs.put(&name.to_string(), commit_hash)?)
+    }
+
+    /// Checkout commit the ref `name` points to, see [MerkleStorage::checkout].
+    pub fn checkout_ref(&mut self, name: &str) -> Result<EntryHash, MerkleError> {
+        let commit_hash = self.get_ref(name)?.ok_or_else(|| MerkleError::RefNotFound { name: name.to_string() })?;
+        self.checkout(&commit_hash)?;
+        Ok(commit_hash)
+    }
+
+    /// Point ref `name` to `commit_hash`, provided it still points to `expected` (`None` if the
+    /// ref should not exist yet). If another writer moved the ref in the meantime, nothing is
+    /// changed and [MerkleError::RefUpdateConflict] is returned.
+    pub fn update_ref(&self, name: &str, expected: Option<&EntryHash>, commit_hash: &EntryHash) -> Result<(), MerkleError> {
+        // refs may point to commits only
+        self.get_commit(commit_hash)?;
+        self.swap_ref(name, expected, Some(commit_hash))
+    }
+
+    /// Delete ref `name`, provided it still points to `expected`, see [MerkleStorage::update_ref].
+    pub fn delete_ref(&self, name: &str, expected: &EntryHash) -> Result<(), MerkleError> {
+        self.swap_ref(name, Some(expected), None)?;
+        let mut parameters = BTreeMap::new();
+        parameters.insert("name".to_string(), name.to_string());
+        parameters.insert("commit".to_string(), HashType::ContextHash.bytes_to_string(expected));
+        let mut counts = BTreeMap::new();
+        counts.insert("refs_deleted".to_string(), 1);
+        self.append_audit_record(DestructiveOperation::DeleteRef, parameters, counts)
+    }
+
+    fn swap_ref(&self, name: &str, expected: Option<&EntryHash>, new: Option<&EntryHash>) -> Result<(), MerkleError> {
+        match self.refs.compare_and_swap(&name.to_string(), expected, new)? {
+            Ok(()) => Ok(()),
+            Err(found) => Err(MerkleError::RefUpdateConflict {
+                name: name.to_string(),
+                expected: expected.map(|hash| HashType::ContextHash.bytes_to_string(hash)),
+                found: found.map(|hash| HashType::ContextHash.bytes_to_string(&hash)),
+            }),
+        }
+    }
+
+    /// Get summary of keys changed by a commit. Returns `None` for commits made while
+    /// [MerkleStorageConfig::commit_annotations] was disabled.
+    pub fn get_commit_annotation(&self, commit_hash: &EntryHash) -> Result<Option<CommitAnnotation>, MerkleError> {
+        Ok(self.annotations.get(commit_hash)?)
+    }
+
+    /// Record cost of applying the block, which produced commit `commit_hash`. Metrics are
+    /// returned by [MerkleStorage::log], recording them again replaces them.
+    pub fn set_apply_metrics(&self, commit_hash: &EntryHash, metrics: &ApplyMetrics) -> Result<(), MerkleError> {
+        self.get_commit(commit_hash)?;
+        Ok(self.apply_metrics.put(commit_hash, metrics)?)
+    }
+
+    /// Get up to `limit` commits starting with `commit_hash` and following its parents, see
+    /// [ContextReader::log].
+    pub fn log(&self, commit_hash: &EntryHash, limit: usize) -> Result<Vec<LogEntry>, MerkleError> {
+        self.reader().log(commit_hash, limit)
+    }
+
+    /// List values changed between two commits with their values, see [ContextReader::diff].
+    pub fn diff(&self, commit_a: &EntryHash, commit_b: &EntryHash) -> Result<Vec<ContextChange>, MerkleError> {
+        self.reader().diff(commit_a, commit_b)
+    }
+
+    /// Like [MerkleStorage::diff], but stops once `budget` is exceeded, see
+    /// [ContextReader::diff_with_budget].
+    pub fn diff_with_budget(&self, commit_a: &EntryHash, commit_b: &EntryHash, budget: QueryBudget) -> Result<Budgeted<Vec<ContextChange>>, MerkleError> {
+        self.reader().diff_with_budget(commit_a, commit_b, budget)
+    }
+
+    /// List values under `prefix` changed between two commits, see [ContextReader::diff_prefix].
+    pub fn diff_prefix(&self, commit_a: &EntryHash, commit_b: &EntryHash, prefix: &ContextKey) -> Result<Vec<ValueChange>, MerkleError> {
+        self.reader().diff_prefix(commit_a, commit_b, prefix)
+    }
+
+    /// Like [MerkleStorage::diff_prefix], but stops once `budget` is exceeded.
+    pub fn diff_prefix_with_budget(&self, commit_a: &EntryHash, commit_b: &EntryHash, prefix: &ContextKey, budget: QueryBudget)
+                                   -> Result<Budgeted<Vec<ValueChange>>, MerkleError> {
+        self.reader().diff_prefix_with_budget(commit_a, commit_b, prefix, budget)
+    }
+
+    /// Append record of a destructive operation to the audit log. Sequence numbers are claimed
+    /// with compare-and-swap, so concurrent writers never overwrite each other's records.
+    fn append_audit_record(&self, operation: DestructiveOperation, parameters: BTreeMap<String, String>, counts: BTreeMap<String, u64>) -> Result<(), MerkleError> {
+        let time = (self.clock)();
+        let record = AuditRecord { time, operation, parameters, counts };
+        loop {
+            let next_seq = match self.audit_log.iterator(IteratorMode::Tail(1))?.next() {
+                Some((seq, _)) => seq.map_err(DBError::from)? + 1,
+                None => 0,
+            };
+            if self.audit_log.compare_and_swap(&next_seq, None, Some(&record))?.is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Get up to `limit` audit log records with sequence number `from_seq` or higher, oldest first.
+    pub fn get_audit_log(&self, from_seq: u64, limit: usize) -> Result<Vec<(u64, AuditRecord)>, MerkleError> {
+        let mut records = Vec::new();
+        for (seq, record) in self.audit_log.iterator(IteratorMode::From(&from_seq, Direction::Forward))?.take(limit) {
+            records.push((seq.map_err(DBError::from)?, record.map_err(DBError::from)?));
+        }
+        Ok(records)
+    }
+
+    /// Epoch of the oldest live snapshot, if any. Head and refs of live snapshots are retained
+    /// by [MerkleStorage::gc].
+    pub fn oldest_pinned_epoch(&self) -> Option<u64> {
+        self.pins.oldest()
+    }
+
+    /// Delete entries not reachable from the last `retain_last_n_commits` commits of head, of
+    /// every ref and of every live snapshot, see [MerkleStorage::gc_with_progress].
+    pub fn gc(&mut self, retain_last_n_commits: usize) -> Result<GcReport, MerkleError> {
+        self.gc_with_progress(retain_last_n_commits, |_, _| ())
+    }
+
+    /// Mark-and-sweep garbage collection. Entries reachable from retained commits are marked
+    /// first, then all other entries are deleted in batches, together with annotations, apply
+    /// metrics and change filters of deleted commits. `progress` is called after every retained
+    /// commit is marked and after every deleted batch.
+    ///
+    /// Head is always retained, so its staged changes stay valid. Values kept in the blob sink
+    /// are not deleted. With [GcMode::RefCounting], ref counts are rebuilt afterwards. Commits
+    /// pinned by readers in other threads, see [ContextReader::pin], are retained like refs, also
+    /// when pinned while collection runs, so collection can run next to live reads and exports.
+    pub fn gc_with_progress<F: FnMut(GcPhase, &GcReport)>(&mut self, retain_last_n_commits: usize, mut progress: F) -> Result<GcReport, MerkleError> {
+        let pins = self.pins.clone();
+        let result = self.collect(retain_last_n_commits, &mut progress);
+        pins.end_collection();
+        result
+    }
+
+    fn collect<F: FnMut(GcPhase, &GcReport)>(&mut self, retain_last_n_commits: usize, progress: &mut F) -> Result<GcReport, MerkleError> {
+        let mut tips: Vec<EntryHash> = self.get_last_commit_hash().into_iter().collect();
+        for (_, commit_hash) in self.refs.iterator(IteratorMode::Start)? {
+            tips.push(commit_hash.map_err(DBError::from)?);
+        }
+        tips.extend(self.pins.start_collection());
+
+        let mut report = GcReport::default();
+        let mut retained = HashSet::new();
+        let mut marked = HashSet::new();
+        for tip in tips {
+            self.mark_retained(tip, retain_last_n_commits, &mut retained, &mut marked, &mut report, progress)?;
+        }
+        let marked = self.pins.start_sweep(marked, |late, marked| {
+            for tip in late {
+                self.mark_retained(tip, retain_last_n_commits, &mut retained, marked, &mut report, progress)?;
+            }
+            Ok(())
+        })?;
+
+        let mut batch = MultiSchemaBatch::default();
+        let (mut batch_entries, mut batch_bytes) = (0, 0);
+        for (hash, bytes) in self.db.iterator(IteratorMode::Start)? {
+            let hash = hash.map_err(DBError::from)?;
+            report.entries_scanned += 1;
+            if marked.contains(&hash) {
+                continue;
+            }
+            batch_bytes += bytes.map_err(DBError::from)?.len() as u64;
+            batch_entries += 1;
+            batch.delete::<MerkleStorage>(&hash)?;
+            batch.delete::<AnnotationSchema>(&hash)?;
+            batch.delete::<ApplyMetricsSchema>(&hash)?;
+            batch.delete::<ChangeFilterSchema>(&hash)?;
+            batch.delete::<CommitHeightSchema>(&hash)?;
+            if let Some(cache) = &self.entry_cache {
+                cache.lock().unwrap().remove(&hash);
+            }
+            if batch_entries == GC_BATCH_ENTRIES {
+                self.apply_gc_batch(std::mem::take(&mut batch), batch_entries, batch_bytes, &mut report)?;
+                progress(GcPhase::Sweep, &report);
+                batch_entries = 0;
+                batch_bytes = 0;
+            }
+        }
+        self.apply_gc_batch(batch, batch_entries, batch_bytes, &mut report)?;
+        progress(GcPhase::Sweep, &report);
+        self.prune_hash_index(&marked)?;
+        if self.config.gc_mode == GcMode::RefCounting {
+            // surviving entries were referenced also by deleted ones
+            self.rebuild_ref_counts()?;
+        }
+
+        let mut parameters = BTreeMap::new();
+        parameters.insert("retain_last_n_commits".to_string(), retain_last_n_commits.to_string());
+        let mut counts = BTreeMap::new();
+        counts.insert("commits_retained".to_string(), report.commits_retained);
+        counts.insert("entries_deleted".to_string(), report.entries_deleted);
+        counts.insert("bytes_reclaimed".to_string(), report.bytes_reclaimed);
+        self.append_audit_record(DestructiveOperation::GarbageCollection, parameters, counts)?;
+        Ok(report)
+    }
+
+    /// Mark entries of up to `retain_last_n_commits` commits from `tip` back.
+    fn mark_retained<F: FnMut(GcPhase, &GcReport)>(&self, tip: EntryHash, retain_last_n_commits: usize, retained: &mut HashSet<EntryHash>,
+                                                   marked: &mut HashSet<EntryHash>, report: &mut GcReport, progress: &mut F) -> Result<(), MerkleError> {
+        let mut next = Some(tip);
+        for _ in 0..retain_last_n_commits.max(1) {
+            let commit_hash = match next {
+                Some(commit_hash) => commit_hash,
+                None => break,
+            };
+            next = match self.get_commit(&commit_hash) {
+                Ok(commit) => commit.parent_commit_hash,
+                // older history was collected before
+                Err(MerkleError::EntryNotFound { .. }) => break,
+                Err(error) => return Err(error),
+            };
+            // ancestors shared with another tip are walked again, as they may be retained
+            // further back
+            if !retained.insert(commit_hash) {
+                continue;
+            }
+            let mut entries = DagIterator::with_visited(self, &commit_hash, std::mem::take(marked));
+            for entry in &mut entries {
+                entry?;
+            }
+            *marked = entries.into_visited();
+            report.commits_retained = retained.len() as u64;
+            report.entries_marked = marked.len() as u64;
+            progress(GcPhase::Mark, report);
+        }
+        Ok(())
+    }
+
+    /// Delete commit `commit_hash` and all entries referenced only by it, see
+    /// [GcMode::RefCounting]. Head, commits refs point to, commits observed by live snapshots
+    /// and pinned commits, see [ContextReader::pin], cannot be deleted. History of descendants
+    /// of the deleted commit ends before it.
+    pub fn delete_commit(&self, commit_hash: &EntryHash) -> Result<GcReport, MerkleError> {
+        if self.config.gc_mode != GcMode::RefCounting {
+            return Err(MerkleError::RefCountingDisabled);
+        }
+        let in_use = |user: String| Err(MerkleError::CommitInUse { hash: HashType::ContextHash.bytes_to_string(commit_hash), user });
+        if self.get_last_commit_hash().as_ref() == Some(commit_hash) {
+            return in_use("head".to_string());
+        }
+        for (name, target) in self.refs.iterator(IteratorMode::Start)? {
+            if target.map_err(DBError::from)? == *commit_hash {
+                return in_use(format!("ref {}", name.map_err(DBError::from)?));
+            }
+        }
+        if !self.pins.start_delete(commit_hash) {
+            return in_use("snapshot or reader".to_string());
+        }
+        let result = self.delete_unpinned_commit(commit_hash);
+        self.pins.end_delete(commit_hash);
+        result
+    }
+
+    fn delete_unpinned_commit(&self, commit_hash: &EntryHash) -> Result<GcReport, MerkleError> {
+        let commit = self.get_commit(commit_hash)?;
+
+        let mut report = GcReport::default();
+        let mut batch = MultiSchemaBatch::default();
+        let mut deleted = vec![*commit_hash];
+        let mut counts: HashMap<EntryHash, u64> = HashMap::new();
+        let mut stack = vec![commit.root_hash];
+        while let Some(hash) = stack.pop() {
+            report.entries_scanned += 1;
+            let count = match counts.get(&hash) {
+                // reference from an entry with inconsistent count
+                Some(0) => continue,
+                Some(count) => *count,
+                None => self.ref_counts.get(&hash)?.unwrap_or(0),
+            };
+            if count > 1 {
+                counts.insert(hash, count - 1);
+                continue;
+            }
+            counts.insert(hash, 0);
+            if let Some(bytes) = self.db.get(&hash)? {
+                stack.extend(referenced_entries(&bincode::deserialize(&bytes)?));
+                deleted.push(hash);
+            }
+        }
+
+        for (hash, count) in counts {
+            match count {
+                0 => batch.delete::<RefCountSchema>(&hash)?,
+                count => batch.put::<RefCountSchema>(&hash, &count)?,
+            }
+        }
+        let mut bytes = 0;
+        for hash in &deleted {
+            bytes += self.db.get_raw(hash)?.map_or(0, |value| value.len() as u64);
+            batch.delete::<MerkleStorage>(hash)?;
+            if let Some(cache) = &self.entry_cache {
+                cache.lock().unwrap().remove(hash);
+            }
+        }
+        batch.delete::<AnnotationSchema>(commit_hash)?;
+        batch.delete::<ApplyMetricsSchema>(commit_hash)?;
+        batch.delete::<ChangeFilterSchema>(commit_hash)?;
+        batch.delete::<CommitHeightSchema>(commit_hash)?;
+        self.unindex_entry_hashes(&deleted, &mut batch)?;
+        self.apply_gc_batch(batch, deleted.len(), bytes, &mut report)?;
+
+        let mut parameters = BTreeMap::new();
+        parameters.insert("commit".to_string(), HashType::ContextHash.bytes_to_string(commit_hash));
+        let mut counts = BTreeMap::new();
+        counts.insert("entries_deleted".to_string(), report.entries_deleted);
+        counts.insert("bytes_reclaimed".to_string(), report.bytes_reclaimed);
+        self.append_audit_record(DestructiveOperation::DeleteCommit, parameters, counts)?;
+        Ok(report)
+    }
+
+    /// Delete batch of unreachable entries and account for them in persistent counters.
+    fn apply_gc_batch(&self, mut batch: MultiSchemaBatch, entries: usize, bytes: u64, report: &mut GcReport) -> Result<(), MerkleError> {
+        if entries == 0 {
+            return Ok(());
+        }
+        self.stage_counters(&mut batch, |counters| counters.gc_reclaimed_bytes += bytes)?;
+        self.schemas.apply_multi(batch)?;
+        report.entries_deleted += entries as u64;
+        report.bytes_reclaimed += bytes;
+        Ok(())
+    }
+
+    /// Remove hashes of deleted entries from the hash prefix index.
+    fn prune_hash_index(&self, marked: &HashSet<EntryHash>) -> Result<(), MerkleError> {
+        let mut batch = MultiSchemaBatch::default();
+        for (prefix, candidates) in self.hash_index.iterator(IteratorMode::Start)? {
+            let (prefix, mut candidates) = (prefix.map_err(DBError::from)?, candidates.map_err(DBError::from)?);
+            let indexed = candidates.0.len();
+            candidates.0.retain(|hash| marked.contains(hash));
+            if candidates.0.is_empty() {
+                batch.delete::<HashIndexSchema>(&prefix)?;
+            } else if candidates.0.len() != indexed {
+                batch.put::<HashIndexSchema>(&prefix, &candidates)?;
+            }
+        }
+        Ok(self.schemas.apply_multi(batch)?)
+    }
+
+    pub fn get_last_commit_hash(&self) -> Option<EntryHash> {
+        match &self.last_commit {
+            Some(c) => Some(hash_commit(&c)),
+            None => None
+        }
+    }
+
+    /// Read cumulative counters persisted in the metadata tree.
+    fn get_counters(&self) -> Result<PersistentCounters, MerkleError> {
+        match self.metadata.get(&COUNTERS_KEY.to_string())? {
+            None => Ok(PersistentCounters::default()),
+            Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+        }
+    }
+
+    /// Apply `update` to persisted cumulative counters. Counters are kept apart from entries, so
+    /// they are not updated atomically with the data they count.
+    fn update_counters<F: FnOnce(&mut PersistentCounters)>(&self, update: F) -> Result<(), MerkleError> {
+        let mut counters = self.get_counters()?;
+        update(&mut counters);
+        self.metadata.put(&COUNTERS_KEY.to_string(), &bincode::serialize(&counters)?)?;
+        Ok(())
+    }
+
+    /// Like [MerkleStorage::update_counters], but the updated counters are added to `batch`.
+    fn stage_counters<F: FnOnce(&mut PersistentCounters)>(&self, batch: &mut MultiSchemaBatch, update: F) -> Result<(), MerkleError> {
+        let mut counters = self.get_counters()?;
+        update(&mut counters);
+        batch.put::<MetadataSchema>(&COUNTERS_KEY.to_string(), &bincode::serialize(&counters)?)?;
+        Ok(())
+    }
+
+    /// Describe optional subsystems of this build and which of them are enabled by config.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            hash_algorithm: HASH_SCHEME.to_string(),
+            entry_format_version: ENTRY_FORMAT_VERSION,
+            compression: self.entry_transforms.contains(&TransformKind::Compression),
+            encryption: self.entry_transforms.contains(&TransformKind::Encryption),
+            garbage_collection: true,
+            async_api: false,
+            serialize: cfg!(feature = "serialize"),
+            failure_injection: cfg!(feature = "testing"),
+            tombstones: self.config.tombstone_retention.is_some(),
+            hash_prefix_index: self.config.hash_prefix_index,
+            commit_annotations: self.config.commit_annotations,
+            value_hash_index: self.config.value_hash_index,
+            external_blobs: self.blob_sink.is_some(),
+            ref_counting: self.config.gc_mode == GcMode::RefCounting,
+            change_filters: self.config.change_filters,
+            key_history: self.config.key_history,
+        }
+    }
+
+    /// Shut the storage down: stop background flushing of its database and flush all writes to
+    /// disk, so errors are reported instead of being lost on drop. Returns final statistics.
+    /// Uncommitted changes are discarded, see [MerkleStorage::set_dirty_drop_hook]. Decoded
+    /// entries are cached in memory only, so there is nothing else to persist. The database
+    /// stays open, and locked, until all readers and snapshots of the storage are dropped too.
+    pub fn close(self) -> Result<MerkleStorageStats, MerkleError> {
+        let stats = self.get_merkle_stats()?;
+        self.schemas.close()?;
+        Ok(stats)
+    }
+
+    pub fn get_merkle_stats(&self) -> Result<MerkleStorageStats, MerkleError> {
+        let mut avg_set_exec_time_ns: f64 = 0.0;
+        if self.set_exec_times > self.set_exec_times_to_discard {
+            avg_set_exec_time_ns = self.cumul_set_exec_time / ((self.set_exec_times - self.set_exec_times_to_discard) as f64);
+        }
+        let (entry_cache_hits, entry_cache_misses) = self.entry_cache.as_ref()
+            .map_or((0, 0), |cache| {
+                let cache = cache.lock().unwrap();
+                (cache.hits, cache.misses)
+            });
+        let perf = MerklePerfStats {
+            avg_set_exec_time_ns,
+            skipped_write_bytes: self.skipped_write_bytes,
+            elided_deletes: self.elided_deletes,
+            entry_cache_hits,
+            entry_cache_misses,
+        };
+        Ok(MerkleStorageStats { map_stats: self.map_stats, perf_stats: perf, counters: self.get_counters()? })
+    }
+}
+
+/// Read access to entries by hash, shared by [MerkleStorage] and its read-only handles.
+trait EntryStore {
+    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError>;
+
+    /// Get serialized form of an entry
+    fn get_entry_raw(&self, hash: &EntryHash) -> Result<IVec, MerkleError>;
+
+    /// Find node of the entry stored under `key`
+    fn find_node(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<Node, MerkleError> {
+        let mut full_path = key.clone();
+        let file = full_path.pop().ok_or(MerkleError::KeyEmpty)?;
+        let path = full_path;
+        let root = self.get_tree(root_hash)?;
+        let node = self.find_tree(&root, &path)?;
+
+        match node.get(&file) {
+            None => Err(MerkleError::ValueNotFound { key: key_to_path(key) }),
+            Some(node) => Ok(node.clone()),
+        }
+    }
+
+    /// Find node stored under `key`, the root tree for an empty key
+    fn find_node_or_root(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<Option<Node>, MerkleError> {
+        if key.is_empty() {
+            return Ok(Some(Node { node_kind: NodeKind::NonLeaf, entry_hash: *root_hash }));
+        }
+        match self.find_node(root_hash, key) {
+            Ok(node) => Ok(Some(node)),
+            Err(MerkleError::ValueNotFound { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Find the child adjacent to the last fragment of `key` in its parent tree, the following
+    /// one if `next`, the preceding one otherwise. Only the parent tree is loaded.
+    fn find_sibling(&self, root: &Tree, key: &ContextKey, next: bool) -> Result<Option<ContextKey>, MerkleError> {
+        let (fragment, path) = key.split_last().ok_or(MerkleError::KeyEmpty)?;
+        let parent = self.find_tree(root, path)?;
+        let sibling = if next {
+            parent.range::<_, String>((Bound::Excluded(fragment), Bound::Unbounded)).next()
+        } else {
+            parent.range::<_, String>(..fragment.clone()).next_back()
+        };
+
+        Ok(sibling.map(|(sibling, _)| {
+            let mut sibling_key = path.to_vec();
+            sibling_key.push(sibling.clone());
+            sibling_key
+        }))
+    }
+
+    /// Check whether a value (possibly empty) is stored under `key`. Directories are not values.
+    fn value_exists(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<bool, MerkleError> {
+        match self.find_node(root_hash, key) {
+            Ok(node) => Ok(matches!(node.node_kind, NodeKind::Leaf)),
+            Err(MerkleError::ValueNotFound { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Check whether a directory is stored under non-empty `key`.
+    fn dir_exists(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<bool, MerkleError> {
+        match self.find_node(root_hash, key) {
+            Ok(node) => Ok(matches!(node.node_kind, NodeKind::NonLeaf)),
+            Err(MerkleError::ValueNotFound { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn get_from_tree(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        match self.get_entry(&self.find_node(root_hash, key)?.entry_hash)? {
+            Entry::Blob(blob) => Ok(blob),
+            _ => Err(MerkleError::ValueIsNotABlob { key: key_to_path(key) })
+        }
+    }
+
+    /// Get value under `key` as a slice of the serialized blob entry, without decoding it.
+    fn get_raw_from_tree(&self, root_hash: &EntryHash, key: &ContextKey) -> Result<IVec, MerkleError> {
+        let hash = self.find_node(root_hash, key)?.entry_hash;
+        let bytes = self.get_entry_raw(&hash)?;
+        if is_external_blob(&bytes) {
+            return self.get_from_tree(root_hash, key).map(IVec::from);
+        }
+        blob_from_raw(bytes, key)
+    }
+
+    /// Collect all key-values under given entry in key order. Traversal uses an explicit stack,
+    /// so deep trees cannot overflow the call stack.
+    ///
+    /// Entries are loaded only when visited, traversal stops once `budget` is exceeded.
+    fn get_key_values_from_tree(&self, path: &str, hash: &EntryHash, entries: &mut Vec<(ContextKey, ContextValue)>, budget: &mut BudgetTracker) -> Result<(), MerkleError> {
+        let mut stack = vec![(path.to_owned(), *hash)];
+        let mut is_root = true;
+
+        while let Some((path, hash)) = stack.pop() {
+            let entry = match self.get_entry(&hash) {
+                Ok(entry) => entry,
+                // children missing in db are skipped
+                Err(_) if !is_root => continue,
+                Err(err) => return Err(err),
+            };
+            is_root = false;
+            if !budget.charge(&entry)? {
+                return Ok(());
+            }
+
+            match entry {
+                Entry::Blob(blob) => {
+                    // push key-value pair
+                    entries.push((key_from_path(&path)?, blob));
+                }
+                Entry::Tree(tree) => {
+                    // push in reverse, so children are visited in key order
+                    for (key, child_node) in tree.iter().rev() {
+                        stack.push((path.clone() + "/" + &escape_fragment(key), child_node.entry_hash));
+                    }
+                }
+                Entry::Commit(commit) => {
+                    stack.push((path, commit.root_hash));
+                }
+                // resolved by `get_entry`
+                Entry::External { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn _get_key_values_by_prefix(&self, root_tree: Tree, prefix: &ContextKey, budget: &mut BudgetTracker) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
+        let prefixed_tree = self.find_tree(&root_tree, prefix)?;
+        let mut keyvalues: Vec<(ContextKey, ContextValue)> = Vec::new();
+
+        for (key, child_node) in prefixed_tree.iter() {
+            if budget.exceeded {
+                break;
+            }
+            let delimiter: &str;
+            if prefix.is_empty() {
+                delimiter = "";
+            } else {
+                delimiter = "/";
+            }
+            let fullpath = key_to_path(prefix) + delimiter + &escape_fragment(key);
+            self.get_key_values_from_tree(&fullpath, &child_node.entry_hash, &mut keyvalues, budget)?;
+        }
+
+        if keyvalues.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(keyvalues))
+        }
+    }
+
+    /// Find tree by path. Return an empty tree if no tree under this path exists or if a blob
+    /// (= value) is encountered along the way.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - reference to a tree in which we search
+    /// * `key` - sought path
+    fn find_tree(&self, root: &Tree, key: &[String]) -> Result<Tree, MerkleError> {
+        let mut tree = root.clone();
+
+        for fragment in key {
+            let child_node = match tree.get(fragment) {
+                Some(hash) => hash,
+                None => return Ok(Tree::new()),
+            };
+
+            tree = match self.get_entry(&child_node.entry_hash)? {
+                Entry::Tree(tree) => tree,
+                Entry::Blob(_) | Entry::External { .. } => return Ok(Tree::new()),
+                Entry::Commit { .. } => return Err(MerkleError::FoundUnexpectedStructure {
+                    sought: "tree".to_string(),
+                    found: "commit".to_string(),
+                })
+            };
+        }
+
+        Ok(tree)
+    }
+
+    fn get_tree(&self, hash: &EntryHash) -> Result<Tree, MerkleError> {
+        match self.get_entry(hash)? {
+            Entry::Tree(tree) => Ok(tree),
+            Entry::Blob(_) | Entry::External { .. } => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "tree".to_string(),
+                found: "blob".to_string(),
+            }),
+            Entry::Commit { .. } => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "tree".to_string(),
+                found: "commit".to_string(),
+            }),
+        }
+    }
+
+    /// Get commit together with its root tree, the two reads almost every operation on a
+    /// commit starts with. The root tree is looked up right after the commit, so with the entry
+    /// cache enabled both typically come from memory.
+    fn get_commit_with_root(&self, hash: &EntryHash) -> Result<(Commit, Tree), MerkleError> {
+        let commit = self.get_commit(hash)?;
+        let root = self.get_tree(&commit.root_hash)?;
+        Ok((commit, root))
+    }
+
+    fn get_commit(&self, hash: &EntryHash) -> Result<Commit, MerkleError> {
+        match self.get_entry(hash)? {
+            Entry::Commit(commit) => Ok(commit),
+            Entry::Tree(_) => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "commit".to_string(),
+                found: "tree".to_string(),
+            }),
+            Entry::Blob(_) | Entry::External { .. } => Err(MerkleError::FoundUnexpectedStructure {
+                sought: "commit".to_string(),
+                found: "blob".to_string(),
+            }),
+        }
+    }
+}
+
+/// Walk trees `left_root` in `left` store and `right_root` in `right` store side by side, and
+/// collect up to `limit` topmost paths, which differ, in key order. Subtrees with equal hashes
+/// are not visited, so the cost is proportional to the size of the difference. Comparison stops
+/// once `budget` is exceeded.
+fn diverging_paths<L: EntryStore, R: EntryStore>(left: &L, left_root: &EntryHash, right: &R, right_root: &EntryHash, limit: usize, budget: &mut BudgetTracker)
+                                                 -> Result<Vec<(ContextKey, PathDivergence)>, MerkleError> {
+    let root = |hash: &EntryHash| Some(Node { node_kind: NodeKind::NonLeaf, entry_hash: *hash });
+    let mut stack = vec![(Vec::new(), root(left_root), root(right_root))];
+    let mut paths = Vec::new();
+
+    while let Some((path, left_node, right_node)) = stack.pop() {
+        if paths.len() >= limit {
+            break;
+        }
+        match (left_node, right_node) {
+            (Some(l), Some(r)) if l.entry_hash == r.entry_hash => {}
+            (Some(_), None) => paths.push((path, PathDivergence::LeftOnly)),
+            (None, Some(_)) => paths.push((path, PathDivergence::RightOnly)),
+            (Some(Node { node_kind: NodeKind::NonLeaf, entry_hash: l }), Some(Node { node_kind: NodeKind::NonLeaf, entry_hash: r })) => {
+                let left_tree = left.get_tree(&l)?;
+                let right_tree = right.get_tree(&r)?;
+                // trees are persistent maps, so cloning them is cheap
+                if !budget.charge(&Entry::Tree(left_tree.clone()))? || !budget.charge(&Entry::Tree(right_tree.clone()))? {
+                    break;
+                }
+                let mut fragments: Vec<&String> = left_tree.keys().chain(right_tree.keys()).collect();
+                fragments.sort();
+                fragments.dedup();
+                // push in reverse, so paths are visited in key order
+                for fragment in fragments.into_iter().rev() {
+                    let mut child_path = path.clone();
+                    child_path.push(fragment.clone());
+                    stack.push((child_path, left_tree.get(fragment).cloned(), right_tree.get(fragment).cloned()));
+                }
+            }
+            _ => paths.push((path, PathDivergence::Different)),
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Count keys changed between trees `old_root` (`None` for the empty context) and `new_root`.
+fn annotate_changes<S: EntryStore>(store: &S, old_root: Option<&EntryHash>, new_root: &EntryHash) -> Result<CommitAnnotation, MerkleError> {
+    let mut annotation = CommitAnnotation::default();
+    for_each_changed_value(store, old_root, new_root, |key, _, _| {
+        annotation.add_changed_key(&key[0]);
+        Ok(())
+    })?;
+    Ok(annotation)
+}
+
+/// Call `visit` with key, old and new value hash of every value changed between trees
+/// `old_root` (`None` for the empty context) and `new_root`. Value hash is `None` on the side,
+/// where the key is missing or is a directory. Subtrees with equal hashes are skipped, subtrees
+/// present on one side only are visited key by key.
+fn for_each_changed_value<S, F>(store: &S, old_root: Option<&EntryHash>, new_root: &EntryHash, visit: F) -> Result<(), MerkleError>
+    where S: EntryStore,
+          F: FnMut(&ContextKey, Option<&EntryHash>, Option<&EntryHash>) -> Result<(), MerkleError>
+{
+    let node = |hash: &EntryHash| Node { node_kind: NodeKind::NonLeaf, entry_hash: *hash };
+    let mut unlimited = BudgetTracker::new(QueryBudget::default());
+    for_each_changed_value_under(store, Vec::new(), old_root.map(node), Some(node(new_root)), &mut unlimited, visit)
+}
+
+/// Like [for_each_changed_value], but compares nodes stored under `key` only. Comparison stops
+/// once `budget` is exceeded.
+fn for_each_changed_value_under<S, F>(store: &S, key: ContextKey, old_node: Option<Node>, new_node: Option<Node>, budget: &mut BudgetTracker, mut visit: F)
+                                      -> Result<(), MerkleError>
+    where S: EntryStore,
+          F: FnMut(&ContextKey, Option<&EntryHash>, Option<&EntryHash>) -> Result<(), MerkleError>
+{
+    let mut stack = vec![(key, old_node, new_node)];
+    let leaf_hash = |node: &Option<Node>| match node {
+        Some(Node { node_kind: NodeKind::Leaf, entry_hash }) => Some(*entry_hash),
+        _ => None,
+    };
+
+    while let Some((key, old_node, new_node)) = stack.pop() {
+        if matches!((&old_node, &new_node), (Some(old), Some(new)) if old.entry_hash == new.entry_hash) {
+            continue;
+        }
+        // a value replaced by a directory or the other way around is a changed value too
+        let (old_value, new_value) = (leaf_hash(&old_node), leaf_hash(&new_node));
+        if old_value.is_some() || new_value.is_some() {
+            visit(&key, old_value.as_ref(), new_value.as_ref())?;
+        }
+        let (old_tree, new_tree) = match (charged_subtree(store, old_node.as_ref(), budget)?, charged_subtree(store, new_node.as_ref(), budget)?) {
+            (Some(old_tree), Some(new_tree)) => (old_tree, new_tree),
+            _ => break,
+        };
+        for fragment in old_tree.keys().chain(new_tree.keys().filter(|fragment| !old_tree.contains_key(*fragment))) {
+            let mut child_key = key.clone();
+            child_key.push(fragment.clone());
+            stack.push((child_key, old_tree.get(fragment).cloned(), new_tree.get(fragment).cloned()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Fold `node` stored under `key` and everything below it, see [ContextReader::fold].
+fn fold_under<S, A, F>(store: &S, key: ContextKey, node: Node, depth_limit: Option<usize>, init: A, budget: &mut BudgetTracker, mut f: F) -> Result<A, MerkleError>
+    where S: EntryStore,
+          F: FnMut(A, &ContextKey, &FoldEntry) -> Result<A, MerkleError>
+{
+    let depth = key.len();
+    let mut acc = init;
+    let mut stack = vec![(key, node)];
+    while let Some((key, node)) = stack.pop() {
+        let hash = node.entry_hash;
+        let entry = store.get_entry(&hash)?;
+        if !budget.charge(&entry)? {
+            break;
+        }
+        match entry {
+            Entry::Tree(tree) => {
+                acc = f(acc, &key, &FoldEntry::Tree { hash, children: tree.len() })?;
+                if matches!(depth_limit, Some(limit) if key.len() - depth >= limit) {
+                    continue;
+                }
+                // pushed in reverse, so children are popped in key order
+                for (fragment, child) in tree.iter().rev() {
+                    let mut child_key = key.clone();
+                    child_key.push(fragment.clone());
+                    stack.push((child_key, child.clone()));
+                }
+            }
+            Entry::Blob(value) => acc = f(acc, &key, &FoldEntry::Value { hash, value })?,
+            Entry::External { .. } => return Err(MerkleError::FoundUnexpectedStructure {
+                sought: "tree or blob".to_string(),
+                found: "external blob".to_string(),
+            }),
+            Entry::Commit { .. } => return Err(MerkleError::FoundUnexpectedStructure {
+                sought: "tree or blob".to_string(),
+                found: "commit".to_string(),
+            }),
+        }
+    }
+    Ok(acc)
+}
+
+/// Children of a node, a leaf or a missing node have none
+fn subtree<S: EntryStore>(store: &S, node: Option<&Node>) -> Result<Tree, MerkleError> {
+    match node {
+        Some(Node { node_kind: NodeKind::NonLeaf, entry_hash }) => store.get_tree(entry_hash),
+        _ => Ok(Tree::new()),
+    }
+}
+
+/// Like [subtree], but a loaded tree is charged to `budget`, `None` once it is exceeded
+fn charged_subtree<S: EntryStore>(store: &S, node: Option<&Node>, budget: &mut BudgetTracker) -> Result<Option<Tree>, MerkleError> {
+    let tree = subtree(store, node)?;
+    // trees are persistent maps, so cloning them is cheap
+    if matches!(node, Some(Node { node_kind: NodeKind::NonLeaf, .. })) && !budget.charge(&Entry::Tree(tree.clone()))? {
+        return Ok(None);
+    }
+    Ok(Some(tree))
+}
+
+impl EntryStore for MerkleStorage {
+    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        match self.staged.get(hash) {
+            None => get_entry_from_db(self.db.as_ref(), self.entry_cache.as_ref(), self.blob_sink.as_deref(), &self.entry_sources, hash),
+            Some(entry) => Ok(entry.clone()),
+        }
+    }
+
+    /// Get serialized form of an entry, staging area is checked first.
+    fn get_entry_raw(&self, hash: &EntryHash) -> Result<IVec, MerkleError> {
+        match self.staged.get(hash) {
+            None => get_entry_raw_from_db(self.db.as_ref(), &self.entry_sources, hash),
+            Some(entry) => Ok(IVec::from(entry.encode()?)),
+        }
+    }
+}
+
+/// Cheap cloneable read-only handle to committed data. Readers can be used from any thread,
+/// while the [MerkleStorage] they were created from keeps exclusive access to the staging area.
+/// Reads of a commit pin it while they run, see [ContextReader::pin], so garbage collection
+/// either retains the commit or the read fails with [MerkleError::EntryNotFound].
+#[derive(Clone)]
+pub struct ContextReader {
+    db: Arc<MerkleStorageKV>,
+    refs: Arc<RefsKV>,
+    annotations: Arc<AnnotationKV>,
+    apply_metrics: Arc<ApplyMetricsKV>,
+    entry_cache: Option<EntryCache>,
+    blob_sink: Option<Arc<dyn BlobSink>>,
+    entry_sources: Vec<Arc<dyn EntrySource>>,
+    pins: Pins,
+}
+
+impl ContextReader {
+    /// Keep commit `commit_hash` and the history retained with it by [MerkleStorage::gc] from
+    /// being collected, until the pin is dropped. Fails with [MerkleError::EntryNotFound] if the
+    /// commit was collected already or is being collected.
+    pub fn pin(&self, commit_hash: &EntryHash) -> Result<CommitPin, MerkleError> {
+        let pin = self.pins.pin_commit(commit_hash)?;
+        self.get_commit(commit_hash)?;
+        Ok(pin)
+    }
+
+    /// Get committed entry stored under `hash`, values kept in the blob sink are returned as
+    /// [Entry::External].
+    pub fn read_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        load_entry_from_db(self.db.as_ref(), self.entry_cache.as_ref(), &self.entry_sources, hash)
+    }
+
+    /// Like [MerkleStorage::get_proof]
+    pub fn get_proof(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        inclusion_proof(self, commit_hash, key)
+    }
+
+    /// Like [MerkleStorage::get_exclusion_proof]
+    pub fn get_exclusion_proof(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        exclusion_proof(self, commit_hash, key)
+    }
+
+    /// Get commit the ref `name` points to.
+    pub fn get_ref(&self, name: &str) -> Result<Option<EntryHash>, MerkleError> {
+        Ok(self.refs.get(&name.to_string())?)
+    }
+
+    /// Names of all refs with the commits they point to, ordered by name. Refs are read by a
+    /// single pass over the refs tree rather than looked up one by one.
+    pub fn list_refs(&self) -> Result<Vec<(String, EntryHash)>, MerkleError> {
+        let mut refs = Vec::new();
+        for (name, commit_hash) in self.refs.iterator(IteratorMode::Start)? {
+            refs.push((name.map_err(DBError::from)?, commit_hash.map_err(DBError::from)?));
+        }
+        Ok(refs)
+    }
+
+    /// Get up to `limit` commits starting with `commit_hash` and following its parents, newest
+    /// first, together with their recorded apply metrics.
+    pub fn log(&self, commit_hash: &EntryHash, limit: usize) -> Result<Vec<LogEntry>, MerkleError> {
+        let mut entries = Vec::new();
+        let mut next = Some(*commit_hash);
+        while let Some(commit_hash) = next {
+            if entries.len() >= limit {
+                break;
+            }
+            let commit = self.get_commit(&commit_hash)?;
+            next = commit.parent_commit_hash;
+            entries.push(LogEntry {
+                commit_hash,
+                parent_commit_hash: commit.parent_commit_hash,
+                time: commit.time,
+                author: commit.author,
+                message: commit.message,
+                apply_metrics: self.apply_metrics.get(&commit_hash)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Get summary of keys changed by a commit, see [MerkleStorage::get_commit_annotation].
+    pub fn get_commit_annotation(&self, commit_hash: &EntryHash) -> Result<Option<CommitAnnotation>, MerkleError> {
+        Ok(self.annotations.get(commit_hash)?)
+    }
+
+    /// List values under `prefix` changed between `commit_a` and `commit_b` in key order. Only
+    /// the subtrees under `prefix` are loaded, and of them only the parts which differ, so the
+    /// cost depends on the size of the change, not of the context.
+    pub fn diff_prefix(&self, commit_a: &EntryHash, commit_b: &EntryHash, prefix: &ContextKey) -> Result<Vec<ValueChange>, MerkleError> {
+        Ok(self.diff_prefix_with_budget(commit_a, commit_b, prefix, QueryBudget::default())?.value)
+    }
+
+    /// Like [ContextReader::diff_prefix], but stops once `budget` is exceeded. Trees loaded from
+    /// both commits count against the budget.
+    pub fn diff_prefix_with_budget(&self, commit_a: &EntryHash, commit_b: &EntryHash, prefix: &ContextKey, budget: QueryBudget)
+                                   -> Result<Budgeted<Vec<ValueChange>>, MerkleError> {
+        let mut budget = BudgetTracker::new(budget);
+        let changes = self.changed_values(commit_a, commit_b, prefix, &mut budget)?;
+        Ok(budget.finish(changes))
+    }
+
+    fn changed_values(&self, commit_a: &EntryHash, commit_b: &EntryHash, prefix: &ContextKey, budget: &mut BudgetTracker) -> Result<Vec<ValueChange>, MerkleError> {
+        let _pins = (self.pins.pin_commit(commit_a)?, self.pins.pin_commit(commit_b)?);
+        let old_node = self.find_node_or_root(&self.get_commit(commit_a)?.root_hash, prefix)?;
+        let new_node = self.find_node_or_root(&self.get_commit(commit_b)?.root_hash, prefix)?;
+        let mut changes = Vec::new();
+        for_each_changed_value_under(self, prefix.clone(), old_node, new_node, budget, |key, old, new| {
+            changes.push(ValueChange { key: key.clone(), old_value_hash: old.copied(), new_value_hash: new.copied() });
+            Ok(())
+        })?;
+        changes.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(changes)
+    }
+
+    /// List values added, removed or modified from `commit_a` to `commit_b` in key order, with
+    /// their old and new values. Like in [ContextReader::diff_prefix], subtrees with equal
+    /// hashes are skipped, so diffing similar commits is fast however large they are. A value
+    /// replaced by a directory is removed, the values of the directory are added.
+    pub fn diff(&self, commit_a: &EntryHash, commit_b: &EntryHash) -> Result<Vec<ContextChange>, MerkleError> {
+        Ok(self.diff_with_budget(commit_a, commit_b, QueryBudget::default())?.value)
+    }
+
+    /// Like [ContextReader::diff], but stops once `budget` is exceeded. Trees compared and values
+    /// of the changes count against the budget, values are loaded after the trees are compared.
+    pub fn diff_with_budget(&self, commit_a: &EntryHash, commit_b: &EntryHash, budget: QueryBudget) -> Result<Budgeted<Vec<ContextChange>>, MerkleError> {
+        let value = |hash: &EntryHash, budget: &mut BudgetTracker| {
+            let entry = self.get_entry(hash)?;
+            budget.charge(&entry)?;
+            match entry {
+                Entry::Blob(value) => Ok(value),
+                _ => Err(MerkleError::FoundUnexpectedStructure { sought: "blob".to_string(), found: "tree or commit".to_string() }),
+            }
+        };
+        let mut budget = BudgetTracker::new(budget);
+        let mut changes = Vec::new();
+        for change in self.changed_values(commit_a, commit_b, &Vec::new(), &mut budget)? {
+            let key = change.key;
+            let change = match (change.old_value_hash, change.new_value_hash) {
+                (None, Some(new)) => ContextChange::Added { key, value: value(&new, &mut budget)? },
+                (Some(old), None) => ContextChange::Removed { key, value: value(&old, &mut budget)? },
+                (Some(old), Some(new)) => ContextChange::Modified { key, old_value: value(&old, &mut budget)?, new_value: value(&new, &mut budget)? },
+                (None, None) => continue,
+            };
+            if budget.exceeded {
+                break;
+            }
+            changes.push(change);
+        }
+        Ok(budget.finish(changes))
+    }
+
+    /// Get commit `commit_hash` with its author, message, time and metadata.
+    pub fn get_commit_info(&self, commit_hash: &EntryHash) -> Result<CommitInfo, MerkleError> {
+        Ok(CommitInfo::new(*commit_hash, self.get_commit(commit_hash)?))
+    }
+
+    /// Get hash of the state root of commit `commit_hash` as the raw 32-byte digest, for
+    /// ecosystems using fixed-size state roots. See [hash_to_hex] and [hash_to_base58] for
+    /// string forms.
+    pub fn root_hash_bytes(&self, commit_hash: &EntryHash) -> Result<EntryHash, MerkleError> {
+        Ok(self.get_commit(commit_hash)?.root_hash)
+    }
+
+    /// Walk commits from `commit_hash` back through its parents, newest first. Commits are loaded
+    /// only when iterated, so walking a part of a long chain is cheap. Iteration ends at the
+    /// first commit without a parent, or at a parent deleted by garbage collection, and stops
+    /// after the first error.
+    pub fn history(&self, commit_hash: &EntryHash) -> Result<HistoryIterator, MerkleError> {
+        let commit = self.get_commit(commit_hash)?;
+        Ok(HistoryIterator { reader: self.clone(), next: Some((*commit_hash, commit)) })
+    }
+
+    /// Visit the tree or value under `prefix` of given commit and everything below it, passing
+    /// the accumulated state from one call of `f` to the next. A tree is visited before its
+    /// children, children in key order, so the order is the same on every run. Trees deeper than
+    /// `depth_limit` levels below `prefix` are not entered. Only the trees on the current path
+    /// are held in memory. A missing prefix visits nothing, an error of `f` stops the fold. The
+    /// commit is pinned while folding, see [ContextReader::pin].
+    pub fn fold<A, F>(&self, commit_hash: &EntryHash, prefix: &ContextKey, depth_limit: Option<usize>, init: A, f: F) -> Result<A, MerkleError>
+        where F: FnMut(A, &ContextKey, &FoldEntry) -> Result<A, MerkleError>
+    {
+        Ok(self.fold_with_budget(commit_hash, prefix, depth_limit, init, QueryBudget::default(), f)?.value)
+    }
+
+    /// Like [ContextReader::fold], but stops once `budget` is exceeded, with the state
+    /// accumulated from the entries visited until then.
+    pub fn fold_with_budget<A, F>(&self, commit_hash: &EntryHash, prefix: &ContextKey, depth_limit: Option<usize>, init: A, budget: QueryBudget, f: F)
+                                  -> Result<Budgeted<A>, MerkleError>
+        where F: FnMut(A, &ContextKey, &FoldEntry) -> Result<A, MerkleError>
+    {
+        let _pin = self.pin(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+        let mut budget = BudgetTracker::new(budget);
+        let acc = match self.find_node_or_root(&commit.root_hash, prefix)? {
+            Some(node) => fold_under(self, prefix.clone(), node, depth_limit, init, &mut budget, f)?,
+            None => init,
+        };
+        Ok(budget.finish(acc))
+    }
+
+    /// Stream all key-values of given commit in key order, e.g. to export them into another
+    /// database. Iteration stops after the first error. The commit stays pinned until the
+    /// iterator is dropped, see [ContextReader::pin].
+    pub fn materialize(&self, commit_hash: &EntryHash) -> Result<MaterializeIterator, MerkleError> {
+        let pin = self.pin(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+        Ok(MaterializeIterator { reader: self.clone(), stack: vec![(Vec::new(), commit.root_hash)], _pin: pin })
+    }
+
+    /// Get value stored under `key` in given commit.
+    pub fn get_at(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+        self.get_from_tree(&commit.root_hash, key)
+    }
+
+    /// Check whether a value is stored under `key` in given commit, empty values included.
+    pub fn exists_at(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<bool, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+        self.value_exists(&commit.root_hash, key)
+    }
+
+    /// Get key of the child following `key` in its parent directory in given commit, see
+    /// [MerkleStorage::next_sibling].
+    pub fn next_sibling_at(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<Option<ContextKey>, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let (_, root) = self.get_commit_with_root(commit_hash)?;
+        self.find_sibling(&root, key, true)
+    }
+
+    /// Get key of the child preceding `key` in its parent directory in given commit.
+    pub fn prev_sibling_at(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<Option<ContextKey>, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let (_, root) = self.get_commit_with_root(commit_hash)?;
+        self.find_sibling(&root, key, false)
+    }
+
+    /// Like [ContextReader::get_at], but the value is returned in the buffer read from database
+    /// without copying.
+    pub fn get_at_raw(&self, commit_hash: &EntryHash, key: &ContextKey) -> Result<IVec, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let commit = self.get_commit(commit_hash)?;
+        self.get_raw_from_tree(&commit.root_hash, key)
+    }
+
+    /// List all key-values under `prefix` in given commit.
+    pub fn list(&self, commit_hash: &EntryHash, prefix: &ContextKey) -> Result<Option<Vec<(ContextKey, ContextValue)>>, MerkleError> {
+        Ok(self.list_with_budget(commit_hash, prefix, QueryBudget::default())?.value)
+    }
+
+    /// Like [ContextReader::list], but stops once `budget` is exceeded.
+    pub fn list_with_budget(&self, commit_hash: &EntryHash, prefix: &ContextKey, budget: QueryBudget) -> Result<Budgeted<Option<ContextKeyValues>>, MerkleError> {
+        let _pin = self.pins.pin_commit(commit_hash)?;
+        let (_, root_tree) = self.get_commit_with_root(commit_hash)?;
+        let mut budget = BudgetTracker::new(budget);
+        let keyvalues = self._get_key_values_by_prefix(root_tree, prefix, &mut budget)?;
+        Ok(budget.finish(keyvalues))
+    }
+
+    /// Compare context of `commit_hash` with context of `other_commit_hash` read from `other`,
+    /// which may be backed by a different database, e.g. of another node. Returns up to `limit`
+    /// first paths in key order, at which the contexts diverge. A directory present on one side
+    /// only is reported once, without its content. Commit metadata is not compared.
+    pub fn diverging_paths(&self, commit_hash: &EntryHash, other: &ContextReader, other_commit_hash: &EntryHash, limit: usize)
+                           -> Result<Vec<(ContextKey, PathDivergence)>, MerkleError> {
+        Ok(self.diverging_paths_with_budget(commit_hash, other, other_commit_hash, limit, QueryBudget::default())?.value)
+    }
+
+    /// Like [ContextReader::diverging_paths], but stops once `budget` is exceeded. Entries
+    /// visited in both contexts count against the budget.
+    pub fn diverging_paths_with_budget(&self, commit_hash: &EntryHash, other: &ContextReader, other_commit_hash: &EntryHash, limit: usize, budget: QueryBudget)
+                                       -> Result<Budgeted<Vec<(ContextKey, PathDivergence)>>, MerkleError> {
+        let _pins = (self.pins.pin_commit(commit_hash)?, other.pins.pin_commit(other_commit_hash)?);
+        let root_hash = self.get_commit(commit_hash)?.root_hash;
+        let other_root_hash = other.get_commit(other_commit_hash)?.root_hash;
+        let mut budget = BudgetTracker::new(budget);
+        let paths = diverging_paths(self, &root_hash, other, &other_root_hash, limit, &mut budget)?;
+        Ok(budget.finish(paths))
+    }
+}
+
+impl EntryStore for ContextReader {
+    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        get_entry_from_db(self.db.as_ref(), self.entry_cache.as_ref(), self.blob_sink.as_deref(), &self.entry_sources, hash)
+    }
+
+    fn get_entry_raw(&self, hash: &EntryHash) -> Result<IVec, MerkleError> {
+        get_entry_raw_from_db(self.db.as_ref(), &self.entry_sources, hash)
+    }
+}
+
+/// Hashes of entries referenced by `entry`, once per reference
+fn referenced_entries(entry: &Entry) -> Vec<EntryHash> {
+    match entry {
+        Entry::Blob(_) | Entry::External { .. } => Vec::new(),
+        Entry::Tree(tree) => tree.values().map(|node| node.entry_hash).collect(),
+        Entry::Commit(commit) => vec![commit.root_hash],
+    }
+}
+
+/// Sort `entries` by their height above the leaves among `entries`, then by hash, so every
+/// entry comes after the entries it references. A write interrupted between runs then leaves
+/// only complete subtrees behind, which a retried commit can skip.
+fn sort_children_first(entries: &mut [(EntryHash, Vec<u8>)]) -> Result<(), MerkleError> {
+    let mut children = HashMap::with_capacity(entries.len());
+    for (hash, bytes) in entries.iter() {
+        children.insert(*hash, referenced_entries(&Entry::decode(bytes)?));
+    }
+
+    let mut heights: HashMap<EntryHash, usize> = HashMap::with_capacity(entries.len());
+    for (hash, _) in entries.iter() {
+        let mut stack = vec![(*hash, false)];
+        while let Some((hash, expanded)) = stack.pop() {
+            if heights.contains_key(&hash) {
+                continue;
+            }
+            // entries outside of `entries` are stored already and do not count
+            let below = children[&hash].iter().filter(|child| children.contains_key(*child));
+            if expanded {
+                let height = below.map(|child| heights[child] + 1).max().unwrap_or(0);
+                heights.insert(hash, height);
+            } else {
+                stack.push((hash, true));
+                stack.extend(below.filter(|child| !heights.contains_key(*child)).map(|child| (*child, false)));
+            }
+        }
+    }
+    entries.sort_unstable_by_key(|(hash, _)| (heights[hash], *hash));
+    Ok(())
+}
+
+/// Get entry, values kept in the blob sink are read from it.
+fn get_entry_from_db(db: &MerkleStorageKV, cache: Option<&EntryCache>, sink: Option<&dyn BlobSink>, sources: &[Arc<dyn EntrySource>], hash: &EntryHash) -> Result<Entry, MerkleError> {
+    match load_entry_from_db(db, cache, sources, hash)? {
+        Entry::External { hash, len } => Ok(Entry::Blob(read_external_blob(sink, &hash, len)?)),
+        entry => Ok(entry),
+    }
+}
+
+/// Get entry as stored in the database, entries missing in it are fetched from `sources`
+fn load_entry_from_db(db: &MerkleStorageKV, cache: Option<&EntryCache>, sources: &[Arc<dyn EntrySource>], hash: &EntryHash) -> Result<Entry, MerkleError> {
+    if let Some(entry) = cache.and_then(|cache| cache.lock().unwrap().get(hash)) {
+        return Ok(entry);
+    }
+    let entry_bytes = match db.get(hash)? {
+        None => fetch_entry(db, sources, hash)?,
+        entry_bytes => entry_bytes,
+    };
+    match entry_bytes {
+        None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(hash) }),
+        Some(entry_bytes) => {
+            let entry: Entry = bincode::deserialize(entry_bytes.as_ref())?;
+            if let Some(cache) = cache {
+                cache.lock().unwrap().insert(*hash, entry.clone());
+            }
+            Ok(entry)
+        }
+    }
+}
+
+fn get_entry_raw_from_db(db: &MerkleStorageKV, sources: &[Arc<dyn EntrySource>], hash: &EntryHash) -> Result<IVec, MerkleError> {
+    match db.get_raw(hash)? {
+        Some(entry_bytes) => Ok(entry_bytes),
+        None => match fetch_entry(db, sources, hash)? {
+            None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(hash) }),
+            Some(entry_bytes) => Ok(IVec::from(entry_bytes)),
+        },
+    }
+}
+
+/// Get entry from the first of `sources` having it and store it in the database. Descendants
+/// of the entry are fetched once they are read.
+fn fetch_entry(db: &MerkleStorageKV, sources: &[Arc<dyn EntrySource>], hash: &EntryHash) -> Result<Option<Vec<u8>>, MerkleError> {
+    for source in sources {
+        if let Some(entry_bytes) = source.get(hash)? {
+            let computed = hash_entry(&bincode::deserialize(&entry_bytes)?);
+            if computed != *hash {
+                return Err(MerkleError::EntryHashMismatch {
+                    hash: HashType::ContextHash.bytes_to_string(hash),
+                    computed: HashType::ContextHash.bytes_to_string(&computed),
+                });
+            }
+            db.put(hash, &entry_bytes)?;
+            return Ok(Some(entry_bytes));
+        }
+    }
+    Ok(None)
+}
+
+fn open_external_blob(sink: Option<&dyn BlobSink>, hash: &EntryHash, len: u64) -> Result<VerifiedBlobReader, MerkleError> {
+    let sink = sink.ok_or_else(|| MerkleError::BlobSinkMissing { hash: HashType::ContextHash.bytes_to_string(hash) })?;
+    Ok(VerifiedBlobReader::new(sink.reader(hash)?, *hash, len))
+}
+
+fn read_external_blob(sink: Option<&dyn BlobSink>, hash: &EntryHash, len: u64) -> Result<ContextValue, MerkleError> {
+    let mut value = Vec::new();
+    match open_external_blob(sink, hash, len)?.read_to_end(&mut value) {
+        Ok(_) => Ok(value),
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => Err(MerkleError::EntryHashMismatch {
+            hash: HashType::ContextHash.bytes_to_string(hash),
+            computed: error.to_string(),
+        }),
+        Err(error) => Err(BlobSinkError::from(error).into()),
+    }
+}
+
+/// Check whether serialized entry is a reference to a value in the blob sink.
+fn is_external_blob(bytes: &[u8]) -> bool {
+    bytes.starts_with(&EXTERNAL_BLOB_VARIANT.to_le_bytes())
+}
+
+/// Slice value out of serialized blob entry stored under `key`.
+fn blob_from_raw(bytes: IVec, key: &ContextKey) -> Result<IVec, MerkleError> {
+    Entry::blob_value(bytes)?.ok_or_else(|| MerkleError::ValueIsNotABlob { key: key_to_path(key) })
+}
+
+/// Audit entries with keys in range `start..end`, unbounded ends are `None`.
+fn audit_entry_range(db: &MerkleStorageKV, start: Option<EntryHash>, end: Option<EntryHash>) -> Result<AuditReport, MerkleError> {
+    let mode = match &start {
+        None => IteratorMode::Start,
+        Some(start) => IteratorMode::From(start, Direction::Forward),
+    };
+
+    let mut report = AuditReport::default();
+    for (key, value) in db.iterator(mode)? {
+        let key = key.map_err(DBError::from)?;
+        if matches!(end, Some(end) if key >= end) {
+            break;
+        }
+        let value = value.map_err(DBError::from)?;
+
+        report.entries_checked += 1;
+        report.bytes_checked += value.len() as u64;
+        match bincode::deserialize::<Entry>(&value) {
+            Ok(entry) => if hash_entry(&entry) != key {
+                report.mismatched.push(key);
+            }
+            Err(_) => report.undecodable.push(key),
+        }
+    }
+    Ok(report)
+}
+
+fn hash_entry(entry: &Entry) -> EntryHash {
+    match entry {
+        Entry::Commit(commit) => hash_commit(&commit),
+        Entry::Tree(tree) => hash_tree(&tree),
+        Entry::Blob(blob) => hash_blob(blob),
+        Entry::External { hash, .. } => *hash,
+    }
+}
+
+/// Hash of a commit as computed by [MerkleStorage::commit], see [Commit::new]
+pub fn hash_commit(commit: &Commit) -> EntryHash {
+    let mut hasher = State::new(HASH_LEN, None).unwrap();
+    hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&commit.root_hash).expect("hasher");
+
+    match &commit.parent_commit_hash {
+        None => {
+            hasher.update(&0u64.to_be_bytes()).expect("hasher");
+        }
+        Some(parent_commit_hash) => {
+            hasher.update(&1u64.to_be_bytes()).expect("hasher"); // # of parents; we support only 1
+            hasher.update(&(parent_commit_hash.len() as u64).to_be_bytes()).expect("hasher");
+            hasher.update(parent_commit_hash).expect("hasher");
+        }
+    }
+    hasher.update(&(commit.time as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&(commit.author.len() as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&commit.author.clone().into_bytes()).expect("hasher");
+    hasher.update(&(commit.message.len() as u64).to_be_bytes()).expect("hasher");
+    hasher.update(&commit.message.clone().into_bytes()).expect("hasher");
+    // commits without metadata hash as they did before metadata was added
+    if !commit.metadata.is_empty() {
+        hasher.update(&(commit.metadata.len() as u64).to_be_bytes()).expect("hasher");
+        for (key, value) in &commit.metadata {
+            hasher.update(&(key.len() as u64).to_be_bytes()).expect("hasher");
+            hasher.update(key.as_bytes()).expect("hasher");
+            let (tag, bytes) = match value {
+                CommitMetadataValue::Int(value) => (0u8, value.to_be_bytes().to_vec()),
+                CommitMetadataValue::Bytes(value) => (1u8, value.clone()),
+                CommitMetadataValue::Text(value) => (2u8, value.clone().into_bytes()),
+            };
+            hasher.update(&[tag]).expect("hasher");
+            hasher.update(&(bytes.len() as u64).to_be_bytes()).expect("hasher");
+            hasher.update(&bytes).expect("hasher");
+        }
+    }
+
+    hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+}
+
+/// Hash of a tree as stored by [MerkleStorage], children point to values hashed by [hash_blob]
+/// and subtrees hashed by [hash_tree]
+pub fn hash_tree(tree: &Tree) -> EntryHash {
+    let mut hasher = State::new(HASH_LEN, None).unwrap();
+
+    hasher.update(&(tree.len() as u64).to_be_bytes()).expect("hasher");
+    tree.iter().for_each(|(k, v)| {
+        hasher.update(&encode_irmin_node_kind(&v.node_kind)).expect("hasher");
+        hasher.update(&[k.len() as u8]).expect("hasher");
+        hasher.update(&k.clone().into_bytes()).expect("hasher");
+        hasher.update(&(HASH_LEN as u64).to_be_bytes()).expect("hasher");
+        hasher.update(&v.entry_hash).expect("hasher");
+    });
+
+    hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+}
+
+/// Hash of `value`, e.g. to look up keys holding it with [MerkleStorage::find_keys_with_value_hash]
+pub fn value_hash(value: &ContextValue) -> EntryHash {
+    hash_blob(value)
+}
+
+/// Collect trees on the path to `key` in commit `commit_hash`, down to the tree holding the
+/// value or to the tree, where the path ends. Returns whether a value is stored under `key`.
+fn path_proof<S: EntryStore>(store: &S, commit_hash: &EntryHash, key: &ContextKey) -> Result<(MerkleProof, bool), MerkleError> {
+    let (last, path) = key.split_last().ok_or(MerkleError::KeyEmpty)?;
+    let (commit, mut tree) = store.get_commit_with_root(commit_hash)?;
+    let mut trees = Vec::with_capacity(key.len());
+    for fragment in path {
+        let next = match tree.get(fragment) {
+            Some(Node { node_kind: NodeKind::NonLeaf, entry_hash }) => store.get_tree(entry_hash)?,
+            _ => {
+                trees.push(tree);
+                return Ok((MerkleProof { commit, trees }, false));
+            }
+        };
+        trees.push(tree);
+        tree = next;
+    }
+    let found = matches!(tree.get(last), Some(node) if node.node_kind == NodeKind::Leaf);
+    trees.push(tree);
+    Ok((MerkleProof { commit, trees }, found))
+}
+
+fn inclusion_proof<S: EntryStore>(store: &S, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+    match path_proof(store, commit_hash, key)? {
+        (proof, true) => Ok(proof),
+        // the whole path exists, so the key is a subtree
+        (proof, false) if proof.trees.len() == key.len() && proof.trees[key.len() - 1].contains_key(&key[key.len() - 1]) => {
+            Err(MerkleError::ValueIsNotABlob { key: key_to_path(key) })
+        }
+        (_, false) => Err(MerkleError::ValueNotFound { key: key_to_path(key) }),
+    }
+}
+
+fn exclusion_proof<S: EntryStore>(store: &S, commit_hash: &EntryHash, key: &ContextKey) -> Result<MerkleProof, MerkleError> {
+    match path_proof(store, commit_hash, key)? {
+        (_, true) => Err(MerkleError::ValueExists { key: key_to_path(key) }),
+        (proof, false) => Ok(proof),
+    }
+}
+
+/// Hash of a value as stored by [MerkleStorage]
+pub fn hash_blob(blob: &ContextValue) -> EntryHash {
+    let mut hasher = State::new(HASH_LEN, None).unwrap();
+    hasher.update(&(blob.len() as u64).to_be_bytes()).expect("Failed to update hasher state");
+    hasher.update(blob).expect("Failed to update hasher state");
+
+    hasher.finalize().unwrap().as_ref().try_into().expect("EntryHash conversion error")
+}
+
+/// Lowercase hex form of `hash`, without `0x` prefix
+pub fn hash_to_hex(hash: &EntryHash) -> String {
+    hex::encode(hash)
+}
+
+/// Parse hash from 64 hex characters, optionally prefixed by `0x`
+pub fn hash_from_hex(hex_hash: &str) -> Result<EntryHash, MerkleError> {
+    let digits = hex_hash.strip_prefix("0x").unwrap_or(hex_hash);
+    let mut hash = [0; HASH_LEN];
+    hex::decode_to_slice(digits, &mut hash).map_err(|_| MerkleError::InvalidHash {
+        hash: hex_hash.to_string(),
+        reason: "expected 64 hex characters",
+    })?;
+    Ok(hash)
+}
+
+/// Base58check form of `hash` with the prefix of Tezos context hashes, e.g. `CoV...`
+pub fn hash_to_base58(hash: &EntryHash) -> String {
+    HashType::ContextHash.bytes_to_string(hash)
+}
+
+/// Parse hash from base58check form of a Tezos context hash
+pub fn hash_from_base58(b58_hash: &str) -> Result<EntryHash, MerkleError> {
+    let invalid = |reason| MerkleError::InvalidHash { hash: b58_hash.to_string(), reason };
+    let bytes = b58_hash.from_base58check().map_err(|_| invalid("invalid base58check encoding"))?;
+    match bytes.strip_prefix(HashType::ContextHash.prefix()) {
+        Some(digest) => digest.try_into().map_err(|_| invalid("wrong length of a context hash")),
+        None => Err(invalid("not a context hash")),
+    }
+}
+
+/// Reader of a value from the blob sink, which hashes the value as it is read. Reading fails
+/// with [io::ErrorKind::InvalidData] once the value turns out to differ from the expected one.
+struct VerifiedBlobReader {
+    inner: Box<dyn Read + Send>,
+    // `None` once verified
+    hasher: Option<State>,
+    hash: EntryHash,
+    remaining: u64,
+}
+
+impl VerifiedBlobReader {
+    fn new(inner: Box<dyn Read + Send>, hash: EntryHash, len: u64) -> Self {
+        let mut hasher = State::new(HASH_LEN, None).unwrap();
+        hasher.update(&len.to_be_bytes()).expect("hasher");
+        VerifiedBlobReader { inner, hasher: Some(hasher), hash, remaining: len }
+    }
+}
+
+impl Read for VerifiedBlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let read = self.inner.read(buf)?;
+        let hasher = match &mut self.hasher {
+            Some(hasher) => hasher,
+            None if read == 0 => return Ok(0),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "value is longer than expected")),
+        };
+        if read as u64 > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "value is longer than expected"));
+        }
+        self.remaining -= read as u64;
+        hasher.update(&buf[..read]).expect("hasher");
+
+        if read == 0 {
+            if self.remaining > 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "value is shorter than expected"));
+            }
+            let digest = self.hasher.take().unwrap().finalize().unwrap();
+            if digest.as_ref() != self.hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "value does not match its hash"));
+            }
+        }
+        Ok(read)
+    }
+}
+
+fn encode_irmin_node_kind(kind: &NodeKind) -> Vec<u8> {
+    match kind {
+        NodeKind::NonLeaf => vec![0, 0, 0, 0, 0, 0, 0, 0],
+        NodeKind::Leaf => vec![255, 0, 0, 0, 0, 0, 0, 0],
+    }
+}
+
+/// Number of live snapshots of an epoch and commits they observe
+type EpochPins = (usize, Vec<EntryHash>);
+
+/// Pins of live snapshots and in-flight reads, shared by a storage, its readers and snapshots.
+/// Running collections are registered too, so a commit pinned meanwhile is either retained or
+/// refused, never deleted under the reader.
+#[derive(Clone, Default)]
+struct Pins(Arc<Mutex<PinState>>);
+
+#[derive(Default)]
+struct PinState {
+    // snapshots by epoch
+    epochs: BTreeMap<u64, EpochPins>,
+    // commits of in-flight reads by pin id
+    reads: HashMap<u64, EntryHash>,
+    next_read: u64,
+    collection: Option<Collection>,
+    // commits being deleted by [MerkleStorage::delete_commit]
+    deleting: HashSet<EntryHash>,
+}
+
+/// Phase of a running [MerkleStorage::gc]
+enum Collection {
+    /// Commits pinned since retained commits were chosen, they are marked before the sweep
+    Marking(Vec<EntryHash>),
+    /// Marked entries, commits outside of them cannot be pinned
+    Sweeping(Arc<HashSet<EntryHash>>),
+}
+
+impl Pins {
+    fn pin(&self, epoch: u64, commits: impl IntoIterator<Item = EntryHash>) {
+        let mut state = self.0.lock().unwrap();
+        let (count, pinned) = state.epochs.entry(epoch).or_default();
+        *count += 1;
+        pinned.extend(commits);
+    }
+
+    fn unpin(&self, epoch: u64) {
+        let mut state = self.0.lock().unwrap();
+        if let Some((count, _)) = state.epochs.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                state.epochs.remove(&epoch);
+            }
+        }
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        self.0.lock().unwrap().epochs.keys().next().copied()
+    }
+
+    /// Heads and ref targets of all live snapshots and commits of in-flight reads
+    fn commits(&self) -> Vec<EntryHash> {
+        let state = self.0.lock().unwrap();
+        state.epochs.values().flat_map(|(_, commits)| commits.iter().copied())
+            .chain(state.reads.values().copied())
+            .collect()
+    }
+
+    /// Pin commit of a read, `None` if the commit is being collected
+    fn pin_read(&self, commit_hash: &EntryHash) -> Option<u64> {
+        let mut state = self.0.lock().unwrap();
+        if state.deleting.contains(commit_hash) {
+            return None;
+        }
+        match &mut state.collection {
+            Some(Collection::Sweeping(marked)) if !marked.contains(commit_hash) => return None,
+            Some(Collection::Marking(late)) => late.push(*commit_hash),
+            _ => {}
+        }
+        let id = state.next_read;
+        state.next_read += 1;
+        state.reads.insert(id, *commit_hash);
+        Some(id)
+    }
+
+    /// Pin commit of a read until the returned pin is dropped, fails with
+    /// [MerkleError::EntryNotFound] if the commit is being collected
+    fn pin_commit(&self, commit_hash: &EntryHash) -> Result<CommitPin, MerkleError> {
+        match self.pin_read(commit_hash) {
+            Some(id) => Ok(CommitPin { pins: self.clone(), id, commit_hash: *commit_hash }),
+            None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(commit_hash) }),
+        }
+    }
+
+    fn unpin_read(&self, id: u64) {
+        self.0.lock().unwrap().reads.remove(&id);
+    }
+
+    /// Register a collection, which retains commits pinned from now on. Returns commits pinned
+    /// until now.
+    fn start_collection(&self) -> Vec<EntryHash> {
+        self.0.lock().unwrap().collection = Some(Collection::Marking(Vec::new()));
+        self.commits()
+    }
+
+    /// Add entries of commits pinned since the collection started to `marked` by `mark`, until
+    /// no more commits are pinned, then refuse pins of commits outside of `marked` until the
+    /// collection ends. Marking runs without the lock held, so pins do not wait for it.
+    fn start_sweep<F>(&self, mut marked: HashSet<EntryHash>, mut mark: F) -> Result<Arc<HashSet<EntryHash>>, MerkleError>
+        where F: FnMut(Vec<EntryHash>, &mut HashSet<EntryHash>) -> Result<(), MerkleError>
+    {
+        loop {
+            let late = {
+                let mut state = self.0.lock().unwrap();
+                match &mut state.collection {
+                    Some(Collection::Marking(late)) if !late.is_empty() => std::mem::take(late),
+                    _ => {
+                        let marked = Arc::new(marked);
+                        state.collection = Some(Collection::Sweeping(marked.clone()));
+                        return Ok(marked);
+                    }
+                }
+            };
+            mark(late, &mut marked)?;
+        }
+    }
+
+    fn end_collection(&self) {
+        self.0.lock().unwrap().collection = None;
+    }
+
+    /// Refuse pins of `commit_hash` until [Pins::end_delete], `false` if it is pinned
+    fn start_delete(&self, commit_hash: &EntryHash) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.reads.values().any(|pinned| pinned == commit_hash)
+            || state.epochs.values().any(|(_, commits)| commits.contains(commit_hash)) {
+            return false;
+        }
+        state.deleting.insert(*commit_hash);
+        true
+    }
+
+    fn end_delete(&self, commit_hash: &EntryHash) {
+        self.0.lock().unwrap().deleting.remove(commit_hash);
+    }
+}
+
+/// Keeps a commit pinned by [ContextReader::pin] from being collected while alive
+pub struct CommitPin {
+    pins: Pins,
+    id: u64,
+    commit_hash: EntryHash,
+}
+
+impl CommitPin {
+    pub fn commit_hash(&self) -> EntryHash {
+        self.commit_hash
+    }
+}
+
+impl Drop for CommitPin {
+    fn drop(&mut self) {
+        self.pins.unpin_read(self.id);
+    }
+}
+
+/// Frozen read-only view of the store created by [MerkleStorage::snapshot]. Head and refs of the
+/// snapshot do not move with later commits, checkouts or ref updates. Snapshot can be sent to
+/// other threads.
+pub struct Snapshot {
+    reader: ContextReader,
+    head: Option<EntryHash>,
+    refs: BTreeMap<String, EntryHash>,
+    epoch: u64,
+    pins: Pins,
+}
+
+impl Snapshot {
+    /// Last commit at the time the snapshot was taken
+    pub fn head(&self) -> Option<EntryHash> {
+        self.head
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Commit the ref `name` pointed to at the time the snapshot was taken
+    pub fn get_ref(&self, name: &str) -> Option<EntryHash> {
+        self.refs.get(name).copied()
+    }
+
+    /// Get value stored under `key` in head of the snapshot.
+    pub fn get(&self, key: &ContextKey) -> Result<ContextValue, MerkleError> {
+        match &self.head {
+            Some(head) => self.reader.get_at(head, key),
+            None => Err(MerkleError::ValueNotFound { key: key_to_path(key) }),
+        }
+    }
+
+    /// List all key-values under `prefix` in head of the snapshot.
+    pub fn list(&self, prefix: &ContextKey) -> Result<Option<ContextKeyValues>, MerkleError> {
+        match &self.head {
+            Some(head) => self.reader.list(head, prefix),
+            None => Ok(None),
+        }
+    }
+
+    /// Reader of committed data, for reads of other commits than the head
+    pub fn reader(&self) -> &ContextReader {
+        &self.reader
+    }
+
+    /// Write refs of the snapshot and all entries reachable from its head and refs to `writer` in
+    /// a background thread, e.g. to take a backup of a live node while commits continue. The snapshot stays
+    /// pinned until the export finishes. Exported stream can be loaded by
+    /// [MerkleStorage::import_snapshot].
+    pub fn export<W: Write + Send + 'static>(self, writer: W, config: ExportConfig) -> ExportHandle {
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let progress = bytes_written.clone();
+        let thread = std::thread::spawn(move || self.export_entries(writer, config, &progress));
+        ExportHandle { bytes_written, thread }
+    }
+
+    /// Write header with refs followed by entries reachable from the head and refs, each as
+    /// `Some((hash, bytes))`, and `None` as end marker, so truncated exports are detected.
+    fn export_entries<W: Write>(&self, mut writer: W, config: ExportConfig, progress: &AtomicU64) -> Result<ExportReport, MerkleError> {
+        let refs = self.refs.clone();
+        let roots = self.head.into_iter().chain(refs.values().copied()).collect();
+        let header = ExportHeader { entry_format_version: ENTRY_FORMAT_VERSION, head: self.head, refs };
+        bincode::serialize_into(&mut writer, &header)?;
+
+        let started = Instant::now();
+        let mut report = ExportReport { head: self.head, ..ExportReport::default() };
+        let mut entries = DagIterator::from_roots(&self.reader, roots, HashSet::new());
+        while let Some(entry) = entries.next_entry() {
+            let (hash, _, bytes) = entry?;
+            bincode::serialize_into(&mut writer, &Some((hash, &bytes[..])))?;
+
+            report.entries += 1;
+            report.bytes += bytes.len() as u64;
+            progress.store(report.bytes, Ordering::Relaxed);
+            if let Some(rate) = config.max_bytes_per_sec {
+                let due = Duration::from_secs_f64(report.bytes as f64 / rate.max(1) as f64);
+                let elapsed = started.elapsed();
+                if due > elapsed {
+                    std::thread::sleep(due - elapsed);
+                }
+            }
+        }
+        bincode::serialize_into(&mut writer, &None::<(EntryHash, &[u8])>)?;
+        writer.flush().map_err(bincode::Error::from)?;
+        Ok(report)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.pins.unpin(self.epoch);
+    }
+}
+
+impl Drop for MerkleStorage {
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Some(hook) = &self.dirty_drop_hook {
+                hook(self.get_last_commit_hash(), self.staged.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_must_use)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::fs;
+    use serial_test::serial;
+    use sled::Config;
+    use crate::database::SledDBWrapper;
+    use crate::key;
+    use crate::profile::PerformanceProfile;
+    use crate::metadata::ENTRY_FORMAT_VERSION;
+    use crate::value_transform::{Checksum, Encryption, ValuePipeline};
+    use crate::entry_codecs::EntryCodecs;
+    use crate::entry_sources::PackEntrySource;
+    use crate::blob_store::FsBlobSink;
+
+    /*
+    * Tests reopening a store need to run sequentially, otherwise they will try to open the
+    * database at the same time.
+    */
+
+    /// Open database at `path`. Sled releases the lock of a dropped database once its background
+    /// writes finish, so a store reopened right away waits for it.
+    fn open_db<P: AsRef<Path>>(path: P, config: Config) -> SledDBWrapper {
+        let config = config.path(path);
+        for _ in 0..100 {
+            match config.open() {
+                Err(sled::Error::Io(error)) if error.to_string().starts_with("could not acquire lock") => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                db => return SledDBWrapper::new(db.expect("error opening database")),
+            }
+        }
+        panic!("database stays locked");
+    }
+
+    /// Database shared by tests reopening a store
+    fn get_db_name() -> &'static str { "_merkle_db_test" }
+
+    /// Open a temporary database, removed once dropped
+    fn get_db(config: Config) -> SledDBWrapper {
+        SledDBWrapper::new(config.temporary(true).open().expect("error opening database"))
+    }
+
+    fn get_storage(config: Config) -> MerkleStorage { MerkleStorage::new(Arc::new(get_db(config))).unwrap() }
+
+    fn get_storage_with_config(config: Config, storage_config: MerkleStorageConfig) -> MerkleStorage {
+        MerkleStorage::with_config(Arc::new(get_db(config)), storage_config).unwrap()
+    }
+
+    /// Open storage over the database at [get_db_name], which survives reopening
+    fn open_storage(config: Config) -> MerkleStorage {
+        MerkleStorage::new(Arc::new(open_db(get_db_name(), config))).unwrap()
+    }
+
+    fn open_storage_with_config(config: Config, storage_config: MerkleStorageConfig) -> MerkleStorage {
+        MerkleStorage::with_config(Arc::new(open_db(get_db_name(), config)), storage_config).unwrap()
+    }
+
+    fn clean_db() {
+        let _ = fs::remove_dir_all(get_db_name());
+    }
+
+    #[test]
+    fn test_tree_hash() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        storage.set(&vec!["a".to_string(), "foo".to_string()], &vec![97, 98, 99]); // abc
+        storage.set(&vec!["b".to_string(), "boo".to_string()], &vec![97, 98]);
+        storage.set(&vec!["a".to_string(), "aaa".to_string()], &vec![97, 98, 99, 100]);
+        storage.set(&vec!["x".to_string()], &vec![97]);
+        storage.set(&vec!["one".to_string(), "two".to_string(), "three".to_string()], &vec![97]);
+        let tree = storage.current_stage_tree.clone().unwrap().clone();
+
+        let hash = hash_tree(&tree);
+
+        assert_eq!([0xDB, 0xAE, 0xD7, 0xB6], hash[0..4]);
+    }
+
+    #[test]
+    fn test_commit_hash() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        storage.set(&vec!["a".to_string()], &vec![97, 98, 99]);
+
+        let commit = storage.commit(
+            0, "Tezos".to_string(), "Genesis".to_string());
+
+        assert_eq!([0xCF, 0x95, 0x18, 0x33], commit.unwrap()[0..4]);
+
+        storage.set(&vec!["data".to_string(), "x".to_string()], &vec![97]);
+        let commit = storage.commit(
+            0, "Tezos".to_string(), "".to_string());
+
+        assert_eq!([0xCA, 0x7B, 0xC7, 0x02], commit.unwrap()[0..4]);
+        // full irmin hash: ca7bc7022ffbd35acc97f7defb00c486bb7f4d19a2d62790d5949775eb74f3c8
+    }
+
+    #[test]
+    fn test_multiple_commit_hash() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let _commit = storage.commit(
+            0, "Tezos".to_string(), "Genesis".to_string());
+
+        storage.set(&vec!["data".to_string(), "a".to_string(), "x".to_string()], &vec![97]);
+        storage.copy(&vec!["data".to_string(), "a".to_string()], &vec!["data".to_string(), "b".to_string()]);
+        storage.delete(&vec!["data".to_string(), "b".to_string(), "x".to_string()]);
+        let commit = storage.commit(
+            0, "Tezos".to_string(), "".to_string());
+
+        assert_eq!([0x9B, 0xB0, 0x0D, 0x6E], commit.unwrap()[0..4]);
+    }
+
+    #[test]
+    #[serial]
+    fn get_test() {
+        clean_db();
+
+        let commit1;
+        let commit2;
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        let key_eab: &ContextKey = &vec!["e".to_string(), "a".to_string(), "b".to_string()];
+        let key_az: &ContextKey = &vec!["a".to_string(), "z".to_string()];
+        let key_d: &ContextKey = &vec!["d".to_string()];
+
+        {
+            let config = Config::new().cache_capacity(32 * 1024 * 1024);
+            let mut storage = open_storage(config);
+            storage.set(key_abc, &vec![1u8, 2u8]);
+            storage.set(key_abx, &vec![3u8]);
+            assert_eq!(storage.get(&key_abc).unwrap(), vec![1u8, 2u8]);
+            assert_eq!(storage.get(&key_abx).unwrap(), vec![3u8]);
+            commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+            storage.set(key_az, &vec![4u8]);
+            storage.set(key_abx, &vec![5u8]);
+            storage.set(key_d, &vec![6u8]);
+            storage.set(key_eab, &vec![7u8]);
+            assert_eq!(storage.get(key_abx).unwrap(), vec![5u8]);
+            commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        }
+
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = open_storage(config);
+        assert_eq!(storage.get_history(&commit1, key_abc).unwrap(), vec![1u8, 2u8]);
+        assert_eq!(storage.get_history(&commit1, key_abx).unwrap(), vec![3u8]);
+        assert_eq!(storage.get_history(&commit2, key_abx).unwrap(), vec![5u8]);
+        assert_eq!(storage.get_history(&commit2, key_az).unwrap(), vec![4u8]);
+        assert_eq!(storage.get_history(&commit2, key_d).unwrap(), vec![6u8]);
+        assert_eq!(storage.get_history(&commit2, key_eab).unwrap(), vec![7u8]);
+    }
+
+    #[test]
+    fn test_copy() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        storage.set(key_abc, &vec![1 as u8]);
+        storage.copy(&vec!["a".to_string()], &vec!["z".to_string()]);
+
+        assert_eq!(
+            vec![1 as u8],
+            storage.get(&vec!["z".to_string(), "b".to_string(), "c".to_string()]).unwrap());
+        // TODO test copy over commits
+    }
+
+    #[test]
+    fn test_copy_values() {
+        let mut storage = get_storage(Config::new());
+        for i in 0..100u8 {
+            storage.set(&key!["data", "big", &i.to_string()], &vec![i]).unwrap();
+        }
+        storage.set(&key!["data", "v"], &vec![1u8]).unwrap();
+        let first = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let written = storage.get_merkle_stats().unwrap().counters.entries_written;
+
+        // `backup` shares all entries with `data`, only the root tree and the commit are written
+        storage.copy(&key!["data", "big"], &key!["backup", "big"]).unwrap();
+        storage.copy(&key!["data", "v"], &key!["backup", "v"]).unwrap();
+        let second = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(written + 2, storage.get_merkle_stats().unwrap().counters.entries_written);
+        assert_eq!(vec![42], storage.get_history(&second, &key!["backup", "big", "42"]).unwrap());
+        assert_eq!(vec![1], storage.get_history(&second, &key!["backup", "v"]).unwrap());
+        assert!(storage.get_history(&first, &key!["backup", "v"]).is_err());
+
+        // copy replaces the target, copy of a missing key changes nothing
+        storage.copy(&key!["data", "v"], &key!["data", "big"]).unwrap();
+        assert_eq!(vec![1], storage.get(&key!["data", "big"]).unwrap());
+        storage.checkout(&second).unwrap();
+        storage.copy(&key!["missing"], &key!["data"]).unwrap();
+        assert!(!storage.is_dirty());
+        assert_eq!(vec![7], storage.get(&key!["data", "big", "7"]).unwrap());
+    }
+
+    #[test]
+    fn test_clock() {
+        let now = Arc::new(AtomicU64::new(100));
+        let mut storage = get_storage(Config::new());
+        let clock = now.clone();
+        storage.set_clock(Arc::new(move || clock.load(Ordering::SeqCst)));
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let first = storage.commit_now("".to_string(), "".to_string()).unwrap();
+        now.store(200, Ordering::SeqCst);
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        let second = storage.commit_now("".to_string(), "".to_string()).unwrap();
+
+        let times: Vec<u64> = storage.history(&second).unwrap().map(|info| info.unwrap().time).collect();
+        assert_eq!(vec![200, 100], times);
+        // same contents committed at the same time give the same hash
+        storage.checkout(&first).unwrap();
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        assert_eq!(second, storage.commit(200, "".to_string(), "".to_string()).unwrap());
+
+        storage.update_ref("r", None, &first).unwrap();
+        storage.delete_ref("r", &first).unwrap();
+        assert_eq!(200, storage.get_audit_log(0, 1).unwrap()[0].1.time);
+    }
+
+    #[test]
+    fn test_public_hashes() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["data", "a"], &vec![1u8]).unwrap();
+        storage.set(&key!["b"], &vec![2u8]).unwrap();
+        let commit_hash = storage.commit(5, "author".to_string(), "message".to_string()).unwrap();
+
+        let data: Tree = OrdMap::unit("a".to_string(), Node::new(NodeKind::Leaf, hash_blob(&vec![1u8])));
+        let root: Tree = OrdMap::unit("b".to_string(), Node::new(NodeKind::Leaf, hash_blob(&vec![2u8])))
+            .update("data".to_string(), Node::new(NodeKind::NonLeaf, hash_tree(&data)));
+        let commit = Commit::new(None, hash_tree(&root), 5, "author".to_string(), "message".to_string());
+        assert_eq!(commit_hash, hash_commit(&commit));
+
+        let mut metadata = CommitMetadata::new();
+        metadata.insert("level".to_string(), CommitMetadataValue::Int(1));
+        storage.set(&key!["b"], &vec![3u8]).unwrap();
+        let next = storage.commit_with_metadata(6, "".to_string(), "".to_string(), metadata.clone()).unwrap();
+        let root_hash = *storage.get_commit(&next).unwrap().root_hash();
+        assert_eq!(next, hash_commit(&Commit::new(Some(commit_hash), root_hash, 6, "".to_string(), "".to_string()).with_metadata(metadata)));
+    }
+
+    #[test]
+    fn test_root_hash_bytes() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let root_hash = storage.root_hash_bytes(&commit).unwrap();
+        assert_eq!(hash_tree(&storage.get_staged_root().unwrap()), root_hash);
+        assert_eq!(root_hash, storage.reader().root_hash_bytes(&commit).unwrap());
+        assert!(matches!(storage.root_hash_bytes(&root_hash), Err(MerkleError::FoundUnexpectedStructure { .. })));
+
+        let hex_hash = hash_to_hex(&root_hash);
+        assert_eq!(64, hex_hash.len());
+        assert_eq!(root_hash, hash_from_hex(&hex_hash).unwrap());
+        assert_eq!(root_hash, hash_from_hex(&format!("0x{}", hex_hash)).unwrap());
+        assert!(matches!(hash_from_hex(&hex_hash[2..]), Err(MerkleError::InvalidHash { .. })));
+
+        let b58_hash = hash_to_base58(&root_hash);
+        assert!(b58_hash.starts_with("Co"));
+        assert_eq!(root_hash, hash_from_base58(&b58_hash).unwrap());
+        assert!(matches!(hash_from_base58(&hex_hash), Err(MerkleError::InvalidHash { .. })));
+        let block_hash = HashType::BlockHash.bytes_to_string(&root_hash);
+        assert!(matches!(hash_from_base58(&block_hash), Err(MerkleError::InvalidHash { .. })));
+    }
+
+    #[test]
+    fn test_commit_metadata() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let plain = storage.commit(1, "a".to_string(), "m".to_string()).unwrap();
+        // empty metadata does not change the hash
+        storage.set(&key!["b"], &vec![1u8]).unwrap();
+        let next = storage.commit(2, "a".to_string(), "m".to_string()).unwrap();
+        storage.checkout(&plain).unwrap();
+        storage.set(&key!["b"], &vec![1u8]).unwrap();
+        assert_eq!(next, storage.commit_with_metadata(2, "a".to_string(), "m".to_string(), CommitMetadata::new()).unwrap());
+        assert!(storage.get_commit_info(&plain).unwrap().metadata.is_empty());
+
+        let mut metadata = CommitMetadata::new();
+        metadata.insert("protocol_level".to_string(), CommitMetadataValue::Int(7));
+        metadata.insert("block_hash".to_string(), CommitMetadataValue::Bytes(vec![1; 32]));
+        metadata.insert("tag".to_string(), CommitMetadataValue::Text("x".to_string()));
+        storage.checkout(&plain).unwrap();
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        let tagged = storage.commit_with_metadata(2, "a".to_string(), "m".to_string(), metadata.clone()).unwrap();
+
+        let info = storage.get_commit_info(&tagged).unwrap();
+        assert_eq!(metadata, info.metadata);
+        assert_eq!(Some(plain), info.parent_commit_hash);
+        let history: Vec<CommitInfo> = storage.history(&tagged).unwrap().map(Result::unwrap).collect();
+        assert_eq!(metadata, history[0].metadata);
+        assert!(history[1].metadata.is_empty());
+
+        // metadata is part of the hash
+        storage.checkout(&plain).unwrap();
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        metadata.insert("protocol_level".to_string(), CommitMetadataValue::Int(8));
+        assert_ne!(tagged, storage.commit_with_metadata(2, "a".to_string(), "m".to_string(), metadata).unwrap());
+
+        // commits without metadata keep the old layout
+        let entry = Entry::Commit(Commit { parent_commit_hash: None, root_hash: [0; HASH_LEN], time: 1,
+            author: "a".to_string(), message: "m".to_string(), metadata: CommitMetadata::new() });
+        let bytes = bincode::serialize(&entry).unwrap();
+        assert_eq!(2u32.to_le_bytes(), bytes[..4]);
+        assert!(matches!(bincode::deserialize::<Entry>(&bytes).unwrap(), Entry::Commit(commit) if commit.metadata.is_empty()));
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["data", "b", "x"], &vec![2u8]).unwrap();
+        storage.set(&key!["data", "a"], &vec![1u8]).unwrap();
+        storage.set(&key!["data", "c", "y", "z"], &vec![3u8]).unwrap();
+        storage.set(&key!["other"], &vec![4u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let visit = |mut acc: Vec<String>, key: &ContextKey, entry: &FoldEntry| {
+            acc.push(match entry {
+                FoldEntry::Tree { children, .. } => format!("{}/ {}", key.join("/"), children),
+                FoldEntry::Value { value, .. } => format!("{} {:?}", key.join("/"), value),
+            });
+            Ok(acc)
+        };
+        let visited = storage.fold(&commit, &key!["data"], None, Vec::new(), visit).unwrap();
+        assert_eq!(vec!["data/ 3", "data/a [1]", "data/b/ 1", "data/b/x [2]", "data/c/ 1", "data/c/y/ 1", "data/c/y/z [3]"], visited);
+        let visited = storage.fold(&commit, &key!["data"], Some(1), Vec::new(), visit).unwrap();
+        assert_eq!(vec!["data/ 3", "data/a [1]", "data/b/ 1", "data/c/ 1"], visited);
+        let visited = storage.fold(&commit, &key!["data", "a"], None, Vec::new(), visit).unwrap();
+        assert_eq!(vec!["data/a [1]"], visited);
+        assert!(storage.fold(&commit, &key!["missing"], None, Vec::new(), visit).unwrap().is_empty());
+
+        // statistics over the whole context
+        let values = storage.fold(&commit, &vec![], None, 0, |count, _, entry| {
+            Ok(count + matches!(entry, FoldEntry::Value { .. }) as usize)
+        }).unwrap();
+        assert_eq!(4, values);
+        let stopped = storage.fold(&commit, &vec![], None, (), |_, key, _| match key.len() {
+            2 => Err(MerkleError::KeyEmpty),
+            _ => Ok(()),
+        });
+        assert!(matches!(stopped, Err(MerkleError::KeyEmpty)));
+    }
+
+    #[test]
+    fn test_hottest_prefixes() {
+        let config = MerkleStorageConfig { access_stats: Some(AccessStatsConfig { sample_every: 1, window: 10 }), ..MerkleStorageConfig::default() };
+        let mut storage = get_storage_with_config(Config::new(), config);
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = now.clone();
+        storage.set_clock(Arc::new(move || clock.load(Ordering::SeqCst)));
+        storage.set(&key!["a", "x"], &vec![1u8]).unwrap();
+        storage.set(&key!["b", "x"], &vec![1u8]).unwrap();
+        now.store(5, Ordering::SeqCst);
+        storage.get(&key!["b", "x"]).unwrap();
+        storage.copy(&key!["b"], &key!["c"]).unwrap();
+
+        assert_eq!(vec![
+            PrefixAccess { prefix: "b".to_string(), reads: 2, writes: 1 },
+            PrefixAccess { prefix: "a".to_string(), reads: 0, writes: 1 },
+        ], storage.hottest_prefixes(2));
+        now.store(12, Ordering::SeqCst);
+        assert_eq!(vec!["b".to_string(), "c".to_string()],
+                   storage.hottest_prefixes(10).into_iter().map(|access| access.prefix).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reset_and_revert() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        storage.reset().unwrap();
+        assert!(!storage.is_dirty());
+        assert!(storage.get(&key!["a"]).is_err());
+
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        storage.set(&key!["c", "x"], &vec![3u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        storage.set(&key!["b", "x"], &vec![1u8]).unwrap();
+        storage.delete(&key!["c"]).unwrap();
+        storage.reset().unwrap();
+        assert!(!storage.is_dirty());
+        assert_eq!(vec![1u8], storage.get(&key!["a"]).unwrap());
+        assert_eq!(vec![3u8], storage.get(&key!["c", "x"]).unwrap());
+        assert!(storage.get(&key!["b", "x"]).is_err());
+
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        storage.set(&key!["b", "x"], &vec![1u8]).unwrap();
+        storage.delete(&key!["c"]).unwrap();
+        storage.revert(&key!["a"]).unwrap();
+        storage.revert(&key!["c"]).unwrap();
+        assert!(storage.is_dirty());
+        assert_eq!(vec![1u8], storage.get(&key!["a"]).unwrap());
+        assert_eq!(vec![3u8], storage.get(&key!["c", "x"]).unwrap());
+        assert_eq!(vec![1u8], storage.get(&key!["b", "x"]).unwrap());
+
+        // nothing left staged
+        storage.revert(&key!["b"]).unwrap();
+        assert!(!storage.is_dirty());
+        let root_hash = *storage.get_commit(&commit).unwrap().root_hash();
+        assert_eq!(root_hash, hash_tree(&storage.get_staged_root().unwrap()));
+    }
+
+    #[test]
+    fn test_staged_changes() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a", "x"], &vec![1u8]).unwrap();
+        storage.set(&key!["a", "y"], &vec![2u8, 2]).unwrap();
+        storage.set(&key!["b"], &vec![3u8]).unwrap();
+        storage.delete(&key!["a"]).unwrap();
+        storage.copy(&key!["b"], &key!["c", "d"]).unwrap();
+        storage.copy(&key!["missing"], &key!["e"]).unwrap();
+        storage.set(&key!["a0"], &vec![4u8]).unwrap();
+
+        assert_eq!(vec![
+            (key!["a"], ChangeKind::Deleted),
+            (key!["a0"], ChangeKind::Set),
+            (key!["b"], ChangeKind::Set),
+            (key!["c", "d"], ChangeKind::Copied { from: key!["b"] }),
+        ], storage.staged_changes());
+        assert_eq!(StagedSummary { sets: 2, deletes: 1, copies: 1, value_bytes: 5 }, storage.staged_summary());
+
+        storage.revert(&key!["c"]).unwrap();
+        assert_eq!(3, storage.staged_changes().len());
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert!(storage.staged_changes().is_empty());
+        assert_eq!(StagedSummary::default(), storage.staged_summary());
+    }
+
+    #[test]
+    fn test_delete_recursively() {
+        let mut storage = get_storage(Config::new());
+        for i in 0..10u8 {
+            storage.set(&key!["data", "a", &i.to_string(), "x"], &vec![i]).unwrap();
+        }
+        storage.set(&key!["data", "b"], &vec![1u8]).unwrap();
+        storage.delete_recursively(&key!["data", "a"]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(1, storage.get_key_values_by_prefix(&commit, &key!["data"]).unwrap().unwrap().len());
+        assert!(storage.get(&key!["data", "a", "3", "x"]).is_err());
+
+        // empty prefix deletes everything
+        storage.delete_recursively(&vec![]).unwrap();
+        assert!(storage.is_dirty());
+        assert!(storage.get(&key!["data", "b"]).is_err());
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert!(storage.get(&key!["data", "b"]).is_err());
+    }
+
+    #[test]
+    fn test_delete() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        storage.set(key_abc, &vec![2 as u8]);
+        storage.set(key_abx, &vec![3 as u8]);
+        storage.delete(key_abx);
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert!(storage.get_history(&commit1, &key_abx).is_err());
+    }
+
+    #[test]
+    fn test_deleted_entry_available() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        storage.set(key_abc, &vec![2 as u8]);
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.delete(key_abc);
+        let _commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(vec![2 as u8], storage.get_history(&commit1, &key_abc).unwrap());
+    }
+
+    #[test]
+    fn test_delete_in_separate_commit() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+        storage.set(key_abc, &vec![2 as u8]).unwrap();
+        storage.set(key_abx, &vec![3 as u8]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.delete(key_abx);
+        let commit2 = storage.commit(
+            0, "".to_string(), "".to_string()).unwrap();
+
+        assert!(storage.get_history(&commit2, &key_abx).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_checkout() {
+        clean_db();
+
+        let commit1;
+        let commit2;
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+
+        {
+            let config = Config::new().cache_capacity(32 * 1024 * 1024);
+            let mut storage = open_storage(config);
+            storage.set(key_abc, &vec![1u8]).unwrap();
+            storage.set(key_abx, &vec![2u8]).unwrap();
+            commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+            storage.set(key_abc, &vec![3u8]).unwrap();
+            storage.set(key_abx, &vec![4u8]).unwrap();
+            commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        }
+
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = open_storage(config);
+        storage.checkout(&commit1);
+        assert_eq!(storage.get(&key_abc).unwrap(), vec![1u8]);
+        assert_eq!(storage.get(&key_abx).unwrap(), vec![2u8]);
+        // this set be wiped by checkout
+        storage.set(key_abc, &vec![8u8]).unwrap();
+
+        storage.checkout(&commit2);
+        assert_eq!(storage.get(&key_abc).unwrap(), vec![3u8]);
+        assert_eq!(storage.get(&key_abx).unwrap(), vec![4u8]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_persistence_over_reopens() {
+        { clean_db(); }
+
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let commit1;
+        {
+            let config = Config::new().cache_capacity(32 * 1024 * 1024);
+            let mut storage = open_storage(config);
+            let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
+            storage.set(key_abc, &vec![2 as u8]).unwrap();
+            storage.set(key_abx, &vec![3 as u8]).unwrap();
+            commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        }
+
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = open_storage(config);
+        assert_eq!(vec![2 as u8], storage.get_history(&commit1, &key_abc).unwrap());
+    }
+
+    #[test]
+    fn test_sorted_runs_write_mode() {
+        let write_commits = |mode: CommitWriteMode| -> (Vec<EntryHash>, PersistentCounters) {
+            let storage_config = MerkleStorageConfig { commit_write_mode: mode, ..Default::default() };
+            let mut storage = get_storage_with_config(Config::new(), storage_config);
+            let mut commits = Vec::new();
+            for c in 0..3u8 {
+                for i in 0..50u8 {
+                    // values repeat across keys, so commits share blobs
+                    storage.set(&vec![(i % 5).to_string(), i.to_string()], &vec![(i + c) % 20]).unwrap();
+                }
+                commits.push(storage.commit(c as u64, "".to_string(), "".to_string()).unwrap());
+            }
+            assert!(storage.audit_entries(1).unwrap().is_clean());
+            assert_eq!(storage.verify_range(&commits[0], &commits[2]).unwrap().commits_verified, 3);
+            (commits, storage.get_merkle_stats().unwrap().counters)
+        };
+
+        let (batch_commits, batch_counters) = write_commits(CommitWriteMode::SingleBatch);
+        let (sorted_commits, sorted_counters) = write_commits(CommitWriteMode::SortedRuns { run_len: 7 });
+        let (atomic_commits, atomic_counters) = write_commits(CommitWriteMode::Atomic);
+        assert_eq!(batch_commits, sorted_commits);
+        assert_eq!(batch_counters, sorted_counters);
+        assert_eq!(batch_commits, atomic_commits);
+        assert_eq!(batch_counters, atomic_counters);
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn test_single_batch_commit_failure() {
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::new(db.clone()).unwrap();
+        for i in 0..300 {
+            storage.set(&key!["data", i], &vec![i as u8, 1]).unwrap();
+        }
+        let stored = |value: Vec<u8>| KeyValueStoreWithSchema::<MerkleStorage>::contains(db.as_ref(), &hash_blob(&value)).unwrap();
+
+        // all entries of a commit are one write, so a failed commit stores none of them
+        db.failure_injection().fail_nth_write(1);
+        assert!(storage.commit(0, "".to_string(), "".to_string()).is_err());
+        db.failure_injection().reset();
+        assert!((0..300).all(|i| !stored(vec![i as u8, 1])));
+
+        // records of the commit are the second write
+        db.failure_injection().fail_nth_write(2);
+        assert!(storage.commit(0, "".to_string(), "".to_string()).is_err());
+        db.failure_injection().reset();
+        assert!((0..300).all(|i| stored(vec![i as u8, 1])));
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn test_sorted_runs_commit_failure() {
+        let storage_config = || MerkleStorageConfig { commit_write_mode: CommitWriteMode::SortedRuns { run_len: 1 }, ..Default::default() };
+        // a crash at any of the runs: 20 blobs, 4 directories, "data", the root and the commit
+        for n in 1..=27 {
+            let db = Arc::new(get_db(Config::new()));
+            let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+            for i in 0..20 {
+                storage.set(&key!["data", i % 4, i], &vec![i as u8]).unwrap();
+            }
+            db.failure_injection().fail_nth_write(n);
+            assert!(storage.commit(0, "".to_string(), "".to_string()).is_err());
+            db.failure_injection().reset();
+
+            // retry skips entries stored by the failed commit, which must be complete subtrees
+            let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+            let storage = MerkleStorage::with_config(db, storage_config()).unwrap();
+            for i in 0..20 {
+                assert_eq!(vec![i as u8], storage.get_history(&commit, &key!["data", i % 4, i]).unwrap());
+            }
+            assert_eq!(1, storage.verify_range(&commit, &commit).unwrap().commits_verified);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "testing")]
+    fn test_atomic_commit_failure() {
+        let db = Arc::new(get_db(Config::new()));
+        let storage_config = MerkleStorageConfig {
+            commit_write_mode: CommitWriteMode::Atomic,
+            hash_prefix_index: true,
+            commit_annotations: true,
+            value_hash_index: true,
+            tombstone_retention: Some(10),
+            ..Default::default()
+        };
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config).unwrap();
+        storage.set(&key!["a", "b"], &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.delete(&key!["a", "b"]).unwrap();
+        storage.set(&key!["c"], &vec![2u8]).unwrap();
+
+        // crash while writing the commit leaves nothing of it behind
+        db.failure_injection().fail_nth_write(1);
+        assert!(storage.commit(1, "".to_string(), "".to_string()).is_err());
+        db.failure_injection().reset();
+        assert!(!KeyValueStoreWithSchema::<MerkleStorage>::contains(db.as_ref(), &hash_blob(&vec![2u8])).unwrap());
+        assert_eq!(1, storage.get_merkle_stats().unwrap().counters.commits);
+        assert!(storage.find_keys_with_value_hash(&hash_blob(&vec![2u8])).unwrap().is_empty());
+
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(2, storage.get_merkle_stats().unwrap().counters.commits);
+        assert_eq!(vec![key!["c"]], storage.find_keys_with_value_hash(&hash_blob(&vec![2u8])).unwrap());
+        assert!(storage.get_commit_annotation(&commit2).unwrap().is_some());
+        assert_eq!(commit2, storage.resolve_hash_prefix(&hex::encode(&commit2[..4])).unwrap());
+        assert!(storage.was_deleted_between(&key!["a", "b"], &commit1, &commit2).unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_persistent_counters() {
+        clean_db();
+
+        let key_ab: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        {
+            let mut storage = open_storage(Config::new());
+            assert_eq!(storage.get_merkle_stats().unwrap().counters, PersistentCounters::default());
+            storage.set(key_ab, &vec![1u8]).unwrap();
+            // commit, root tree, tree `a` and blob
+            storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        }
+
+        let mut storage = open_storage(Config::new());
+        let counters = storage.get_merkle_stats().unwrap().counters;
+        assert_eq!(counters.commits, 1);
+        assert_eq!(counters.entries_written, 4);
+
+        storage.set(&vec!["x".to_string()], &vec![1u8]).unwrap();
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        // blob is shared, so only commit and root tree are written
+        let counters = storage.get_merkle_stats().unwrap().counters;
+        assert_eq!(counters.commits, 2);
+        assert_eq!(counters.entries_written, 6);
+        assert_eq!(counters.gc_reclaimed_bytes, 0);
+    }
+
+    #[test]
+    fn test_commit_annotations() {
+        let storage_config = MerkleStorageConfig { commit_annotations: true, ..MerkleStorageConfig::default() };
+        let mut storage = get_storage_with_config(Config::new(), storage_config);
+        storage.set(&key!["data", "a", "x"], &vec![1u8]).unwrap();
+        storage.set(&key!["data", "b"], &vec![2u8]).unwrap();
+        storage.set(&key!["rolls", "1"], &vec![3u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let annotation = storage.get_commit_annotation(&commit1).unwrap().unwrap();
+        assert_eq!(annotation.changed_keys, 3);
+        assert_eq!(annotation.changed_keys_by_domain.get("data"), Some(&2));
+        assert_eq!(annotation.changed_keys_by_domain.get("rolls"), Some(&1));
+
+        // modify, add, delete a whole directory and replace a value by a directory
+        storage.set(&key!["data", "b"], &vec![4u8]).unwrap();
+        storage.set(&key!["data", "c"], &vec![]).unwrap();
+        storage.delete(&key!["data", "a"]).unwrap();
+        storage.set(&key!["rolls", "1", "y"], &vec![5u8]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        let annotation = storage.reader().get_commit_annotation(&commit2).unwrap().unwrap();
+        assert_eq!(annotation.changed_keys, 5);
+        assert_eq!(annotation.changed_keys_by_domain.get("data"), Some(&3));
+        assert_eq!(annotation.changed_keys_by_domain.get("rolls"), Some(&2));
+    }
+
+    #[test]
+    fn test_commit_annotations_disabled() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.get_commit_annotation(&commit).unwrap(), None);
+    }
+
+    #[test]
+    fn test_performance_profiles() {
+        let key_ab: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        for profile in [PerformanceProfile::LowMemory, PerformanceProfile::Balanced, PerformanceProfile::Throughput].iter() {
+            let db = Arc::new(get_db(profile.sled_config()));
+            let mut storage = MerkleStorage::with_config(db, profile.storage_config()).unwrap();
+
+            storage.set(key_ab, &vec![1u8, 2]).unwrap();
+            storage.set(&vec!["c".to_string()], &vec![]).unwrap();
+            assert_eq!(storage.get(key_ab).unwrap(), vec![1u8, 2]);
+            let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+            storage.set(key_ab, &vec![3u8]).unwrap();
+
+            assert_eq!(storage.get(key_ab).unwrap(), vec![3u8]);
+            assert_eq!(storage.get(&vec!["c".to_string()]).unwrap(), Vec::<u8>::new());
+            assert_eq!(storage.get_history(&commit, key_ab).unwrap(), vec![1u8, 2]);
+            assert!(matches!(storage.get_history(&commit, &vec!["a".to_string()]), Err(MerkleError::ValueIsNotABlob { .. })));
+        }
+    }
+
+    #[test]
+    fn test_get_errors() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+
+        let res = storage.get(&vec![]);
+        assert!(if let MerkleError::KeyEmpty = res.err().unwrap() { true } else { false });
+
+        let res = storage.get(&vec!["a".to_string()]);
+        assert!(if let MerkleError::ValueNotFound { .. } = res.err().unwrap() { true } else { false });
+    }
+
+    #[test]
+    fn test_mem_dirmem() {
+        let mut storage = get_storage(Config::new());
+        assert!(!storage.dirmem(&vec![]).unwrap());
+        storage.set(&key!["a", "b", "c"], &vec![1u8]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a", "v"], &vec![]).unwrap();
+
+        assert!(storage.mem(&key!["a", "b", "c"]).unwrap());
+        assert!(storage.mem(&key!["a", "v"]).unwrap());
+        assert!(!storage.mem(&key!["a", "b"]).unwrap());
+        assert!(!storage.mem(&key!["a", "x"]).unwrap());
+
+        assert!(storage.dirmem(&vec![]).unwrap());
+        assert!(storage.dirmem(&key!["a"]).unwrap());
+        assert!(storage.dirmem(&key!["a", "b"]).unwrap());
+        assert!(!storage.dirmem(&key!["a", "b", "c"]).unwrap());
+        assert!(!storage.dirmem(&key!["a", "v"]).unwrap());
+        assert!(!storage.dirmem(&key!["a", "b", "c", "d"]).unwrap());
+
+        storage.delete(&key!["a", "b", "c"]).unwrap();
+        assert!(!storage.dirmem(&key!["a", "b"]).unwrap());
+    }
+
+    #[test]
+    fn test_empty_value_vs_missing_key() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_flag: &ContextKey = &vec!["a".to_string(), "flag".to_string()];
+        storage.set(&vec!["a".to_string(), "other".to_string()], &vec![1u8]).unwrap();
+        let commit_missing = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert!(!storage.exists(key_flag).unwrap());
+        assert!(matches!(storage.get(key_flag), Err(MerkleError::ValueNotFound { .. })));
+
+        // staged empty value exists
+        storage.set(key_flag, &vec![]).unwrap();
+        assert!(storage.exists(key_flag).unwrap());
+        assert_eq!(storage.get(key_flag).unwrap(), Vec::<u8>::new());
+        let commit_empty = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_ne!(storage.get_commit(&commit_missing).unwrap().root_hash, storage.get_commit(&commit_empty).unwrap().root_hash);
+
+        // directories are not values
+        assert!(!storage.exists(&vec!["a".to_string()]).unwrap());
+        assert!(!storage.exists(&vec!["a".to_string(), "flag".to_string(), "x".to_string()]).unwrap());
+        assert!(matches!(storage.exists(&vec![]), Err(MerkleError::KeyEmpty)));
+
+        let reader = storage.reader();
+        assert!(reader.exists_at(&commit_empty, key_flag).unwrap());
+        assert!(!reader.exists_at(&commit_missing, key_flag).unwrap());
+        assert_eq!(reader.get_at(&commit_empty, key_flag).unwrap(), Vec::<u8>::new());
+        assert_eq!(reader.list(&commit_empty, &vec!["a".to_string()]).unwrap().unwrap().len(), 2);
+
+        // deleting the empty value restores the original hash
+        storage.delete(key_flag).unwrap();
+        assert!(!storage.exists(key_flag).unwrap());
+        let commit_deleted = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.get_commit(&commit_missing).unwrap().root_hash, storage.get_commit(&commit_deleted).unwrap().root_hash);
+    }
+
+    #[test]
+    fn test_get_raw() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_ab: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        let key_empty: &ContextKey = &vec!["e".to_string()];
+
+        storage.set(key_ab, &vec![1u8, 2u8, 3u8]).unwrap();
+        storage.set(key_empty, &vec![]).unwrap();
+        // staged value
+        assert_eq!(storage.get_raw(key_ab).unwrap(), vec![1u8, 2u8, 3u8]);
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(storage.get_raw(key_ab).unwrap(), vec![1u8, 2u8, 3u8]);
+        assert_eq!(storage.get_history_raw(&commit, key_ab).unwrap(), vec![1u8, 2u8, 3u8]);
+        assert_eq!(storage.reader().get_at_raw(&commit, key_empty).unwrap(), Vec::<u8>::new());
+        assert!(matches!(storage.get_history_raw(&commit, &vec!["a".to_string()]), Err(MerkleError::ValueIsNotABlob { .. })));
+        assert!(matches!(storage.get_history_raw(&commit, &vec!["x".to_string()]), Err(MerkleError::ValueNotFound { .. })));
+    }
+
+    #[test]
+    fn test_blob_format() {
+        let value = vec![1u8, 2u8, 3u8];
+        let bytes = Entry::Blob(value.clone()).encode().unwrap();
+        assert_eq!(&bytes[..4], &BLOB_VARIANT.to_le_bytes());
+        assert_eq!(&bytes[4..BLOB_HEADER_LEN], &3u64.to_le_bytes());
+        assert!(matches!(Entry::decode(&bytes).unwrap(), Entry::Blob(decoded) if decoded == value));
+        assert_eq!(Entry::blob_value(IVec::from(bytes)).unwrap().unwrap(), value);
+
+        let tree = Entry::Tree(Tree::new()).encode().unwrap();
+        assert!(Entry::blob_value(IVec::from(tree)).unwrap().is_none());
+        let mut truncated = Entry::Blob(value).encode().unwrap();
+        truncated.pop();
+        assert!(Entry::blob_value(IVec::from(truncated)).is_err());
+    }
+
+
+    #[test]
+    fn test_max_key_depth() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage_with_config(config, MerkleStorageConfig { max_key_depth: 3, ..Default::default() });
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_abcd: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()];
+
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        let res = storage.set(key_abcd, &vec![1u8]);
+        assert!(matches!(res.err().unwrap(), MerkleError::KeyTooDeep { depth: 4, max_depth: 3, .. }));
+        let res = storage.copy(&vec!["a".to_string()], key_abcd);
+        assert!(matches!(res.err().unwrap(), MerkleError::KeyTooDeep { .. }));
+
+        // a/b/c copied under x/y would end up as x/y/b/c
+        let res = storage.copy(&vec!["a".to_string()], &vec!["x".to_string(), "y".to_string()]);
+        assert!(matches!(res.err().unwrap(), MerkleError::KeyTooDeep { depth: 4, max_depth: 3, ref key } if key == "x/y/b/c"));
+        assert!(storage.get(&vec!["x".to_string(), "y".to_string(), "b".to_string()]).is_err());
+        storage.copy(&vec!["a".to_string(), "b".to_string()], &vec!["x".to_string(), "y".to_string()]).unwrap();
+        assert_eq!(storage.get(&vec!["x".to_string(), "y".to_string(), "c".to_string()]).unwrap(), vec![1u8]);
+
+        assert_eq!(storage.get(key_abc).unwrap(), vec![1u8]);
+    }
+
+    #[test]
+    fn test_deep_keys() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage_with_config(config, MerkleStorageConfig { max_key_depth: 10_000, ..Default::default() });
+        let key: ContextKey = (0..5_000).map(|i| i.to_string()).collect();
+
+        storage.set(&key, &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.checkout(&commit).unwrap();
+
+        assert_eq!(storage.get(&key).unwrap(), vec![1u8]);
+        assert_eq!(storage.get_key_values_by_prefix(&commit, &vec![]).unwrap().unwrap(), vec![(key.clone(), vec![1u8])]);
+        assert_eq!(storage.dag_iterator(&commit).unwrap().count(), 5_002);
+
+        storage.delete(&key).unwrap();
+        assert!(storage.get(&key).is_err());
+    }
+
+    #[test]
+    fn test_delete_last_key() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        storage.delete(key_abc).unwrap();
+
+        assert!(storage.get(key_abc).is_err());
+        assert!(storage.get_by_prefix(&vec![]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_empty_tree_policy() {
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_ax: &ContextKey = &vec!["a".to_string(), "x".to_string()];
+        let key_ayz: &ContextKey = &vec!["a".to_string(), "y".to_string(), "z".to_string()];
+        let root_hash = |storage: &mut MerkleStorage| {
+            let root = storage.get_staged_root().unwrap();
+            hash_tree(&root)
+        };
+
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut only_ax = get_storage(config);
+        only_ax.set(key_ax, &vec![1u8]).unwrap();
+        let only_ax_hash = root_hash(&mut only_ax);
+        drop(only_ax);
+
+        // pruned: deleting last key of `a/b` removes the directory
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        storage.set(key_ax, &vec![1u8]).unwrap();
+        storage.set(key_abc, &vec![2u8]).unwrap();
+        storage.delete(key_abc).unwrap();
+        assert_eq!(only_ax_hash, root_hash(&mut storage));
+        drop(storage);
+
+        // kept: `a/b` stays as an empty tree, but deleting a missing key creates nothing
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage_with_config(config, MerkleStorageConfig { empty_tree_policy: EmptyTreePolicy::Keep, ..Default::default() });
+        storage.set(key_ax, &vec![1u8]).unwrap();
+        storage.delete(key_ayz).unwrap();
+        assert_eq!(only_ax_hash, root_hash(&mut storage));
+        storage.set(key_abc, &vec![2u8]).unwrap();
+        storage.delete(key_abc).unwrap();
+        assert_ne!(only_ax_hash, root_hash(&mut storage));
+        let root = storage.get_staged_root().unwrap();
+        assert!(storage.find_tree(&root, &["a".to_string()]).unwrap().contains_key("b"));
+    }
+
+    #[test]
+    fn test_skip_existing_entries_on_commit() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        let key_a: &ContextKey = &vec!["a".to_string()];
+        let key_bc: &ContextKey = &vec!["b".to_string(), "c".to_string()];
+        storage.set(key_a, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(key_bc, &vec![2u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(0, storage.get_merkle_stats().unwrap().perf_stats.skipped_write_bytes);
+
+        // applying the same changes again produces only entries which are already persisted
+        storage.checkout(&commit1).unwrap();
+        storage.set(key_bc, &vec![2u8]).unwrap();
+        assert_eq!(commit2, storage.commit(0, "".to_string(), "".to_string()).unwrap());
+        assert!(storage.get_merkle_stats().unwrap().perf_stats.skipped_write_bytes > 0);
+        assert_eq!(vec![2u8], storage.get_history(&commit2, key_bc).unwrap());
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage_with_config(config, MerkleStorageConfig { tombstone_retention: Some(100), ..Default::default() });
+        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let key_x: &ContextKey = &vec!["x".to_string()];
+
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        storage.set(key_x, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(10, "".to_string(), "".to_string()).unwrap();
+        storage.delete(&vec!["a".to_string(), "b".to_string()]).unwrap();
+        let commit2 = storage.commit(20, "".to_string(), "".to_string()).unwrap();
+        // delete followed by set within one commit leaves no tombstone
+        storage.delete(key_x).unwrap();
+        storage.set(key_x, &vec![2u8]).unwrap();
+        let commit3 = storage.commit(30, "".to_string(), "".to_string()).unwrap();
+
+        assert!(storage.was_deleted_between(key_abc, &commit1, &commit2).unwrap());
+        assert!(storage.was_deleted_between(key_abc, &commit1, &commit3).unwrap());
+        assert!(!storage.was_deleted_between(key_abc, &commit2, &commit3).unwrap());
+        assert!(!storage.was_deleted_between(key_x, &commit1, &commit3).unwrap());
+
+        // tombstones out of retention window are dropped by the next commit
+        assert!(storage.tombstones.contains(&"a/b".to_string()).unwrap());
+        storage.set(key_abc, &vec![1u8]).unwrap();
+        storage.commit(200, "".to_string(), "".to_string()).unwrap();
+        assert!(!storage.tombstones.contains(&"a/b".to_string()).unwrap());
+        assert!(!storage.tombstone_expiry.contains(&20).unwrap());
+        assert!(!storage.was_deleted_between(key_abc, &commit1, &commit2).unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_resolve_hash_prefix() {
+        clean_db();
+
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = open_storage_with_config(config, MerkleStorageConfig { hash_prefix_index: true, ..Default::default() });
+        let key: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        storage.set(key, &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let root_hash = storage.get_commit(&commit).unwrap().root_hash;
+
+        for hash in &[commit, root_hash] {
+            let hex_hash = hex::encode(hash);
+            assert_eq!(storage.resolve_hash_prefix(&hex_hash[..6]).unwrap(), *hash);
+            assert_eq!(storage.resolve_hash_prefix(&hex_hash[..11].to_uppercase()).unwrap(), *hash);
+            assert_eq!(storage.resolve_hash_prefix(&hex_hash).unwrap(), *hash);
+        }
+
+        assert!(matches!(storage.resolve_hash_prefix("9ae1b"), Err(MerkleError::InvalidHashPrefix { .. })));
+        assert!(matches!(storage.resolve_hash_prefix("9ae1bz"), Err(MerkleError::InvalidHashPrefix { .. })));
+        let mut missing = hex::encode(commit);
+        missing.replace_range(..1, if missing.starts_with('0') { "1" } else { "0" });
+        assert!(matches!(storage.resolve_hash_prefix(&missing), Err(MerkleError::EntryNotFound { .. })));
+
+        drop(storage);
+        let storage = open_storage(Config::new());
+        assert!(matches!(storage.resolve_hash_prefix(&hex::encode(commit)), Err(MerkleError::HashPrefixIndexDisabled)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_dirty_drop_hook() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        clean_db();
+
+        let dropped_dirty = Arc::new(AtomicUsize::new(0));
+        let key_a: &ContextKey = &vec!["a".to_string()];
+        {
+            let config = Config::new().cache_capacity(32 * 1024 * 1024);
+            let mut storage = open_storage(config);
+            let counter = dropped_dirty.clone();
+            storage.set_dirty_drop_hook(Box::new(move |_, _| { counter.fetch_add(1, Ordering::SeqCst); }));
+
+            assert!(!storage.is_dirty());
+            storage.set(key_a, &vec![1u8]).unwrap();
+            assert!(storage.is_dirty());
+            storage.commit(0, "".to_string(), "".to_string()).unwrap();
+            assert!(!storage.is_dirty());
+        }
+        assert_eq!(0, dropped_dirty.load(Ordering::SeqCst));
+
+        {
+            let config = Config::new().cache_capacity(32 * 1024 * 1024);
+            let mut storage = open_storage(config);
+            let counter = dropped_dirty.clone();
+            storage.set_dirty_drop_hook(Box::new(move |_, staged| {
+                assert!(staged > 0);
+                counter.fetch_add(1, Ordering::SeqCst);
+            }));
+            storage.delete(key_a).unwrap();
+            assert!(storage.is_dirty());
+        }
+        assert_eq!(1, dropped_dirty.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_commit_expecting() {
+        let mut storage = get_storage(Config::new());
+        let mut reference = get_storage(Config::new());
+        for storage in &mut [&mut storage, &mut reference] {
+            storage.set(&key!["a"], &vec![1u8]).unwrap();
+        }
+        let expected = reference.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        // nothing is persisted on mismatch
+        assert!(matches!(storage.commit_expecting(2, "".to_string(), "".to_string(), &expected), Err(MerkleError::CommitHashMismatch { .. })));
+        assert_eq!(None, storage.get_last_commit_hash());
+        assert!(storage.get_commit(&expected).is_err());
+        assert_eq!(expected, storage.commit_expecting(1, "".to_string(), "".to_string(), &expected).unwrap());
+        assert_eq!(Some(expected), storage.get_last_commit_hash());
+    }
+
+    #[test]
+    fn test_commit_validator() {
+        let mut storage = get_storage(Config::new());
+        let (key_a, key_b) = (key!["a"], key!["b", "c"]);
+        storage.set(&key_a, &vec![1u8]).unwrap();
+        let first = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.set_commit_validator(Box::new(|proposal| {
+            if proposal.changes.iter().any(|change| change.key[0] == "b") {
+                Err(format!("{} may not change b", proposal.author))
+            } else {
+                Ok(())
+            }
+        }));
+        storage.set(&key_b, &vec![2u8]).unwrap();
+        storage.delete(&key_a).unwrap();
+        let rejected = storage.commit(1, "mallory".to_string(), "".to_string());
+        assert!(matches!(rejected, Err(MerkleError::CommitRejected { ref reason }) if reason == "mallory may not change b"));
+
+        // nothing persisted, staging intact
+        assert_eq!(first, storage.get_last_commit_hash().unwrap());
+        assert!(storage.is_dirty());
+        assert_eq!(vec![2u8], storage.get(&key_b).unwrap());
+
+        storage.set_commit_validator(Box::new(move |proposal| {
+            assert_eq!(Some(first), proposal.parent_commit_hash);
+            let changed: Vec<_> = proposal.changes.iter()
+                .map(|change| (change.key.clone(), change.old_value_hash.is_some(), change.new_value_hash.is_some()))
+                .collect();
+            assert!(changed.contains(&(key!["a"], true, false)));
+            assert!(changed.contains(&(key!["b", "c"], false, true)));
+            assert_eq!(2, changed.len());
+            Ok(())
+        }));
+        let second = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(second, storage.get_last_commit_hash().unwrap());
+    }
+
+    #[test]
+    fn test_diff_prefix() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["data", "votes", "a"], &vec![1u8]).unwrap();
+        storage.set(&key!["data", "votes", "b"], &vec![2u8]).unwrap();
+        storage.set(&key!["data", "rolls", "a"], &vec![3u8]).unwrap();
+        let first = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.set(&key!["data", "votes", "a"], &vec![4u8]).unwrap();
+        storage.delete(&key!["data", "votes", "b"]).unwrap();
+        storage.set(&key!["data", "votes", "c", "d"], &vec![5u8]).unwrap();
+        storage.set(&key!["data", "rolls", "a"], &vec![6u8]).unwrap();
+        let second = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        let changes = storage.diff_prefix(&first, &second, &key!["data", "votes"]).unwrap();
+        let changed: Vec<_> = changes.iter().map(|change| change.key.clone()).collect();
+        assert_eq!(vec![key!["data", "votes", "a"], key!["data", "votes", "b"], key!["data", "votes", "c", "d"]], changed);
+        assert_eq!(Some(hash_blob(&vec![1u8])), changes[0].old_value_hash);
+        assert_eq!(Some(hash_blob(&vec![4u8])), changes[0].new_value_hash);
+        assert_eq!(None, changes[1].new_value_hash);
+        assert_eq!(None, changes[2].old_value_hash);
+
+        // prefix pointing to a value, missing prefix, whole context, reversed direction
+        assert_eq!(1, storage.diff_prefix(&first, &second, &key!["data", "rolls", "a"]).unwrap().len());
+        assert!(storage.diff_prefix(&first, &second, &key!["data", "missing"]).unwrap().is_empty());
+        assert_eq!(4, storage.diff_prefix(&first, &second, &key![]).unwrap().len());
+        let reversed = storage.reader().diff_prefix(&second, &first, &key!["data", "votes"]).unwrap();
+        assert_eq!(Some(hash_blob(&vec![2u8])), reversed[1].new_value_hash);
+        assert!(storage.diff_prefix(&first, &first, &key!["data"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["data", "a"], &vec![1u8]).unwrap();
+        storage.set(&key!["data", "b"], &vec![2u8]).unwrap();
+        storage.set(&key!["data", "c"], &vec![3u8]).unwrap();
+        for i in 0..100u8 {
+            storage.set(&key!["big", &i.to_string()], &vec![i]).unwrap();
+        }
+        let first = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.set(&key!["data", "a"], &vec![4u8]).unwrap();
+        storage.delete(&key!["data", "b"]).unwrap();
+        storage.set(&key!["data", "c", "d"], &vec![5u8]).unwrap();
+        storage.set(&key!["data", "e"], &vec![6u8]).unwrap();
+        let second = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        assert_eq!(vec![
+            ContextChange::Modified { key: key!["data", "a"], old_value: vec![1], new_value: vec![4] },
+            ContextChange::Removed { key: key!["data", "b"], value: vec![2] },
+            ContextChange::Removed { key: key!["data", "c"], value: vec![3] },
+            ContextChange::Added { key: key!["data", "c", "d"], value: vec![5] },
+            ContextChange::Added { key: key!["data", "e"], value: vec![6] },
+        ], storage.diff(&first, &second).unwrap());
+        assert_eq!(&key!["data", "a"], storage.reader().diff(&second, &first).unwrap()[0].key());
+        assert!(storage.diff(&first, &first).unwrap().is_empty());
+        assert!(storage.diff(&first, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_export() {
+        let export_path = "_merkle_export_test";
+
+        let mut storage = get_storage(Config::new());
+        for i in 0..50 {
+            storage.set(&key!["data", i, "x"], &vec![i as u8; 100]).unwrap();
+        }
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let file = fs::File::create(export_path).unwrap();
+        let handle = storage.snapshot().unwrap().export(file, ExportConfig { max_bytes_per_sec: Some(100_000) });
+        // commits continue while export runs
+        storage.set(&key!["data", "0", "x"], &vec![255u8]).unwrap();
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        let report = handle.join().unwrap();
+        assert_eq!(Some(commit), report.head);
+        // commit, root, data, 50 trees and 50 distinct blobs
+        assert_eq!(103, report.entries);
+        assert!(storage.oldest_pinned_epoch().is_none());
+
+        let mut imported = get_storage(Config::new());
+        let import_report = imported.import_snapshot(fs::File::open(export_path).unwrap(), ImportConfig::default()).unwrap();
+        assert_eq!(report, import_report);
+        assert_eq!(vec![7u8; 100], imported.get_history(&commit, &key!["data", 7, "x"]).unwrap());
+        assert_eq!(vec![0u8; 100], imported.get_history(&commit, &key!["data", 0, "x"]).unwrap());
+
+        // truncated export
+        let bytes = fs::read(export_path).unwrap();
+        assert!(imported.import_snapshot(&bytes[..bytes.len() - 1], ImportConfig::default()).is_err());
+        let _ = fs::remove_file(export_path);
+    }
+
+    #[test]
+    fn test_snapshot_export_refs() {
+        let export_path = "_merkle_export_refs_test";
+
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let first = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.update_ref("checkpoint", None, &first).unwrap();
+        // ref of a branch not reachable from head
+        storage.set(&key!["b"], &vec![2u8]).unwrap();
+        let side = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        storage.update_ref("side", None, &side).unwrap();
+        storage.checkout(&first).unwrap();
+        storage.set(&key!["c"], &vec![3u8]).unwrap();
+        let head = storage.commit(2, "".to_string(), "".to_string()).unwrap();
+        storage.snapshot().unwrap().export(fs::File::create(export_path).unwrap(), ExportConfig::default()).join().unwrap();
+
+        let mut data_only = get_storage(Config::new());
+        data_only.import_snapshot(fs::File::open(export_path).unwrap(), ImportConfig::default()).unwrap();
+        assert_eq!(None, data_only.get_ref("checkpoint").unwrap());
+        assert_eq!(vec![2u8], data_only.get_history(&side, &key!["b"]).unwrap());
+        assert_eq!(vec![3u8], data_only.get_history(&head, &key!["c"]).unwrap());
+
+        let mut restored = get_storage(Config::new());
+        restored.import_snapshot(fs::File::open(export_path).unwrap(), ImportConfig { restore_refs: true }).unwrap();
+        assert_eq!(Some(first), restored.get_ref("checkpoint").unwrap());
+        assert_eq!(Some(side), restored.get_ref("side").unwrap());
+        assert_eq!(vec![2u8], restored.get_history(&side, &key!["b"]).unwrap());
+        let _ = fs::remove_file(export_path);
+    }
+
+    /// Peer serving entries of a reader, counting the served ones
+    struct PeerSource(ContextReader, AtomicU64);
+
+    impl EntrySource for PeerSource {
+        fn get(&self, hash: &EntryHash) -> Result<Option<Vec<u8>>, EntrySourceError> {
+            match self.0.read_entry(hash) {
+                Ok(entry) => {
+                    self.1.fetch_add(1, Ordering::Relaxed);
+                    Ok(Some(entry.encode().unwrap()))
+                }
+                Err(MerkleError::EntryNotFound { .. }) => Ok(None),
+                Err(error) => Err(EntrySourceError::Failed { reason: error.to_string() }),
+            }
+        }
+    }
+
+    #[test]
+    fn test_entry_sources() {
+        let pack_path = "_merkle_pack_test";
+
+        let mut storage = get_storage(Config::new());
+        for i in 0..50 {
+            storage.set(&key!["data", i, "x"], &vec![i as u8; 100]).unwrap();
+        }
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let file = fs::File::create(pack_path).unwrap();
+        storage.snapshot().unwrap().export(file, ExportConfig::default()).join().unwrap();
+        storage.set(&key!["data", "0", "x"], &vec![255u8]).unwrap();
+        let commit = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        // entries of the last commit are fetched from the peer, the rest from the pack
+        let pack = PackEntrySource::open(pack_path).unwrap();
+        assert_eq!(103, pack.entries());
+        let peer = Arc::new(PeerSource(storage.reader(), AtomicU64::new(0)));
+        let mut synced = get_storage(Config::new());
+        synced.add_entry_source(Arc::new(pack));
+        synced.add_entry_source(peer.clone());
+        synced.checkout(&commit).unwrap();
+        assert_eq!(vec![7u8; 100], synced.get(&key!["data", 7, "x"]).unwrap());
+        assert_eq!(vec![255u8], synced.get(&key!["data", 0, "x"]).unwrap());
+        // commit, root, data, data/0 and its blob
+        assert_eq!(5, peer.1.load(Ordering::Relaxed));
+        assert!(synced.db.contains(&commit).unwrap());
+        assert_eq!(vec![255u8], synced.reader().get_at(&commit, &key!["data", 0, "x"]).unwrap());
+        assert_eq!(5, peer.1.load(Ordering::Relaxed));
+        assert!(matches!(synced.get_commit(&[1; HASH_LEN]), Err(MerkleError::EntryNotFound { .. })));
+
+        // entries not matching their hash are rejected
+        struct LyingSource;
+        impl EntrySource for LyingSource {
+            fn get(&self, _: &EntryHash) -> Result<Option<Vec<u8>>, EntrySourceError> {
+                Ok(Some(Entry::Blob(vec![1u8]).encode().unwrap()))
+            }
+        }
+        synced.add_entry_source(Arc::new(LyingSource));
+        assert!(matches!(synced.get_commit(&[1; HASH_LEN]), Err(MerkleError::EntryHashMismatch { .. })));
+        let _ = fs::remove_file(pack_path);
+    }
+
+    #[test]
+    fn test_entry_cache() {
+        let storage_config = MerkleStorageConfig { entry_cache_capacity: 20, ..MerkleStorageConfig::default() };
+        let mut storage = get_storage_with_config(Config::new(), storage_config);
+        for i in 0..100 {
+            storage.set(&key!["data", i], &vec![i as u8]).unwrap();
+        }
+        storage.set(&key!["hot", "a"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let reader = storage.reader();
+
+        // hot path read twice is protected
+        reader.get_at(&commit, &key!["hot", "a"]).unwrap();
+        reader.get_at(&commit, &key!["hot", "a"]).unwrap();
+        let stats = storage.get_merkle_stats().unwrap().perf_stats;
+        assert!(stats.entry_cache_hits > 0);
+
+        // traversal larger than the cache does not evict it
+        assert_eq!(101, reader.materialize(&commit).unwrap().count());
+        let before = storage.get_merkle_stats().unwrap().perf_stats;
+        reader.get_at(&commit, &key!["hot", "a"]).unwrap();
+        let after = storage.get_merkle_stats().unwrap().perf_stats;
+        assert_eq!(before.entry_cache_misses, after.entry_cache_misses);
+        assert_eq!(before.entry_cache_hits + 4, after.entry_cache_hits);
+    }
+
+    #[test]
+    fn test_keys_with_separator_in_fragment() {
+        let mut storage = get_storage(Config::new());
+        let odd: ContextKey = vec!["data".to_string(), "a/b".to_string(), "c\\".to_string()];
+        storage.set(&odd, &vec![1u8]).unwrap();
+        storage.set(&key!["data", "a", "b"], &vec![2u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let listed = storage.get_key_values_by_prefix(&commit, &key!["data"]).unwrap().unwrap();
+        assert_eq!(vec![(key!["data", "a", "b"], vec![2u8]), (odd, vec![1u8])], listed);
+    }
+
+    #[test]
+    fn test_external_blobs() {
+        let sink_dir = std::env::temp_dir().join(format!("_merkle_blob_sink_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&sink_dir);
+
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::new(db.clone()).unwrap();
+        storage.set_blob_sink(Arc::new(FsBlobSink::new(&sink_dir).unwrap()), 1000);
+        let mut inline = MerkleStorage::new(Arc::new(SledDBWrapper::new(Config::new().temporary(true).open().unwrap()))).unwrap();
+        let huge = vec![7u8; 5000];
+        for storage in [&mut storage, &mut inline].iter_mut() {
+            storage.set(&key!["small"], &vec![1u8; 10]).unwrap();
+            storage.set(&key!["a", "huge"], &huge).unwrap();
+        }
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        // hashes don't depend on where values are kept
+        assert_eq!(commit, inline.commit(0, "".to_string(), "".to_string()).unwrap());
+
+        let hash = hash_blob(&huge);
+        assert!(KeyValueStoreWithSchema::<MerkleStorage>::get(db.as_ref(), &hash).unwrap().unwrap().len() < 100);
+        assert_eq!(huge.len() as u64, fs::metadata(sink_dir.join(hex::encode(hash))).unwrap().len());
+        assert_eq!(huge, storage.get(&key!["a", "huge"]).unwrap());
+        assert_eq!(&huge[..], &storage.get_history_raw(&commit, &key!["a", "huge"]).unwrap()[..]);
+        assert_eq!(huge, storage.reader().get_at(&commit, &key!["a", "huge"]).unwrap());
+        let mut streamed = Vec::new();
+        storage.get_blob_reader(&key!["a", "huge"]).unwrap().read_to_end(&mut streamed).unwrap();
+        assert_eq!(huge, streamed);
+        assert!(storage.capabilities().external_blobs);
+        assert!(storage.audit_entries(1).unwrap().is_clean());
+
+        let mut histograms = NodeHistograms::default();
+        histograms.blob_sizes.record(10);
+        histograms.blob_sizes.record(5000);
+        assert_eq!(histograms.blob_sizes, storage.node_histograms().unwrap().blob_sizes);
+
+        // corrupted value in the sink
+        fs::write(sink_dir.join(hex::encode(hash)), vec![8u8; 5000]).unwrap();
+        assert!(matches!(storage.get(&key!["a", "huge"]), Err(MerkleError::EntryHashMismatch { .. })));
+        let mut streamed = Vec::new();
+        let error = storage.get_blob_reader(&key!["a", "huge"]).unwrap().read_to_end(&mut streamed).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        let mut without_sink = MerkleStorage::new(db).unwrap();
+        without_sink.checkout(&commit).unwrap();
+        assert_eq!(vec![1u8; 10], without_sink.get(&key!["small"]).unwrap());
+        assert!(matches!(without_sink.get(&key!["a", "huge"]), Err(MerkleError::BlobSinkMissing { .. })));
+
+        let _ = fs::remove_dir_all(&sink_dir);
+    }
+
+    #[test]
+    fn test_corrupted_data_is_error() {
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::new(db.clone()).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        storage.set(&key!["b", "c"], &vec![2u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let root_hash = storage.get_commit(&commit).unwrap().root_hash;
+        let tree_hash = storage.find_node(&root_hash, &key!["b"]).unwrap().entry_hash;
+
+        // blob claiming to be longer than the address space
+        let mut huge_blob = BLOB_VARIANT.to_le_bytes().to_vec();
+        huge_blob.extend(&u64::MAX.to_le_bytes());
+        KeyValueStoreWithSchema::<MerkleStorage>::put(db.as_ref(), &hash_blob(&vec![1u8]), &huge_blob).unwrap();
+        assert!(storage.get_history_raw(&commit, &key!["a"]).is_err());
+        assert!(storage.get_history(&commit, &key!["a"]).is_err());
+
+        KeyValueStoreWithSchema::<MerkleStorage>::put(db.as_ref(), &tree_hash, &vec![0xffu8; 7]).unwrap();
+        assert!(storage.get_history(&commit, &key!["b", "c"]).is_err());
+        assert!(storage.reader().materialize(&commit).unwrap().any(|item| item.is_err()));
+        assert!(storage.audit_entries(2).is_ok());
+
+        // import stream announcing a huge record
+        let header = ExportHeader { entry_format_version: ENTRY_FORMAT_VERSION, head: None, refs: BTreeMap::new() };
+        let mut stream = bincode::serialize(&header).unwrap();
+        stream.push(1);
+        stream.extend(&[0u8; HASH_LEN]);
+        stream.extend(&u64::MAX.to_le_bytes());
+        assert!(storage.import_snapshot(&stream[..], ImportConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_get_commit_with_root() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a", "b"], &vec![1u8]).unwrap();
+        storage.set(&key!["c"], &vec![2u8]).unwrap();
+        let commit_hash = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let (commit, root) = storage.reader().get_commit_with_root(&commit_hash).unwrap();
+        assert_eq!(commit.root_hash, hash_tree(&root));
+        assert_eq!(vec!["a", "c"], root.keys().collect::<Vec<_>>());
+        assert!(matches!(storage.get_commit_with_root(&commit.root_hash), Err(MerkleError::FoundUnexpectedStructure { .. })));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut storage = get_storage(Config::new());
+        let key_a: &ContextKey = &vec!["a".to_string()];
+        let empty = storage.snapshot().unwrap();
+        assert!(empty.head().is_none());
+        assert!(matches!(empty.get(key_a), Err(MerkleError::ValueNotFound { .. })));
+
+        storage.set(key_a, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let snapshot = storage.snapshot().unwrap();
+        assert_eq!(storage.oldest_pinned_epoch(), Some(empty.epoch()));
+        drop(empty);
+        assert_eq!(storage.oldest_pinned_epoch(), Some(snapshot.epoch()));
+
+        storage.set(key_a, &vec![2u8]).unwrap();
+        storage.set(&vec!["b".to_string()], &vec![2u8]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            assert_eq!(snapshot.head(), Some(commit1));
+            assert_eq!(snapshot.get(&vec!["a".to_string()]).unwrap(), vec![1u8]);
+            assert_eq!(snapshot.list(&vec![]).unwrap().unwrap().len(), 1);
+            assert_eq!(snapshot.reader().get_at(&commit2, &vec!["a".to_string()]).unwrap(), vec![2u8]);
+        });
+        handle.join().unwrap();
+        assert_eq!(storage.oldest_pinned_epoch(), None);
+    }
+
+    #[test]
+    fn test_refs_compare_and_swap() {
+        let db = Arc::new(get_db(Config::new()));
+        let mut writer1 = MerkleStorage::new(db.clone()).unwrap();
+        let writer2 = MerkleStorage::new(db).unwrap();
+        writer1.set(&vec!["a".to_string()], &vec![1u8]).unwrap();
+        let commit1 = writer1.commit(0, "".to_string(), "".to_string()).unwrap();
+        writer1.set(&vec!["a".to_string()], &vec![2u8]).unwrap();
+        let commit2 = writer1.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        writer1.update_ref("main", None, &commit1).unwrap();
+        assert!(matches!(writer2.update_ref("main", None, &commit2), Err(MerkleError::RefUpdateConflict { .. })));
+        let snapshot = writer1.snapshot().unwrap();
+        writer2.update_ref("main", Some(&commit1), &commit2).unwrap();
+
+        // writer1 lost the race
+        match writer1.update_ref("main", Some(&commit1), &commit1) {
+            Err(MerkleError::RefUpdateConflict { name, expected, found }) => {
+                assert_eq!(name, "main");
+                assert_eq!(expected, Some(HashType::ContextHash.bytes_to_string(&commit1)));
+                assert_eq!(found, Some(HashType::ContextHash.bytes_to_string(&commit2)));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(writer1.get_ref("main").unwrap(), Some(commit2));
+        assert_eq!(writer1.reader().get_ref("main").unwrap(), Some(commit2));
+        assert_eq!(snapshot.get_ref("main"), Some(commit1));
+
+        // refs point to commits only
+        let root_hash = writer1.get_commit(&commit2).unwrap().root_hash;
+        assert!(writer1.update_ref("other", None, &root_hash).is_err());
+
+        assert!(matches!(writer1.delete_ref("main", &commit1), Err(MerkleError::RefUpdateConflict { .. })));
+        writer1.delete_ref("main", &commit2).unwrap();
+        assert_eq!(writer2.get_ref("main").unwrap(), None);
+    }
+
+    #[test]
+    fn test_named_refs() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        storage.set_ref("head", &commit2).unwrap();
+        storage.set_ref("checkpoint-42", &commit1).unwrap();
+        assert_eq!(vec![("checkpoint-42".to_string(), commit1), ("head".to_string(), commit2)], storage.list_refs().unwrap());
+        let root_hash = storage.get_commit(&commit2).unwrap().root_hash;
+        assert!(storage.set_ref("head", &root_hash).is_err());
+
+        assert_eq!(commit1, storage.checkout_ref("checkpoint-42").unwrap());
+        assert_eq!(vec![1u8], storage.get(&key!["a"]).unwrap());
+        assert_eq!(Some(commit1), storage.get_last_commit_hash());
+
+        // overwritten regardless of where the ref pointed
+        storage.set_ref("head", &commit1).unwrap();
+        assert_eq!(Some(commit1), storage.get_ref("head").unwrap());
+        assert!(matches!(storage.checkout_ref("missing"), Err(MerkleError::RefNotFound { .. })));
+        assert_eq!(Some(commit1), storage.get_last_commit_hash());
+    }
+
+    #[test]
+    fn test_auto_advance_ref() {
+        let db = Arc::new(get_db(Config::new()));
+        let storage_config = MerkleStorageConfig { auto_advance_ref: Some("main".to_string()), ..Default::default() };
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(Some(commit1), storage.get_ref("main").unwrap());
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(Some(commit2), storage.get_ref("main").unwrap());
+
+        // commit on top of an older commit would orphan commit2
+        storage.checkout(&commit1).unwrap();
+        storage.set(&key!["b"], &vec![3u8]).unwrap();
+        match storage.commit(2, "".to_string(), "".to_string()) {
+            Err(MerkleError::NonFastForward { name, tip, parent }) => {
+                assert_eq!(name, "main");
+                assert_eq!(tip, Some(HashType::ContextHash.bytes_to_string(&commit2)));
+                assert_eq!(parent, Some(HashType::ContextHash.bytes_to_string(&commit1)));
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(Some(commit2), storage.get_ref("main").unwrap());
+        // staged changes are kept
+        assert!(storage.is_dirty());
+
+        // ref moved by another writer
+        storage.checkout(&commit2).unwrap();
+        storage.set(&key!["c"], &vec![4u8]).unwrap();
+        MerkleStorage::new(db.clone()).unwrap().update_ref("main", Some(&commit2), &commit1).unwrap();
+        assert!(matches!(storage.commit(3, "".to_string(), "".to_string()), Err(MerkleError::NonFastForward { .. })));
+        assert_eq!(Some(commit1), storage.get_ref("main").unwrap());
+        assert_eq!(Some(commit2), storage.get_last_commit_hash());
+
+        // ref moved while the commit is being made, nothing is persisted
+        storage.checkout(&commit1).unwrap();
+        storage.set(&key!["d"], &vec![5u8]).unwrap();
+        let refs = db.clone();
+        storage.set_commit_validator(Box::new(move |_| {
+            KeyValueStoreWithSchema::<RefSchema>::put(refs.as_ref(), &"main".to_string(), &commit2).map_err(|error| error.to_string())
+        }));
+        let commits = storage.get_merkle_stats().unwrap().counters.commits;
+        assert!(matches!(storage.commit(4, "".to_string(), "".to_string()), Err(MerkleError::NonFastForward { .. })));
+        assert_eq!(Some(commit2), storage.get_ref("main").unwrap());
+        assert_eq!(commits, storage.get_merkle_stats().unwrap().counters.commits);
+        assert!(storage.is_dirty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_close() {
+        clean_db();
+
+        let db = Arc::new(open_db(get_db_name(), Config::new().flush_every_ms(None)));
+        db.set_flush_interval(Some(Duration::from_millis(10)));
+        let mut storage = MerkleStorage::new(db).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let stats = storage.close().unwrap();
+        assert_eq!(1, stats.counters.commits);
+
+        // lock is released, so the database can be opened again
+        let mut storage = open_storage(Config::new());
+        storage.checkout(&commit).unwrap();
+        assert_eq!(vec![1u8], storage.get(&key!["a"]).unwrap());
+    }
+
+    #[test]
+    fn test_database_header() {
+        let db = Arc::new(get_db(Config::new()));
+        let storage = MerkleStorage::new(db.clone()).unwrap();
+        let write_header = |header: &DatabaseHeader| storage.metadata.put(&HEADER_KEY.to_string(), &bincode::serialize(header).unwrap()).unwrap();
+        let stored: DatabaseHeader = bincode::deserialize(&storage.metadata.get(&HEADER_KEY.to_string()).unwrap().unwrap()).unwrap();
+        assert_eq!(stored, DatabaseHeader::current());
+        assert!(MerkleStorage::new(db.clone()).is_ok());
+
+        // builds of other versions with the same formats are compatible
+        write_header(&DatabaseHeader { crate_version: "0.0.1".to_string(), ..DatabaseHeader::current() });
+        assert!(MerkleStorage::new(db.clone()).is_ok());
+
+        write_header(&DatabaseHeader { hash_scheme: "sha256".to_string(), ..DatabaseHeader::current() });
+        match MerkleStorage::new(db.clone()) {
+            Err(MerkleError::IncompatibleDatabase { field, expected, found }) => {
+                assert_eq!(field, "hash scheme");
+                assert_eq!(expected, DatabaseHeader::current().hash_scheme);
+                assert_eq!(found, "sha256");
+            }
+            res => panic!("unexpected result {:?}", res.err()),
+        }
+
+        write_header(&DatabaseHeader { entry_format_version: ENTRY_FORMAT_VERSION + 1, ..DatabaseHeader::current() });
+        assert!(matches!(MerkleStorage::new(db.clone()), Err(MerkleError::IncompatibleDatabase { field: "entry format version", .. })));
+
+        // older entry formats are readable, the header is upgraded
+        write_header(&DatabaseHeader::legacy());
+        assert!(MerkleStorage::new(db.clone()).is_ok());
+        let stored: DatabaseHeader = bincode::deserialize(&storage.metadata.get(&HEADER_KEY.to_string()).unwrap().unwrap()).unwrap();
+        assert_eq!(stored, DatabaseHeader::current());
+
+        storage.metadata.put(&HEADER_KEY.to_string(), &vec![1, 2]).unwrap();
+        assert!(matches!(MerkleStorage::new(db), Err(MerkleError::IncompatibleDatabase { field: "header", .. })));
+
+        // database with entries but no header predates headers, it is not reported as created
+        let db = Arc::new(get_db(Config::new()));
+        let blob = Entry::Blob(vec![1u8]);
+        KeyValueStoreWithSchema::<MerkleStorage>::put(db.as_ref(), &blob.hash(), &blob.encode().unwrap()).unwrap();
+        assert!(!MerkleStorage::new(db.clone()).unwrap().open_report().created);
+        let stored = KeyValueStoreWithSchema::<MetadataSchema>::get(db.as_ref(), &HEADER_KEY.to_string()).unwrap().unwrap();
+        assert_eq!(bincode::deserialize::<DatabaseHeader>(&stored).unwrap(), DatabaseHeader::current());
+    }
+
+    #[test]
+    fn test_legacy_entry_format() {
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::new(db.clone()).unwrap();
+        storage.set(&key!["a", "b"], &vec![1u8]).unwrap();
+        storage.set(&key!["c"], &vec![2u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        drop(storage);
+
+        // rewrite the database as written before headers, with child names of trees in full
+        let entries: Vec<_> = KeyValueStoreWithSchema::<MerkleStorage>::iterator(db.as_ref(), IteratorMode::Start).unwrap()
+            .map(|(hash, bytes)| (hash.unwrap(), Entry::decode(&bytes.unwrap()).unwrap()))
+            .collect();
+        for (hash, entry) in &entries {
+            if let Entry::Tree(tree) = entry {
+                let legacy = bincode::serialize(&(TREE_VARIANT, tree)).unwrap();
+                assert_eq!(Some(EntryKind::Tree), EntryKind::of(&legacy));
+                KeyValueStoreWithSchema::<MerkleStorage>::put(db.as_ref(), hash, &legacy).unwrap();
+            }
+        }
+        KeyValueStoreWithSchema::<MetadataSchema>::delete(db.as_ref(), &HEADER_KEY.to_string()).unwrap();
+
+        let config = MerkleStorageConfig { write_once: WriteOnceMode::Reject, ..Default::default() };
+        let mut storage = MerkleStorage::with_config(db, config).unwrap();
+        assert_eq!(vec![1u8], storage.get_history(&commit, &key!["a", "b"]).unwrap());
+        assert_eq!(1, storage.verify_range(&commit, &commit).unwrap().commits_verified);
+
+        // trees equal to legacy ones are written again in the current format without violations
+        storage.checkout(&commit).unwrap();
+        storage.delete(&key!["a"]).unwrap();
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a", "b"], &vec![1u8]).unwrap();
+        let recommitted = storage.commit(2, "".to_string(), "".to_string()).unwrap();
+        let root_hash = storage.get_commit(&commit).unwrap().root_hash;
+        assert_eq!(root_hash, storage.get_commit(&recommitted).unwrap().root_hash);
+    }
+
+    #[test]
+    fn test_materialize() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["b", "x"], &vec![2u8]).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        storage.set(&key!["b", "w", "z"], &vec![]).unwrap();
+        storage.set(&key!["c"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["d"], &vec![4u8]).unwrap();
+
+        let key_values: Vec<_> = storage.materialize(&commit).unwrap().map(Result::unwrap).collect();
+        assert_eq!(key_values, vec![
+            (key!["a"], vec![1u8]),
+            (key!["b", "w", "z"], vec![]),
+            (key!["b", "x"], vec![2u8]),
+            (key!["c"], vec![1u8]),
+        ]);
+
+        let root_hash = storage.get_commit(&commit).unwrap().root_hash;
+        assert!(storage.reader().materialize(&root_hash).is_err());
+    }
+
+    #[test]
+    fn test_elide_noop_deletes() {
+        let staged_root_hash = |storage: &mut MerkleStorage| hash_tree(&storage.get_staged_root().unwrap());
+        let storage_config = |elide_noop_deletes| MerkleStorageConfig {
+            empty_tree_policy: EmptyTreePolicy::Keep,
+            elide_noop_deletes,
+            ..MerkleStorageConfig::default()
+        };
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config(true)).unwrap();
+        storage.set(&key!["a", "x"], &vec![1u8]).unwrap();
+        storage.set(&key!["b"], &vec![2u8]).unwrap();
+        storage.delete(&key!["a", "x"]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let root_hash = staged_root_hash(&mut storage);
+
+        storage.delete(&key!["a", "y"]).unwrap();
+        storage.delete(&key!["b", "y"]).unwrap();
+        storage.delete(&key!["c"]).unwrap();
+        assert!(!storage.is_dirty());
+        assert_eq!(staged_root_hash(&mut storage), root_hash);
+        assert_eq!(storage.get_merkle_stats().unwrap().perf_stats.elided_deletes, 3);
+
+        // deletes of existing values and directories are applied
+        storage.delete(&key!["a"]).unwrap();
+        assert!(storage.is_dirty());
+        assert_ne!(staged_root_hash(&mut storage), root_hash);
+        assert_eq!(storage.get_merkle_stats().unwrap().perf_stats.elided_deletes, 3);
+
+        // without elision, delete under the empty tree kept by the policy removes it
+        let mut storage = MerkleStorage::with_config(db, storage_config(false)).unwrap();
+        storage.checkout(&commit).unwrap();
+        storage.delete(&key!["a", "y"]).unwrap();
+        assert!(storage.is_dirty());
+        assert_ne!(staged_root_hash(&mut storage), root_hash);
+        assert_eq!(storage.get_merkle_stats().unwrap().perf_stats.elided_deletes, 0);
+    }
+
+    #[test]
+    fn test_open_report() {
+        let storage_config = || MerkleStorageConfig { auto_advance_ref: Some("main".to_string()), ..MerkleStorageConfig::default() };
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        assert_eq!(&OpenReport { created: true, ..OpenReport::default() }, storage.open_report());
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.update_ref("tag", None, &commit).unwrap();
+
+        let storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        let report = storage.open_report();
+        assert!(report.is_clean());
+        assert_eq!((false, Some(commit), 2), (report.created, report.head, report.refs_checked));
+
+        // ref to a tree and ref to a commit, which root tree is gone
+        let root_hash = storage.get_commit(&commit).unwrap().root_hash;
+        let orphan = Commit { parent_commit_hash: None, root_hash: [0; HASH_LEN], time: 0,
+            author: "".to_string(), message: "".to_string(), metadata: CommitMetadata::new() };
+        let orphan_hash = hash_commit(&orphan);
+        storage.db.put(&orphan_hash, &bincode::serialize(&Entry::Commit(orphan)).unwrap()).unwrap();
+        KeyValueStoreWithSchema::<RefSchema>::put(db.as_ref(), &"tree".to_string(), &root_hash).unwrap();
+        KeyValueStoreWithSchema::<RefSchema>::put(db.as_ref(), &"orphan".to_string(), &orphan_hash).unwrap();
+
+        let storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        let report = storage.open_report();
+        assert!(!report.is_clean());
+        assert_eq!(4, report.refs_checked);
+        let broken: Vec<&str> = report.broken_refs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(vec!["orphan", "tree"], broken);
+        assert!(!report.refs_truncated);
+
+        // only a bounded number of refs is read
+        for i in 0..OPEN_CHECK_MAX_REFS {
+            storage.set_ref(&format!("many/{}", i), &commit).unwrap();
+        }
+        let storage = MerkleStorage::with_config(db, storage_config()).unwrap();
+        let report = storage.open_report();
+        assert_eq!(OPEN_CHECK_MAX_REFS, report.refs_checked);
+        assert!(report.refs_truncated);
+    }
+
+    #[test]
+    fn test_staging_journal() {
+        let storage_config = || MerkleStorageConfig { persist_staging: true, ..MerkleStorageConfig::default() };
+        let db = Arc::new(get_db(Config::new()));
+        let flushes = Arc::new(AtomicU64::new(0));
+        let observed = flushes.clone();
+        db.add_flush_observer(Box::new(move |_| { observed.fetch_add(1, Ordering::Relaxed); }));
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        storage.set(&key!["a", "b"], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a", "c"], &vec![2u8]).unwrap();
+        storage.copy(&key!["a"], &key!["d"]).unwrap();
+        storage.delete(&key!["a", "b"]).unwrap();
+        storage.revert(&key!["a", "b"]).unwrap();
+        let staged_root_hash = hash_tree(&storage.get_staged_root().unwrap());
+        // every journaled change is flushed
+        assert_eq!(5, flushes.load(Ordering::Relaxed));
+        // crash with changes staged
+        drop(storage);
+
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        assert_eq!(4, storage.open_report().staged_actions);
+        assert!(matches!(storage.set(&key!["e"], &vec![3u8]), Err(MerkleError::StagingNotRecovered { actions: 4 })));
+        assert!(matches!(storage.checkout(&commit), Err(MerkleError::StagingNotRecovered { .. })));
+        assert_eq!(4, storage.recover_staging().unwrap());
+        assert_eq!(0, storage.recover_staging().unwrap());
+        assert_eq!(staged_root_hash, hash_tree(&storage.get_staged_root().unwrap()));
+        assert_eq!(vec![1u8], storage.get(&key!["a", "b"]).unwrap());
+        assert_eq!(vec![2u8], storage.get(&key!["d", "c"]).unwrap());
+        assert_eq!(Some(commit), storage.get_last_commit_hash());
+
+        // recovered actions stay journaled until committed
+        storage.set(&key!["e"], &vec![3u8]).unwrap();
+        drop(storage);
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        assert_eq!(5, storage.recover_staging().unwrap());
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        drop(storage);
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        assert_eq!(0, storage.open_report().staged_actions);
+
+        // reset discards the journal
+        storage.set(&key!["f"], &vec![4u8]).unwrap();
+        drop(storage);
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        storage.reset().unwrap();
+        storage.set(&key!["g"], &vec![5u8]).unwrap();
+        assert!(storage.mem(&key!["g"]).unwrap());
+        drop(storage);
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config()).unwrap();
+        assert_eq!(1, storage.recover_staging().unwrap());
+        assert!(!storage.mem(&key!["f"]).unwrap());
+        assert!(storage.mem(&key!["g"]).unwrap());
+
+        let flushed = flushes.load(Ordering::Relaxed);
+        let storage_config = MerkleStorageConfig { sync_staging_journal: false, ..storage_config() };
+        let mut storage = MerkleStorage::with_config(db, storage_config).unwrap();
+        storage.reset().unwrap();
+        storage.set(&key!["h"], &vec![6u8]).unwrap();
+        assert_eq!(flushed, flushes.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[cfg(feature = "parallel-hashing")]
+    fn test_parallel_hashing() {
+        let commit_with = |parallel_hashing| {
+            clean_db();
+            let storage_config = MerkleStorageConfig { parallel_hashing, ..MerkleStorageConfig::default() };
+            let mut storage = get_storage_with_config(Config::new(), storage_config);
+            for i in 0..50 {
+                for j in 0..20 {
+                    storage.set(&key!["data", i.to_string(), j.to_string()], &vec![i as u8, j as u8]).unwrap();
+                }
+            }
+            storage.copy(&key!["data", "7"], &key!["copy"]).unwrap();
+            let first = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+            storage.set(&key!["data", "3", "0"], &vec![9u8]).unwrap();
+            let second = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+            let stored = storage.db.iterator(IteratorMode::Start).unwrap().count();
+            (first, second, stored, storage.get_merkle_stats().unwrap().perf_stats)
+        };
+
+        let (first, second, stored, stats) = commit_with(true);
+        let (sequential_first, sequential_second, sequential_stored, sequential_stats) = commit_with(false);
+        assert_eq!((sequential_first, sequential_second, sequential_stored), (first, second, stored));
+        assert_eq!(sequential_stats.skipped_write_bytes, stats.skipped_write_bytes);
+    }
+
+    #[test]
+    fn test_staging_quotas() {
+        let storage_config = MerkleStorageConfig { max_staged_keys: Some(2), max_staged_bytes: Some(10), ..MerkleStorageConfig::default() };
+        let mut storage = get_storage_with_config(Config::new(), storage_config);
+        storage.set(&key!["a"], &vec![0u8; 4]).unwrap();
+        // changing the same key again does not count as another key
+        storage.set(&key!["a"], &vec![1u8; 4]).unwrap();
+        assert!(matches!(storage.set(&key!["b"], &vec![0u8; 3]), Err(MerkleError::StagedBytesQuotaExceeded { limit: 10 })));
+        storage.delete(&key!["b"]).unwrap();
+        assert!(matches!(storage.set(&key!["c"], &vec![]), Err(MerkleError::StagedKeysQuotaExceeded { limit: 2 })));
+        assert!(matches!(storage.copy(&key!["a"], &key!["c"]), Err(MerkleError::StagedKeysQuotaExceeded { limit: 2 })));
+        // rejected changes are not applied
+        assert!(!storage.exists(&key!["c"]).unwrap());
+
+        // quotas apply per commit
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["c"], &vec![0u8; 10]).unwrap();
+        storage.set(&key!["d"], &vec![]).unwrap();
+    }
+
+    #[test]
+    fn test_gc() {
+        let storage_config = MerkleStorageConfig { hash_prefix_index: true, ..MerkleStorageConfig::default() };
+        let mut storage = get_storage_with_config(Config::new(), storage_config);
+        let mut commits = Vec::new();
+        for i in 0..4u8 {
+            storage.set(&key!["a", "b"], &vec![i]).unwrap();
+            storage.set(&key!["c", "x"], &vec![9]).unwrap();
+            commits.push(storage.commit(i as u64, "".to_string(), "".to_string()).unwrap());
+        }
+        storage.set_apply_metrics(&commits[2], &ApplyMetrics::default()).unwrap();
+        storage.update_ref("keep", None, &commits[0]).unwrap();
+        storage.checkout(&commits[1]).unwrap();
+        let snapshot = storage.snapshot().unwrap();
+        storage.checkout(&commits[3]).unwrap();
+        storage.set(&key!["d"], &vec![4]).unwrap();
+
+        let mut phases = Vec::new();
+        let report = storage.gc_with_progress(1, |phase, _| phases.push(phase)).unwrap();
+        assert_eq!(3, report.commits_retained);
+        assert!(report.entries_deleted > 0 && report.entries_deleted < report.entries_scanned);
+        assert_eq!(vec![GcPhase::Mark, GcPhase::Mark, GcPhase::Mark, GcPhase::Sweep], phases);
+        assert!(matches!(storage.get_history(&commits[2], &key!["a", "b"]), Err(MerkleError::EntryNotFound { .. })));
+        assert!(storage.resolve_hash_prefix(&hex::encode(&commits[2][..4])).is_err());
+        assert!(storage.apply_metrics.get(&commits[2]).unwrap().is_none());
+        assert_eq!(vec![0], storage.get_history(&commits[0], &key!["a", "b"]).unwrap());
+        assert_eq!(vec![1], snapshot.reader().get_at(&commits[1], &key!["a", "b"]).unwrap());
+        assert_eq!(vec![4], storage.get(&key!["d"]).unwrap());
+        assert_eq!(report.bytes_reclaimed, storage.get_merkle_stats().unwrap().counters.gc_reclaimed_bytes);
+        let (_, record) = storage.get_audit_log(0, 10).unwrap().pop().unwrap();
+        assert_eq!(DestructiveOperation::GarbageCollection, record.operation);
+        assert_eq!(Some(&report.entries_deleted), record.counts.get("entries_deleted"));
+
+        // collected values can be committed again
+        drop(snapshot);
+        storage.delete_ref("keep", &commits[0]).unwrap();
+        storage.set(&key!["a", "b"], &vec![2]).unwrap();
+        let commit = storage.commit(4, "".to_string(), "".to_string()).unwrap();
+        let report = storage.gc(1).unwrap();
+        assert_eq!(1, report.commits_retained);
+        assert_eq!(report.entries_marked, report.entries_scanned - report.entries_deleted);
+        assert_eq!(vec![2], storage.get_history(&commit, &key!["a", "b"]).unwrap());
+        assert!(storage.get_history(&commits[3], &key!["a", "b"]).is_err());
+        assert_eq!(0, storage.gc(5).unwrap().entries_deleted);
+    }
+
+    #[test]
+    fn test_gc_with_pins() {
+        let mut storage = get_storage(Config::new());
+        let mut commits = Vec::new();
+        for i in 0..6u8 {
+            storage.set(&key!["a"], &vec![i]).unwrap();
+            commits.push(storage.commit(i as u64, "".to_string(), "".to_string()).unwrap());
+        }
+        let reader = storage.reader();
+        let pin = reader.pin(&commits[0]).unwrap();
+        assert_eq!(commits[0], pin.commit_hash());
+        let materialized = reader.materialize(&commits[1]).unwrap();
+
+        // commits pinned or read while marking are retained, unmarked ones cannot be pinned nor
+        // read while sweeping
+        let mut late_pin = None;
+        let report = storage.gc_with_progress(1, |phase, _| match phase {
+            GcPhase::Mark if late_pin.is_none() => {
+                late_pin = Some(reader.pin(&commits[2]).unwrap());
+                assert_eq!(vec![3u8], reader.get_at(&commits[3], &key!["a"]).unwrap());
+            }
+            GcPhase::Mark => {}
+            GcPhase::Sweep => {
+                assert!(matches!(reader.pin(&commits[4]), Err(MerkleError::EntryNotFound { .. })));
+                assert!(matches!(reader.get_at(&commits[4], &key!["a"]), Err(MerkleError::EntryNotFound { .. })));
+            }
+        }).unwrap();
+        assert_eq!(5, report.commits_retained);
+        for i in &[0, 1, 2, 3, 5] {
+            assert_eq!(vec![*i as u8], reader.get_at(&commits[*i], &key!["a"]).unwrap());
+        }
+        assert!(reader.get_at(&commits[4], &key!["a"]).is_err());
+        assert_eq!(1, materialized.count());
+
+        drop(pin);
+        drop(late_pin);
+        storage.gc(1).unwrap();
+        assert!(matches!(reader.pin(&commits[0]), Err(MerkleError::EntryNotFound { .. })));
+        assert!(reader.get_at(&commits[2], &key!["a"]).is_err());
+        assert!(reader.get_at(&commits[3], &key!["a"]).is_err());
+        assert!(reader.pin(&commits[5]).is_ok());
+    }
+
+    #[test]
+    #[serial]
+    fn test_ref_counting() {
+        clean_db();
+
+        let storage_config = MerkleStorageConfig { gc_mode: GcMode::RefCounting, hash_prefix_index: true, ..MerkleStorageConfig::default() };
+        let ref_counts = |storage: &MerkleStorage| storage.ref_counts.iterator(IteratorMode::Start).unwrap()
+            .map(|(hash, count)| (hash.unwrap(), count.unwrap()))
+            .collect::<Vec<_>>();
+        let counts = {
+            let mut storage = open_storage_with_config(Config::new(), storage_config.clone());
+            assert!(storage.capabilities().ref_counting);
+            let mut commits = Vec::new();
+            for i in 0..3u8 {
+                storage.set(&key!["a", "b"], &vec![i]).unwrap();
+                storage.set(&key!["c", "x"], &vec![9]).unwrap();
+                commits.push(storage.commit(i as u64, "".to_string(), "".to_string()).unwrap());
+            }
+            assert!(matches!(storage.delete_commit(&commits[2]), Err(MerkleError::CommitInUse { .. })));
+            storage.update_ref("keep", None, &commits[0]).unwrap();
+            assert!(matches!(storage.delete_commit(&commits[0]), Err(MerkleError::CommitInUse { .. })));
+
+            // commit, its root tree, tree `a` and its value are exclusive to the commit
+            let report = storage.delete_commit(&commits[1]).unwrap();
+            assert_eq!(4, report.entries_deleted);
+            assert!(storage.get_history(&commits[1], &key!["a", "b"]).is_err());
+            assert!(storage.resolve_hash_prefix(&hex::encode(&commits[1][..4])).is_err());
+            assert_eq!(vec![0], storage.get_history(&commits[0], &key!["a", "b"]).unwrap());
+            assert_eq!(vec![9], storage.get_history(&commits[2], &key!["c", "x"]).unwrap());
+            assert_eq!(report.bytes_reclaimed, storage.get_merkle_stats().unwrap().counters.gc_reclaimed_bytes);
+            let (_, record) = storage.get_audit_log(0, 10).unwrap().pop().unwrap();
+            assert_eq!(DestructiveOperation::DeleteCommit, record.operation);
+            ref_counts(&storage)
+        };
+
+        // counts are dropped while not maintained and rebuilt once they are again
+        {
+            let storage = open_storage(Config::new());
+            assert!(matches!(storage.delete_commit(&[0; 32]), Err(MerkleError::RefCountingDisabled)));
         }
+        let storage = open_storage_with_config(Config::new(), storage_config);
+        assert_eq!(counts, ref_counts(&storage));
     }
 
-    fn get_non_leaf(&self, hash: EntryHash) -> Node {
-        Node { node_kind: NodeKind::NonLeaf, entry_hash: hash }
+    #[test]
+    fn test_capabilities() {
+        let storage_config = MerkleStorageConfig { tombstone_retention: Some(10), ..MerkleStorageConfig::default() };
+        let capabilities = get_storage_with_config(Config::new(), storage_config).capabilities();
+        assert_eq!(capabilities.hash_algorithm, HASH_SCHEME);
+        assert_eq!(capabilities.serialize, cfg!(feature = "serialize"));
+        assert!(capabilities.tombstones);
+        assert!(!capabilities.hash_prefix_index);
+        assert!(!capabilities.commit_annotations);
+        assert!(!capabilities.encryption);
+        assert!(capabilities.garbage_collection);
     }
 
-    fn key_to_string(&self, key: &ContextKey) -> String {
-        key.join("/")
+    #[test]
+    fn test_compression_stats() {
+        // checksum stands in for a codec of blobs, it adds the same number of bytes to each
+        let pipeline = ValuePipeline::new().then(EntryCodecs::new().blobs(Checksum));
+        let db = Arc::new(get_db(Config::new()).with_value_pipeline::<MerkleStorage>(pipeline));
+        let mut storage = MerkleStorage::new(db).unwrap();
+        assert!(storage.capabilities().compression);
+        storage.set(&key!["a", "b"], &vec![1u8; 100]).unwrap();
+        storage.set(&key!["c"], &vec![2u8; 10]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(vec![1u8; 100], storage.get_history(&commit, &key!["a", "b"]).unwrap());
+
+        // pipeline header and codec id are added to every entry, checksum to blobs only
+        let stats = storage.compression_stats().unwrap();
+        assert_eq!((2, 1, 2), (stats.blobs.entries, stats.commits.entries, stats.trees.entries));
+        assert_eq!(stats.blobs.plain_bytes + 2 * (3 + 16), stats.blobs.stored_bytes);
+        assert_eq!(stats.trees.plain_bytes + 2 * 3, stats.trees.stored_bytes);
+        assert_eq!(stats.commits.plain_bytes + 3, stats.commits.stored_bytes);
+        assert!(stats.blobs.ratio().unwrap() > 1.0);
     }
 
-    fn string_to_key(&self, string: &str) -> ContextKey {
-        string.split('/').map(str::to_string).collect()
+    #[test]
+    fn test_encrypted_entries() {
+        let pipeline = ValuePipeline::new().then(Encryption::new([3; 32])).then(Checksum);
+        let db = Arc::new(get_db(Config::new()).with_value_pipeline::<MerkleStorage>(pipeline));
+        let storage_config = MerkleStorageConfig { decode_on_demand: true, ..MerkleStorageConfig::default() };
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config).unwrap();
+        let capabilities = storage.capabilities();
+        assert!(capabilities.encryption && !capabilities.compression);
+
+        storage.set(&key!["a", "b"], &b"secret".to_vec()).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(b"secret".to_vec(), storage.get_history(&commit, &key!["a", "b"]).unwrap());
+        assert_eq!(&b"secret"[..], &storage.get_history_raw(&commit, &key!["a", "b"]).unwrap()[..]);
+
+        // plaintext is not stored
+        let stored = db.tree_named(None).unwrap().iter().values()
+            .map(|value| value.unwrap())
+            .collect::<Vec<_>>();
+        assert!(!stored.is_empty());
+        assert!(stored.iter().all(|value| !value.windows(6).any(|window| window == b"secret")));
+
+        let mut reopened = MerkleStorage::new(db).unwrap();
+        reopened.checkout(&commit).unwrap();
+        assert_eq!(b"secret".to_vec(), reopened.get(&key!["a", "b"]).unwrap());
     }
 
-    pub fn get_last_commit_hash(&self) -> Option<EntryHash> {
-        match &self.last_commit {
-            Some(c) => Some(self.hash_commit(&c)),
-            None => None
-        }
+    #[test]
+    #[serial]
+    fn test_value_hash_index() {
+        clean_db();
+
+        let storage_config = MerkleStorageConfig { value_hash_index: true, ..MerkleStorageConfig::default() };
+        let mut storage = open_storage_with_config(Config::new(), storage_config);
+        let (one, two) = (value_hash(&vec![1u8]), value_hash(&vec![2u8]));
+        storage.set(&key!["b", "x"], &vec![1u8]).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        storage.set(&key!["c"], &vec![2u8]).unwrap();
+        // staged changes are not indexed
+        assert!(storage.find_keys_with_value_hash(&one).unwrap().is_empty());
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.find_keys_with_value_hash(&one).unwrap(), vec![key!["a"], key!["b", "x"]]);
+        assert_eq!(storage.find_keys_with_value_hash(&two).unwrap(), vec![key!["c"]]);
+
+        storage.set(&key!["b", "x"], &vec![2u8]).unwrap();
+        storage.delete(&key!["c"]).unwrap();
+        storage.set(&key!["a", "y"], &vec![1u8]).unwrap();
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(storage.find_keys_with_value_hash(&one).unwrap(), vec![key!["a", "y"]]);
+        assert_eq!(storage.find_keys_with_value_hash(&two).unwrap(), vec![key!["b", "x"]]);
+
+        storage.checkout(&commit1).unwrap();
+        assert_eq!(storage.find_keys_with_value_hash(&one).unwrap(), vec![key!["a"], key!["b", "x"]]);
+        assert_eq!(storage.find_keys_with_value_hash(&two).unwrap(), vec![key!["c"]]);
+        assert!(storage.find_keys_with_value_hash(&value_hash(&vec![3u8])).unwrap().is_empty());
+        drop(storage);
+
+        let storage = open_storage(Config::new());
+        assert!(matches!(storage.find_keys_with_value_hash(&one), Err(MerkleError::ValueHashIndexDisabled)));
     }
 
-    pub fn get_merkle_stats(&self) -> Result<MerkleStorageStats, MerkleError> {
-        let mut avg_set_exec_time_ns: f64 = 0.0;
-        if self.set_exec_times > self.set_exec_times_to_discard {
-            avg_set_exec_time_ns = self.cumul_set_exec_time / ((self.set_exec_times - self.set_exec_times_to_discard) as f64);
+    #[test]
+    fn test_siblings() {
+        let mut storage = get_storage(Config::new());
+        for name in &["b", "d", "f"] {
+            storage.set(&key!["data", name], &vec![1u8]).unwrap();
         }
-        let perf = MerklePerfStats { avg_set_exec_time_ns: avg_set_exec_time_ns };
-        Ok(MerkleStorageStats { map_stats: self.map_stats, perf_stats: perf })
+        storage.set(&key!["data", "c", "x"], &vec![2u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["data", "e"], &vec![3u8]).unwrap();
+
+        // directories are siblings too
+        assert_eq!(storage.next_sibling(&key!["data", "b"]).unwrap(), Some(key!["data", "c"]));
+        assert_eq!(storage.next_sibling(&key!["data", "d"]).unwrap(), Some(key!["data", "e"]));
+        assert_eq!(storage.next_sibling(&key!["data", "f"]).unwrap(), None);
+        assert_eq!(storage.prev_sibling(&key!["data", "b"]).unwrap(), None);
+        assert_eq!(storage.prev_sibling(&key!["data", "d"]).unwrap(), Some(key!["data", "c"]));
+        // missing keys are positions between siblings
+        assert_eq!(storage.next_sibling(&key!["data", "a"]).unwrap(), Some(key!["data", "b"]));
+        assert_eq!(storage.prev_sibling(&key!["data", "z"]).unwrap(), Some(key!["data", "f"]));
+        assert_eq!(storage.next_sibling(&key!["data"]).unwrap(), None);
+        assert_eq!(storage.next_sibling(&key!["missing", "a"]).unwrap(), None);
+        assert!(matches!(storage.next_sibling(&vec![]), Err(MerkleError::KeyEmpty)));
+
+        let reader = storage.reader();
+        assert_eq!(reader.next_sibling_at(&commit, &key!["data", "d"]).unwrap(), Some(key!["data", "f"]));
+        assert_eq!(reader.prev_sibling_at(&commit, &key!["data", "f"]).unwrap(), Some(key!["data", "d"]));
     }
-}
-
-#[cfg(test)]
-#[allow(unused_must_use)]
-mod tests {
-    use super::*;
-    use std::path::Path;
-    use std::fs;
-    use serial_test::serial;
-    use sled::Config;
-    use crate::database::SledDBWrapper;
-
-    /*
-    * Tests need to run sequentially, otherwise they will try to open RocksDB at the same time.
-    */
 
-    fn open_db<P: AsRef<Path>>(path: P, config: Config) -> SledDBWrapper {
-        let db = config.path(path).open().expect("error opening database");
-        SledDBWrapper::new(db)
+    #[test]
+    fn test_log_with_apply_metrics() {
+        let mut storage = get_storage(Config::new());
+        let mut commits = Vec::new();
+        for i in 0..3u8 {
+            storage.set(&key!["a"], &vec![i]).unwrap();
+            commits.push(storage.commit(i as u64, "tezos".to_string(), format!("block {}", i)).unwrap());
+        }
+        let metrics = ApplyMetrics { duration_micros: 1500, reads: 10, writes: 1 };
+        storage.set_apply_metrics(&commits[1], &metrics).unwrap();
+        let root_hash = storage.get_commit(&commits[1]).unwrap().root_hash;
+        assert!(storage.set_apply_metrics(&root_hash, &metrics).is_err());
+
+        let log = storage.log(&commits[2], 10).unwrap();
+        assert_eq!(log.iter().map(|entry| entry.commit_hash).collect::<Vec<_>>(), vec![commits[2], commits[1], commits[0]]);
+        assert_eq!(log[0].parent_commit_hash, Some(commits[1]));
+        assert_eq!(log[2].parent_commit_hash, None);
+        assert_eq!(log[1].message, "block 1");
+        assert_eq!(log[1].time, 1);
+        assert_eq!(log[0].apply_metrics, None);
+        assert_eq!(log[1].apply_metrics, Some(metrics));
+
+        assert_eq!(storage.reader().log(&commits[2], 2).unwrap().len(), 2);
+        assert!(storage.log(&commits[2], 0).unwrap().is_empty());
     }
 
-    fn get_db_name() -> &'static str { "_merkle_db_test" }
-
-    fn get_db(config: Config) -> SledDBWrapper { open_db(get_db_name(), config) }
-
-    fn get_storage(config: Config) -> MerkleStorage { MerkleStorage::new(Arc::new(get_db(config))) }
+    #[test]
+    fn test_history() {
+        let mut storage = get_storage(Config::new());
+        let mut commits = Vec::new();
+        for i in 0..3u8 {
+            storage.set(&key!["a"], &vec![i]).unwrap();
+            commits.push(storage.commit(i as u64, "tezos".to_string(), format!("block {}", i)).unwrap());
+        }
 
-    fn clean_db() {
-        let _ = sled::open(get_db_name()).expect("error opening database").clear();
-        let _ = fs::remove_dir_all(get_db_name());
+        let history = storage.history(&commits[2]).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(history.iter().map(|info| info.commit_hash).collect::<Vec<_>>(), vec![commits[2], commits[1], commits[0]]);
+        assert_eq!(history[1].root_hash, storage.get_commit(&commits[1]).unwrap().root_hash);
+        assert_eq!((history[1].parent_commit_hash, history[2].parent_commit_hash), (Some(commits[0]), None));
+        assert_eq!((history[0].time, history[0].author.as_str(), history[0].message.as_str()), (2, "tezos", "block 2"));
+        assert_eq!(storage.reader().history(&commits[1]).unwrap().count(), 2);
+        assert!(matches!(storage.history(&[0; 32]), Err(MerkleError::EntryNotFound { .. })));
+
+        // history ends at collected commits
+        storage.gc(2).unwrap();
+        assert_eq!(storage.history(&commits[2]).unwrap().map(Result::unwrap).count(), 2);
     }
 
     #[test]
-    #[serial]
-    fn test_tree_hash() {
-
-        let config = Config::new().cache_capacity(32 * 1024 * 1024).path(get_db_name());
-        let mut storage = get_storage(config);
-        storage.set(&vec!["a".to_string(), "foo".to_string()], &vec![97, 98, 99]); // abc
-        storage.set(&vec!["b".to_string(), "boo".to_string()], &vec![97, 98]);
-        storage.set(&vec!["a".to_string(), "aaa".to_string()], &vec![97, 98, 99, 100]);
-        storage.set(&vec!["x".to_string()], &vec![97]);
-        storage.set(&vec!["one".to_string(), "two".to_string(), "three".to_string()], &vec![97]);
-        let tree = storage.current_stage_tree.clone().unwrap().clone();
-
-        let hash = storage.hash_tree(&tree);
-
-        assert_eq!([0xDB, 0xAE, 0xD7, 0xB6], hash[0..4]);
+    fn test_checkout_partial() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&key!["data", "contracts", "a"], &vec![1]).unwrap();
+        storage.set(&key!["data", "rolls", "b"], &vec![2]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        storage.checkout_partial(&commit, &[key!["data", "contracts"]]).unwrap();
+        assert_eq!(Some(&[key!["data", "contracts"]][..]), storage.writable_prefixes());
+        storage.set(&key!["data", "contracts", "c"], &vec![3]).unwrap();
+        storage.delete(&key!["data", "contracts", "a"]).unwrap();
+        assert!(matches!(storage.set(&key!["data", "rolls", "b"], &vec![4]), Err(MerkleError::OutsidePartialCheckout { .. })));
+        assert!(matches!(storage.delete(&key!["data"]), Err(MerkleError::OutsidePartialCheckout { .. })));
+        assert!(matches!(storage.copy(&key!["data", "contracts"], &key!["backup"]), Err(MerkleError::OutsidePartialCheckout { .. })));
+        assert_eq!(vec![2], storage.get(&key!["data", "rolls", "b"]).unwrap());
+
+        // rest of the context is committed unchanged
+        let patched = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(vec![2], storage.get_history(&patched, &key!["data", "rolls", "b"]).unwrap());
+        assert_eq!(vec![3], storage.get_history(&patched, &key!["data", "contracts", "c"]).unwrap());
+
+        storage.checkout(&patched).unwrap();
+        assert_eq!(None, storage.writable_prefixes());
+        storage.set(&key!["data", "rolls", "b"], &vec![4]).unwrap();
     }
 
     #[test]
-    #[serial]
-    fn test_commit_hash() {
-        let config = Config::new().cache_capacity(32 * 1024 * 1024).path(get_db_name());
-        let mut storage = get_storage(config);
-        storage.set(&vec!["a".to_string()], &vec![97, 98, 99]);
+    fn test_key_history() {
+        let db = Arc::new(get_db(Config::new()));
+        let mut storage = MerkleStorage::new(db.clone()).unwrap();
+        assert!(matches!(storage.get_value_at(&[0; HASH_LEN], &key!["a"]), Err(MerkleError::KeyHistoryDisabled)));
+        storage.set(&key!["a"], &vec![0u8]).unwrap();
+        storage.set(&key!["c"], &vec![0u8]).unwrap();
+        let commit0 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let storage_config = MerkleStorageConfig { key_history: true, ..MerkleStorageConfig::default() };
+        let mut storage = MerkleStorage::with_config(db, storage_config).unwrap();
+        assert!(storage.capabilities().key_history);
+        storage.checkout(&commit0).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        let commit1 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a"], &vec![2u8]).unwrap();
+        storage.set(&key!["b"], &vec![1u8]).unwrap();
+        let commit2 = storage.commit(2, "".to_string(), "".to_string()).unwrap();
+        storage.delete(&key!["a"]).unwrap();
+        let commit3 = storage.commit(3, "".to_string(), "".to_string()).unwrap();
+
+        // height of the first recorded commit is counted from its unrecorded parents
+        let history = storage.get_key_history(&key!["a"]).unwrap();
+        let heights: Vec<u64> = history.0.iter().map(|version| version.height).collect();
+        assert_eq!(vec![1, 2, 3], heights);
+        assert_eq!(None, history.0[2].new_value_hash);
+
+        for (commit, a, b) in &[(commit0, Some(vec![0u8]), None), (commit1, Some(vec![1u8]), None),
+                                (commit2, Some(vec![2u8]), Some(vec![1u8])), (commit3, None, Some(vec![1u8]))] {
+            assert_eq!(*a, storage.get_value_at(commit, &key!["a"]).unwrap());
+            assert_eq!(*b, storage.get_value_at(commit, &key!["b"]).unwrap());
+        }
+        // unchanged keys are looked up in the tree
+        assert!(storage.get_key_history(&key!["c"]).unwrap().0.is_empty());
+        assert_eq!(Some(vec![0u8]), storage.get_value_at(&commit3, &key!["c"]).unwrap());
+    }
 
-        let commit = storage.commit(
-            0, "Tezos".to_string(), "Genesis".to_string());
+    #[test]
+    fn test_commits_touching() {
+        let storage_config = MerkleStorageConfig { change_filters: true, ..MerkleStorageConfig::default() };
+        let mut storage = get_storage_with_config(Config::new(), storage_config);
+        storage.set(&key!["data", "rolls", "1"], &vec![0]).unwrap();
+        let base = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let mut commits = Vec::new();
+        for (i, key) in [key!["data", "rolls", "1"], key!["data", "votes"], key!["data", "rolls", "2"]].iter().enumerate() {
+            storage.set(key, &vec![i as u8 + 1]).unwrap();
+            commits.push(storage.commit(i as u64 + 1, "".to_string(), "".to_string()).unwrap());
+        }
 
-        assert_eq!([0xCF, 0x95, 0x18, 0x33], commit.unwrap()[0..4]);
+        let filter = storage.get_change_filter(&commits[1]).unwrap().unwrap();
+        assert!(filter.may_touch(&key!["data", "votes"]) && !filter.may_touch(&key!["data", "rolls"]));
+        assert_eq!(vec![commits[2], commits[0]], storage.commits_touching(&base, &commits[2], &key!["data", "rolls"]).unwrap());
+        assert_eq!(vec![commits[2]], storage.commits_touching(&base, &commits[2], &key!["data", "rolls", "2"]).unwrap());
+        assert_eq!(vec![commits[1]], storage.commits_touching(&commits[0], &commits[2], &key!["data", "votes"]).unwrap());
+        assert!(storage.commits_touching(&commits[1], &commits[2], &key!["data", "votes", "x"]).unwrap().is_empty());
+        assert_eq!(3, storage.commits_touching(&base, &commits[2], &Vec::new()).unwrap().len());
+        assert!(matches!(storage.commits_touching(&commits[2], &base, &key!["data"]), Err(MerkleError::NotAnAncestor { .. })));
+    }
 
-        storage.set(&vec!["data".to_string(), "x".to_string()], &vec![97]);
-        let commit = storage.commit(
-            0, "Tezos".to_string(), "".to_string());
+    #[test]
+    fn test_prefix_compressed_tree() {
+        let node = Node { node_kind: NodeKind::Leaf, entry_hash: [1u8; HASH_LEN] };
+        let mut tree = Tree::new();
+        for name in &["", "tz1abc", "tz1abd", "tz1b", "é", "éa", "ê", "x"] {
+            tree.insert(name.to_string(), node.clone());
+        }
+        let bytes = bincode::serialize(&Entry::Tree(tree.clone())).unwrap();
+        match bincode::deserialize::<Entry>(&bytes).unwrap() {
+            Entry::Tree(decoded) => {
+                assert_eq!(decoded.keys().collect::<Vec<_>>(), tree.keys().collect::<Vec<_>>());
+                assert_eq!(hash_tree(&decoded), hash_tree(&tree));
+            }
+            entry => panic!("unexpected entry {:?}", entry),
+        }
 
-        assert_eq!([0xCA, 0x7B, 0xC7, 0x02], commit.unwrap()[0..4]);
-        // full irmin hash: ca7bc7022ffbd35acc97f7defb00c486bb7f4d19a2d62790d5949775eb74f3c8
+        // similar names take less space than when stored in full
+        let mut wide_tree = Tree::new();
+        for i in 0..100 {
+            wide_tree.insert(format!("tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZ{:03}", i), node.clone());
+        }
+        let full_len = bincode::serialize(&(TREE_VARIANT, &wide_tree)).unwrap().len();
+        let compressed_len = bincode::serialize(&Entry::Tree(wide_tree)).unwrap().len();
+        // 35 shared bytes of every name but the first one are saved
+        assert!(compressed_len + 30 * 99 < full_len);
+
+        // shared prefix longer than the previous name
+        let invalid = bincode::serialize(&(PREFIX_COMPRESSED_TREE_VARIANT, vec![(0u32, "a", &node), (2u32, "b", &node)])).unwrap();
+        assert!(bincode::deserialize::<Entry>(&invalid).is_err());
+
+        // trees of entry format 1 store full names
+        let legacy = bincode::serialize(&(TREE_VARIANT, &tree)).unwrap();
+        match Entry::decode(&legacy).unwrap() {
+            Entry::Tree(decoded) => assert_eq!(hash_tree(&decoded), hash_tree(&tree)),
+            entry => panic!("unexpected entry {:?}", entry),
+        }
     }
 
     #[test]
-    #[serial]
-    fn test_multiple_commit_hash() {
-        clean_db();
+    fn test_audit_log() {
+        let mut storage = get_storage(Config::new());
+        storage.set(&vec!["a".to_string()], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.update_ref("main", None, &commit).unwrap();
+        storage.update_ref("feature", None, &commit).unwrap();
+        assert!(storage.get_audit_log(0, 10).unwrap().is_empty());
+
+        storage.delete_ref("feature", &commit).unwrap();
+        storage.update_ref("feature", None, &commit).unwrap();
+        storage.delete_ref("feature", &commit).unwrap();
+        // failed delete is not recorded
+        assert!(storage.delete_ref("main", &[0u8; HASH_LEN]).is_err());
+
+        let log = storage.get_audit_log(0, 10).unwrap();
+        assert_eq!(log.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![0, 1]);
+        let (_, record) = &log[0];
+        assert_eq!(record.operation, DestructiveOperation::DeleteRef);
+        assert_eq!(record.parameters.get("name"), Some(&"feature".to_string()));
+        assert_eq!(record.parameters.get("commit"), Some(&HashType::ContextHash.bytes_to_string(&commit)));
+        assert_eq!(record.counts.get("refs_deleted"), Some(&1));
+        assert!(record.time > 0);
+
+        assert_eq!(storage.get_audit_log(1, 10).unwrap().len(), 1);
+        assert_eq!(storage.get_audit_log(0, 1).unwrap().len(), 1);
+    }
 
-        let config = Config::new().cache_capacity(32 * 1024 * 1024).path(get_db_name());
+    #[test]
+    fn test_reader() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        let _commit = storage.commit(
-            0, "Tezos".to_string(), "Genesis".to_string());
+        let key_ab: &ContextKey = &vec!["a".to_string(), "b".to_string()];
+        storage.set(key_ab, &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
 
-        storage.set(&vec!["data".to_string(), "a".to_string(), "x".to_string()], &vec![97]);
-        storage.copy(&vec!["data".to_string(), "a".to_string()], &vec!["data".to_string(), "b".to_string()]);
-        storage.delete(&vec!["data".to_string(), "b".to_string(), "x".to_string()]);
-        let commit = storage.commit(
-            0, "Tezos".to_string(), "".to_string());
+        let reader = storage.reader();
+        let handle = {
+            let reader = reader.clone();
+            std::thread::spawn(move || reader.get_at(&commit1, &vec!["a".to_string(), "b".to_string()]).unwrap())
+        };
+        assert_eq!(vec![1u8], handle.join().unwrap());
 
-        assert_eq!([0x9B, 0xB0, 0x0D, 0x6E], commit.unwrap()[0..4]);
+        // staged changes are not visible to readers
+        storage.set(key_ab, &vec![2u8]).unwrap();
+        assert_eq!(vec![1u8], reader.get_at(&commit1, key_ab).unwrap());
+        assert!(reader.list(&commit1, &vec!["x".to_string()]).unwrap().is_none());
+
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(vec![(key_ab.clone(), vec![2u8])], reader.list(&commit2, &vec!["a".to_string()]).unwrap().unwrap());
     }
 
     #[test]
-    #[serial]
-    fn get_test() {
-        clean_db();
-
-        let commit1;
-        let commit2;
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
-        let key_eab: &ContextKey = &vec!["e".to_string(), "a".to_string(), "b".to_string()];
-        let key_az: &ContextKey = &vec!["a".to_string(), "z".to_string()];
-        let key_d: &ContextKey = &vec!["d".to_string()];
-
-        {
-            let config = Config::new().cache_capacity(32 * 1024 * 1024);
-            let mut storage = get_storage(config);
-            storage.set(key_abc, &vec![1u8, 2u8]);
-            storage.set(key_abx, &vec![3u8]);
-            assert_eq!(storage.get(&key_abc).unwrap(), vec![1u8, 2u8]);
-            assert_eq!(storage.get(&key_abx).unwrap(), vec![3u8]);
-            commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+    fn test_diverging_paths() {
 
-            storage.set(key_az, &vec![4u8]);
-            storage.set(key_abx, &vec![5u8]);
-            storage.set(key_d, &vec![6u8]);
-            storage.set(key_eab, &vec![7u8]);
-            assert_eq!(storage.get(key_abx).unwrap(), vec![5u8]);
-            commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut left = get_storage(config);
+        let mut right = get_storage(Config::new());
+        let key = |path: &str| -> ContextKey { path.split('/').map(|s| s.to_string()).collect() };
+
+        for storage in &mut [&mut left, &mut right] {
+            storage.set(&key("a/b/c"), &vec![1u8]).unwrap();
+            storage.set(&key("a/d"), &vec![2u8]).unwrap();
+            storage.set(&key("e"), &vec![3u8]).unwrap();
         }
-
-        let config = Config::new().cache_capacity(32 * 1024 * 1024).path(get_db_name());
-        let mut storage = get_storage(config);
-        assert_eq!(storage.get_history(&commit1, key_abc).unwrap(), vec![1u8, 2u8]);
-        assert_eq!(storage.get_history(&commit1, key_abx).unwrap(), vec![3u8]);
-        assert_eq!(storage.get_history(&commit2, key_abx).unwrap(), vec![5u8]);
-        assert_eq!(storage.get_history(&commit2, key_az).unwrap(), vec![4u8]);
-        assert_eq!(storage.get_history(&commit2, key_d).unwrap(), vec![6u8]);
-        assert_eq!(storage.get_history(&commit2, key_eab).unwrap(), vec![7u8]);
+        left.set(&key("a/b/x"), &vec![4u8]).unwrap();
+        left.set(&key("a/d"), &vec![5u8]).unwrap();
+        right.set(&key("e/f"), &vec![6u8]).unwrap();
+        right.set(&key("g/h"), &vec![7u8]).unwrap();
+        let left_commit = left.commit(0, "".to_string(), "".to_string()).unwrap();
+        let right_commit = right.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        let paths = left.reader().diverging_paths(&left_commit, &right.reader(), &right_commit, 10).unwrap();
+        assert_eq!(paths, vec![
+            (key("a/b/x"), PathDivergence::LeftOnly),
+            (key("a/d"), PathDivergence::Different),
+            (key("e"), PathDivergence::Different),
+            (key("g"), PathDivergence::RightOnly),
+        ]);
+
+        let paths = left.reader().diverging_paths(&left_commit, &right.reader(), &right_commit, 2).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(left.reader().diverging_paths(&left_commit, &left.reader(), &left_commit, 10).unwrap().is_empty());
     }
 
     #[test]
-    #[serial]
-    fn test_copy() {
-        clean_db();
+    fn test_dag_iterator() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        storage.set(key_abc, &vec![1 as u8]);
-        storage.copy(&vec!["a".to_string()], &vec!["z".to_string()]);
+        storage.set(&vec!["a".to_string(), "b".to_string()], &vec![1u8]).unwrap();
+        storage.set(&vec!["a".to_string(), "c".to_string()], &vec![1u8]).unwrap();
+        storage.set(&vec!["d".to_string()], &vec![2u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
 
-        assert_eq!(
-            vec![1 as u8],
-            storage.get(&vec!["z".to_string(), "b".to_string(), "c".to_string()]).unwrap());
-        // TODO test copy over commits
+        let entries: Vec<(EntryHash, EntryKind, usize)> = storage.dag_iterator(&commit).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let kinds: Vec<EntryKind> = entries.iter().map(|(_, kind, _)| *kind).collect();
+
+        // a/b and a/c share the same blob, so it is yielded once
+        assert_eq!(vec![EntryKind::Commit, EntryKind::Tree, EntryKind::Tree, EntryKind::Blob, EntryKind::Blob], kinds);
+        assert_eq!(commit, entries[0].0);
+        assert!(entries.iter().all(|(_, _, size)| *size > 0));
+
+        assert!(storage.dag_iterator(&entries[1].0).is_err());
     }
 
     #[test]
-    #[serial]
-    fn test_delete() {
-        clean_db();
+    fn test_entry_encoding_is_stable() {
+        let blob = Entry::Blob(vec![7, 8]);
+        assert_eq!(vec![1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 7, 8], blob.encode().unwrap());
+
+        let mut tree = Tree::new();
+        tree.insert("a".to_string(), Node { node_kind: NodeKind::Leaf, entry_hash: [9u8; HASH_LEN] });
+        let mut expected = vec![5, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a', 1, 0, 0, 0];
+        expected.extend_from_slice(&[9u8; HASH_LEN]);
+        assert_eq!(expected, Entry::Tree(tree.clone()).encode().unwrap());
+
+        match Entry::decode(&expected).unwrap() {
+            Entry::Tree(decoded) => {
+                assert_eq!(NodeKind::Leaf, decoded["a"].node_kind());
+                assert_eq!(&[9u8; HASH_LEN], decoded["a"].entry_hash());
+            }
+            entry => panic!("unexpected entry {:?}", entry),
+        }
+        assert_eq!(hash_tree(&tree), Entry::Tree(tree).hash());
+        assert!(Entry::decode(&[6, 0, 0, 0]).is_err());
+    }
 
+    #[test]
+    fn test_read_entry() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
-        storage.set(key_abc, &vec![2 as u8]);
-        storage.set(key_abx, &vec![3 as u8]);
-        storage.delete(key_abx);
-        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&key!["a", "b"], &vec![1u8, 2]).unwrap();
+        let commit_hash = storage.commit(5, "author".to_string(), "message".to_string()).unwrap();
 
-        assert!(storage.get_history(&commit1, &key_abx).is_err());
+        let reader = storage.reader();
+        let commit = match reader.read_entry(&commit_hash).unwrap() {
+            Entry::Commit(commit) => commit,
+            entry => panic!("unexpected entry {:?}", entry),
+        };
+        assert_eq!((None, 5, "author", "message"), (commit.parent_commit_hash(), commit.time(), commit.author(), commit.message()));
+
+        // walk a/b by hand
+        let mut hash = *commit.root_hash();
+        for name in &["a", "b"] {
+            let entry = storage.read_entry(&hash).unwrap();
+            assert_eq!(hash, entry.hash());
+            match entry {
+                Entry::Tree(tree) => hash = *tree[*name].entry_hash(),
+                entry => panic!("unexpected entry {:?}", entry),
+            }
+        }
+        let value = reader.read_entry(&hash).unwrap();
+        assert_eq!(EntryKind::Blob, value.kind());
+        assert_eq!(value.encode().unwrap(), Entry::Blob(vec![1, 2]).encode().unwrap());
+        assert!(matches!(reader.read_entry(&[0u8; HASH_LEN]), Err(MerkleError::EntryNotFound { .. })));
     }
 
     #[test]
-    #[serial]
-    fn test_deleted_entry_available() {
-        clean_db();
+    fn test_merkle_proof() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        storage.set(key_abc, &vec![2 as u8]);
+        storage.set(&key!["a", "b"], &vec![1u8]).unwrap();
+        storage.set(&key!["a", "c"], &vec![2u8]).unwrap();
+        storage.set(&key!["d"], &vec![3u8]).unwrap();
         let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
-        storage.delete(key_abc);
-        let _commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
-
-        assert_eq!(vec![2 as u8], storage.get_history(&commit1, &key_abc).unwrap());
+        storage.set(&key!["a", "b"], &vec![4u8]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+
+        let proof = storage.get_proof(&commit1, &key!["a", "b"]).unwrap();
+        assert_eq!(2, proof.trees.len());
+        assert!(proof.verify(&commit1, &key!["a", "b"], &vec![1u8]));
+        assert!(!proof.verify(&commit1, &key!["a", "b"], &vec![4u8]));
+        assert!(!proof.verify(&commit2, &key!["a", "b"], &vec![1u8]));
+        // the same trees prove siblings too
+        assert!(proof.verify(&commit1, &key!["a", "c"], &vec![2u8]));
+        assert!(!proof.verify(&commit1, &key!["d"], &vec![3u8]));
+        assert!(storage.reader().get_proof(&commit2, &key!["a", "b"]).unwrap().verify(&commit2, &key!["a", "b"], &vec![4u8]));
+        assert!(storage.get_proof(&commit1, &key!["d"]).unwrap().verify(&commit1, &key!["d"], &vec![3u8]));
+
+        // tampered sibling changes the hash of its tree
+        let mut tampered = proof.clone();
+        let sibling = tampered.trees[1]["c"].clone();
+        tampered.trees[1].insert("c".to_string(), Node { entry_hash: [0u8; HASH_LEN], ..sibling });
+        assert!(!tampered.verify(&commit1, &key!["a", "b"], &vec![1u8]));
+
+        assert!(matches!(storage.get_proof(&commit1, &key!["a"]), Err(MerkleError::ValueIsNotABlob { .. })));
+        assert!(matches!(storage.get_proof(&commit1, &key!["a", "x"]), Err(MerkleError::ValueNotFound { .. })));
+        assert!(matches!(storage.get_proof(&commit1, &key!["d", "x"]), Err(MerkleError::ValueNotFound { .. })));
+        assert!(matches!(storage.get_proof(&commit1, &vec![]), Err(MerkleError::KeyEmpty)));
     }
 
     #[test]
-    #[serial]
-    fn test_delete_in_separate_commit() {
-        clean_db();
-
+    fn test_node_histograms() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
-        storage.set(key_abc, &vec![2 as u8]).unwrap();
-        storage.set(key_abx, &vec![3 as u8]).unwrap();
-        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(NodeHistograms::default(), storage.node_histograms().unwrap());
 
-        storage.delete(key_abx);
-        let commit2 = storage.commit(
-            0, "".to_string(), "".to_string()).unwrap();
+        for i in 0..5u8 {
+            storage.set(&vec!["a".to_string(), i.to_string()], &vec![i; 100]).unwrap();
+        }
+        storage.set(&vec!["b".to_string()], &vec![]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
 
-        assert!(storage.get_history(&commit2, &key_abx).is_err());
+        let histograms = storage.node_histograms().unwrap();
+        // root has 2 children, `a` has 5
+        assert_eq!(vec![0, 0, 1, 1], histograms.directory_fan_out.buckets);
+        assert_eq!(5, histograms.directory_fan_out.max);
+        assert_eq!(3.5, histograms.directory_fan_out.mean());
+        // one empty value, five values of 100 bytes in bucket 64..128
+        assert_eq!(6, histograms.blob_sizes.count);
+        assert_eq!(1, histograms.blob_sizes.buckets[0]);
+        assert_eq!(5, histograms.blob_sizes.buckets[7]);
+        assert_eq!(500, histograms.blob_sizes.sum);
     }
 
     #[test]
-    #[serial]
-    fn test_checkout() {
-        clean_db();
-
-        let commit1;
-        let commit2;
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
-
-        {
-            let config = Config::new().cache_capacity(32 * 1024 * 1024);
-            let mut storage = get_storage(config);
-            storage.set(key_abc, &vec![1u8]).unwrap();
-            storage.set(key_abx, &vec![2u8]).unwrap();
-            commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
-
-            storage.set(key_abc, &vec![3u8]).unwrap();
-            storage.set(key_abx, &vec![4u8]).unwrap();
-            commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
-        }
-
+    fn test_verify_range() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        storage.checkout(&commit1);
-        assert_eq!(storage.get(&key_abc).unwrap(), vec![1u8]);
-        assert_eq!(storage.get(&key_abx).unwrap(), vec![2u8]);
-        // this set be wiped by checkout
-        storage.set(key_abc, &vec![8u8]).unwrap();
-
-        storage.checkout(&commit2);
-        assert_eq!(storage.get(&key_abc).unwrap(), vec![3u8]);
-        assert_eq!(storage.get(&key_abx).unwrap(), vec![4u8]);
+        storage.set(&vec!["a".to_string(), "b".to_string()], &vec![1u8]).unwrap();
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&vec!["c".to_string()], &vec![2u8]).unwrap();
+        let commit2 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&vec!["c".to_string()], &vec![3u8]).unwrap();
+        let commit3 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        let report = storage.verify_range(&commit1, &commit3).unwrap();
+        assert_eq!(3, report.commits_verified);
+        // commit1: commit, root, a, blob 1; commit2: commit, root, blob 2; commit3: commit, root, blob 3
+        assert_eq!(10, report.entries_verified);
+        // subtree `a` is reused by commit2 and commit3
+        assert_eq!(2, report.entries_reused);
+
+        assert_eq!(1, storage.verify_range(&commit2, &commit2).unwrap().commits_verified);
+        assert!(matches!(storage.verify_range(&commit3, &commit1).err().unwrap(), MerkleError::NotAnAncestor { .. }));
+
+        // corrupt blob 1
+        let corrupted = bincode::serialize(&Entry::Blob(vec![9u8])).unwrap();
+        storage.db.put(&hash_blob(&vec![1u8]), &corrupted).unwrap();
+        assert!(matches!(storage.verify_range(&commit1, &commit3).err().unwrap(), MerkleError::EntryHashMismatch { .. }));
     }
 
     #[test]
-    #[serial]
-    fn test_persistence_over_reopens() {
-        { clean_db(); }
-
-        let key_abc: &ContextKey = &vec!["a".to_string(), "b".to_string(), "c".to_string()];
-        let commit1;
-        {
-            let config = Config::new().cache_capacity(32 * 1024 * 1024);
-            let mut storage = get_storage(config);
-            let key_abx: &ContextKey = &vec!["a".to_string(), "b".to_string(), "x".to_string()];
-            storage.set(key_abc, &vec![2 as u8]).unwrap();
-            storage.set(key_abx, &vec![3 as u8]).unwrap();
-            commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
-        }
-
+    fn test_audit_entries() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
-        assert_eq!(vec![2 as u8], storage.get_history(&commit1, &key_abc).unwrap());
+        for i in 0..100u8 {
+            storage.set(&vec![(i % 7).to_string(), i.to_string()], &vec![i]).unwrap();
+        }
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let entries_written = storage.get_merkle_stats().unwrap().counters.entries_written;
+
+        let report = storage.audit_entries(4).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.entries_checked, entries_written);
+        assert_eq!(storage.audit_entries(1).unwrap().bytes_checked, report.bytes_checked);
+
+        // corrupt a blob and add unreachable garbage
+        let corrupted_hash = hash_blob(&vec![1u8]);
+        storage.db.put(&corrupted_hash, &bincode::serialize(&Entry::Blob(vec![2u8])).unwrap()).unwrap();
+        let garbage_hash = [7u8; HASH_LEN];
+        storage.db.put(&garbage_hash, &vec![0xffu8; 3]).unwrap();
+
+        let report = storage.audit_entries(3).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.entries_checked, entries_written + 1);
+        assert_eq!(report.mismatched, vec![corrupted_hash]);
+        assert_eq!(report.undecodable, vec![garbage_hash]);
     }
 
     #[test]
-    #[serial]
-    fn test_get_errors() {
-        { clean_db(); }
+    fn test_write_once() {
+        let export_path = "_merkle_write_once_export";
+
+        let db = Arc::new(get_db(Config::new()));
+        let storage_config = MerkleStorageConfig { write_once: WriteOnceMode::Reject, ..Default::default() };
+        let mut storage = MerkleStorage::with_config(db.clone(), storage_config).unwrap();
+        storage.set(&key!["a"], &vec![1u8]).unwrap();
+        storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let file = fs::File::create(export_path).unwrap();
+        storage.snapshot().unwrap().export(file, ExportConfig::default()).join().unwrap();
+        // stored entries are not written again
+        let skipped_write_bytes = storage.get_merkle_stats().unwrap().perf_stats.skipped_write_bytes;
+        let written = storage.get_counters().unwrap().entries_written;
+        assert_eq!(0, storage.import_snapshot(fs::File::open(export_path).unwrap(), ImportConfig::default()).unwrap().entries);
+        assert!(storage.get_merkle_stats().unwrap().perf_stats.skipped_write_bytes > skipped_write_bytes);
+        assert_eq!(written, storage.get_counters().unwrap().entries_written);
+
+        // blob 1 is stored with bytes of blob 2
+        let corrupted_hash = hash_blob(&vec![1u8]);
+        let corrupted = bincode::serialize(&Entry::Blob(vec![2u8])).unwrap();
+        storage.db.put(&corrupted_hash, &corrupted).unwrap();
+        storage.set(&key!["b"], &vec![1u8]).unwrap();
+        assert!(matches!(storage.commit(1, "".to_string(), "".to_string()).err().unwrap(), MerkleError::EntryOverwrite { .. }));
+        assert!(matches!(storage.import_snapshot(fs::File::open(export_path).unwrap(), ImportConfig::default()).err().unwrap(), MerkleError::EntryOverwrite { .. }));
+
+        let storage_config = MerkleStorageConfig { write_once: WriteOnceMode::Report, ..Default::default() };
+        let mut storage = MerkleStorage::with_config(db, storage_config).unwrap();
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let reported = violations.clone();
+        storage.set_write_once_violation_hook(Box::new(move |hash, stored, _| {
+            reported.lock().unwrap().push((*hash, stored.to_vec()));
+        }));
+        storage.set(&key!["b"], &vec![1u8]).unwrap();
+        storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        assert_eq!(0, storage.import_snapshot(fs::File::open(export_path).unwrap(), ImportConfig::default()).unwrap().entries);
+        assert_eq!(vec![(corrupted_hash, corrupted.clone()); 2], *violations.lock().unwrap());
+        // stored entry is kept
+        assert_eq!(corrupted, storage.db.get_raw(&corrupted_hash).unwrap().unwrap().to_vec());
+        let _ = fs::remove_file(export_path);
+    }
 
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn test_serialize_public_types() {
         let config = Config::new().cache_capacity(32 * 1024 * 1024);
         let mut storage = get_storage(config);
+        storage.set(&vec!["a".to_string()], &vec![1u8]).unwrap();
+        let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
 
-        let res = storage.get(&vec![]);
-        assert!(if let MerkleError::KeyEmpty = res.err().unwrap() { true } else { false });
+        let report = storage.verify_range(&commit, &commit).unwrap();
+        let report: VerificationReport = serde_json::from_str(&serde_json::to_string(&report).unwrap()).unwrap();
+        assert_eq!(3, report.entries_verified);
 
-        let res = storage.get(&vec!["a".to_string()]);
-        assert!(if let MerkleError::ValueNotFound { .. } = res.err().unwrap() { true } else { false });
-    }
+        let kind: EntryKind = serde_json::from_str(&serde_json::to_string(&EntryKind::Commit).unwrap()).unwrap();
+        assert_eq!(EntryKind::Commit, kind);
 
+        let stats = storage.get_merkle_stats().unwrap();
+        let _: MerkleStorageStats = serde_json::from_str(&serde_json::to_string(&stats).unwrap()).unwrap();
+    }
 
     // Test getting entire tree in string format for JSON RPC
     #[test]
-    #[serial]
     fn test_get_context_tree_by_prefix() {
-        { clean_db(); }
-
         let all_json = "[[[\"adata\",\"b\",\"x\",\"y\"],[12,15]],[[\"data\",\"a\",\"x\",\"y\"],[5,6]],[[\"data\",\"b\",\"x\",\"y\"],[7,8]],[[\"data\",\"c\"],[2,5]]]";
         let data_json = "[[[\"data\",\"a\",\"x\",\"y\"],[5,6]],[[\"data\",\"b\",\"x\",\"y\"],[7,8]],[[\"data\",\"c\"],[2,5]]]";
 
@@ -940,5 +7602,90 @@ mod tests {
         let rv_data = storage.get_key_values_by_prefix(&EntryHash::decode(&commit).unwrap(), &vec!["data".to_string()]).unwrap();
         assert_eq!(all_json, serde_json::to_string(&rv_all.unwrap()).unwrap());
         assert_eq!(data_json, serde_json::to_string(&rv_data.unwrap()).unwrap());
+
+        // reading an older commit does not touch the working tree
+        storage.set(&vec!["data".to_string(), "c".to_string()], &vec![9]).unwrap();
+        let rv_data = storage.get_key_values_by_prefix(&EntryHash::decode(&commit).unwrap(), &vec!["data".to_string()]).unwrap();
+        assert_eq!(data_json, serde_json::to_string(&rv_data.unwrap()).unwrap());
+        assert_eq!(vec![9], storage.get(&vec!["data".to_string(), "c".to_string()]).unwrap());
+        assert!(storage.is_dirty());
+        assert_eq!(None, storage.get_key_values_by_prefix(&EntryHash::decode(&commit).unwrap(), &vec!["missing".to_string()]).unwrap());
+    }
+
+    #[test]
+    fn test_query_budget() {
+        let config = Config::new().cache_capacity(32 * 1024 * 1024);
+        let mut storage = get_storage(config);
+        for i in 0..10 {
+            storage.set(&vec!["d".to_string(), i.to_string()], &vec![i as u8; 10]).unwrap();
+        }
+        let commit1 = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        storage.set(&vec!["d".to_string(), "0".to_string()], &vec![]).unwrap();
+        let commit2 = storage.commit(1, "".to_string(), "".to_string()).unwrap();
+        let prefix = vec!["d".to_string()];
+
+        let res = storage.get_key_values_by_prefix_with_budget(&commit1, &prefix, QueryBudget::default()).unwrap();
+        assert!(!res.budget_exceeded);
+        assert_eq!(res.value.unwrap().len(), 10);
+
+        let budget = QueryBudget { max_entries: Some(3), ..QueryBudget::default() };
+        let res = storage.get_by_prefix_with_budget(&prefix, budget).unwrap();
+        assert!(res.budget_exceeded);
+        let keys: Vec<ContextKey> = res.value.unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![vec!["d".to_string(), "0".to_string()], vec!["d".to_string(), "1".to_string()], vec!["d".to_string(), "2".to_string()]]);
+
+        // serialized blob of 10 bytes takes 22 bytes
+        let budget = QueryBudget { max_bytes: Some(50), ..QueryBudget::default() };
+        let res = storage.reader().list_with_budget(&commit1, &prefix, budget).unwrap();
+        assert!(res.budget_exceeded);
+        assert_eq!(res.value.unwrap().len(), 2);
+
+        let budget = QueryBudget { max_duration: Some(Duration::from_secs(0)), ..QueryBudget::default() };
+        let res = storage.reader().list_with_budget(&commit1, &prefix, budget).unwrap();
+        assert!(res.budget_exceeded);
+        let budget = QueryBudget { max_duration: Some(Duration::from_secs(3600)), ..QueryBudget::default() };
+        assert!(!storage.reader().list_with_budget(&commit1, &prefix, budget).unwrap().budget_exceeded);
+
+        let reader = storage.reader();
+        let budget = QueryBudget { max_entries: Some(2), ..QueryBudget::default() };
+        let res = reader.diverging_paths_with_budget(&commit1, &reader, &commit2, 10, budget).unwrap();
+        assert!(res.budget_exceeded);
+        assert!(res.value.is_empty());
+        let res = reader.diverging_paths_with_budget(&commit1, &reader, &commit2, 10, QueryBudget::default()).unwrap();
+        assert!(!res.budget_exceeded);
+        assert_eq!(res.value, vec![(vec!["d".to_string(), "0".to_string()], PathDivergence::Different)]);
+
+        // root, `d` and the first two values
+        let count = |count, _: &ContextKey, _: &FoldEntry| Ok(count + 1);
+        let budget = QueryBudget { max_entries: Some(4), ..QueryBudget::default() };
+        let res = reader.fold_with_budget(&commit1, &Vec::new(), None, 0, budget, count).unwrap();
+        assert!(res.budget_exceeded);
+        assert_eq!(res.value, 4);
+        let res = storage.fold_with_budget(&commit1, &Vec::new(), None, 0, QueryBudget::default(), count).unwrap();
+        assert!(!res.budget_exceeded);
+        assert_eq!(res.value, 12);
+
+        // roots and `d` of both commits
+        let budget = QueryBudget { max_entries: Some(3), ..QueryBudget::default() };
+        let res = reader.diff_prefix_with_budget(&commit1, &commit2, &Vec::new(), budget).unwrap();
+        assert!(res.budget_exceeded);
+        assert!(res.value.is_empty());
+        let budget = QueryBudget { max_entries: Some(4), ..QueryBudget::default() };
+        let res = storage.diff_prefix_with_budget(&commit1, &commit2, &Vec::new(), budget).unwrap();
+        assert!(!res.budget_exceeded);
+        assert_eq!(res.value.len(), 1);
+
+        // values of the change count too
+        let res = reader.diff_with_budget(&commit1, &commit2, budget).unwrap();
+        assert!(res.budget_exceeded);
+        assert!(res.value.is_empty());
+        let budget = QueryBudget { max_entries: Some(6), ..QueryBudget::default() };
+        let res = storage.diff_with_budget(&commit1, &commit2, budget).unwrap();
+        assert!(!res.budget_exceeded);
+        assert_eq!(res.value.len(), 1);
+
+        let budget = QueryBudget { max_duration: Some(Duration::from_secs(0)), ..QueryBudget::default() };
+        assert!(reader.fold_with_budget(&commit1, &Vec::new(), None, 0, budget, count).unwrap().budget_exceeded);
+        assert!(reader.diff_with_budget(&commit1, &commit2, budget).unwrap().budget_exceeded);
     }
 }
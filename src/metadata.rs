@@ -0,0 +1,92 @@
+//! Store-wide metadata.
+//!
+//! Small named records describing the store as a whole are kept in their own sled tree, apart
+//! from the content addressed entries.
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::schema::KeyValueSchema;
+
+pub type MetadataKV = dyn KeyValueStoreWithSchema<MetadataSchema> + Sync + Send;
+
+/// Key of [PersistentCounters] in the metadata tree
+pub const COUNTERS_KEY: &str = "counters";
+
+/// Key of [DatabaseHeader] in the metadata tree
+pub const HEADER_KEY: &str = "header";
+
+/// Version of serialized form of entries, bumped on every incompatible change
+pub const ENTRY_FORMAT_VERSION: u32 = 2;
+
+/// Entry format of databases written before [DatabaseHeader] was introduced, which have none
+pub const LEGACY_ENTRY_FORMAT_VERSION: u32 = 1;
+
+/// Whether entries of format `version` can be read, those of older formats are decoded alongside
+/// the current ones
+pub fn is_readable_entry_format(version: u32) -> bool {
+    (LEGACY_ENTRY_FORMAT_VERSION..=ENTRY_FORMAT_VERSION).contains(&version)
+}
+
+/// Hashing of entries, hashes of the same data differ across schemes
+pub const HASH_SCHEME: &str = "blake2b-256-irmin";
+
+/// Encoding of entry keys in the database
+pub const KEY_ENCODING: &str = "raw-entry-hash";
+
+/// Fingerprint of the build, which created a database, written on creation and validated on
+/// every open. Only the crate version may differ between compatible builds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatabaseHeader {
+    pub crate_version: String,
+    pub hash_scheme: String,
+    pub entry_format_version: u32,
+    pub key_encoding: String,
+}
+
+impl BincodeEncoded for DatabaseHeader {}
+
+impl DatabaseHeader {
+    /// Header of databases created by this build
+    pub fn current() -> Self {
+        DatabaseHeader {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            hash_scheme: HASH_SCHEME.to_string(),
+            entry_format_version: ENTRY_FORMAT_VERSION,
+            key_encoding: KEY_ENCODING.to_string(),
+        }
+    }
+
+    /// Header assumed for databases written before headers were introduced
+    pub fn legacy() -> Self {
+        DatabaseHeader { crate_version: String::new(), entry_format_version: LEGACY_ENTRY_FORMAT_VERSION, ..Self::current() }
+    }
+}
+
+/// Cumulative operation counters, which survive restarts of the process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistentCounters {
+    pub commits: u64,
+    pub entries_written: u64,
+    pub gc_reclaimed_bytes: u64,
+}
+
+impl BincodeEncoded for PersistentCounters {}
+
+/// Serialized metadata records keyed by name
+pub struct MetadataSchema;
+
+impl KeyValueSchema for MetadataSchema {
+    type Key = String;
+    type Value = Vec<u8>;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_metadata"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,72 @@
+//! Presets of IO and memory related settings.
+//!
+//! A [PerformanceProfile] bundles sled settings (cache capacity, storage mode, segment size,
+//! flush interval) with crate-level settings of [MerkleStorageConfig], so embedders can pick a
+//! sensible combination instead of tuning each knob separately.
+use sled::Mode;
+
+use crate::merkle_storage::MerkleStorageConfig;
+
+/// Preset of IO and memory related settings, `Balanced` by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum PerformanceProfile {
+    /// Small page cache, compact storage and values sliced from read buffers instead of decoded
+    LowMemory,
+    /// Moderate page cache with sled's default storage settings
+    #[default]
+    Balanced,
+    /// Large page cache, large segments and storage mode tuned for write throughput at the cost
+    /// of space
+    Throughput,
+}
+
+impl PerformanceProfile {
+    /// Page cache capacity of sled in bytes
+    pub fn cache_capacity(&self) -> u64 {
+        match self {
+            PerformanceProfile::LowMemory => 16 * 1024 * 1024,
+            PerformanceProfile::Balanced => 256 * 1024 * 1024,
+            PerformanceProfile::Throughput => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// Size of sled's log segments in bytes. Segment size is fixed when a database is created,
+    /// it has no effect on existing databases.
+    pub fn segment_size(&self) -> usize {
+        match self {
+            PerformanceProfile::LowMemory => 256 * 1024,
+            PerformanceProfile::Balanced => 512 * 1024,
+            PerformanceProfile::Throughput => 8 * 1024 * 1024,
+        }
+    }
+
+    /// Interval of background flushes of sled in milliseconds
+    pub fn flush_every_ms(&self) -> u64 {
+        match self {
+            PerformanceProfile::LowMemory | PerformanceProfile::Balanced => 500,
+            PerformanceProfile::Throughput => 1000,
+        }
+    }
+
+    /// Sled configuration of this profile, a path still has to be set before it is opened.
+    pub fn sled_config(&self) -> sled::Config {
+        let mode = match self {
+            PerformanceProfile::Throughput => Mode::HighThroughput,
+            _ => Mode::LowSpace,
+        };
+        sled::Config::new()
+            .cache_capacity(self.cache_capacity())
+            .segment_size(self.segment_size())
+            .flush_every_ms(Some(self.flush_every_ms()))
+            .mode(mode)
+    }
+
+    /// Storage configuration of this profile, settings not related to performance are default.
+    pub fn storage_config(&self) -> MerkleStorageConfig {
+        MerkleStorageConfig {
+            decode_on_demand: matches!(self, PerformanceProfile::LowMemory),
+            ..MerkleStorageConfig::default()
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Verification of Merkle proofs.
+//!
+//! Proofs produced by [MerkleStorage::get_proof](crate::merkle_storage::MerkleStorage::get_proof)
+//! and [MerkleStorage::get_exclusion_proof](crate::merkle_storage::MerkleStorage::get_exclusion_proof)
+//! are checked with the hashing code only, no database is involved, so light clients can verify
+//! values, or their absence, served by a node without keeping any state.
+#[cfg(feature = "serialize")]
+use serde::{Deserialize, Serialize};
+
+use crate::merkle_storage::{hash_blob, hash_commit, hash_tree, Commit, ContextKey, ContextValue, EntryHash, NodeKind, Tree};
+
+/// Proof that a value is, or is not, stored under a key in a commit. It consists of the commit
+/// and of every tree on the path to the key, so the hashes of all siblings along the path are
+/// included.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct MerkleProof {
+    pub commit: Commit,
+    /// Trees from the root tree down to the tree holding the value, one per key fragment. Proofs
+    /// of absence end with the tree, in which the path to the key ends.
+    pub trees: Vec<Tree>,
+}
+
+impl MerkleProof {
+    /// Check that `value` is stored under `key` in commit `commit_hash`.
+    pub fn verify(&self, commit_hash: &EntryHash, key: &ContextKey, value: &ContextValue) -> bool {
+        hash_commit(&self.commit) == *commit_hash && verify_proof(self.commit.root_hash(), key, value, self)
+    }
+
+    /// Check that no value is stored under `key` in commit `commit_hash`.
+    pub fn verify_exclusion(&self, commit_hash: &EntryHash, key: &ContextKey) -> bool {
+        hash_commit(&self.commit) == *commit_hash && verify_exclusion_proof(self.commit.root_hash(), key, self)
+    }
+}
+
+/// Check that `value` is stored under `key` in the tree with hash `root_hash`. The commit of
+/// the proof is not checked, see [MerkleProof::verify].
+pub fn verify_proof(root_hash: &EntryHash, key: &ContextKey, value: &ContextValue, proof: &MerkleProof) -> bool {
+    if key.is_empty() || key.len() != proof.trees.len() {
+        return false;
+    }
+    let mut expected = *root_hash;
+    for (depth, (tree, fragment)) in proof.trees.iter().zip(key).enumerate() {
+        if hash_tree(tree) != expected {
+            return false;
+        }
+        let expected_kind = if depth + 1 == key.len() { NodeKind::Leaf } else { NodeKind::NonLeaf };
+        match tree.get(fragment) {
+            Some(node) if node.node_kind() == expected_kind => expected = *node.entry_hash(),
+            _ => return false,
+        }
+    }
+    expected == hash_blob(value)
+}
+
+/// Check that no value is stored under `key` in the tree with hash `root_hash`: the path to
+/// `key` ends in the last tree of the proof, either because the child is missing, or because it
+/// is a value while the key continues, or because `key` itself is a tree.
+pub fn verify_exclusion_proof(root_hash: &EntryHash, key: &ContextKey, proof: &MerkleProof) -> bool {
+    if key.is_empty() || proof.trees.is_empty() || proof.trees.len() > key.len() {
+        return false;
+    }
+    let mut expected = *root_hash;
+    for (depth, (tree, fragment)) in proof.trees.iter().zip(key).enumerate() {
+        if hash_tree(tree) != expected {
+            return false;
+        }
+        let last_tree = depth + 1 == proof.trees.len();
+        match tree.get(fragment) {
+            None => return last_tree,
+            Some(node) if node.node_kind() == NodeKind::Leaf => return last_tree && depth + 1 < key.len(),
+            Some(_) if depth + 1 == key.len() => return last_tree,
+            Some(node) => expected = *node.entry_hash(),
+        }
+    }
+    // path continues past the last tree
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::database::SledDBWrapper;
+    use crate::merkle_storage::{MerkleError, MerkleStorage};
+
+    #[test]
+    fn test_verify_proof() {
+        let db = sled::Config::new().temporary(true).open().expect("error opening database");
+        let mut storage = MerkleStorage::new(Arc::new(SledDBWrapper::new(db))).unwrap();
+        let key = vec!["a".to_string(), "b".to_string()];
+        storage.set(&key, &vec![1u8]).unwrap();
+        let commit_hash = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+        let proof = storage.get_proof(&commit_hash, &key).unwrap();
+        drop(storage);
+
+        let root_hash = *proof.commit.root_hash();
+        assert!(verify_proof(&root_hash, &key, &vec![1u8], &proof));
+        assert!(!verify_proof(&root_hash, &key, &vec![2u8], &proof));
+        assert!(!verify_proof(&[0u8; 32], &key, &vec![1u8], &proof));
+        assert!(!verify_proof(&root_hash, &key[..1].to_vec(), &vec![1u8], &proof));
+        assert!(!verify_proof(&root_hash, &vec!["a".to_string(), "c".to_string()], &vec![1u8], &proof));
+    }
+
+    #[test]
+    fn test_verify_exclusion_proof() {
+        let db = sled::Config::new().temporary(true).open().expect("error opening database");
+        let mut storage = MerkleStorage::new(Arc::new(SledDBWrapper::new(db))).unwrap();
+        let key = |path: &str| path.split('/').map(str::to_string).collect::<ContextKey>();
+        storage.set(&key("a/b"), &vec![1u8]).unwrap();
+        storage.set(&key("a/d"), &vec![2u8]).unwrap();
+        let commit_hash = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+        // missing child, child which is a value, key which is a tree
+        for (path, trees) in &[("a/c", 2), ("x/y", 1), ("a/b/c", 2), ("a", 1)] {
+            let proof = storage.get_exclusion_proof(&commit_hash, &key(path)).unwrap();
+            assert_eq!(*trees, proof.trees.len());
+            assert!(proof.verify_exclusion(&commit_hash, &key(path)));
+        }
+        assert!(matches!(storage.get_exclusion_proof(&commit_hash, &key("a/b")), Err(MerkleError::ValueExists { .. })));
+
+        // proof of absence of a/c covers paths through its value siblings, not the siblings
+        let proof = storage.get_exclusion_proof(&commit_hash, &key("a/c")).unwrap();
+        let root_hash = *proof.commit.root_hash();
+        assert!(!verify_exclusion_proof(&root_hash, &key("a/b"), &proof));
+        assert!(verify_exclusion_proof(&root_hash, &key("a/d/e/f"), &proof));
+        assert!(!verify_exclusion_proof(&root_hash, &key("a"), &proof));
+        assert!(!verify_exclusion_proof(&[0u8; 32], &key("a/c"), &proof));
+
+        // truncated proof
+        let truncated = MerkleProof { commit: proof.commit.clone(), trees: proof.trees[..1].to_vec() };
+        assert!(!verify_exclusion_proof(&root_hash, &key("a/c"), &truncated));
+    }
+}
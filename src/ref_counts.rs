@@ -0,0 +1,32 @@
+//! Reference counts of entries.
+//!
+//! With [GcMode::RefCounting](crate::merkle_storage::GcMode::RefCounting) every stored tree and
+//! blob counts the stored trees and commits referencing it, so
+//! [MerkleStorage::delete_commit](crate::merkle_storage::MerkleStorage::delete_commit) reclaims
+//! entries exclusive to a commit at once, without scanning the whole store.
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type RefCountKV = dyn KeyValueStoreWithSchema<RefCountSchema> + Sync + Send;
+
+/// Key of the metadata record present while ref counts are maintained
+pub const REF_COUNTS_KEY: &str = "ref_counts";
+
+/// Numbers of references to entries keyed by entry hash, unreferenced entries have no record
+pub struct RefCountSchema;
+
+impl KeyValueSchema for RefCountSchema {
+    type Key = EntryHash;
+    type Value = u64;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_ref_counts"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
@@ -0,0 +1,27 @@
+//! Named references to commits, like branches.
+//!
+//! Refs are kept in their own sled tree and updated with compare-and-swap, so several writers
+//! sharing one database can coordinate ref updates through the storage itself.
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type RefsKV = dyn KeyValueStoreWithSchema<RefSchema> + Sync + Send;
+
+/// Commit hashes keyed by ref name
+pub struct RefSchema;
+
+impl KeyValueSchema for RefSchema {
+    type Key = String;
+    type Value = EntryHash;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_refs"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
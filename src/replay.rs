@@ -0,0 +1,342 @@
+//! Replay of recorded context actions.
+//!
+//! Actions done by the protocol while applying blocks can be recorded with [write_action] and
+//! re-applied later by [apply_recorded], which checks every commit against the context hash
+//! recorded with it. Replaying real chain history is the way to validate changes of the
+//! storage, and the per-block timings it reports make regressions visible.
+//!
+//! [check_order_independence] replays actions with independent writes shuffled, to test that
+//! commit hashes do not depend on the order keys were staged in.
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::apply_metrics::ApplyMetrics;
+use crate::hash::HashType;
+use crate::merkle_storage::{ContextKey, ContextValue, EntryHash, MerkleError, MerkleStorage};
+
+#[derive(Debug, Fail)]
+pub enum ReplayError {
+    #[fail(display = "Merkle storage error: {}", error)]
+    MerkleError { error: MerkleError },
+    #[fail(display = "Invalid recorded action: {}", error)]
+    DecodeError { error: bincode::Error },
+    #[fail(display = "Context hash mismatch at commit {}, expected {}, computed {}", commit, expected, computed)]
+    ContextHashMismatch { commit: u64, expected: String, computed: String },
+    #[fail(display = "Commit {} to start from was not found in recorded actions", commit)]
+    StartNotFound { commit: String },
+}
+
+impl From<MerkleError> for ReplayError {
+    fn from(error: MerkleError) -> Self {
+        ReplayError::MerkleError { error }
+    }
+}
+
+impl From<bincode::Error> for ReplayError {
+    fn from(error: bincode::Error) -> Self {
+        ReplayError::DecodeError { error }
+    }
+}
+
+/// Context action done by the protocol
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContextAction {
+    Set { key: ContextKey, value: ContextValue },
+    Delete { key: ContextKey },
+    Copy { from_key: ContextKey, to_key: ContextKey },
+    Get { key: ContextKey },
+    Mem { key: ContextKey },
+    Checkout { context_hash: EntryHash },
+    /// Commit, which resulted in `context_hash`
+    Commit { time: u64, author: String, message: String, context_hash: EntryHash },
+}
+
+/// Append `action` to a recorded stream.
+pub fn write_action<W: Write>(writer: &mut W, action: &ContextAction) -> Result<(), ReplayError> {
+    Ok(bincode::serialize_into(writer, action)?)
+}
+
+/// Actions read from a stream written by [write_action]
+pub struct ActionReader<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> ActionReader<R> {
+    pub fn new(reader: R) -> Self {
+        ActionReader { reader }
+    }
+}
+
+impl<R: BufRead> Iterator for ActionReader<R> {
+    type Item = Result<ContextAction, ReplayError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // stream ends between actions, an action cut short is an error
+        match self.reader.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(bincode::deserialize_from(&mut self.reader).map_err(ReplayError::from)),
+            Err(error) => Some(Err(bincode::Error::from(error).into())),
+        }
+    }
+}
+
+/// Result of [apply_recorded]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct ReplayReport {
+    pub actions_applied: u64,
+    /// Verified commits with the cost of the actions leading to them
+    pub commits: Vec<(EntryHash, ApplyMetrics)>,
+    pub duration: Duration,
+}
+
+/// Apply recorded `actions` to `storage` and check the hash of every commit. Replay stops at
+/// the first commit with a hash other than the recorded one.
+///
+/// With `from_commit`, `storage` is switched to that commit and actions up to and including
+/// its recorded commit are skipped, so a range of a recording of the whole chain can be
+/// replayed against a store holding its beginning.
+pub fn apply_recorded<I>(storage: &mut MerkleStorage, actions: I, from_commit: Option<&EntryHash>) -> Result<ReplayReport, ReplayError>
+    where I: IntoIterator<Item = Result<ContextAction, ReplayError>>
+{
+    let started = Instant::now();
+    let mut actions = actions.into_iter();
+    if let Some(from_commit) = from_commit {
+        storage.checkout(from_commit)?;
+        loop {
+            match actions.next().transpose()? {
+                Some(ContextAction::Commit { context_hash, .. }) if context_hash == *from_commit => break,
+                Some(_) => continue,
+                None => return Err(ReplayError::StartNotFound { commit: HashType::ContextHash.bytes_to_string(from_commit) }),
+            }
+        }
+    }
+
+    let mut report = ReplayReport::default();
+    let (mut block_started, mut block) = (Instant::now(), ApplyMetrics::default());
+    for action in actions {
+        report.actions_applied += 1;
+        match action? {
+            ContextAction::Set { key, value } => {
+                block.writes += 1;
+                storage.set(&key, &value)?;
+            }
+            ContextAction::Delete { key } => {
+                block.writes += 1;
+                storage.delete(&key)?;
+            }
+            ContextAction::Copy { from_key, to_key } => {
+                block.writes += 1;
+                storage.copy(&from_key, &to_key)?;
+            }
+            ContextAction::Get { key } => {
+                block.reads += 1;
+                match storage.get(&key) {
+                    Ok(_) | Err(MerkleError::ValueNotFound { .. }) => (),
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            ContextAction::Mem { key } => {
+                block.reads += 1;
+                storage.exists(&key)?;
+            }
+            ContextAction::Checkout { context_hash } => storage.checkout(&context_hash)?,
+            ContextAction::Commit { time, author, message, context_hash } => {
+                let computed = storage.commit(time, author, message)?;
+                if computed != context_hash {
+                    return Err(ReplayError::ContextHashMismatch {
+                        commit: report.commits.len() as u64,
+                        expected: HashType::ContextHash.bytes_to_string(&context_hash),
+                        computed: HashType::ContextHash.bytes_to_string(&computed),
+                    });
+                }
+                block.duration_micros = block_started.elapsed().as_micros() as u64;
+                report.commits.push((computed, block));
+                block_started = Instant::now();
+                block = ApplyMetrics::default();
+            }
+        }
+    }
+    report.duration = started.elapsed();
+    Ok(report)
+}
+
+/// Shuffle `actions` without changing their outcome. Commits, checkouts and copies keep their
+/// positions, actions between them are reordered, except that writes keep their order relative
+/// to other actions on the same key, its prefixes or its descendants.
+pub fn shuffle_independent<R: Rng>(actions: &[ContextAction], rng: &mut R) -> Vec<ContextAction> {
+    let mut shuffled = Vec::with_capacity(actions.len());
+    let mut pending = Vec::new();
+    for action in actions {
+        match action {
+            ContextAction::Set { .. } | ContextAction::Delete { .. } | ContextAction::Get { .. } | ContextAction::Mem { .. } => {
+                pending.push(action.clone());
+            }
+            _ => {
+                shuffle_pending(&mut pending, rng, &mut shuffled);
+                shuffled.push(action.clone());
+            }
+        }
+    }
+    shuffle_pending(&mut pending, rng, &mut shuffled);
+    shuffled
+}
+
+/// Move `pending` actions to `shuffled` in random order respecting their dependencies.
+fn shuffle_pending<R: Rng>(pending: &mut Vec<ContextAction>, rng: &mut R, shuffled: &mut Vec<ContextAction>) {
+    while !pending.is_empty() {
+        let ready: Vec<usize> = (0..pending.len())
+            .filter(|&i| pending[..i].iter().all(|earlier| !depends(earlier, &pending[i])))
+            .collect();
+        let next = ready[rng.gen_range(0, ready.len())];
+        shuffled.push(pending.remove(next));
+    }
+}
+
+/// Whether order of two key actions matters
+fn depends(a: &ContextAction, b: &ContextAction) -> bool {
+    let (key_a, write_a) = key_action(a);
+    let (key_b, write_b) = key_action(b);
+    (write_a || write_b) && (key_a.starts_with(key_b) || key_b.starts_with(key_a))
+}
+
+fn key_action(action: &ContextAction) -> (&ContextKey, bool) {
+    match action {
+        ContextAction::Set { key, .. } | ContextAction::Delete { key } => (key, true),
+        ContextAction::Get { key } | ContextAction::Mem { key } => (key, false),
+        _ => unreachable!("only key actions are shuffled"),
+    }
+}
+
+/// Replay `actions` `rounds` times, each time shuffled by [shuffle_independent] into a fresh
+/// storage from `new_storage`. Fails with [ReplayError::ContextHashMismatch] if a commit hash
+/// depends on the order of staged writes.
+pub fn check_order_independence<R, F>(actions: &[ContextAction], rounds: usize, rng: &mut R, mut new_storage: F) -> Result<(), ReplayError>
+    where R: Rng, F: FnMut() -> MerkleStorage
+{
+    for _ in 0..rounds {
+        let shuffled = shuffle_independent(actions, rng);
+        apply_recorded(&mut new_storage(), shuffled.into_iter().map(Ok), None)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use crate::database::SledDBWrapper;
+
+    fn get_storage() -> MerkleStorage {
+        let db = sled::Config::new().temporary(true).open().expect("error opening database");
+        MerkleStorage::new(Arc::new(SledDBWrapper::new(db))).unwrap()
+    }
+
+    fn key(path: &str) -> ContextKey {
+        path.split('/').map(str::to_string).collect()
+    }
+
+    /// Record three blocks applied to a fresh storage
+    fn record() -> (Vec<u8>, Vec<EntryHash>) {
+        let mut storage = get_storage();
+        let (mut stream, mut commits) = (Vec::new(), Vec::new());
+        for block in 0..3u8 {
+            let mut actions = vec![
+                ContextAction::Get { key: key("data/counter") },
+                ContextAction::Set { key: key("data/counter"), value: vec![block] },
+                ContextAction::Set { key: key(&format!("data/blocks/{}", block)), value: vec![block; 10] },
+                ContextAction::Copy { from_key: key("data/blocks"), to_key: key("backup") },
+                ContextAction::Mem { key: key("data/missing") },
+            ];
+            if block == 2 {
+                actions.push(ContextAction::Delete { key: key("backup/0") });
+            }
+            for action in &actions {
+                apply_recorded(&mut storage, vec![Ok(action.clone())], None).unwrap();
+                write_action(&mut stream, action).unwrap();
+            }
+            let commit = storage.commit(block as u64, "baker".to_string(), format!("block {}", block)).unwrap();
+            let action = ContextAction::Commit { time: block as u64, author: "baker".to_string(), message: format!("block {}", block), context_hash: commit };
+            write_action(&mut stream, &action).unwrap();
+            commits.push(commit);
+        }
+        (stream, commits)
+    }
+
+    #[test]
+    fn test_apply_recorded() -> Result<(), ReplayError> {
+        let (stream, commits) = record();
+
+        let mut storage = get_storage();
+        let report = apply_recorded(&mut storage, ActionReader::new(Cursor::new(&stream)), None)?;
+        assert_eq!(commits, report.commits.iter().map(|(hash, _)| *hash).collect::<Vec<_>>());
+        assert_eq!((2, 3), (report.commits[0].1.reads, report.commits[0].1.writes));
+        assert_eq!(4, report.commits[2].1.writes);
+        assert_eq!(19, report.actions_applied);
+
+        // range of the recording
+        let report = apply_recorded(&mut storage, ActionReader::new(Cursor::new(&stream)), Some(&commits[0]))?;
+        assert_eq!(&commits[1..], &report.commits.iter().map(|(hash, _)| *hash).collect::<Vec<_>>()[..]);
+        let result = apply_recorded(&mut storage, ActionReader::new(Cursor::new(&stream)), Some(&[0; 32]));
+        assert!(matches!(result, Err(ReplayError::MerkleError { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_errors() {
+        let (stream, commits) = record();
+        let mut actions: Vec<_> = ActionReader::new(Cursor::new(&stream)).map(Result::unwrap).collect();
+
+        // storage change altering hashes of the second block
+        actions.insert(7, ContextAction::Set { key: key("data/extra"), value: vec![1] });
+        let mut storage = get_storage();
+        match apply_recorded(&mut storage, actions.into_iter().map(Ok), None) {
+            Err(ReplayError::ContextHashMismatch { commit, expected, .. }) => {
+                assert_eq!(1, commit);
+                assert_eq!(HashType::ContextHash.bytes_to_string(&commits[1]), expected);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let mut storage = get_storage();
+        let result = apply_recorded(&mut storage, ActionReader::new(Cursor::new(&stream[..stream.len() - 3])), None);
+        assert!(matches!(result, Err(ReplayError::DecodeError { .. })));
+        assert_eq!(Some(commits[1]), storage.get_last_commit_hash());
+    }
+
+    #[test]
+    fn test_order_independence() -> Result<(), ReplayError> {
+        let (stream, _) = record();
+        let actions: Vec<_> = ActionReader::new(Cursor::new(&stream)).collect::<Result<_, _>>()?;
+        let mut rng = StdRng::seed_from_u64(510);
+        check_order_independence(&actions, 20, &mut rng, get_storage)?;
+
+        // dependent writes keep their order, barriers their positions
+        let actions = vec![
+            ContextAction::Set { key: key("a/b"), value: vec![1] },
+            ContextAction::Get { key: key("c") },
+            ContextAction::Delete { key: key("a") },
+            ContextAction::Get { key: key("a/b") },
+            ContextAction::Set { key: key("a/c"), value: vec![2] },
+            ContextAction::Checkout { context_hash: [0; 32] },
+            ContextAction::Set { key: key("d"), value: vec![3] },
+        ];
+        for _ in 0..20 {
+            let shuffled = shuffle_independent(&actions, &mut rng);
+            let position = |action: &ContextAction| shuffled.iter().position(|a| a == action).unwrap();
+            assert!(position(&actions[0]) < position(&actions[2]));
+            assert!(position(&actions[2]) < position(&actions[3]) && position(&actions[2]) < position(&actions[4]));
+            assert_eq!(&actions[5..], &shuffled[5..]);
+        }
+        Ok(())
+    }
+}
@@ -3,7 +3,7 @@
 
 
 
-use crate::codec::Codec;
+use crate::codec::{Codec, Decoder, SchemaError};
 
 /// This trait extends basic column family by introducing Codec types safety and enforcement
 pub trait KeyValueSchema {
@@ -11,6 +11,55 @@ pub trait KeyValueSchema {
     type Value: Codec;
 
     fn name() -> &'static str;
+
+    /// Name of the sled tree holding this schema. Schemas without their own tree share the
+    /// default tree of the database.
+    fn tree_name() -> Option<&'static str> {
+        None
+    }
+
+    /// Whether puts of this schema may be buffered by the write coalescer of
+    /// [SledDBWrapper](crate::database::SledDBWrapper), if it has one. Buffered puts are visible to
+    /// reads through the wrapper, but are lost on crash until flushed.
+    fn coalesce_writes() -> bool {
+        false
+    }
+
+    /// Decode value written in an older format of this schema, `None` if `bytes` are in none of
+    /// them. Tried only when `bytes` do not decode as the current [Value](Self::Value), so formats
+    /// can evolve without migrating stored values first, see [upgrade_from].
+    fn upgrade_value(_bytes: &[u8]) -> Option<Self::Value> {
+        None
+    }
+
+    /// Whether values upgraded on `get` are written back in the current format, so later reads
+    /// decode them directly.
+    fn rewrite_upgraded() -> bool {
+        false
+    }
+
+    /// Number of entries iterators of this schema read from sled at once, unless changed by
+    /// [IteratorWithSchema::read_batch](crate::database::IteratorWithSchema::read_batch)
+    fn read_batch_size() -> usize {
+        1
+    }
+}
+
+/// Decode `bytes` in the older format `Old` and upgrade the result by `upgrade`. Older formats
+/// of [KeyValueSchema::upgrade_value] are chained from the newest to the oldest, e.g.
+/// `upgrade_from(bytes, V1::into).or_else(|| upgrade_from(bytes, |v: V0| V1::from(v).into()))`.
+#[allow(dead_code)]
+pub fn upgrade_from<Old: Decoder, New, F: FnOnce(Old) -> New>(bytes: &[u8], upgrade: F) -> Option<New> {
+    Old::decode(bytes).ok().map(upgrade)
+}
+
+/// Decode stored value of schema `S`, upgrading it from an older format if needed. Second item
+/// tells whether the value was upgraded.
+pub(crate) fn decode_value<S: KeyValueSchema + ?Sized>(bytes: &[u8]) -> Result<(S::Value, bool), SchemaError> {
+    match S::Value::decode(bytes) {
+        Ok(value) => Ok((value, false)),
+        Err(error) => S::upgrade_value(bytes).map(|value| (value, true)).ok_or(error),
+    }
 }
 
 pub struct CommitLogDescriptor {
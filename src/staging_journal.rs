@@ -0,0 +1,50 @@
+//! Journal of staged changes.
+//!
+//! When enabled, every change of the staging area is appended to the journal and flushed before
+//! the call returns, unless
+//! [MerkleStorageConfig::sync_staging_journal](crate::merkle_storage::MerkleStorageConfig::sync_staging_journal)
+//! is off. The journal is cleared by the next commit, checkout or reset. A process, which
+//! crashed mid-block, finds the journal non-empty on restart and can replay it on top of the
+//! commit it was staged on, or discard it.
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::{ContextKey, ContextValue};
+use crate::schema::KeyValueSchema;
+
+/// Metadata key of the commit the journaled actions were staged on, missing if they were staged
+/// on an empty tree
+pub const STAGING_BASE_KEY: &str = "staging_base";
+
+pub type StagingJournalKV = dyn KeyValueStoreWithSchema<StagingJournalSchema> + Sync + Send;
+
+/// Change of the staging area, as done by the method of the same name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StagedAction {
+    Set { key: ContextKey, value: ContextValue },
+    Delete { key: ContextKey },
+    DeleteRecursively { prefix: ContextKey },
+    Copy { from_key: ContextKey, to_key: ContextKey },
+    Revert { key: ContextKey },
+}
+
+impl BincodeEncoded for StagedAction {}
+
+/// Staged actions keyed by sequence number, starting from 0 after every clear
+pub struct StagingJournalSchema;
+
+impl KeyValueSchema for StagingJournalSchema {
+    type Key = u64;
+    type Value = StagedAction;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_staging_journal"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
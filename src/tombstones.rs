@@ -0,0 +1,77 @@
+//! Tombstones of deleted context keys.
+//!
+//! When enabled, every commit records the keys deleted by it together with the commit hash and
+//! time. Tombstoned keys are also indexed by that time, so each commit drops the tombstones which
+//! fell out of the configured retention window without scanning all of them. The schemas stay
+//! small while recent deletes can be queried without diffing commits.
+use serde::{Deserialize, Serialize};
+
+use crate::codec::BincodeEncoded;
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+pub type TombstoneKV = dyn KeyValueStoreWithSchema<TombstoneSchema> + Sync + Send;
+pub type TombstoneExpiryKV = dyn KeyValueStoreWithSchema<TombstoneExpirySchema> + Sync + Send;
+
+/// Delete of a key performed by a commit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub commit_hash: EntryHash,
+    pub time: u64,
+}
+
+/// All retained tombstones of a single key, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Tombstones(pub Vec<Tombstone>);
+
+impl BincodeEncoded for Tombstones {}
+
+/// Keys tombstoned by commits made at one time
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TombstonedKeys(pub Vec<String>);
+
+impl BincodeEncoded for TombstonedKeys {}
+
+impl Tombstones {
+    /// Drop tombstones which fell out of the retention window ending at `now`.
+    pub fn retain_since(&mut self, now: u64, retention: u64) {
+        self.0.retain(|tombstone| tombstone.time.saturating_add(retention) >= now);
+    }
+}
+
+/// Tombstones keyed by slash separated context key
+pub struct TombstoneSchema;
+
+impl KeyValueSchema for TombstoneSchema {
+    type Key = String;
+    type Value = Tombstones;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_tombstones"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
+
+/// Tombstoned keys keyed by commit time, oldest first
+pub struct TombstoneExpirySchema;
+
+impl KeyValueSchema for TombstoneExpirySchema {
+    type Key = u64;
+    type Value = TombstonedKeys;
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_tombstone_expiry"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
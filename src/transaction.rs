@@ -0,0 +1,402 @@
+//! Atomic updates spanning several schemas.
+//!
+//! Schemas with their own sled tree cannot be updated together by a write batch. A
+//! [SchemaTransaction] gives typed access to trees of several schemas inside one sled
+//! transaction, so e.g. entries, refs and indexes can be changed all at once or not at all.
+//! Writes prepared ahead, without reads, can be collected in a [MultiSchemaBatch] instead.
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use sled::Batch;
+use sled::transaction::{Transactional, TransactionError, TransactionalTree};
+pub use sled::transaction::ConflictableTransactionError;
+
+use crate::codec::{Encoder, SchemaError};
+use crate::database::{DBError, SledDBWrapper};
+use crate::schema::{decode_value, KeyValueSchema};
+
+/// Result of operations inside a transaction. Returning [ConflictableTransactionError::Abort]
+/// from the transaction closure rolls back all its changes.
+pub type TransactionResult<T> = Result<T, ConflictableTransactionError<DBError>>;
+
+/// Typed access to trees of schemas taking part in a transaction, see
+/// [SledDBWrapper::transaction]. Reads see writes done earlier in the same transaction.
+pub struct SchemaTransaction<'a> {
+    db: &'a SledDBWrapper,
+    tree_names: &'a [Option<&'static str>],
+    trees: &'a [TransactionalTree],
+}
+
+impl<'a> SchemaTransaction<'a> {
+    /// Insert key value pair, overriding existing value if exists.
+    pub fn put<S: KeyValueSchema>(&self, key: &S::Key, value: &S::Value) -> TransactionResult<()> {
+        let key = key.encode().map_err(abort_on_schema_error)?;
+        let value = self.db.encode_value::<S>(value).map_err(ConflictableTransactionError::Abort)?;
+        self.tree::<S>()?.insert(key, value)?;
+        Ok(())
+    }
+
+    /// Read value associated with given key, if exists.
+    pub fn get<S: KeyValueSchema>(&self, key: &S::Key) -> TransactionResult<Option<S::Value>> {
+        let key = key.encode().map_err(abort_on_schema_error)?;
+        match self.tree::<S>()?.get(key)? {
+            Some(value) => {
+                let value = self.db.decode_stored::<S>(value).map_err(ConflictableTransactionError::Abort)?;
+                Ok(Some(decode_value::<S>(&value).map_err(abort_on_schema_error)?.0))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Delete value associated with given key, if exists.
+    pub fn delete<S: KeyValueSchema>(&self, key: &S::Key) -> TransactionResult<()> {
+        let key = key.encode().map_err(abort_on_schema_error)?;
+        self.tree::<S>()?.remove(key)?;
+        Ok(())
+    }
+
+    /// Typed view of schema `S` in this transaction
+    pub fn store<S: KeyValueSchema>(&self) -> TransactionalStore<'_, S> {
+        TransactionalStore { tx: self, _schema: PhantomData }
+    }
+
+    fn tree<S: KeyValueSchema>(&self) -> TransactionResult<&TransactionalTree> {
+        match self.tree_names.iter().position(|name| *name == self.db.schema_tree_name::<S>()) {
+            Some(idx) => Ok(&self.trees[idx]),
+            None => Err(ConflictableTransactionError::Abort(DBError::SchemaNotInTransaction { schema: S::name() })),
+        }
+    }
+
+    /// Tree taking part in the transaction, `tree_name` has to be one of them
+    fn tree_named(&self, tree_name: Option<&'static str>) -> &TransactionalTree {
+        let idx = self.tree_names.iter().position(|name| *name == tree_name).expect("tree is part of the transaction");
+        &self.trees[idx]
+    }
+}
+
+/// Access to a single schema inside a transaction, see [SledDBWrapper::schema_transaction]
+pub struct TransactionalStore<'a, S: KeyValueSchema> {
+    tx: &'a SchemaTransaction<'a>,
+    _schema: PhantomData<S>,
+}
+
+impl<'a, S: KeyValueSchema> TransactionalStore<'a, S> {
+    /// Insert key value pair, overriding existing value if exists.
+    pub fn put(&self, key: &S::Key, value: &S::Value) -> TransactionResult<()> {
+        self.tx.put::<S>(key, value)
+    }
+
+    /// Read value associated with given key, if exists.
+    pub fn get(&self, key: &S::Key) -> TransactionResult<Option<S::Value>> {
+        self.tx.get::<S>(key)
+    }
+
+    /// Delete value associated with given key, if exists.
+    pub fn delete(&self, key: &S::Key) -> TransactionResult<()> {
+        self.tx.delete::<S>(key)
+    }
+}
+
+/// Writes to trees of several schemas, applied all at once by [SledDBWrapper::apply_multi], or
+/// [MerkleBackend::apply_multi](crate::backend::MerkleBackend::apply_multi) of another backend.
+/// Writes may be made conditional on current values of other keys, e.g. to move a ref
+/// together with the entries it points to.
+#[derive(Default)]
+pub struct MultiSchemaBatch {
+    // in order of addition, a later write of a key replaces an earlier one
+    pub(crate) writes: Vec<SchemaWrite>,
+    pub(crate) expectations: Vec<Expectation>,
+}
+
+impl MultiSchemaBatch {
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty() && self.expectations.is_empty()
+    }
+
+    /// Add insert of key value pair of schema `S`.
+    pub fn put<S: KeyValueSchema>(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        self.writes.push(SchemaWrite::of::<S>(key.encode()?, Some(value.encode()?)));
+        Ok(())
+    }
+
+    /// Add delete of key of schema `S`.
+    pub fn delete<S: KeyValueSchema>(&mut self, key: &S::Key) -> Result<(), DBError> {
+        self.writes.push(SchemaWrite::of::<S>(key.encode()?, None));
+        Ok(())
+    }
+
+    /// Apply the batch only if `key` of schema `S` holds `expected` (`None` for a missing key),
+    /// otherwise applying it fails with [DBError::BatchPreconditionFailed].
+    pub fn expect<S: KeyValueSchema>(&mut self, key: &S::Key, expected: Option<&S::Value>) -> Result<(), DBError> {
+        self.expectations.push(SchemaWrite::of::<S>(key.encode()?, expected.map(|value| value.encode()).transpose()?));
+        Ok(())
+    }
+
+    /// Writes in order of addition, each as (schema name, [KeyValueSchema::tree_name], encoded
+    /// key, encoded value or `None` for a delete), e.g. for other backends to apply them
+    pub fn writes(&self) -> impl Iterator<Item = BatchWrite<'_>> {
+        self.writes.iter().map(SchemaWrite::view)
+    }
+
+    /// Values keys have to hold for the batch to be applied, in the form of
+    /// [MultiSchemaBatch::writes], `None` for a missing key
+    pub fn expectations(&self) -> impl Iterator<Item = BatchWrite<'_>> {
+        self.expectations.iter().map(SchemaWrite::view)
+    }
+
+    /// Writes in their stored form grouped into a sled batch per tree of `db`
+    fn sled_batches(&self, db: &SledDBWrapper) -> Result<HashMap<Option<&'static str>, Batch>, DBError> {
+        let mut batches: HashMap<Option<&'static str>, Batch> = HashMap::new();
+        for write in &self.writes {
+            let batch = batches.entry(db.tree_name_of(write.schema, write.tree_name)).or_default();
+            match &write.value {
+                Some(value) => batch.insert(write.key.as_slice(), db.encode_stored(write.schema, value.clone())?),
+                None => batch.remove(write.key.as_slice()),
+            }
+        }
+        Ok(batches)
+    }
+}
+
+/// Key of a schema with its encoded value, before it passes through a value pipeline. `None`
+/// stands for a missing key.
+pub(crate) struct SchemaWrite {
+    pub(crate) schema: &'static str,
+    /// [KeyValueSchema::tree_name] of the schema
+    pub(crate) tree_name: Option<&'static str>,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Option<Vec<u8>>,
+}
+
+impl SchemaWrite {
+    fn of<S: KeyValueSchema>(key: Vec<u8>, value: Option<Vec<u8>>) -> Self {
+        SchemaWrite { schema: S::name(), tree_name: S::tree_name(), key, value }
+    }
+
+    fn view(&self) -> BatchWrite<'_> {
+        (self.schema, self.tree_name, &self.key, self.value.as_deref())
+    }
+}
+
+/// Schema name, tree name, key and value of a write of [MultiSchemaBatch], see
+/// [MultiSchemaBatch::writes]
+pub type BatchWrite<'a> = (&'static str, Option<&'static str>, &'a [u8], Option<&'a [u8]>);
+
+/// Value a key has to hold for [MultiSchemaBatch] to be applied
+pub(crate) type Expectation = SchemaWrite;
+
+fn abort_on_schema_error(error: SchemaError) -> ConflictableTransactionError<DBError> {
+    ConflictableTransactionError::Abort(DBError::SchemaError { error })
+}
+
+impl SledDBWrapper {
+    /// Run `f` in a single transaction over trees of given schemas, identified by their
+    /// [SledDBWrapper::schema_tree_name], e.g.
+    /// `&[db.schema_tree_name::<RefSchema>(), db.schema_tree_name::<MetadataSchema>()]`.
+    /// Schemas sharing a tree need to be listed once. The closure may be run several times
+    /// when it conflicts with concurrent writes, so it should have no side effects.
+    pub fn transaction<R, F>(&self, tree_names: &[Option<&'static str>], f: F) -> Result<R, DBError>
+        where F: Fn(&SchemaTransaction) -> TransactionResult<R>
+    {
+        self.before_write()?;
+        // transactions don't see coalesced puts
+        self.flush_writes()?;
+        let mut unique_names: Vec<Option<&'static str>> = Vec::with_capacity(tree_names.len());
+        for name in tree_names {
+            if !unique_names.contains(name) {
+                unique_names.push(*name);
+            }
+        }
+        let trees = unique_names.iter()
+            .map(|name| self.tree_named(*name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let result = trees.as_slice().transaction(|trees| {
+            f(&SchemaTransaction { db: self, tree_names: &unique_names, trees })
+        });
+        match result {
+            Ok(value) => Ok(value),
+            Err(TransactionError::Abort(error)) => Err(error),
+            Err(TransactionError::Storage(error)) => Err(DBError::SledError { error }),
+        }
+    }
+
+    /// Add insert of key value pair of schema `S` to `batch`, see [MultiSchemaBatch::put].
+    pub fn put_schema_batch<S: KeyValueSchema>(&self, batch: &mut MultiSchemaBatch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        batch.put::<S>(key, value)
+    }
+
+    /// Add delete of key of schema `S` to `batch`, see [MultiSchemaBatch::delete].
+    pub fn delete_schema_batch<S: KeyValueSchema>(&self, batch: &mut MultiSchemaBatch, key: &S::Key) -> Result<(), DBError> {
+        batch.delete::<S>(key)
+    }
+
+    /// Apply `batch` only if `key` of schema `S` holds `expected`, see [MultiSchemaBatch::expect].
+    pub fn expect_schema_batch<S: KeyValueSchema>(&self, batch: &mut MultiSchemaBatch, key: &S::Key, expected: Option<&S::Value>) -> Result<(), DBError> {
+        batch.expect::<S>(key, expected)
+    }
+
+    /// Apply `batch` in a single transaction over all trees it touches, so either all its
+    /// writes are persisted or none of them.
+    pub fn apply_multi(&self, batch: MultiSchemaBatch) -> Result<(), DBError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let tree_name = |write: &SchemaWrite| self.tree_name_of(write.schema, write.tree_name);
+        let trees = batch.sled_batches(self)?;
+        let tree_names: Vec<_> = trees.keys().copied()
+            .chain(batch.expectations.iter().map(tree_name))
+            .collect();
+        self.transaction(&tree_names, |tx| {
+            for expectation in &batch.expectations {
+                let current = match tx.tree_named(tree_name(expectation)).get(&expectation.key)? {
+                    Some(stored) => Some(self.decode_stored_named(expectation.schema, stored).map_err(ConflictableTransactionError::Abort)?),
+                    None => None,
+                };
+                if current.as_deref() != expectation.value.as_deref() {
+                    return Err(ConflictableTransactionError::Abort(DBError::BatchPreconditionFailed { schema: expectation.schema }));
+                }
+            }
+            for (tree_name, batch) in &trees {
+                tx.tree_named(*tree_name).apply_batch(batch)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Run `f` in a single transaction over schema `S`, e.g. to read, modify and write back
+    /// several keys atomically. See [SledDBWrapper::transaction] for transactions spanning
+    /// several schemas.
+    pub fn schema_transaction<S, R, F>(&self, f: F) -> Result<R, DBError>
+        where S: KeyValueSchema,
+              F: Fn(&TransactionalStore<S>) -> TransactionResult<R>
+    {
+        self.transaction(&[self.schema_tree_name::<S>()], |tx| f(&tx.store::<S>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::KeyValueStoreWithSchema;
+
+    struct NumberSchema;
+
+    impl KeyValueSchema for NumberSchema {
+        type Key = u64;
+        type Value = String;
+
+        fn name() -> &'static str {
+            "test_numbers"
+        }
+
+        fn tree_name() -> Option<&'static str> {
+            Some(Self::name())
+        }
+    }
+
+    struct NameSchema;
+
+    impl KeyValueSchema for NameSchema {
+        type Key = String;
+        type Value = u64;
+
+        fn name() -> &'static str {
+            "test_names"
+        }
+    }
+
+    fn get_db() -> SledDBWrapper {
+        SledDBWrapper::new(sled::Config::new().temporary(true).open().expect("error opening database"))
+    }
+
+    #[test]
+    fn test_transaction() -> Result<(), DBError> {
+        let db = get_db();
+        let schemas = [NumberSchema::tree_name(), NameSchema::tree_name()];
+
+        let previous = db.transaction(&schemas, |tx| {
+            tx.put::<NumberSchema>(&1, &"one".to_string())?;
+            tx.put::<NameSchema>(&"one".to_string(), &1)?;
+            tx.get::<NumberSchema>(&1)
+        })?;
+        assert_eq!(Some("one".to_string()), previous);
+        assert_eq!(Some(1), KeyValueStoreWithSchema::<NameSchema>::get(&db, &"one".to_string())?);
+
+        // aborted transaction changes nothing
+        let result: Result<(), DBError> = db.transaction(&schemas, |tx| {
+            tx.delete::<NumberSchema>(&1)?;
+            tx.put::<NameSchema>(&"two".to_string(), &2)?;
+            Err(ConflictableTransactionError::Abort(DBError::SchemaError { error: SchemaError::EncodeError }))
+        });
+        assert!(matches!(result, Err(DBError::SchemaError { .. })));
+        assert_eq!(Some("one".to_string()), KeyValueStoreWithSchema::<NumberSchema>::get(&db, &1)?);
+        assert!(!KeyValueStoreWithSchema::<NameSchema>::contains(&db, &"two".to_string())?);
+
+        let result = db.transaction(&[NameSchema::tree_name()], |tx| tx.get::<NumberSchema>(&1));
+        assert!(matches!(result, Err(DBError::SchemaNotInTransaction { schema: "test_numbers" })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_transaction() -> Result<(), DBError> {
+        let db = get_db();
+        KeyValueStoreWithSchema::<NameSchema>::put(&db, &"a".to_string(), &10)?;
+        KeyValueStoreWithSchema::<NameSchema>::put(&db, &"b".to_string(), &5)?;
+
+        // move 3 from a to b
+        let total = db.schema_transaction::<NameSchema, _, _>(|names| {
+            let a = names.get(&"a".to_string())?.unwrap_or(0);
+            let b = names.get(&"b".to_string())?.unwrap_or(0);
+            names.put(&"a".to_string(), &(a - 3))?;
+            names.put(&"b".to_string(), &(b + 3))?;
+            Ok(a + b)
+        })?;
+        assert_eq!(15, total);
+        assert_eq!(Some(7), KeyValueStoreWithSchema::<NameSchema>::get(&db, &"a".to_string())?);
+        assert_eq!(Some(8), KeyValueStoreWithSchema::<NameSchema>::get(&db, &"b".to_string())?);
+
+        let result: Result<(), DBError> = db.schema_transaction::<NameSchema, _, _>(|names| {
+            names.delete(&"a".to_string())?;
+            Err(ConflictableTransactionError::Abort(DBError::SchemaError { error: SchemaError::EncodeError }))
+        });
+        assert!(result.is_err());
+        assert_eq!(Some(7), KeyValueStoreWithSchema::<NameSchema>::get(&db, &"a".to_string())?);
+
+        let value = db.transaction(&[NumberSchema::tree_name()], |tx| {
+            tx.store::<NumberSchema>().put(&1, &"one".to_string())?;
+            tx.store::<NumberSchema>().get(&1)
+        })?;
+        assert_eq!(Some("one".to_string()), value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_schema_batch() -> Result<(), DBError> {
+        let db = get_db();
+        let mut batch = MultiSchemaBatch::default();
+        assert!(batch.is_empty());
+        db.put_schema_batch::<NumberSchema>(&mut batch, &1, &"one".to_string())?;
+        db.put_schema_batch::<NameSchema>(&mut batch, &"one".to_string(), &1)?;
+        db.expect_schema_batch::<NameSchema>(&mut batch, &"head".to_string(), None)?;
+        db.put_schema_batch::<NameSchema>(&mut batch, &"head".to_string(), &1)?;
+        db.apply_multi(batch)?;
+        assert_eq!(Some("one".to_string()), KeyValueStoreWithSchema::<NumberSchema>::get(&db, &1)?);
+        assert_eq!(Some(1), KeyValueStoreWithSchema::<NameSchema>::get(&db, &"head".to_string())?);
+
+        // head moved, nothing is written
+        let mut batch = MultiSchemaBatch::default();
+        db.delete_schema_batch::<NumberSchema>(&mut batch, &1)?;
+        db.expect_schema_batch::<NameSchema>(&mut batch, &"head".to_string(), Some(&2))?;
+        assert!(matches!(db.apply_multi(batch), Err(DBError::BatchPreconditionFailed { schema: "test_names" })));
+        assert!(KeyValueStoreWithSchema::<NumberSchema>::contains(&db, &1)?);
+
+        let mut batch = MultiSchemaBatch::default();
+        db.expect_schema_batch::<NameSchema>(&mut batch, &"head".to_string(), Some(&1))?;
+        db.delete_schema_batch::<NumberSchema>(&mut batch, &1)?;
+        db.apply_multi(batch)?;
+        assert!(!KeyValueStoreWithSchema::<NumberSchema>::contains(&db, &1)?);
+        Ok(())
+    }
+}
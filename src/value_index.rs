@@ -0,0 +1,64 @@
+//! Index of context keys by hash of their value.
+//!
+//! When enabled, keys of the head context are recorded under the hash of their value, so keys
+//! holding a given value can be found without scanning the context, e.g. for deduplication
+//! analysis. The index is a set of `(value hash, key)` pairs stored with empty values, updated
+//! by diffing the indexed root with the new head on every commit and checkout.
+use crate::codec::{Decoder, Encoder, SchemaError};
+use crate::database::KeyValueStoreWithSchema;
+use crate::merkle_storage::EntryHash;
+use crate::schema::KeyValueSchema;
+
+/// Key of the root hash of the indexed context in the metadata tree
+pub const VALUE_HASH_INDEX_ROOT_KEY: &str = "value_hash_index_root";
+
+const VALUE_HASH_LEN: usize = std::mem::size_of::<EntryHash>();
+
+pub type ValueHashIndexKV = dyn KeyValueStoreWithSchema<ValueHashIndexSchema> + Sync + Send;
+
+/// Context key holding a value with given hash, encoded as the hash followed by the slash
+/// separated key. Key with an empty `key` encodes to a prefix of all keys of `value_hash`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueHashIndexKey {
+    pub value_hash: EntryHash,
+    pub key: String,
+}
+
+impl Encoder for ValueHashIndexKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut bytes = Vec::with_capacity(VALUE_HASH_LEN + self.key.len());
+        bytes.extend_from_slice(&self.value_hash);
+        bytes.extend_from_slice(self.key.as_bytes());
+        Ok(bytes)
+    }
+}
+
+impl Decoder for ValueHashIndexKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() < VALUE_HASH_LEN {
+            return Err(SchemaError::DecodeError);
+        }
+        let mut value_hash = EntryHash::default();
+        value_hash.copy_from_slice(&bytes[..VALUE_HASH_LEN]);
+        let key = String::from_utf8(bytes[VALUE_HASH_LEN..].to_vec()).map_err(|_| SchemaError::DecodeError)?;
+        Ok(ValueHashIndexKey { value_hash, key })
+    }
+}
+
+/// Set of `(value hash, key)` pairs of the indexed context
+pub struct ValueHashIndexSchema;
+
+impl KeyValueSchema for ValueHashIndexSchema {
+    type Key = ValueHashIndexKey;
+    type Value = ();
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage_value_hash_index"
+    }
+
+    #[inline]
+    fn tree_name() -> Option<&'static str> {
+        Some(Self::name())
+    }
+}
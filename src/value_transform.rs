@@ -0,0 +1,253 @@
+//! Transformations of stored values.
+//!
+//! Values of a schema can be passed through a [ValuePipeline] of [ValueTransform] stages
+//! (compression, encryption, checksum, ...) on their way to the database, see
+//! [SledDBWrapper::with_value_pipeline](crate::database::SledDBWrapper::with_value_pipeline).
+//! Stages are applied in the configured order and reversed on read. Every stored value starts
+//! with a header listing ids of the applied stages, so values written by a different pipeline,
+//! e.g. before a stage was added, stay readable as long as the pipeline knows their stages.
+use std::sync::Arc;
+
+use failure::Fail;
+use sodiumoxide::crypto::secretbox;
+
+use crate::blake2b;
+
+/// Id of [Encryption] stage
+pub const ENCRYPTION_STAGE_ID: u8 = 1;
+
+/// Id of [Checksum] stage
+pub const CHECKSUM_STAGE_ID: u8 = 2;
+
+/// Id of [EntryCodecs](crate::entry_codecs::EntryCodecs) stage
+pub const ENTRY_CODECS_STAGE_ID: u8 = 3;
+
+/// Ids from this one up are free for stages implemented outside of this crate
+pub const FIRST_CUSTOM_STAGE_ID: u8 = 128;
+
+const CHECKSUM_LEN: usize = 16;
+
+#[derive(Debug, Fail)]
+pub enum TransformError {
+    #[fail(display = "Header of transformed value is malformed.")]
+    InvalidHeader,
+    #[fail(display = "Value was transformed by stage {}, which is not in the pipeline.", id)]
+    UnknownStage { id: u8 },
+    #[fail(display = "Checksum of value does not match.")]
+    ChecksumMismatch,
+    #[fail(display = "Value cannot be decrypted.")]
+    DecryptionFailed,
+    #[fail(display = "Stage {} failed: {}.", id, reason)]
+    StageFailed { id: u8, reason: String },
+}
+
+/// What a stage does to values, reported by [MerkleStorage::capabilities](crate::merkle_storage::MerkleStorage::capabilities)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransformKind {
+    Compression,
+    Encryption,
+    Checksum,
+    Other,
+}
+
+/// Single reversible stage of a [ValuePipeline]
+pub trait ValueTransform: Send + Sync {
+    /// Identifier recorded in headers of values, must be stable across releases
+    fn id(&self) -> u8;
+
+    fn kind(&self) -> TransformKind;
+
+    fn apply(&self, value: &[u8]) -> Result<Vec<u8>, TransformError>;
+
+    fn reverse(&self, value: &[u8]) -> Result<Vec<u8>, TransformError>;
+}
+
+/// Ordered stages applied to values of a schema, e.g.
+/// `ValuePipeline::new().then(compression).then(Encryption::new(key)).then(Checksum)`
+#[derive(Clone, Default)]
+pub struct ValuePipeline {
+    stages: Vec<Arc<dyn ValueTransform>>,
+}
+
+impl ValuePipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `stage`, it is applied after all stages added before.
+    ///
+    /// # Panics
+    /// If the pipeline already contains a stage with the same id.
+    pub fn then<T: ValueTransform + 'static>(mut self, stage: T) -> Self {
+        assert!(self.stage(stage.id()).is_none(), "duplicate value transform stage {}", stage.id());
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    /// Kinds of stages in order of application
+    pub fn kinds(&self) -> Vec<TransformKind> {
+        self.stages.iter().map(|stage| stage.kind()).collect()
+    }
+
+    /// Apply all stages to `value` and prepend header recording them.
+    pub fn encode(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let mut transformed = value.to_vec();
+        for stage in &self.stages {
+            transformed = stage.apply(&transformed)?;
+        }
+        let mut encoded = Vec::with_capacity(1 + self.stages.len() + transformed.len());
+        encoded.push(self.stages.len() as u8);
+        encoded.extend(self.stages.iter().map(|stage| stage.id()));
+        encoded.extend_from_slice(&transformed);
+        Ok(encoded)
+    }
+
+    /// Reverse stages recorded in header of `encoded` in opposite order.
+    pub fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let (count, rest) = encoded.split_first().ok_or(TransformError::InvalidHeader)?;
+        if rest.len() < *count as usize {
+            return Err(TransformError::InvalidHeader);
+        }
+        let (ids, transformed) = rest.split_at(*count as usize);
+        let mut value = transformed.to_vec();
+        for id in ids.iter().rev() {
+            let stage = self.stage(*id).ok_or(TransformError::UnknownStage { id: *id })?;
+            value = stage.reverse(&value)?;
+        }
+        Ok(value)
+    }
+
+    fn stage(&self, id: u8) -> Option<&Arc<dyn ValueTransform>> {
+        self.stages.iter().find(|stage| stage.id() == id)
+    }
+}
+
+/// Authenticated encryption with a secret key (XSalsa20-Poly1305), every value gets a random nonce
+pub struct Encryption {
+    key: secretbox::Key,
+}
+
+impl Encryption {
+    pub fn new(key: [u8; secretbox::KEYBYTES]) -> Self {
+        Encryption { key: secretbox::Key(key) }
+    }
+}
+
+impl ValueTransform for Encryption {
+    fn id(&self) -> u8 {
+        ENCRYPTION_STAGE_ID
+    }
+
+    fn kind(&self) -> TransformKind {
+        TransformKind::Encryption
+    }
+
+    fn apply(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let nonce = secretbox::gen_nonce();
+        let mut sealed = nonce.0.to_vec();
+        sealed.extend(secretbox::seal(value, &nonce, &self.key));
+        Ok(sealed)
+    }
+
+    fn reverse(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        if value.len() < secretbox::NONCEBYTES {
+            return Err(TransformError::DecryptionFailed);
+        }
+        let (nonce, sealed) = value.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce).ok_or(TransformError::DecryptionFailed)?;
+        secretbox::open(sealed, &nonce, &self.key).map_err(|_| TransformError::DecryptionFailed)
+    }
+}
+
+/// Blake2b checksum appended to values, detects corruption on read
+pub struct Checksum;
+
+impl ValueTransform for Checksum {
+    fn id(&self) -> u8 {
+        CHECKSUM_STAGE_ID
+    }
+
+    fn kind(&self) -> TransformKind {
+        TransformKind::Checksum
+    }
+
+    fn apply(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let mut checked = value.to_vec();
+        checked.extend(blake2b::digest_128(value));
+        Ok(checked)
+    }
+
+    fn reverse(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+        if value.len() < CHECKSUM_LEN {
+            return Err(TransformError::ChecksumMismatch);
+        }
+        let (value, checksum) = value.split_at(value.len() - CHECKSUM_LEN);
+        if blake2b::digest_128(value) != checksum {
+            return Err(TransformError::ChecksumMismatch);
+        }
+        Ok(value.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reverses bytes, stands in for a compression stage
+    struct Reverse;
+
+    impl ValueTransform for Reverse {
+        fn id(&self) -> u8 {
+            FIRST_CUSTOM_STAGE_ID
+        }
+
+        fn kind(&self) -> TransformKind {
+            TransformKind::Compression
+        }
+
+        fn apply(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+            Ok(value.iter().rev().copied().collect())
+        }
+
+        fn reverse(&self, value: &[u8]) -> Result<Vec<u8>, TransformError> {
+            self.apply(value)
+        }
+    }
+
+    #[test]
+    fn test_pipeline_roundtrip() -> Result<(), TransformError> {
+        let pipeline = ValuePipeline::new().then(Reverse).then(Encryption::new([7; 32])).then(Checksum);
+        assert_eq!(vec![TransformKind::Compression, TransformKind::Encryption, TransformKind::Checksum], pipeline.kinds());
+
+        let value = b"hello world".to_vec();
+        let encoded = pipeline.encode(&value)?;
+        assert_eq!(&[3, FIRST_CUSTOM_STAGE_ID, ENCRYPTION_STAGE_ID, CHECKSUM_STAGE_ID], &encoded[..4]);
+        assert_eq!(value, pipeline.decode(&encoded)?);
+        assert!(pipeline.decode(&[]).is_err());
+
+        // values written by a shorter pipeline stay readable
+        let old = ValuePipeline::new().then(Checksum).encode(&value)?;
+        assert_eq!(value, pipeline.decode(&old)?);
+        assert_eq!(value, pipeline.decode(&ValuePipeline::new().encode(&value)?)?);
+        assert!(matches!(ValuePipeline::new().decode(&old), Err(TransformError::UnknownStage { id: CHECKSUM_STAGE_ID })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_values() -> Result<(), TransformError> {
+        let mut checked = ValuePipeline::new().then(Checksum).encode(b"value")?;
+        checked[3] ^= 1;
+        assert!(matches!(ValuePipeline::new().then(Checksum).decode(&checked), Err(TransformError::ChecksumMismatch)));
+
+        let encrypted = ValuePipeline::new().then(Encryption::new([1; 32])).encode(b"value")?;
+        let other_key = ValuePipeline::new().then(Encryption::new([2; 32]));
+        assert!(matches!(other_key.decode(&encrypted), Err(TransformError::DecryptionFailed)));
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_duplicate_stage() {
+        ValuePipeline::new().then(Checksum).then(Checksum);
+    }
+}
@@ -0,0 +1,132 @@
+//! [MerkleBackend] implemented outside of the crate
+use std::sync::{Arc, Mutex};
+
+use merkle_storage::prelude::{BatchWrite, DBError, DBStats, InMemoryStore, IteratorMode, IteratorWithSchema, IVec, KeyValueSchema,
+                               KeyValueStoreWithSchema, MerkleBackend, MerkleStorage, MerkleStorageConfig, MultiSchemaBatch, RefSchema,
+                               SchemaSubscriber, StoredEntries};
+use sled::Batch;
+
+/// Write of a batch in owned form: schema name, tree name, key and value
+type LoggedWrite = (&'static str, Option<&'static str>, Vec<u8>, Option<Vec<u8>>);
+
+/// Backend keeping data in an [InMemoryStore], which logs writes and expectations of applied
+/// batches, e.g. to ship them to a replica
+#[derive(Default)]
+struct ReplicatedStore {
+    inner: InMemoryStore,
+    writes: Mutex<Vec<LoggedWrite>>,
+    expectations: Mutex<Vec<LoggedWrite>>,
+}
+
+fn owned(write: BatchWrite) -> LoggedWrite {
+    let (schema, tree_name, key, value) = write;
+    (schema, tree_name, key.to_vec(), value.map(|value| value.to_vec()))
+}
+
+impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for ReplicatedStore {
+    fn put(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::put(&self.inner, key, value)
+    }
+
+    fn delete(&self, key: &S::Key) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::delete(&self.inner, key)
+    }
+
+    fn merge(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::merge(&self.inner, key, value)
+    }
+
+    fn compare_and_swap(&self, key: &S::Key, expected: Option<&S::Value>, new: Option<&S::Value>) -> Result<Result<(), Option<S::Value>>, DBError> {
+        KeyValueStoreWithSchema::<S>::compare_and_swap(&self.inner, key, expected, new)
+    }
+
+    fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
+        KeyValueStoreWithSchema::<S>::get(&self.inner, key)
+    }
+
+    fn get_raw(&self, key: &S::Key) -> Result<Option<IVec>, DBError> {
+        KeyValueStoreWithSchema::<S>::get_raw(&self.inner, key)
+    }
+
+    fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<'_, S>, DBError> {
+        KeyValueStoreWithSchema::<S>::iterator(&self.inner, mode)
+    }
+
+    fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<'_, S>, DBError> {
+        KeyValueStoreWithSchema::<S>::prefix_iterator(&self.inner, key)
+    }
+
+    fn watch_prefix(&self, key: &S::Key) -> Result<SchemaSubscriber<S>, DBError> {
+        KeyValueStoreWithSchema::<S>::watch_prefix(&self.inner, key)
+    }
+
+    fn contains(&self, key: &S::Key) -> Result<bool, DBError> {
+        KeyValueStoreWithSchema::<S>::contains(&self.inner, key)
+    }
+
+    fn put_batch(&self, batch: &mut Batch, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::put_batch(&self.inner, batch, key, value)
+    }
+
+    fn write_batch(&self, batch: Batch) -> Result<(), DBError> {
+        KeyValueStoreWithSchema::<S>::write_batch(&self.inner, batch)
+    }
+
+    fn get_mem_use_stats(&self) -> Result<DBStats, DBError> {
+        KeyValueStoreWithSchema::<S>::get_mem_use_stats(&self.inner)
+    }
+
+    fn split_points(&self, n: usize) -> Result<Vec<S::Key>, DBError> {
+        KeyValueStoreWithSchema::<S>::split_points(&self.inner, n)
+    }
+}
+
+impl MerkleBackend for ReplicatedStore {
+    fn apply_multi(&self, batch: MultiSchemaBatch) -> Result<(), DBError> {
+        let writes: Vec<_> = batch.writes().map(owned).collect();
+        let expectations: Vec<_> = batch.expectations().map(owned).collect();
+        self.inner.apply_multi(batch)?;
+        self.writes.lock().unwrap().extend(writes);
+        self.expectations.lock().unwrap().extend(expectations);
+        Ok(())
+    }
+
+    fn stored_entries(&self) -> Result<StoredEntries<'_>, DBError> {
+        self.inner.stored_entries()
+    }
+}
+
+#[test]
+fn test_external_backend() {
+    let db = Arc::new(ReplicatedStore::default());
+    let config = MerkleStorageConfig { auto_advance_ref: Some("main".to_string()), ..MerkleStorageConfig::default() };
+    let mut storage = MerkleStorage::with_config(db.clone(), config).unwrap();
+    storage.set(&vec!["a".to_string()], &vec![1u8]).unwrap();
+    let commit = storage.commit(0, "".to_string(), "".to_string()).unwrap();
+    assert_eq!(Some(commit), storage.get_ref("main").unwrap());
+
+    let writes = db.writes.lock().unwrap();
+    assert!(writes.iter().any(|(schema, tree_name, key, value)| {
+        *schema == RefSchema::name() && *tree_name == RefSchema::tree_name() && key == b"main" && value.as_deref() == Some(&commit[..])
+    }));
+    // ref did not exist before the first commit
+    let expectations = db.expectations.lock().unwrap();
+    assert_eq!(vec![(RefSchema::name(), RefSchema::tree_name(), "main".as_bytes().to_vec(), None)], *expectations);
+}
+
+#[test]
+fn test_external_backend_split_points() {
+    let db = Arc::new(ReplicatedStore::default());
+    let mut storage = MerkleStorage::new(db.clone()).unwrap();
+    for i in 0..20 {
+        storage.set(&vec!["data".to_string(), i.to_string()], &vec![i as u8]).unwrap();
+    }
+    storage.commit(0, "".to_string(), "".to_string()).unwrap();
+
+    // audit is split into ranges at the points of the wrapped store
+    let points = KeyValueStoreWithSchema::<MerkleStorage>::split_points(db.as_ref(), 4).unwrap();
+    assert_eq!(3, points.len());
+    let report = storage.audit_entries(4).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(KeyValueStoreWithSchema::<MerkleStorage>::iterator(db.as_ref(), IteratorMode::Start).unwrap().count() as u64, report.entries_checked);
+}